@@ -0,0 +1,173 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A client sending requests to the `AvailabilityStoreSubsystem` and awaiting their answers.
+
+use futures::{channel::oneshot, Sink, SinkExt};
+
+use polkadot_primitives::v1::{
+	AvailableData, BlockNumber, CandidateHash, ErasureChunk, Hash, ValidatorIndex,
+};
+use polkadot_subsystem::messages::{
+	AllMessages, AvailabilityStoreMessage, ErasureCodingVersion, StoreError,
+};
+
+/// Errors of requests made through an `AvailabilityStoreClient`.
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+	/// The request could not be sent.
+	#[error("Failed to send the request to the availability store")]
+	Send,
+
+	/// The availability store dropped the request without answering it.
+	#[error(transparent)]
+	Oneshot(#[from] oneshot::Canceled),
+
+	/// The availability store failed to store the data.
+	#[error(transparent)]
+	Store(#[from] StoreError),
+}
+
+/// Sends requests to the availability store through anything that accepts `AllMessages`,
+/// e.g. the sending half of a channel to the overseer, and awaits their answers.
+///
+/// This requires a `Sink` of `AllMessages`. Within a subsystem, messages are sent through
+/// `SubsystemContext::send_message` instead, which this client does not wrap.
+///
+/// Only the most common queries and stores have methods of their own. Any other request is
+/// sent with [`AvailabilityStoreClient::request`].
+pub struct AvailabilityStoreClient<S> {
+	sender: S,
+}
+
+impl<S> AvailabilityStoreClient<S>
+where
+	S: Sink<AllMessages> + Unpin,
+{
+	/// Create a new client sending its requests through `sender`.
+	pub fn new(sender: S) -> Self {
+		Self { sender }
+	}
+
+	/// Consume the client, returning the underlying sender.
+	pub fn into_inner(self) -> S {
+		self.sender
+	}
+
+	/// Send the message built by `make_msg` around the sender of its answer, and await it.
+	///
+	/// This covers the requests without a method of their own, e.g.
+	/// `client.request(|tx| AvailabilityStoreMessage::QueryHealth(tx))`.
+	pub async fn request<T>(
+		&mut self,
+		make_msg: impl FnOnce(oneshot::Sender<T>) -> AvailabilityStoreMessage,
+	) -> Result<T, ClientError> {
+		let (tx, rx) = oneshot::channel();
+
+		self.sender
+			.send(AllMessages::AvailabilityStore(make_msg(tx)))
+			.await
+			.map_err(|_| ClientError::Send)?;
+
+		Ok(rx.await?)
+	}
+
+	/// Query the `AvailableData` of a candidate.
+	pub async fn query_available_data(
+		&mut self,
+		candidate_hash: CandidateHash,
+	) -> Result<Option<AvailableData>, ClientError> {
+		self.request(|tx| AvailabilityStoreMessage::QueryAvailableData(candidate_hash, tx)).await
+	}
+
+	/// Query whether the `AvailableData` of a candidate is stored.
+	pub async fn query_data_availability(
+		&mut self,
+		candidate_hash: CandidateHash,
+	) -> Result<bool, ClientError> {
+		self.request(|tx| AvailabilityStoreMessage::QueryDataAvailability(candidate_hash, tx)).await
+	}
+
+	/// Query the `ErasureChunk` of a candidate for a validator.
+	pub async fn query_chunk(
+		&mut self,
+		candidate_hash: CandidateHash,
+		validator_index: ValidatorIndex,
+	) -> Result<Option<ErasureChunk>, ClientError> {
+		self.request(|tx| AvailabilityStoreMessage::QueryChunk(candidate_hash, validator_index, tx)).await
+	}
+
+	/// Query whether the `ErasureChunk` of a candidate for a validator is stored.
+	pub async fn query_chunk_availability(
+		&mut self,
+		candidate_hash: CandidateHash,
+		validator_index: ValidatorIndex,
+	) -> Result<bool, ClientError> {
+		self.request(|tx| AvailabilityStoreMessage::QueryChunkAvailability(
+			candidate_hash,
+			validator_index,
+			tx,
+		)).await
+	}
+
+	/// Store an `ErasureChunk`.
+	///
+	/// See `AvailabilityStoreMessage::StoreChunk` for the meaning of the arguments.
+	pub async fn store_chunk(
+		&mut self,
+		candidate_hash: CandidateHash,
+		relay_parent: Hash,
+		block_number: Option<BlockNumber>,
+		validator_index: ValidatorIndex,
+		chunk: ErasureChunk,
+	) -> Result<(), ClientError> {
+		self.request(|tx| AvailabilityStoreMessage::StoreChunk {
+			candidate_hash,
+			relay_parent,
+			block_number,
+			validator_index,
+			chunk,
+			tx,
+		}).await??;
+
+		Ok(())
+	}
+
+	/// Store an `AvailableData` erasure-coded for `n_validators` with `coding_version`.
+	///
+	/// See `AvailabilityStoreMessage::StoreAvailableData` for the meaning of the arguments.
+	pub async fn store_available_data(
+		&mut self,
+		candidate_hash: CandidateHash,
+		own_index: Option<ValidatorIndex>,
+		n_validators: u32,
+		available_data: AvailableData,
+		expected_erasure_root: Option<Hash>,
+		coding_version: ErasureCodingVersion,
+	) -> Result<(), ClientError> {
+		self.request(|tx| AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			own_index,
+			n_validators,
+			available_data,
+			expected_erasure_root,
+			coding_version,
+			tx,
+		)).await??;
+
+		Ok(())
+	}
+}
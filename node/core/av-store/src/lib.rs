@@ -20,20 +20,26 @@
 #![warn(missing_docs)]
 
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque, hash_map::RandomState};
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, SystemTimeError, UNIX_EPOCH};
 
-use parity_scale_codec::{Encode, Decode};
-use futures::{select, channel::oneshot, future::{self, Either}, Future, FutureExt};
+use parity_scale_codec::{Encode, Decode, Compact};
+use futures::{
+	select, channel::{mpsc, oneshot}, future::{self, BoxFuture, Either}, stream::{self, FuturesUnordered},
+	Future, FutureExt, SinkExt, StreamExt,
+};
 use futures_timer::Delay;
 use kvdb_rocksdb::{Database, DatabaseConfig};
-use kvdb::{KeyValueDB, DBTransaction};
+use kvdb::{KeyValueDB, DBKey, DBOp, DBTransaction, IoStats, IoStatsKind};
 
 use polkadot_primitives::v1::{
 	Hash, AvailableData, BlockNumber, CandidateEvent, ErasureChunk, ValidatorIndex, CandidateHash,
+	BlakeTwo256, HashT, CandidateReceipt, Id as ParaId,
 };
 use polkadot_subsystem::{
 	FromOverseer, OverseerSignal, SubsystemError, Subsystem, SubsystemContext, SpawnedSubsystem,
@@ -42,9 +48,16 @@ use polkadot_subsystem::{
 };
 use polkadot_node_subsystem_util::metrics::{self, prometheus};
 use polkadot_subsystem::messages::{
-	AllMessages, AvailabilityStoreMessage, ChainApiMessage, RuntimeApiMessage, RuntimeApiRequest,
+	AllMessages, AvailabilityStatus, AvailabilityStoreMessage, ChainApiMessage, ErasureCodingVersion,
+	Health, IntegrityReport, RuntimeApiMessage, RuntimeApiRequest, StorageStats, StoreError,
 };
 
+pub use polkadot_subsystem::messages::CandidateState;
+
+mod client;
+
+pub use client::{AvailabilityStoreClient, ClientError};
+
 const LOG_TARGET: &str = "availability";
 
 mod columns {
@@ -53,6 +66,20 @@ mod columns {
 	pub const NUM_COLUMNS: u32 = 2;
 }
 
+/// The number of columns a database backing the availability store has to provide.
+pub const NUM_COLUMNS: u32 = columns::NUM_COLUMNS;
+
+/// The sub-directory of the substrate database path the availability store is kept in by default.
+pub const DEFAULT_SUBDIR: &str = "parachains/av-store";
+
+/// The number of chunks needed to reconstruct the `AvailableData` of a candidate that was
+/// erasure-coded for `n_validators`, i.e. one more than the number of faulty validators tolerated.
+///
+/// Mirrors `polkadot_erasure_coding::recovery_threshold`, which rejects unsupported validator counts.
+pub fn recovery_threshold(n_validators: usize) -> usize {
+	n_validators.saturating_sub(1) / 3 + 1
+}
+
 #[derive(Debug, thiserror::Error)]
 #[allow(missing_docs)]
 pub enum Error {
@@ -68,6 +95,9 @@ pub enum Error {
 	#[error(transparent)]
 	Io(#[from] io::Error),
 
+	#[error(transparent)]
+	Codec(#[from] parity_scale_codec::Error),
+
 	#[error(transparent)]
 	Oneshot(#[from] oneshot::Canceled),
 
@@ -79,16 +109,88 @@ pub enum Error {
 
 	#[error("Custom databases are not supported")]
 	CustomDatabase,
+
+	#[error("Block number overflow")]
+	BlockNumberOverflow,
+
+	#[error("Relay parent {0} is unknown")]
+	UnknownRelayParent(Hash),
+
+	#[error("Database schema version {found} is newer than the supported version {supported}")]
+	UnsupportedSchemaVersion { found: u32, supported: u32 },
+
+	#[error("Database has {found} columns but {expected} are expected")]
+	ColumnCountMismatch { expected: u32, found: u32 },
+
+	#[error("Chunk does not match the erasure root")]
+	InvalidChunkProof,
+
+	#[error("The availability store is read-only")]
+	ReadOnly,
+
+	#[error("Stored value does not match its checksum")]
+	ChecksumMismatch,
+
+	#[error("Too many stores are waiting to be erasure-coded")]
+	Overloaded,
+
+	#[error("Erasure root {found} does not match the expected {expected}")]
+	ErasureRootMismatch { expected: Hash, found: Hash },
+
+	#[error("Cannot erasure-code data for {0} validators, expected between 2 and {max}", max = MAX_VALIDATORS)]
+	InvalidValidatorCount(u32),
+
+	#[error("Chunk index {index} is out of range for {n_validators} validators")]
+	ChunkIndexOutOfRange { index: ValidatorIndex, n_validators: u32 },
+
+	#[error("Expiry time {0:?} since UNIX_EPOCH has already passed")]
+	ExpiryInThePast(Duration),
+
+	#[error("Failed to process candidate {candidate_hash:?}: {source}")]
+	ForCandidate { candidate_hash: CandidateHash, source: Box<Error> },
 }
 
 impl Error {
 	fn trace(&self) {
-		match self {
+		let (candidate_hash, err) = match self {
+			Self::ForCandidate { candidate_hash, source } => (Some(candidate_hash), &**source),
+			err => (None, err),
+		};
+
+		match err {
 			// don't spam the log with spurious errors
 			Self::RuntimeApi(_) |
-			Self::Oneshot(_) => tracing::debug!(target: LOG_TARGET, err = ?self),
+			Self::Oneshot(_) |
+			Self::Overloaded => tracing::debug!(target: LOG_TARGET, ?candidate_hash, err = ?err),
 			// it's worth reporting otherwise
-			_ => tracing::warn!(target: LOG_TARGET, err = ?self),
+			_ => tracing::warn!(target: LOG_TARGET, ?candidate_hash, err = ?err),
+		}
+	}
+
+	/// Attribute the error to the candidate it occurred for, unless it already is.
+	fn for_candidate(self, candidate_hash: CandidateHash) -> Self {
+		match self {
+			Self::ForCandidate { .. } => self,
+			source => Self::ForCandidate { candidate_hash, source: Box::new(source) },
+		}
+	}
+
+	/// The reason reported back to the requester of a failed store operation.
+	fn store_error(&self) -> StoreError {
+		match self {
+			Self::ForCandidate { source, .. } => source.store_error(),
+			Self::Erasure(_) => StoreError::Erasure,
+			Self::BlockNumberOverflow => StoreError::BlockNumberOverflow,
+			Self::UnknownRelayParent(_) => StoreError::UnknownRelayParent,
+			Self::InvalidChunkProof => StoreError::InvalidChunkProof,
+			Self::ReadOnly => StoreError::ReadOnly,
+			Self::Overloaded => StoreError::Overloaded,
+			Self::ErasureRootMismatch { .. } => StoreError::ErasureRootMismatch,
+			Self::InvalidValidatorCount(_) => StoreError::InvalidValidatorCount,
+			Self::ChunkIndexOutOfRange { .. } => StoreError::ChunkIndexOutOfRange,
+			Self::ExpiryInThePast(_) => StoreError::ExpiryInThePast,
+			Self::Io(_) => StoreError::Database,
+			_ => StoreError::Internal,
 		}
 	}
 }
@@ -104,18 +206,16 @@ enum PruningDelay {
 }
 
 impl PruningDelay {
-	fn now() -> Result<Self, Error> {
-		Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.into())
-	}
-
 	fn into_the_future(duration: Duration) -> Result<Self, Error> {
 		Ok(Self::In(SystemTime::now().duration_since(UNIX_EPOCH)? + duration))
 	}
 
-	fn as_duration(&self) -> Option<Duration> {
-		match self {
-			PruningDelay::In(d) => Some(*d),
-			PruningDelay::Indefinite => None,
+	// The delay of data stored now, which expires at `expire_at` if given and is kept for
+	// `keep_for` otherwise.
+	fn for_stored(expire_at: Option<Duration>, keep_for: Duration) -> Result<Self, Error> {
+		match expire_at {
+			Some(expire_at) => Ok(Self::In(expire_at)),
+			None => Self::into_the_future(keep_for),
 		}
 	}
 }
@@ -158,18 +258,57 @@ impl Ord for PruningDelay {
 	}
 }
 
-/// A key for chunk pruning records.
+/// A key for the sorted vector of chunk pruning records used before schema version 3.
 const CHUNK_PRUNING_KEY: [u8; 14] = *b"chunks_pruning";
 
+/// A prefix for the keys of individual chunk pruning records.
+const CHUNK_PRUNING_RECORD_PREFIX: [u8; 20] = *b"chunk_pruning_record";
+
+/// A prefix for the keys of the chunk pruning index, which is ordered by pruning time.
+const CHUNK_PRUNING_INDEX_PREFIX: [u8; 19] = *b"chunk_pruning_index";
+
 /// A key for PoV pruning records.
 const POV_PRUNING_KEY: [u8; 11] = *b"pov_pruning";
 
-/// A key for a cached value of next scheduled PoV pruning.
+/// A prefix for the keys of the buckets PoV pruning records are spilled into once there are
+/// too many of them to be kept under `POV_PRUNING_KEY`, followed by the big-endian bucket number.
+const POV_PRUNING_BUCKET_PREFIX: [u8; 18] = *b"pov_pruning_bucket";
+
+/// A prefix for the keys of the index of candidates by the block number their data was stored for.
+const BLOCK_CANDIDATES_PREFIX: [u8; 16] = *b"block_candidates";
+
+/// A prefix for the keys of the index of candidates by the parachain they belong to.
+const PARA_CANDIDATES_PREFIX: [u8; 15] = *b"para_candidates";
+
+/// A key for the pruning delay of the first PoV pruning record, which caches the next scheduled
+/// PoV pruning. The key is absent if there are no records.
 const NEXT_POV_PRUNING: [u8; 16] = *b"next_pov_pruning";
 
-/// A key for a cached value of next scheduled chunk pruning.
+/// A key for a cached value of next scheduled chunk pruning used before schema version 3.
 const NEXT_CHUNK_PRUNING: [u8; 18] = *b"next_chunk_pruning";
 
+/// A key for the counts and sizes of the stored values reported by `QueryStorageStats`, which are
+/// updated along with the values.
+const STORAGE_STATS_KEY: [u8; 13] = *b"storage_stats";
+
+/// A key for the version of the database schema.
+const SCHEMA_VERSION_KEY: [u8; 14] = *b"schema_version";
+
+/// The version of the database schema written by this code.
+///
+/// Databases created before the schema was versioned carry no version and are treated as
+/// version 0, which shares the layout of version 1. Version 2 adds the time at which the
+/// data of a candidate was first stored, which older versions would never prune. Version 3
+/// keeps every chunk pruning record under its own key together with an index ordered by
+/// pruning time instead of a single sorted vector. Version 4 may spill the PoV pruning records
+/// into buckets ordered by pruning time. Version 5 indexes the candidates with stored data by
+/// block number. Version 6 keeps the pruning delay of the first PoV pruning record under
+/// `NEXT_POV_PRUNING` even if it is indefinite, to tell it apart from there being no records.
+/// Version 7 indexes the candidates with a stored receipt by parachain. Version 8 keeps the
+/// counts and sizes of the stored values under `STORAGE_STATS_KEY`, which older versions would
+/// not update.
+const CURRENT_SCHEMA_VERSION: u32 = 8;
+
 /// The following constants are used under normal conditions:
 
 /// Stored block is kept available for 1 hour.
@@ -181,55 +320,152 @@ const KEEP_FINALIZED_BLOCK_FOR: Duration = Duration::from_secs(24 * 60 * 60);
 /// Keep chunk of the finalized block for 1 day + 1 hour.
 const KEEP_FINALIZED_CHUNK_FOR: Duration = Duration::from_secs(25 * 60 * 60);
 
+/// PoV and chunk prunes due within 1 second of each other are performed together.
+const COALESCE_PRUNING_WITHIN: Duration = Duration::from_secs(1);
+
+/// At most this many blocks whose candidate events could not be fetched are kept for a retry.
+const MAX_DEFERRED_ACTIVATIONS: usize = 64;
+
+/// Fetching the candidate events of an activated block is attempted at most this many times.
+const MAX_ACTIVATION_ATTEMPTS: u32 = 3;
+
+/// Compact the data column after 256 MiB worth of values were pruned from it.
+const COMPACTION_THRESHOLD: u64 = 256 * 1024 * 1024;
+
+/// PoV pruning records are spilled into buckets once there are more than 16384 of them.
+const POV_PRUNING_SPILL_THRESHOLD: usize = 16 * 1024;
+
+/// If at most 10 percent of the spilled PoV pruning records change on finalization, only the
+/// buckets they moved between are rewritten.
+const POV_PRUNING_INCREMENTAL_PERCENT: usize = 10;
+
+/// Spilled PoV pruning records due within the same 10 minutes share a bucket.
+const POV_PRUNING_BUCKET_WIDTH: Duration = Duration::from_secs(10 * 60);
+
+/// The chunks of at most 4 candidates regenerated from their full data are kept in memory.
+const REGENERATED_CHUNKS_CACHE_SIZE: usize = 4;
+
+/// Regenerated chunks are reused for 10 seconds, which covers a burst of queries.
+const REGENERATED_CHUNKS_TTL: Duration = Duration::from_secs(10);
+
+/// The largest number of validators data is erasure-coded for, the most the erasure coding supports.
+pub const MAX_VALIDATORS: u32 = 1 << 16;
+
+/// The IO statistics of an on-disk database are exported every 10 seconds.
+const IO_STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The time since the last prune is exported every 10 seconds.
+const PRUNE_AGE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// After a failed iteration of the main loop the next one is delayed by 10 milliseconds, doubling
+/// with every further consecutive failure.
+const ERROR_BACKOFF_INITIAL: Duration = Duration::from_millis(10);
+
+/// The delay between consecutive failed iterations of the main loop is at most 30 seconds.
+const MAX_ERROR_BACKOFF: Duration = Duration::from_secs(30);
+
+/// At most 1024 messages are taken from the overseer ahead of processing them.
+const MAX_QUEUED_MESSAGES: usize = 1024;
+
+/// The number of stored values checked by every integrity sample.
+const INTEGRITY_SAMPLE_SIZE: usize = 16;
+
+/// The number of keys copied in a single transaction by `migrate_to` and compactions.
+const COPY_BATCH_SIZE: usize = 1024;
+
+/// The version of the checksum appended to stored data and chunks, followed by the
+/// blake2 hash of the encoded value.
+const CHECKSUM_VERSION: u8 = 1;
+
 /// At which point in time since UNIX_EPOCH we need to wakeup and do next pruning of blocks.
 /// Essenially this is the first element in the sorted array of pruning data,
 /// we just want to cache it here to avoid lifting the whole array just to look at the head.
 ///
-/// This record exists under `NEXT_POV_PRUNING` key, if it does not either:
-///  a) There are no records and nothing has to be pruned.
+/// It is derived from the `PruningDelay` under the `NEXT_POV_PRUNING` key, there is none if either:
+///  a) There are no records and nothing has to be pruned, in which case the key is absent.
 ///  b) There are records but all of them are in `Included` state and do not have exact time to
-///     be pruned.
-#[derive(Decode, Encode)]
+///     be pruned, in which case the key holds `PruningDelay::Indefinite`.
+#[derive(Clone, Copy, Decode, Encode)]
 struct NextPoVPruning(Duration);
 
-impl NextPoVPruning {
-	// After which duration from `now` this should fire.
-	fn should_fire_in(&self) -> Result<Duration, Error> {
-		Ok(self.0.checked_sub(SystemTime::now().duration_since(UNIX_EPOCH)?).unwrap_or_default())
-	}
-}
-
 /// At which point in time since UNIX_EPOCH we need to wakeup and do next pruning of chunks.
-/// Essentially this is the first element in the sorted array of pruning data,
-/// we just want to cache it here to avoid lifting the whole array just to look at the head.
+/// Essentially this is the first entry of the chunk pruning index,
+/// we just want to cache it here to avoid seeking the index on every iteration.
 ///
-/// This record exists under `NEXT_CHUNK_PRUNING` key, if it does not either:
+/// The index has no entries if either:
 ///  a) There are no records and nothing has to be pruned.
 ///  b) There are records but all of them are in `Included` state and do not have exact time to
 ///     be pruned.
-#[derive(Decode, Encode)]
+#[derive(Clone, Copy, Decode, Encode)]
 struct NextChunkPruning(Duration);
 
-impl NextChunkPruning {
-	// After which amount of seconds into the future from `now` this should fire.
-	fn should_fire_in(&self) -> Result<Duration, Error> {
-		Ok(self.0.checked_sub(SystemTime::now().duration_since(UNIX_EPOCH)?).unwrap_or_default())
-	}
+/// How the retention of the data and chunks of finalized blocks is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+	/// Keep them for `keep_finalized_block_for` and `keep_finalized_chunk_for` respectively.
+	ByTime,
+	/// Keep them until this many blocks on top of theirs are finalized, which is more precise
+	/// than wall time on chains with irregular block times.
+	ByBlocks(BlockNumber),
 }
 
-/// Struct holding pruning timing configuration.
-/// The only purpose of this structure is to use different timing
-/// configurations in production and in testing.
-#[derive(Clone)]
-struct PruningConfig {
+/// Struct holding the pruning configuration.
+/// Besides the retention of stored data and chunks, it tunes how the pruning records are
+/// kept and processed.
+///
+/// Start from the default configuration and override the durations that matter:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use polkadot_node_core_av_store::PruningConfig;
+/// let config = PruningConfig::default()
+/// 	.with_keep_stored_block_for(Duration::from_secs(10))
+/// 	.with_keep_finalized_block_for(Duration::from_secs(60))
+/// 	.with_keep_finalized_chunk_for(Duration::from_secs(120));
+///
+/// assert_eq!(config.keep_stored_block_for, Duration::from_secs(10));
+/// assert_eq!(config.keep_finalized_chunk_for, Some(Duration::from_secs(120)));
+/// ```
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PruningConfig {
 	/// How long should a stored block stay available.
-	keep_stored_block_for: Duration,
+	pub keep_stored_block_for: Duration,
 
 	/// How long should a finalized block stay available.
-	keep_finalized_block_for: Duration,
+	///
+	/// Chunks can be used to recover the full data, so this should not exceed
+	/// `keep_finalized_chunk_for`.
+	pub keep_finalized_block_for: Duration,
 
 	/// How long should a chunk of a finalized block stay available.
-	keep_finalized_chunk_for: Duration,
+	///
+	/// If `None` the chunks of finalized blocks are never pruned, e.g. for validators acting as
+	/// long-term availability providers.
+	pub keep_finalized_chunk_for: Option<Duration>,
+
+	/// PoV and chunk prunes due within this duration of each other are performed together.
+	pub coalesce_pruning_within: Duration,
+
+	/// How the retention of finalized blocks is measured. Data that is not finalized is always
+	/// kept for `keep_stored_block_for`.
+	pub retention: Retention,
+
+	/// Longer retention of the data and chunks of finalized candidates of specific parachains.
+	/// The durations are used instead of `keep_finalized_block_for` and
+	/// `keep_finalized_chunk_for` where they are longer. A candidate is matched by the receipt
+	/// seen when it was included, so candidates that were never seen included are not affected.
+	pub retention_overrides: HashMap<ParaId, Duration>,
+
+	/// The maximum number of PoV records and of chunk records removed in a single prune pass,
+	/// which bounds the size of its transaction, e.g. after the node was offline for long.
+	/// The remaining outdated records are removed by follow-up passes. If `None` the number
+	/// is unlimited.
+	pub max_prune_batch: Option<usize>,
+
+	/// Number of PoV pruning records above which they are no longer kept in a single value
+	/// but spilled into buckets by pruning time.
+	pub pov_pruning_spill_threshold: usize,
 }
 
 impl Default for PruningConfig {
@@ -237,16 +473,91 @@ impl Default for PruningConfig {
 		Self {
 			keep_stored_block_for: KEEP_STORED_BLOCK_FOR,
 			keep_finalized_block_for: KEEP_FINALIZED_BLOCK_FOR,
-			keep_finalized_chunk_for: KEEP_FINALIZED_CHUNK_FOR,
+			keep_finalized_chunk_for: Some(KEEP_FINALIZED_CHUNK_FOR),
+			coalesce_pruning_within: COALESCE_PRUNING_WITHIN,
+			retention: Retention::ByTime,
+			retention_overrides: HashMap::new(),
+			max_prune_batch: None,
+			pov_pruning_spill_threshold: POV_PRUNING_SPILL_THRESHOLD,
 		}
 	}
 }
 
-#[derive(Debug, Decode, Encode, Eq, PartialEq)]
-enum CandidateState {
-	Stored,
-	Included,
-	Finalized,
+impl PruningConfig {
+	/// Set how long a stored block stays available.
+	pub fn with_keep_stored_block_for(mut self, keep_for: Duration) -> Self {
+		self.keep_stored_block_for = keep_for;
+		self
+	}
+
+	/// Set how long a finalized block stays available.
+	pub fn with_keep_finalized_block_for(mut self, keep_for: Duration) -> Self {
+		self.keep_finalized_block_for = keep_for;
+		self
+	}
+
+	/// Set how long a chunk of a finalized block stays available, `None` to keep it indefinitely.
+	pub fn with_keep_finalized_chunk_for(mut self, keep_for: impl Into<Option<Duration>>) -> Self {
+		self.keep_finalized_chunk_for = keep_for.into();
+		self
+	}
+
+	/// Set the window within which PoV and chunk prunes are performed together.
+	pub fn with_coalesce_pruning_within(mut self, within: Duration) -> Self {
+		self.coalesce_pruning_within = within;
+		self
+	}
+
+	/// Set how the retention of finalized blocks is measured.
+	pub fn with_retention(mut self, retention: Retention) -> Self {
+		self.retention = retention;
+		self
+	}
+
+	/// Keep the finalized data and chunks of `para_id` for at least `keep_for`.
+	pub fn with_retention_override(mut self, para_id: ParaId, keep_for: Duration) -> Self {
+		self.retention_overrides.insert(para_id, keep_for);
+		self
+	}
+
+	/// Set the maximum number of PoV and of chunk records removed in a single prune pass.
+	pub fn with_max_prune_batch(mut self, max: impl Into<Option<usize>>) -> Self {
+		self.max_prune_batch = max.into();
+		self
+	}
+
+	/// Set the number of PoV pruning records above which they are spilled into buckets.
+	pub fn with_pov_pruning_spill_threshold(mut self, threshold: usize) -> Self {
+		self.pov_pruning_spill_threshold = threshold;
+		self
+	}
+
+	// The pruning time of a record of the block `record_block` once `finalized` is finalized.
+	//
+	// Measured in blocks, records are due right away once enough blocks are finalized on top
+	// of theirs and are kept indefinitely until then. Records without a `keep_for` are kept
+	// indefinitely either way.
+	fn finalized_prune_at(
+		&self,
+		record_block: BlockNumber,
+		finalized: BlockNumber,
+		keep_for: Option<Duration>,
+		now: Duration,
+	) -> PruningDelay {
+		let keep_for = match keep_for {
+			Some(keep_for) => keep_for,
+			None => return PruningDelay::Indefinite,
+		};
+
+		match self.retention {
+			Retention::ByTime => PruningDelay::In(now + keep_for),
+			Retention::ByBlocks(keep_blocks) => if record_block.saturating_add(keep_blocks) <= finalized {
+				PruningDelay::In(now)
+			} else {
+				PruningDelay::Indefinite
+			},
+		}
+	}
 }
 
 #[derive(Debug, Decode, Encode, Eq)]
@@ -259,17 +570,17 @@ struct PoVPruningRecord {
 
 impl PartialEq for PoVPruningRecord {
 	fn eq(&self, other: &Self) -> bool {
-		self.candidate_hash == other.candidate_hash
+		self.cmp(other) == Ordering::Equal
 	}
 }
 
+// Records are ordered by their pruning time, ties are broken by the candidate hash
+// to keep the ordering total. Comparing the pruning time first means records with an
+// `Indefinite` pruning time always sort last, whatever their hashes.
 impl Ord for PoVPruningRecord {
 	fn cmp(&self, other: &Self) -> Ordering {
-		if self.candidate_hash == other.candidate_hash {
-			return Ordering::Equal;
-		}
-
 		self.prune_at.cmp(&other.prune_at)
+			.then_with(|| self.candidate_hash.0.cmp(&other.candidate_hash.0))
 	}
 }
 
@@ -290,18 +601,17 @@ struct ChunkPruningRecord {
 
 impl PartialEq for ChunkPruningRecord {
 	fn eq(&self, other: &Self) -> bool {
-		self.candidate_hash == other.candidate_hash &&
-			self.chunk_index == other.chunk_index
+		self.cmp(other) == Ordering::Equal
 	}
 }
 
+// Records are ordered by their pruning time, ties are broken by the candidate hash
+// and the chunk index to keep the ordering total.
 impl Ord for ChunkPruningRecord {
 	fn cmp(&self, other: &Self) -> Ordering {
-		if self.candidate_hash == other.candidate_hash {
-			return Ordering::Equal;
-		}
-
 		self.prune_at.cmp(&other.prune_at)
+			.then_with(|| self.candidate_hash.0.cmp(&other.candidate_hash.0))
+			.then_with(|| self.chunk_index.cmp(&other.chunk_index))
 	}
 }
 
@@ -316,784 +626,4281 @@ pub struct AvailabilityStoreSubsystem {
 	pruning_config: PruningConfig,
 	inner: Arc<dyn KeyValueDB>,
 	metrics: Metrics,
+	/// The compaction of `columns::DATA` once enough data was pruned from it.
+	compaction: Compaction,
+	/// The share of spilled PoV pruning records in percent up to which only the changed ones
+	/// are rewritten on finalization.
+	pov_pruning_incremental_percent: usize,
+	/// In-memory copy of the record stored under `NEXT_POV_PRUNING`.
+	next_pov_pruning: Option<NextPoVPruning>,
+	/// In-memory copy of the head of the chunk pruning index.
+	next_chunk_pruning: Option<NextChunkPruning>,
+	/// Activated blocks whose candidate events could not be fetched yet, along with the
+	/// number of attempts made so far. These are retried on the next `ActiveLeaves` update.
+	deferred_activations: VecDeque<(Hash, u32)>,
+	/// The maximum number of candidates whose data is kept, if limited.
+	max_stored_candidates: Option<usize>,
+	/// Whether the database is only inspected, in which case nothing is stored or pruned.
+	read_only: bool,
+	/// Whether checksums are appended to stored data and chunks and verified on reads.
+	verify_checksums: bool,
+	/// `StoreAvailableData` requests waiting for their chunk to be erasure-coded.
+	pending_stores: FuturesUnordered<BoxFuture<'static, PendingStore>>,
+	/// The maximum number of `pending_stores` beyond which further requests are rejected, if limited.
+	max_pending_stores: Option<usize>,
+	/// Called with the candidate hash of every PoV and chunk record removed by a prune pass.
+	on_prune: Option<Arc<dyn Fn(&CandidateHash) + Send + Sync>>,
+	/// Recently regenerated chunks along with their erasure root and the time they were
+	/// regenerated at, least recently used first.
+	regenerated_chunks: VecDeque<(CandidateHash, Instant, Arc<Vec<ErasureChunk>>, Hash)>,
+	/// The last error returned by an iteration of the main loop, reported by `QueryHealth`.
+	last_error: Option<String>,
+	/// Listeners informed about every candidate whose records became finalized.
+	finalized_listeners: Vec<mpsc::Sender<CandidateHash>>,
+	/// The point in time since UNIX_EPOCH at which the IO statistics of the database are
+	/// exported next. `None` for backends that don't collect any.
+	next_io_stats_update: Option<Duration>,
+	/// The instant of the last successful prune, or of the start of the subsystem before.
+	last_prune: Instant,
+	/// The instant at which the time since `last_prune` is exported next.
+	next_prune_age_update: Instant,
+	/// The maximum number of waiting `StoreChunk` requests written in a single transaction.
+	store_batch_size: usize,
+	/// Signals and messages received from the overseer but not processed yet, in the order in
+	/// which they were received.
+	incoming: VecDeque<FromOverseer<AvailabilityStoreMessage>>,
+	/// The number of messages waiting in `incoming` from which costly messages are shed, if limited.
+	shed_queue_depth: Option<usize>,
+	/// Whether the `AvailableData` of a candidate is reconstructed once enough chunks are stored.
+	auto_reconstruct: bool,
+	/// Automatic reconstructions of `AvailableData` from stored chunks that are not done yet.
+	pending_reconstructions: FuturesUnordered<BoxFuture<'static, PendingReconstruction>>,
+	/// The number of chunks the last automatic reconstruction of a candidate started with, whether
+	/// it is still pending or failed. Candidates are only attempted again with more chunks.
+	reconstruction_attempts: HashMap<CandidateHash, usize>,
+	/// The periodic check of a sample of the stored values for corruption, if enabled.
+	integrity_sampling: Option<IntegritySampling>,
+}
+
+// The compaction of `columns::DATA` after pruning.
+struct Compaction {
+	/// Number of bytes pruned from `columns::DATA` since the last compaction.
+	pruned_bytes: u64,
+	/// Once `pruned_bytes` exceeds this value `compact` is run on `columns::DATA`.
+	threshold: u64,
+	/// Compacts a column of the database, if provided with `with_compaction`.
+	compact: Option<Arc<dyn Fn(u32) -> io::Result<()> + Send + Sync>>,
+}
+
+// The periodic check of a sample of the stored values for corruption.
+struct IntegritySampling {
+	/// The interval at which a sample is checked.
+	interval: Duration,
+	/// The instant at which the next sample is checked.
+	next_sample: Instant,
+}
+
+impl IntegritySampling {
+	fn new(interval: Duration) -> Self {
+		Self { interval, next_sample: Instant::now() + interval }
+	}
+}
+
+// A `StoreAvailableData` request along with the result of erasure coding its chunks.
+struct PendingStore {
+	candidate_hash: CandidateHash,
+	index: ValidatorIndex,
+	n_validators: u32,
+	available_data: AvailableData,
+	coding_version: ErasureCodingVersion,
+	expire_at: Option<Duration>,
+	chunks: Result<(Vec<ErasureChunk>, Hash), Error>,
+	tx: oneshot::Sender<Result<(), StoreError>>,
+}
+
+// An automatic reconstruction along with the `AvailableData` reconstructed from the chunks.
+struct PendingReconstruction {
+	candidate_hash: CandidateHash,
+	n_validators: u32,
+	data: Result<AvailableData, Error>,
 }
 
 impl AvailabilityStoreSubsystem {
-	// Perform pruning of PoVs
+	// The retention of a finalized candidate, extended if one of `retention_overrides` applies
+	// to its parachain.
+	fn extended_retention(&self, candidate_hash: &CandidateHash, keep_for: Duration) -> Duration {
+		// Avoid looking up the receipt of every record unless there are overrides.
+		if self.pruning_config.retention_overrides.is_empty() {
+			return keep_for;
+		}
+
+		candidate_receipt(&self.inner, candidate_hash)
+			.and_then(|receipt| self.pruning_config.retention_overrides.get(&receipt.descriptor.para_id))
+			.map_or(keep_for, |retention| keep_for.max(*retention))
+	}
+
+	// Perform pruning of PoVs and/or chunks, writing the result in a single transaction.
 	#[tracing::instrument(level = "trace", skip(self), fields(subsystem = LOG_TARGET))]
-	fn prune_povs(&self) -> Result<(), Error> {
-		let _timer = self.metrics.time_prune_povs();
+	fn prune(&mut self, povs: bool, chunks: bool) -> Result<(), Error> {
+		let _pov_timer = if povs { Some(self.metrics.time_prune_povs()) } else { None };
+		let _chunk_timer = if chunks { Some(self.metrics.time_prune_chunks()) } else { None };
 
 		let mut tx = DBTransaction::new();
 		let mut pov_pruning = pov_pruning(&self.inner).unwrap_or_default();
-		let now = PruningDelay::now()?;
-
-		tracing::trace!(target: LOG_TARGET, "Pruning PoVs");
-		let outdated_records_count = pov_pruning.iter()
-			.take_while(|r| r.prune_at <= now)
-			.count();
+		let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
 
-		for record in pov_pruning.drain(..outdated_records_count) {
-			tracing::trace!(target: LOG_TARGET, record = ?record, "Removing record");
-			tx.delete(
-				columns::DATA,
-				available_data_key(&record.candidate_hash).as_slice(),
-			);
+		if !is_sorted(&pov_pruning) {
+			tracing::warn!(target: LOG_TARGET, "PoV pruning records are out of order, sorting them");
+			pov_pruning.sort();
 		}
 
-		put_pov_pruning(&self.inner, Some(tx), pov_pruning)?;
+		// `take_while` below relies on `Indefinite` records never preceding `In(..)` ones.
+		debug_assert!(pov_pruning.windows(2).all(|w| w[0].prune_at <= w[1].prune_at));
 
-		Ok(())
-	}
+		// Outdated records beyond the batch size are left for a follow-up pass, which is due
+		// right away as the next pruning time is still in the past.
+		let max_prune_batch = self.pruning_config.max_prune_batch.unwrap_or(usize::MAX);
 
-	// Perform pruning of chunks.
-	#[tracing::instrument(level = "trace", skip(self), fields(subsystem = LOG_TARGET))]
-	fn prune_chunks(&self) -> Result<(), Error> {
-		let _timer = self.metrics.time_prune_chunks();
+		let outdated_povs_count = if povs {
+			outdated_pov_pruning_count(&pov_pruning, now, max_prune_batch)
+		} else {
+			0
+		};
 
-		let mut tx = DBTransaction::new();
-		let mut chunk_pruning = chunk_pruning(&self.inner).unwrap_or_default();
-		let now = PruningDelay::now()?;
+		let outdated_chunks = if chunks {
+			outdated_chunk_pruning_records(&self.inner, now, max_prune_batch)
+		} else {
+			Vec::new()
+		};
+
+		let mut pruned_bytes = 0;
 
-		tracing::trace!(target: LOG_TARGET, "Pruning Chunks");
-		let outdated_records_count = chunk_pruning.iter()
-			.take_while(|r| r.prune_at <= now)
-			.count();
+		let mut pruned_candidates = HashSet::new();
+		let mut pruned_povs = Vec::with_capacity(outdated_povs_count);
 
-		for record in chunk_pruning.drain(..outdated_records_count) {
-			tracing::trace!(target: LOG_TARGET, record = ?record, "Removing record");
+		tracing::trace!(target: LOG_TARGET, povs, chunks, "Pruning");
+
+		for record in pov_pruning.drain(..outdated_povs_count) {
+			tracing::trace!(
+				target: LOG_TARGET,
+				candidate_hash = ?record.candidate_hash,
+				state = ?record.candidate_state,
+				"Pruning available data",
+			);
+			let key = available_data_key(&record.candidate_hash);
+			pruned_bytes += query_len_inner(&self.inner, columns::DATA, &key).unwrap_or_default() as u64;
 			tx.delete(
 				columns::DATA,
-				erasure_chunk_key(&record.candidate_hash, record.chunk_index).as_slice(),
+				key.as_slice(),
 			);
+			tx.delete(
+				columns::META,
+				stored_at_key(&record.candidate_hash).as_slice(),
+			);
+			tx.delete(
+				columns::META,
+				block_candidate_key(record.block_number, &record.candidate_hash).as_slice(),
+			);
+			pruned_candidates.insert(record.candidate_hash);
+			pruned_povs.push(record);
 		}
 
-		put_chunk_pruning(&self.inner, Some(tx), chunk_pruning)?;
+		let mut pruned_chunks = HashSet::new();
 
-		Ok(())
-	}
+		for record in &outdated_chunks {
+			tracing::trace!(
+				target: LOG_TARGET,
+				candidate_hash = ?record.candidate_hash,
+				chunk_index = record.chunk_index,
+				state = ?record.candidate_state,
+				"Pruning chunk",
+			);
+			let key = erasure_chunk_key(&record.candidate_hash, record.chunk_index);
+			pruned_bytes += query_len_inner(&self.inner, columns::DATA, &key).unwrap_or_default() as u64;
+			tx.delete(
+				columns::DATA,
+				key.as_slice(),
+			);
+			delete_chunk_pruning_record(&mut tx, record);
+			pruned_candidates.insert(record.candidate_hash);
+			pruned_chunks.insert((record.candidate_hash, record.chunk_index));
+		}
 
-	// Return a `Future` that either resolves when another PoV pruning has to happen
-	// or is indefinitely `pending` in case no pruning has to be done.
-	// Just a helper to `select` over multiple things at once.
-	#[tracing::instrument(level = "trace", skip(self), fields(subsystem = LOG_TARGET))]
-	fn maybe_prune_povs(&self) -> Result<impl Future<Output = ()>, Error> {
-		let future = match get_next_pov_pruning_time(&self.inner) {
-			Some(pruning) => {
-				Either::Left(Delay::new(pruning.should_fire_in()?))
+		// The validator count outlives the data for as long as any chunks are kept and vice versa.
+		for candidate_hash in pruned_candidates {
+			let still_referenced = pov_pruning.iter().any(|r| r.candidate_hash == candidate_hash) ||
+				chunk_pruning_records(&self.inner, &candidate_hash)
+					.iter()
+					.any(|r| !pruned_chunks.contains(&(r.candidate_hash, r.chunk_index)));
+
+			if !still_referenced {
+				tx.delete(
+					columns::META,
+					n_validators_key(&candidate_hash).as_slice(),
+				);
+				tx.delete(
+					columns::META,
+					erasure_root_key(&candidate_hash).as_slice(),
+				);
+				tx.delete(
+					columns::META,
+					coding_version_key(&candidate_hash).as_slice(),
+				);
+				delete_candidate_receipt(&self.inner, &mut tx, &candidate_hash);
+				self.reconstruction_attempts.remove(&candidate_hash);
 			}
-			None => Either::Right(future::pending::<()>()),
+		}
+
+		let next_pov_pruning = if povs {
+			// The records were sorted above and only their head was removed.
+			Some(stage_pov_pruning_presorted(
+				&self.inner,
+				self.pruning_config.pov_pruning_spill_threshold,
+				&mut tx,
+				pov_pruning,
+			))
+		} else {
+			None
 		};
 
-		Ok(future)
-	}
+		write_counted(&self.inner, tx)?;
 
-	// Return a `Future` that either resolves when another chunk pruning has to happen
-	// or is indefinitely `pending` in case no pruning has to be done.
-	// Just a helper to `select` over multiple things at once.
-	#[tracing::instrument(level = "trace", skip(self), fields(subsystem = LOG_TARGET))]
-	fn maybe_prune_chunks(&self) -> Result<impl Future<Output = ()>, Error> {
-		let future = match get_next_chunk_pruning_time(&self.inner) {
-			Some(pruning) => {
-				Either::Left(Delay::new(pruning.should_fire_in()?))
+		// Only update the cached values once the write succeeded.
+		if let Some(next_pov_pruning) = next_pov_pruning {
+			self.next_pov_pruning = next_pov_pruning;
+		}
+		if chunks {
+			self.next_chunk_pruning = get_next_chunk_pruning_time(&self.inner);
+		}
+
+		if let Some(on_prune) = &self.on_prune {
+			let pov_hashes = pruned_povs.iter().map(|record| &record.candidate_hash);
+			let chunk_hashes = outdated_chunks.iter().map(|record| &record.candidate_hash);
+			for candidate_hash in pov_hashes.chain(chunk_hashes) {
+				on_prune(candidate_hash);
 			}
-			None => Either::Right(future::pending::<()>()),
-		};
+		}
 
-		Ok(future)
-	}
-}
+		self.last_prune = Instant::now();
+		self.update_prune_age();
 
-fn available_data_key(candidate_hash: &CandidateHash) -> Vec<u8> {
-	(candidate_hash, 0i8).encode()
-}
+		self.compaction.pruned_bytes += pruned_bytes;
+		self.metrics.on_povs_pruned(&pruned_povs);
+		self.metrics.on_chunks_pruned(&outdated_chunks);
+		self.maybe_compact();
 
-fn erasure_chunk_key(candidate_hash: &CandidateHash, index: u32) -> Vec<u8> {
-	(candidate_hash, index, 0i8).encode()
-}
+		Ok(())
+	}
 
-#[derive(Encode, Decode)]
-struct StoredAvailableData {
-	data: AvailableData,
-	n_validators: u32,
-}
+	// The PoVs and chunks the next pass of `prune` would remove, without removing them.
+	fn dry_run_prune(&self) -> Result<(Vec<CandidateHash>, Vec<(CandidateHash, u32)>), Error> {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+		let max_prune_batch = self.pruning_config.max_prune_batch.unwrap_or(usize::MAX);
 
-/// Configuration for the availability store.
-pub struct Config {
-	/// Total cache size in megabytes. If `None` the default (128 MiB per column) is used.
-	pub cache_size: Option<usize>,
-	/// Path to the database.
-	pub path: PathBuf,
-}
+		let mut pov_pruning = pov_pruning(&self.inner).unwrap_or_default();
+		pov_pruning.sort();
 
-impl std::convert::TryFrom<sc_service::config::DatabaseConfig> for Config {
-	type Error = Error;
+		let outdated_povs_count = outdated_pov_pruning_count(&pov_pruning, now, max_prune_batch);
+		let povs = pov_pruning.into_iter()
+			.take(outdated_povs_count)
+			.map(|record| record.candidate_hash)
+			.collect();
 
-	fn try_from(config: sc_service::config::DatabaseConfig) -> Result<Self, Self::Error> {
-		let path = config.path().ok_or(Error::CustomDatabase)?;
+		let chunks = outdated_chunk_pruning_records(&self.inner, now, max_prune_batch)
+			.into_iter()
+			.map(|record| (record.candidate_hash, record.chunk_index))
+			.collect();
 
-		Ok(Self {
-			// substrate cache size is improper here; just use the default
-			cache_size: None,
-			// DB path is a sub-directory of substrate db path to give two properties:
-			// 1: column numbers don't conflict with substrate
-			// 2: commands like purge-chain work without further changes
-			path: path.join("parachains").join("av-store"),
-		})
+		Ok((povs, chunks))
 	}
-}
 
-impl AvailabilityStoreSubsystem {
-	/// Create a new `AvailabilityStoreSubsystem` with a given config on disk.
-	pub fn new_on_disk(config: Config, metrics: Metrics) -> io::Result<Self> {
-		let mut db_config = DatabaseConfig::with_columns(columns::NUM_COLUMNS);
+	// Perform pruning of the PoVs and chunks that are due at this point.
+	fn prune_due(&mut self) -> Result<(), Error> {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
 
-		if let Some(cache_size) = config.cache_size {
-			let mut memory_budget = HashMap::new();
+		let povs = self.next_pov_pruning.map_or(false, |pruning| pruning.0 <= now);
+		let chunks = self.next_chunk_pruning.map_or(false, |pruning| pruning.0 <= now);
 
-			for i in 0..columns::NUM_COLUMNS {
-				memory_budget.insert(i, cache_size / columns::NUM_COLUMNS as usize);
-			}
-			db_config.memory_budget = memory_budget;
+		if !povs && !chunks {
+			return Ok(());
 		}
 
-		let path = config.path.to_str().ok_or_else(|| io::Error::new(
-			io::ErrorKind::Other,
-			format!("Bad database path: {:?}", config.path),
-		))?;
+		self.prune(povs, chunks)
+	}
 
-		std::fs::create_dir_all(&path)?;
-		let db = Database::open(&db_config, &path)?;
+	// Keep an activated block whose candidate events could not be fetched for a retry,
+	// unless it has run out of attempts. The oldest block is dropped once the queue is full.
+	fn defer_activation(&mut self, hash: Hash, attempts: u32) {
+		if attempts >= MAX_ACTIVATION_ATTEMPTS {
+			tracing::warn!(
+				target: LOG_TARGET,
+				hash = %hash,
+				attempts,
+				"Giving up on fetching the candidate events of an activated block",
+			);
+			return;
+		}
 
-		Ok(Self {
-			pruning_config: PruningConfig::default(),
-			inner: Arc::new(db),
-			metrics,
+		if self.deferred_activations.len() >= MAX_DEFERRED_ACTIVATIONS {
+			if let Some((dropped, _)) = self.deferred_activations.pop_front() {
+				tracing::warn!(
+					target: LOG_TARGET,
+					hash = %dropped,
+					"Too many deferred activated blocks, dropping the oldest one",
+				);
+			}
+		}
+
+		self.deferred_activations.push_back((hash, attempts));
+	}
+
+	/// Iterate over all chunks in the store, e.g. to export them.
+	///
+	/// Chunks that cannot be decoded are yielded as errors.
+	pub fn iter_chunks(&self) -> impl Iterator<Item = Result<(CandidateHash, u32, ErasureChunk), Error>> + '_ {
+		self.inner.iter(columns::DATA).filter_map(|(key, value)| {
+			let (candidate_hash, index) = decode_erasure_chunk_key(&key)?;
+
+			Some(
+				ErasureChunk::decode(&mut &value[..])
+					.map(|chunk| (candidate_hash, index, chunk))
+					.map_err(Error::from)
+			)
 		})
 	}
 
-	#[cfg(test)]
-	fn new_in_memory(inner: Arc<dyn KeyValueDB>, pruning_config: PruningConfig) -> Self {
-		Self {
-			pruning_config,
-			inner,
-			metrics: Metrics(None),
-		}
+	/// Copy all data, chunks and pruning metadata of the store to `dest`, e.g. to move it to
+	/// another backend or disk.
+	///
+	/// `dest` has to provide [`NUM_COLUMNS`] columns. The keys are written in batches of
+	/// transactions, so the subsystem should not process messages until the copy is done.
+	pub fn migrate_to(&self, dest: Arc<dyn KeyValueDB>) -> Result<(), Error> {
+		copy_columns(&*self.inner, &*dest)?;
+
+		tracing::debug!(target: LOG_TARGET, "Migrated the availability store");
+
+		Ok(())
 	}
-}
 
-fn get_next_pov_pruning_time(db: &Arc<dyn KeyValueDB>) -> Option<NextPoVPruning> {
-	query_inner(db, columns::META, &NEXT_POV_PRUNING)
-}
+	// Repair inconsistencies between the stored data and the pruning records, e.g. left behind
+	// by a process killed between separate writes.
+	//
+	// Data and chunks without a pruning record would never be pruned, so records pruning them
+	// after the default delay are added. Records without data are dropped, along with the meta
+	// entries of their candidate once none of its data or chunks are left.
+	fn reconcile(&mut self) -> Result<(), Error> {
+		if self.read_only {
+			return Ok(());
+		}
 
-fn get_next_chunk_pruning_time(db: &Arc<dyn KeyValueDB>) -> Option<NextChunkPruning> {
-	query_inner(db, columns::META, &NEXT_CHUNK_PRUNING)
-}
+		let prune_at = SystemTime::now().duration_since(UNIX_EPOCH)?
+			+ self.pruning_config.keep_stored_block_for;
 
-#[tracing::instrument(skip(subsystem, ctx), fields(subsystem = LOG_TARGET))]
-async fn run<Context>(mut subsystem: AvailabilityStoreSubsystem, mut ctx: Context)
-where
-	Context: SubsystemContext<Message=AvailabilityStoreMessage>,
-{
-	loop {
-		let res = run_iteration(&mut subsystem, &mut ctx).await;
-		match res {
-			Err(e) => {
-				e.trace();
+		let mut stored_data = HashSet::new();
+		let mut stored_chunks = HashSet::new();
+
+		// Only the keys are inspected, the values are dropped as they are read without being
+		// copied or decoded.
+		for key in self.inner.iter(columns::DATA).map(|(key, _)| key) {
+			if let Some(candidate_hash) = decode_available_data_key(&key) {
+				stored_data.insert(candidate_hash);
+			} else if let Some(chunk) = decode_erasure_chunk_key(&key) {
+				stored_chunks.insert(chunk);
 			}
-			Ok(true) => {
-				tracing::info!(target: LOG_TARGET, "received `Conclude` signal, exiting");
-				break;
-			},
-			Ok(false) => continue,
 		}
-	}
-}
 
-#[tracing::instrument(level = "trace", skip(subsystem, ctx), fields(subsystem = LOG_TARGET))]
-async fn run_iteration<Context>(subsystem: &mut AvailabilityStoreSubsystem, ctx: &mut Context)
-	-> Result<bool, Error>
-where
-	Context: SubsystemContext<Message=AvailabilityStoreMessage>,
-{
-	// Every time the following two methods are called a read from DB is performed.
-	// But given that these are very small values which are essentially a newtype
-	// wrappers around `Duration` (`NextChunkPruning` and `NextPoVPruning`) and also the
-	// fact of the frequent reads itself we assume these to end up cached in the memory
-	// anyway and thus these db reads to be reasonably fast.
-	let pov_pruning_time = subsystem.maybe_prune_povs()?;
-	let chunk_pruning_time = subsystem.maybe_prune_chunks()?;
+		let block_number = |candidate_hash: &CandidateHash| available_data(&self.inner, candidate_hash)
+			.map(|stored| stored.data.validation_data.block_number)
+			.unwrap_or_default();
 
-	let mut pov_pruning_time = pov_pruning_time.fuse();
-	let mut chunk_pruning_time = chunk_pruning_time.fuse();
+		let mut tx = DBTransaction::new();
+		let mut stale = 0;
+		let mut orphaned = 0;
+		let mut stale_candidates = HashSet::new();
 
-	select! {
-		incoming = ctx.recv().fuse() => {
-			match incoming? {
-				FromOverseer::Signal(OverseerSignal::Conclude) => return Ok(true),
-				FromOverseer::Signal(OverseerSignal::ActiveLeaves(
-					ActiveLeavesUpdate { activated, .. })
-				) => {
-					for (activated, _span) in activated.into_iter() {
-						process_block_activated(ctx, &subsystem.inner, activated, &subsystem.metrics).await?;
-					}
-				}
-				FromOverseer::Signal(OverseerSignal::BlockFinalized(_hash, number)) => {
-					process_block_finalized(subsystem, &subsystem.inner, number).await?;
-				}
-				FromOverseer::Communication { msg } => {
-					process_message(subsystem, ctx, msg).await?;
-				}
+		let mut pov_pruning = pov_pruning(&self.inner).unwrap_or_default();
+		let pov_records = pov_pruning.len();
+		pov_pruning.retain(|record| {
+			let has_data = stored_data.contains(&record.candidate_hash);
+			if !has_data {
+				tx.delete(columns::META, &stored_at_key(&record.candidate_hash));
+				tx.delete(columns::META, &block_candidate_key(record.block_number, &record.candidate_hash));
+				stale_candidates.insert(record.candidate_hash);
+			}
+			has_data
+		});
+		stale += pov_records - pov_pruning.len();
+
+		for candidate_hash in &stored_data {
+			if !pov_pruning.iter().any(|record| record.candidate_hash == *candidate_hash) {
+				tracing::warn!(target: LOG_TARGET, ?candidate_hash, "Found data without a pruning record");
+
+				let candidate_block = block_number(candidate_hash);
+				tx.put_vec(columns::META, &block_candidate_key(candidate_block, candidate_hash), Vec::new());
+
+				pov_pruning.push(PoVPruningRecord {
+					candidate_hash: *candidate_hash,
+					block_number: candidate_block,
+					candidate_state: CandidateState::Stored,
+					prune_at: PruningDelay::In(prune_at),
+				});
+				orphaned += 1;
 			}
 		}
-		_ = pov_pruning_time => {
-			subsystem.prune_povs()?;
-		}
-		_ = chunk_pruning_time => {
-			subsystem.prune_chunks()?;
-		}
-		complete => return Ok(true),
-	}
-
-	Ok(false)
-}
 
-/// As soon as certain block is finalized its pruning records and records of all
-/// blocks that we keep that are `older` than the block in question have to be updated.
-///
-/// The state of data has to be changed from
-/// `CandidateState::Included` to `CandidateState::Finalized` and their pruning times have
-/// to be updated to `now` + keep_finalized_{block, chunk}_for`.
-#[tracing::instrument(level = "trace", skip(subsystem, db), fields(subsystem = LOG_TARGET))]
-async fn process_block_finalized(
-	subsystem: &AvailabilityStoreSubsystem,
-	db: &Arc<dyn KeyValueDB>,
-	block_number: BlockNumber,
-) -> Result<(), Error> {
-	let _timer = subsystem.metrics.time_process_block_finalized();
+		let chunk_records = chunk_pruning(&self.inner).unwrap_or_default();
 
-	if let Some(mut pov_pruning) = pov_pruning(db) {
-		// Since the records are sorted by time in which they need to be pruned and not by block
-		// numbers we have to iterate through the whole collection here.
-		for record in pov_pruning.iter_mut() {
-			if record.block_number <= block_number {
-				tracing::trace!(
-					target: LOG_TARGET,
-					block_number = %record.block_number,
-					"Updating pruning record for finalized block",
-				);
+		for record in &chunk_records {
+			if !stored_chunks.contains(&(record.candidate_hash, record.chunk_index)) {
+				delete_chunk_pruning_record(&mut tx, record);
+				stale_candidates.insert(record.candidate_hash);
+				stale += 1;
+			}
+		}
 
-				record.prune_at = PruningDelay::into_the_future(
-					subsystem.pruning_config.keep_finalized_block_for
-				)?;
-				record.candidate_state = CandidateState::Finalized;
+		// As when pruning, the validator count and erasure root outlive the data for as long as
+		// any chunks are kept and vice versa.
+		for candidate_hash in stale_candidates {
+			let still_stored = stored_data.contains(&candidate_hash)
+				|| stored_chunks.iter().any(|(chunk_candidate, _)| *chunk_candidate == candidate_hash);
+
+			if !still_stored {
+				tx.delete(columns::META, &n_validators_key(&candidate_hash));
+				tx.delete(columns::META, &erasure_root_key(&candidate_hash));
+				tx.delete(columns::META, &coding_version_key(&candidate_hash));
+				delete_candidate_receipt(&self.inner, &mut tx, &candidate_hash);
 			}
 		}
 
-		put_pov_pruning(db, None, pov_pruning)?;
-	}
+		for (candidate_hash, chunk_index) in &stored_chunks {
+			let has_record = chunk_records.iter()
+				.any(|r| r.candidate_hash == *candidate_hash && r.chunk_index == *chunk_index);
 
-	if let Some(mut chunk_pruning) = chunk_pruning(db) {
-		for record in chunk_pruning.iter_mut() {
-			if record.block_number <= block_number {
-				tracing::trace!(
+			if !has_record {
+				tracing::warn!(
 					target: LOG_TARGET,
-					block_number = %record.block_number,
-					"Updating chunk pruning record for finalized block",
+					?candidate_hash,
+					chunk_index,
+					"Found a chunk without a pruning record",
 				);
 
-				record.prune_at = PruningDelay::into_the_future(
-					subsystem.pruning_config.keep_finalized_chunk_for
-				)?;
-				record.candidate_state = CandidateState::Finalized;
+				put_chunk_pruning_record(&mut tx, &ChunkPruningRecord {
+					candidate_hash: *candidate_hash,
+					block_number: block_number(candidate_hash),
+					candidate_state: CandidateState::Stored,
+					chunk_index: *chunk_index,
+					prune_at: PruningDelay::In(prune_at),
+				});
+				orphaned += 1;
 			}
 		}
 
-		put_chunk_pruning(db, None, chunk_pruning)?;
-	}
+		if stale == 0 && orphaned == 0 {
+			return Ok(());
+		}
 
-	Ok(())
-}
+		tracing::warn!(
+			target: LOG_TARGET,
+			stale,
+			orphaned,
+			"Reconciled the pruning records with the stored data",
+		);
 
-#[tracing::instrument(level = "trace", skip(ctx, db, metrics), fields(subsystem = LOG_TARGET))]
-async fn process_block_activated<Context>(
-	ctx: &mut Context,
-	db: &Arc<dyn KeyValueDB>,
-	hash: Hash,
-	metrics: &Metrics,
-) -> Result<(), Error>
-where
-	Context: SubsystemContext<Message=AvailabilityStoreMessage>
-{
-	let _timer = metrics.time_block_activated();
+		put_pov_pruning(self, Some(tx), pov_pruning)?;
+		self.next_chunk_pruning = get_next_chunk_pruning_time(&self.inner);
 
-	let events = match request_candidate_events(ctx, hash).await {
-		Ok(events) => events,
-		Err(err) => {
-			tracing::debug!(target: LOG_TARGET, err = ?err, "requesting candidate events failed");
+		Ok(())
+	}
+
+	// Remove the records that became outdated while the node was offline in one go
+	// instead of waiting for the pruning timers to fire one after another.
+	fn catch_up_prune(&mut self) -> Result<(), Error> {
+		if self.read_only {
 			return Ok(());
 		}
-	};
 
-	tracing::trace!(target: LOG_TARGET, hash = %hash, "block activated");
-	let mut included = HashSet::new();
+		self.prune(true, true)
+	}
 
-	for event in events.into_iter() {
-		if let CandidateEvent::CandidateIncluded(receipt, _) = event {
-			tracing::trace!(
-				target: LOG_TARGET,
-				hash = %receipt.hash(),
-				"Candidate {:?} was included", receipt.hash(),
-			);
-			included.insert(receipt.hash());
+	// Compact `columns::DATA` once enough data has been pruned from it.
+	//
+	// On-disk stores are compacted with `compact_rocksdb`, other backends only if a compaction
+	// was provided with `with_compaction`. Without one this is a no-op, e.g. for in-memory
+	// databases, which don't need compacting.
+	fn maybe_compact(&mut self) {
+		let compaction = &mut self.compaction;
+
+		if compaction.pruned_bytes < compaction.threshold {
+			return;
+		}
+
+		let compact = match &compaction.compact {
+			Some(compact) => compact,
+			None => return,
+		};
+
+		match compact(columns::DATA) {
+			Ok(()) => {
+				tracing::debug!(
+					target: LOG_TARGET,
+					pruned_bytes = compaction.pruned_bytes,
+					"Compacted the data column",
+				);
+
+				compaction.pruned_bytes = 0;
+				self.metrics.on_compaction();
+			}
+			Err(e) => {
+				tracing::warn!(target: LOG_TARGET, err = ?e, "Failed to compact the data column");
+
+				// Retry once as much data was pruned again rather than on every prune.
+				compaction.pruned_bytes = 0;
+			}
 		}
 	}
 
-	if let Some(mut pov_pruning) = pov_pruning(db) {
-		for record in pov_pruning.iter_mut() {
-			if included.contains(&record.candidate_hash) {
-				record.prune_at = PruningDelay::Indefinite;
-				record.candidate_state = CandidateState::Included;
+	// The point in time since UNIX_EPOCH at which the next prune has to happen.
+	//
+	// If the next PoV and chunk prunes are due within `coalesce_pruning_within` of each
+	// other, the earlier one is postponed so that both are performed in a single pass.
+	fn next_prune_at(&self) -> Option<Duration> {
+		let next_pov_pruning = self.next_pov_pruning.map(|pruning| pruning.0);
+		let next_chunk_pruning = self.next_chunk_pruning.map(|pruning| pruning.0);
+
+		match (next_pov_pruning, next_chunk_pruning) {
+			(Some(pov), Some(chunk)) => {
+				let (earlier, later) = if pov <= chunk { (pov, chunk) } else { (chunk, pov) };
+
+				if later - earlier <= self.pruning_config.coalesce_pruning_within {
+					Some(later)
+				} else {
+					Some(earlier)
+				}
 			}
+			(pov, chunk) => pov.or(chunk),
 		}
+	}
 
-		pov_pruning.sort();
+	// Return a `Future` that either resolves when another pruning has to happen
+	// or is indefinitely `pending` in case no pruning has to be done.
+	// Just a helper to `select` over multiple things at once.
+	#[tracing::instrument(level = "trace", skip(self), fields(subsystem = LOG_TARGET))]
+	fn maybe_prune(&self) -> Result<impl Future<Output = ()>, Error> {
+		let future = match self.next_prune_at().filter(|_| !self.read_only) {
+			Some(prune_at) => {
+				let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+				Either::Left(Delay::new(prune_at.checked_sub(now).unwrap_or_default()))
+			}
+			None => Either::Right(future::pending::<()>()),
+		};
 
-		put_pov_pruning(db, None, pov_pruning)?;
+		Ok(future)
 	}
 
-	if let Some(mut chunk_pruning) = chunk_pruning(db) {
-		for record in chunk_pruning.iter_mut() {
-			if included.contains(&record.candidate_hash) {
-				record.prune_at = PruningDelay::Indefinite;
-				record.candidate_state = CandidateState::Included;
+	// Return a `Future` that resolves when the IO statistics of the database have to be
+	// exported next, or is indefinitely `pending` if the backend doesn't collect any.
+	fn maybe_update_io_stats(&self) -> Result<impl Future<Output = ()>, Error> {
+		let future = match self.next_io_stats_update {
+			Some(update_at) => {
+				let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+				Either::Left(Delay::new(update_at.checked_sub(now).unwrap_or_default()))
 			}
-		}
-
-		chunk_pruning.sort();
+			None => Either::Right(future::pending::<()>()),
+		};
 
-		put_chunk_pruning(db, None, chunk_pruning)?;
+		Ok(future)
 	}
 
-	Ok(())
-}
+	// Export the IO statistics the database collected since the last update.
+	fn update_io_stats(&mut self) -> Result<(), Error> {
+		let stats = self.inner.io_stats(IoStatsKind::SincePrevious);
+
+		tracing::trace!(
+			target: LOG_TARGET,
+			reads = stats.reads,
+			cache_reads = stats.cache_reads,
+			bytes_read = stats.bytes_read,
+			bytes_written = stats.bytes_written,
+			"Database IO statistics",
+		);
 
-#[tracing::instrument(level = "trace", skip(ctx), fields(subsystem = LOG_TARGET))]
-async fn request_candidate_events<Context>(
-	ctx: &mut Context,
-	hash: Hash,
-) -> Result<Vec<CandidateEvent>, Error>
-where
-	Context: SubsystemContext<Message=AvailabilityStoreMessage>
-{
-	let (tx, rx) = oneshot::channel();
+		self.metrics.on_io_stats(&stats);
 
-	let msg = AllMessages::RuntimeApi(RuntimeApiMessage::Request(
-		hash,
-		RuntimeApiRequest::CandidateEvents(tx),
-	));
+		let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+		self.next_io_stats_update = Some(now + IO_STATS_INTERVAL);
 
-	ctx.send_message(msg.into()).await;
+		Ok(())
+	}
 
-	Ok(rx.await??)
-}
+	// Return a `Future` that resolves when the time since the last prune has to be exported next.
+	fn maybe_update_prune_age(&self) -> impl Future<Output = ()> {
+		Delay::new(self.next_prune_age_update.saturating_duration_since(Instant::now()))
+	}
 
-#[tracing::instrument(level = "trace", skip(subsystem, ctx), fields(subsystem = LOG_TARGET))]
-async fn process_message<Context>(
-	subsystem: &mut AvailabilityStoreSubsystem,
-	ctx: &mut Context,
-	msg: AvailabilityStoreMessage,
-) -> Result<(), Error>
-where
-	Context: SubsystemContext<Message=AvailabilityStoreMessage>
-{
-	use AvailabilityStoreMessage::*;
+	// Export the time since the last prune, which keeps growing if the pruning timer stops firing.
+	fn update_prune_age(&mut self) {
+		let now = Instant::now();
 
-	let _timer = subsystem.metrics.time_process_message();
+		self.metrics.on_prune_age(now.saturating_duration_since(self.last_prune));
+		self.next_prune_age_update = now + PRUNE_AGE_INTERVAL;
+	}
 
-	match msg {
-		QueryAvailableData(hash, tx) => {
-			tx.send(available_data(&subsystem.inner, &hash).map(|d| d.data)).map_err(|_| oneshot::Canceled)?;
+	// A future that resolves when the next sample of stored values is due, if sampling is enabled.
+	fn maybe_sample_integrity(&self) -> impl Future<Output = ()> {
+		match &self.integrity_sampling {
+			Some(sampling) => Either::Left(Delay::new(sampling.next_sample.saturating_duration_since(Instant::now()))),
+			None => Either::Right(future::pending::<()>()),
 		}
-		QueryDataAvailability(hash, tx) => {
-			let result = available_data(&subsystem.inner, &hash).is_some();
+	}
 
-			tracing::trace!(
+	// Check a sample of the stored values for corruption.
+	fn sample_integrity(&mut self) {
+		let report = sample_data_integrity(&self.inner, INTEGRITY_SAMPLE_SIZE);
+
+		if !report.corrupt.is_empty() {
+			tracing::warn!(
 				target: LOG_TARGET,
-				candidate_hash = ?hash,
-				availability = ?result,
-				"Queried data availability",
+				checked = report.checked,
+				corrupt = ?report.corrupt,
+				"Found corrupt values in the availability store",
 			);
-
-			tx.send(result).map_err(|_| oneshot::Canceled)?;
 		}
-		QueryChunk(hash, id, tx) => {
-			tx.send(get_chunk(subsystem, &hash, id)?).map_err(|_| oneshot::Canceled)?;
-		}
-		QueryChunkAvailability(hash, id, tx) => {
-			let result = get_chunk(subsystem, &hash, id).map(|r| r.is_some());
 
-			tracing::trace!(
-				target: LOG_TARGET,
-				candidate_hash = ?hash,
-				availability = ?result,
-				"Queried chunk availability",
-			);
+		self.metrics.on_corrupt_values(report.corrupt.len());
 
-			tx.send(result?).map_err(|_| oneshot::Canceled)?;
+		if let Some(sampling) = &mut self.integrity_sampling {
+			sampling.next_sample = Instant::now() + sampling.interval;
 		}
-		StoreChunk { candidate_hash, relay_parent, validator_index, chunk, tx } => {
-			let chunk_index = chunk.index;
-			// Current block number is relay_parent block number + 1.
-			let block_number = get_block_number(ctx, relay_parent).await? + 1;
-			let result = store_chunk(subsystem, &candidate_hash, validator_index, chunk, block_number);
+	}
+}
 
-			tracing::trace!(
-				target: LOG_TARGET,
-				%chunk_index,
-				?candidate_hash,
-				%block_number,
-				?result,
-				"Stored chunk",
-			);
+fn available_data_key(candidate_hash: &CandidateHash) -> Vec<u8> {
+	(candidate_hash, 0i8).encode()
+}
 
-			match result {
-				Err(e) => {
-					tx.send(Err(())).map_err(|_| oneshot::Canceled)?;
-					return Err(e);
-				}
-				Ok(()) => {
-					tx.send(Ok(())).map_err(|_| oneshot::Canceled)?;
-				}
-			}
-		}
-		StoreAvailableData(hash, id, n_validators, av_data, tx) => {
-			let result = store_available_data(subsystem, &hash, id, n_validators, av_data);
+fn erasure_chunk_key(candidate_hash: &CandidateHash, index: u32) -> Vec<u8> {
+	(candidate_hash, index, 0i8).encode()
+}
 
-			tracing::trace!(target: LOG_TARGET, candidate_hash = ?hash, ?result, "Stored available data");
+// All keys of a candidate in `columns::DATA` start with its fixed-width hash, so its data and
+// chunks can be iterated by this prefix without matching those of any other candidate.
+fn candidate_data_prefix(candidate_hash: &CandidateHash) -> Vec<u8> {
+	candidate_hash.encode()
+}
 
-			match result {
-				Err(e) => {
-					tx.send(Err(())).map_err(|_| oneshot::Canceled)?;
-					return Err(e);
-				}
-				Ok(()) => {
-					tx.send(Ok(())).map_err(|_| oneshot::Canceled)?;
-				}
-			}
-		}
+// Chunk keys have a fixed width of 37 bytes: the 32 byte candidate hash, the little-endian
+// chunk index and a zero tag. Available data keys are only 33 bytes wide and never match.
+fn decode_erasure_chunk_key(key: &[u8]) -> Option<(CandidateHash, u32)> {
+	if key.len() != 37 {
+		return None;
 	}
 
-	Ok(())
+	match <(CandidateHash, u32, i8)>::decode(&mut &key[..]) {
+		Ok((candidate_hash, index, 0)) => Some((candidate_hash, index)),
+		_ => None,
+	}
 }
 
-fn available_data(
-	db: &Arc<dyn KeyValueDB>,
-	candidate_hash: &CandidateHash,
-) -> Option<StoredAvailableData> {
-	query_inner(db, columns::DATA, &available_data_key(candidate_hash))
+// Available data keys have a fixed width of 33 bytes: the 32 byte candidate hash and a zero tag.
+fn decode_available_data_key(key: &[u8]) -> Option<CandidateHash> {
+	if key.len() != 33 {
+		return None;
+	}
+
+	match <(CandidateHash, i8)>::decode(&mut &key[..]) {
+		Ok((candidate_hash, 0)) => Some(candidate_hash),
+		_ => None,
+	}
 }
 
-fn pov_pruning(db: &Arc<dyn KeyValueDB>) -> Option<Vec<PoVPruningRecord>> {
-	query_inner(db, columns::META, &POV_PRUNING_KEY)
+fn n_validators_key(candidate_hash: &CandidateHash) -> Vec<u8> {
+	(candidate_hash, 1i8).encode()
 }
 
-fn chunk_pruning(db: &Arc<dyn KeyValueDB>) -> Option<Vec<ChunkPruningRecord>> {
-	query_inner(db, columns::META, &CHUNK_PRUNING_KEY)
+fn stored_at_key(candidate_hash: &CandidateHash) -> Vec<u8> {
+	(candidate_hash, 2i8).encode()
 }
 
-#[tracing::instrument(level = "trace", skip(db, tx), fields(subsystem = LOG_TARGET))]
-fn put_pov_pruning(
-	db: &Arc<dyn KeyValueDB>,
-	tx: Option<DBTransaction>,
-	mut pov_pruning: Vec<PoVPruningRecord>,
-) -> Result<(), Error> {
-	let mut tx = tx.unwrap_or_default();
+fn erasure_root_key(candidate_hash: &CandidateHash) -> Vec<u8> {
+	(candidate_hash, 3i8).encode()
+}
 
-	pov_pruning.sort();
+fn candidate_receipt_key(candidate_hash: &CandidateHash) -> Vec<u8> {
+	(candidate_hash, 4i8).encode()
+}
 
-	tx.put_vec(
-		columns::META,
-		&POV_PRUNING_KEY,
-		pov_pruning.encode(),
-	);
+fn coding_version_key(candidate_hash: &CandidateHash) -> Vec<u8> {
+	(candidate_hash, 5i8).encode()
+}
 
-	match pov_pruning.get(0) {
-		// We want to wake up in case we have some records that are not scheduled to be kept
-		// indefinitely (data is included and waiting to move to the finalized state) and so
-		// the is at least one value that is not `PruningDelay::Indefinite`.
-		Some(PoVPruningRecord { prune_at: PruningDelay::In(prune_at), .. }) => {
-			tx.put_vec(
-				columns::META,
-				&NEXT_POV_PRUNING,
-				NextPoVPruning(*prune_at).encode(),
-			);
-		}
-		_ => {
-			// If there is no longer any records, delete the cached pruning time record.
-			tx.delete(
-				columns::META,
-				&NEXT_POV_PRUNING,
-			);
-		}
-	}
+fn chunk_pruning_record_key(candidate_hash: &CandidateHash, index: u32) -> Vec<u8> {
+	(CHUNK_PRUNING_RECORD_PREFIX, candidate_hash, index).encode()
+}
 
-	db.write(tx)?;
+// The entries of the chunk pruning index consist of the big-endian pruning time, the candidate
+// hash and the big-endian chunk index, so that iterating the index in key order yields the
+// records in the order they have to be pruned in.
+fn chunk_pruning_index_key(prune_at: &Duration, candidate_hash: &CandidateHash, index: u32) -> Vec<u8> {
+	let mut key = CHUNK_PRUNING_INDEX_PREFIX.to_vec();
 
-	Ok(())
+	key.extend_from_slice(&prune_at.as_secs().to_be_bytes());
+	key.extend_from_slice(&prune_at.subsec_nanos().to_be_bytes());
+	key.extend_from_slice(candidate_hash.0.as_bytes());
+	key.extend_from_slice(&index.to_be_bytes());
+
+	key
 }
 
-#[tracing::instrument(level = "trace", skip(db, tx), fields(subsystem = LOG_TARGET))]
-fn put_chunk_pruning(
-	db: &Arc<dyn KeyValueDB>,
-	tx: Option<DBTransaction>,
-	mut chunk_pruning: Vec<ChunkPruningRecord>,
-) -> Result<(), Error> {
-	let mut tx = tx.unwrap_or_default();
+// Decode the pruning time, candidate hash and chunk index from an index entry.
+fn decode_chunk_pruning_index_key(key: &[u8]) -> Option<(Duration, CandidateHash, u32)> {
+	let prefix_len = CHUNK_PRUNING_INDEX_PREFIX.len();
 
-	chunk_pruning.sort();
+	if key.len() != prefix_len + 48 || !key.starts_with(&CHUNK_PRUNING_INDEX_PREFIX) {
+		return None;
+	}
 
-	tx.put_vec(
-		columns::META,
-		&CHUNK_PRUNING_KEY,
-		chunk_pruning.encode(),
-	);
+	let key = &key[prefix_len..];
+	let secs = u64::from_be_bytes(key[0..8].try_into().ok()?);
+	let nanos = u32::from_be_bytes(key[8..12].try_into().ok()?);
+	let candidate_hash = CandidateHash(Hash::from_slice(&key[12..44]));
+	let index = u32::from_be_bytes(key[44..48].try_into().ok()?);
 
-	match chunk_pruning.get(0) {
-		Some(ChunkPruningRecord { prune_at: PruningDelay::In(prune_at), .. }) => {
-			tx.put_vec(
-				columns::META,
-				&NEXT_CHUNK_PRUNING,
-				NextChunkPruning(*prune_at).encode(),
+	Some((Duration::new(secs, nanos), candidate_hash, index))
+}
+
+// The entries of the block index consist of the big-endian block number and the candidate hash,
+// so that all candidates of a block share a prefix.
+fn block_candidates_prefix(block_number: BlockNumber) -> Vec<u8> {
+	let mut key = BLOCK_CANDIDATES_PREFIX.to_vec();
+
+	key.extend_from_slice(&block_number.to_be_bytes());
+
+	key
+}
+
+fn block_candidate_key(block_number: BlockNumber, candidate_hash: &CandidateHash) -> Vec<u8> {
+	let mut key = block_candidates_prefix(block_number);
+
+	key.extend_from_slice(candidate_hash.0.as_bytes());
+
+	key
+}
+
+// The entries of the parachain index consist of the big-endian parachain id and the candidate
+// hash, so that all candidates of a parachain share a prefix.
+fn para_candidates_prefix(para_id: ParaId) -> Vec<u8> {
+	let mut key = PARA_CANDIDATES_PREFIX.to_vec();
+
+	key.extend_from_slice(&u32::from(para_id).to_be_bytes());
+
+	key
+}
+
+fn para_candidate_key(para_id: ParaId, candidate_hash: &CandidateHash) -> Vec<u8> {
+	let mut key = para_candidates_prefix(para_id);
+
+	key.extend_from_slice(candidate_hash.0.as_bytes());
+
+	key
+}
+
+// The candidates of `para_id` with a stored receipt.
+fn candidates_for_para(db: &Arc<dyn KeyValueDB>, para_id: ParaId) -> Vec<CandidateHash> {
+	let prefix = para_candidates_prefix(para_id);
+
+	db.iter_with_prefix(columns::META, &prefix)
+		.filter(|(key, _)| key.len() == prefix.len() + 32)
+		.map(|(key, _)| CandidateHash(Hash::from_slice(&key[prefix.len()..])))
+		.collect()
+}
+
+// Stage the removal of the receipt of a candidate along with its entry in the parachain index.
+fn delete_candidate_receipt(db: &Arc<dyn KeyValueDB>, tx: &mut DBTransaction, candidate_hash: &CandidateHash) {
+	if let Some(receipt) = candidate_receipt(db, candidate_hash) {
+		tx.delete(columns::META, &para_candidate_key(receipt.descriptor.para_id, candidate_hash));
+	}
+
+	tx.delete(columns::META, candidate_receipt_key(candidate_hash).as_slice());
+}
+
+// The candidates whose data was stored for `block_number`.
+fn candidates_at_block(db: &Arc<dyn KeyValueDB>, block_number: BlockNumber) -> Vec<CandidateHash> {
+	let prefix = block_candidates_prefix(block_number);
+
+	db.iter_with_prefix(columns::META, &prefix)
+		.filter(|(key, _)| key.len() == prefix.len() + 32)
+		.map(|(key, _)| CandidateHash(Hash::from_slice(&key[prefix.len()..])))
+		.collect()
+}
+
+#[derive(Encode, Decode)]
+struct StoredAvailableData {
+	data: AvailableData,
+	n_validators: u32,
+}
+
+/// Configuration for the availability store.
+///
+/// Writes are not synced to disk by the store, so their durability follows the rocksdb
+/// options of `kvdb-rocksdb`: they survive the node exiting or crashing, but not necessarily
+/// a power failure. `kvdb-rocksdb` offers no way to flush or sync its write-ahead log, so there
+/// is no option to sync the writes when the subsystem concludes or a block is finalized.
+pub struct Config {
+	/// Total cache size in megabytes. If `None` the default (128 MiB per column) is used.
+	pub cache_size: Option<usize>,
+	/// Cache size of the data column in megabytes. Overrides the share of `cache_size`.
+	pub data_cache_size: Option<usize>,
+	/// Cache size of the meta column in megabytes. Overrides the share of `cache_size`.
+	pub meta_cache_size: Option<usize>,
+	/// Path to the database.
+	pub path: PathBuf,
+	/// Number of bytes pruned from the data column after which the database is compacted.
+	/// If `None` the default (256 MiB) is used.
+	pub compaction_threshold: Option<u64>,
+	/// The pruning configuration.
+	pub pruning: PruningConfig,
+	/// The maximum number of candidates whose data is kept. Once reached, storing the data of
+	/// another candidate evicts the data and chunks of the oldest one that is not finalized,
+	/// which is the first one due to be pruned. If `None` the number is unlimited.
+	pub max_stored_candidates: Option<usize>,
+	/// Open the database as a secondary instance only to inspect it, e.g. next to a running node.
+	/// Nothing is stored or pruned and store requests fail with `Error::ReadOnly`.
+	pub read_only: bool,
+	/// The detection of corrupted values.
+	pub integrity: IntegrityConfig,
+	/// The maximum number of `StoreAvailableData` requests waiting to be erasure-coded. Once
+	/// reached, further requests are rejected with `StoreError::Overloaded` until some of them
+	/// are done. If `None` the number is unlimited.
+	pub max_pending_stores: Option<usize>,
+	/// The maximum number of `StoreChunk` requests that are already waiting to be processed
+	/// which are written in a single transaction, to save many small writes under burst load.
+	/// If `None` every request is written on its own.
+	pub store_batch_size: Option<usize>,
+	/// The number of received messages waiting to be processed from which costly messages are
+	/// shed: stores are rejected with `StoreError::Overloaded` and queries reading data or chunks
+	/// are dropped without an answer. Signals and other messages are always processed. If `None`
+	/// nothing is shed.
+	pub shed_queue_depth: Option<usize>,
+	/// Reconstruct and store the `AvailableData` of a candidate once `StoreChunk` requests
+	/// provided enough of its chunks, to speed up later queries of the full data. This is only
+	/// done for candidates whose number of validators is known, e.g. from imported chunks.
+	pub auto_reconstruct: bool,
+}
+
+/// Configuration of the detection of corrupted values in the availability store.
+#[derive(Debug, Clone, Default)]
+pub struct IntegrityConfig {
+	/// Append a checksum to stored data and chunks and verify it when they are read, failing
+	/// with `Error::ChecksumMismatch` on corruption. Values stored without one are still read.
+	pub verify_checksums: bool,
+	/// Check a small random sample of the stored data and chunks for corruption at this interval,
+	/// counting corrupt values in `parachain_av_store_corrupt_values_total`. If `None` values are
+	/// only checked on request.
+	pub sample_interval: Option<Duration>,
+}
+
+impl Config {
+	/// A configuration with default settings for a database kept in `subdir` of `base`.
+	///
+	/// The components of `subdir` are separated by `/`, e.g. `"parachains/av-store"`.
+	pub fn with_subdir(base: PathBuf, subdir: &str) -> Self {
+		let path = subdir.split('/')
+			.filter(|component| !component.is_empty())
+			.fold(base, |path, component| path.join(component));
+
+		Self {
+			// substrate cache size is improper here; just use the default
+			cache_size: None,
+			data_cache_size: None,
+			meta_cache_size: None,
+			path,
+			compaction_threshold: None,
+			pruning: PruningConfig::default(),
+			max_stored_candidates: None,
+			read_only: false,
+			integrity: IntegrityConfig::default(),
+			max_pending_stores: None,
+			store_batch_size: None,
+			shed_queue_depth: None,
+			auto_reconstruct: false,
+		}
+	}
+}
+
+impl std::convert::TryFrom<sc_service::config::DatabaseConfig> for Config {
+	type Error = Error;
+
+	/// The database is kept in `DEFAULT_SUBDIR` of the substrate database path. To relocate
+	/// it, e.g. to run nodes of several networks on one machine, override `path` afterwards
+	/// or use `Config::with_subdir`.
+	fn try_from(config: sc_service::config::DatabaseConfig) -> Result<Self, Self::Error> {
+		let path = config.path().ok_or(Error::CustomDatabase)?;
+
+		// DB path is a sub-directory of substrate db path to give two properties:
+		// 1: column numbers don't conflict with substrate
+		// 2: commands like purge-chain work without further changes
+		Ok(Self::with_subdir(path.to_path_buf(), DEFAULT_SUBDIR))
+	}
+}
+
+// Build the rocksdb configuration, applying the cache budgets of `config`.
+//
+// Columns with an explicit cache size use it, the remaining ones get an even share of
+// `cache_size`. Columns without any budget fall back to the rocksdb default.
+fn database_config(config: &Config) -> DatabaseConfig {
+	let mut db_config = DatabaseConfig::with_columns(columns::NUM_COLUMNS);
+
+	let shared = config.cache_size.map(|cache_size| cache_size / columns::NUM_COLUMNS as usize);
+
+	for (column, cache_size) in [
+		(columns::DATA, config.data_cache_size),
+		(columns::META, config.meta_cache_size),
+	].iter() {
+		if let Some(cache_size) = cache_size.or(shared) {
+			db_config.memory_budget.insert(*column, cache_size);
+		}
+	}
+
+	db_config
+}
+
+impl AvailabilityStoreSubsystem {
+	/// Create a new `AvailabilityStoreSubsystem` with a given config on disk.
+	///
+	/// Fails if the database was written by a newer version of the schema or has a different
+	/// number of columns.
+	pub fn new_on_disk(config: Config, metrics: Metrics) -> Result<Self, Error> {
+		let mut db_config = database_config(&config);
+
+		let path_str = |path: &Path| path.to_str().map(ToOwned::to_owned).ok_or_else(|| io::Error::new(
+			io::ErrorKind::Other,
+			format!("Bad database path: {:?}", path),
+		));
+
+		let path = path_str(&config.path)?;
+
+		if config.read_only {
+			// The secondary instance keeps its own logs apart from those of the primary one.
+			db_config.secondary = Some(path_str(&config.path.join("secondary"))?);
+		} else {
+			std::fs::create_dir_all(&path)?;
+		}
+
+		if let Some(found) = column_count_on_disk(&config.path)? {
+			if found != columns::NUM_COLUMNS {
+				return Err(Error::ColumnCountMismatch { expected: columns::NUM_COLUMNS, found });
+			}
+		}
+
+		let rocksdb = Arc::new(Database::open(&db_config, &path)?);
+		let db: Arc<dyn KeyValueDB> = rocksdb.clone();
+
+		let subsystem = if config.read_only {
+			check_read_only_schema_version(&db)?;
+
+			Self {
+				read_only: true,
+				..Self::with_db(db, config.pruning, metrics)
+			}
+		} else {
+			Self::new_with_db(db, config.pruning, metrics)?
+		};
+
+		// `KeyValueDB` can't be downcast to the rocksdb `Database`, so the compaction keeps the
+		// handle opened above. Secondary instances don't prune and are never compacted.
+		let compact = if config.read_only {
+			None
+		} else {
+			let copy_path = {
+				let mut name = config.path.file_name().map(ToOwned::to_owned).unwrap_or_default();
+				name.push("-compaction");
+				path_str(&config.path.with_file_name(name))?
+			};
+
+			let compact: Arc<dyn Fn(u32) -> io::Result<()> + Send + Sync> = Arc::new(
+				move |_column| compact_rocksdb(&rocksdb, &db_config, &copy_path),
 			);
+
+			Some(compact)
+		};
+
+		Ok(Self {
+			compaction: Compaction {
+				pruned_bytes: 0,
+				threshold: config.compaction_threshold.unwrap_or(COMPACTION_THRESHOLD),
+				compact,
+			},
+			max_stored_candidates: config.max_stored_candidates,
+			verify_checksums: config.integrity.verify_checksums,
+			max_pending_stores: config.max_pending_stores,
+			store_batch_size: config.store_batch_size.unwrap_or(1).max(1),
+			shed_queue_depth: config.shed_queue_depth,
+			auto_reconstruct: config.auto_reconstruct && !config.read_only,
+			integrity_sampling: config.integrity.sample_interval.map(IntegritySampling::new),
+			// Only rocksdb collects IO statistics, the first ones are exported right away.
+			next_io_stats_update: Some(Duration::default()),
+			..subsystem
+		})
+	}
+
+	/// Create a new `AvailabilityStoreSubsystem` on top of any `KeyValueDB` backend.
+	///
+	/// The database has to provide [`NUM_COLUMNS`] columns that are not shared with
+	/// anything else. Fails if the database was written by a newer version of the schema.
+	pub fn new_with_db(
+		db: Arc<dyn KeyValueDB>,
+		pruning_config: PruningConfig,
+		metrics: Metrics,
+	) -> Result<Self, Error> {
+		ensure_schema_version(&db)?;
+
+		Ok(Self::with_db(db, pruning_config, metrics))
+	}
+
+	/// Create a new `AvailabilityStoreSubsystem` backed by an ephemeral in-memory database.
+	///
+	/// Nothing is persisted to disk. If `pruning_config` is `None` the default is used.
+	pub fn new_in_memory(pruning_config: Option<PruningConfig>, metrics: Metrics) -> Self {
+		Self::with_db(
+			Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS)),
+			pruning_config.unwrap_or_default(),
+			metrics,
+		)
+	}
+
+	fn with_db(inner: Arc<dyn KeyValueDB>, pruning_config: PruningConfig, metrics: Metrics) -> Self {
+		Self {
+			pruning_config,
+			next_pov_pruning: get_next_pov_pruning_time(&inner),
+			next_chunk_pruning: get_next_chunk_pruning_time(&inner),
+			inner,
+			metrics,
+			compaction: Compaction {
+				pruned_bytes: 0,
+				threshold: COMPACTION_THRESHOLD,
+				compact: None,
+			},
+			pov_pruning_incremental_percent: POV_PRUNING_INCREMENTAL_PERCENT,
+			deferred_activations: VecDeque::new(),
+			max_stored_candidates: None,
+			read_only: false,
+			verify_checksums: false,
+			pending_stores: FuturesUnordered::new(),
+			max_pending_stores: None,
+			on_prune: None,
+			regenerated_chunks: VecDeque::new(),
+			last_error: None,
+			finalized_listeners: Vec::new(),
+			next_io_stats_update: None,
+			last_prune: Instant::now(),
+			next_prune_age_update: Instant::now(),
+			store_batch_size: 1,
+			incoming: VecDeque::new(),
+			shed_queue_depth: None,
+			auto_reconstruct: false,
+			pending_reconstructions: FuturesUnordered::new(),
+			reconstruction_attempts: HashMap::new(),
+			integrity_sampling: None,
 		}
-		_ => {
-			tx.delete(
-				columns::META,
-				&NEXT_CHUNK_PRUNING,
+	}
+
+	/// Register a callback invoked with the candidate hash of every PoV and chunk record
+	/// removed by a prune pass, e.g. for external bookkeeping.
+	///
+	/// The callback runs on the subsystem's main loop, so it must be cheap and must not block.
+	pub fn with_on_prune(mut self, on_prune: Arc<dyn Fn(&CandidateHash) + Send + Sync>) -> Self {
+		self.on_prune = Some(on_prune);
+		self
+	}
+
+	/// Provide a compaction of a database column, run on the data column once
+	/// `Config::compaction_threshold` bytes were pruned from it.
+	///
+	/// Stores opened with `new_on_disk` compact their rocksdb database on their own. Other
+	/// backends are not compacted unless one is provided, as `KeyValueDB` does not expose
+	/// compaction. The compaction runs on the subsystem's main loop.
+	pub fn with_compaction(mut self, compact: Arc<dyn Fn(u32) -> io::Result<()> + Send + Sync>) -> Self {
+		self.compaction.compact = Some(compact);
+		self
+	}
+}
+
+// Compact the rocksdb database `db` opened with `db_config`.
+//
+// `kvdb-rocksdb` does not expose rocksdb's range compaction, so all live values are copied into
+// a fresh database at `copy_path`, which is then swapped in with `Database::restore`. This drops
+// the values deleted by pruning along with their tombstones. The database is closed while it is
+// swapped, so nothing else may use it until this returns.
+fn compact_rocksdb(db: &Database, db_config: &DatabaseConfig, copy_path: &str) -> io::Result<()> {
+	// Left behind by a compaction that was interrupted.
+	if Path::new(copy_path).exists() {
+		std::fs::remove_dir_all(copy_path)?;
+	}
+
+	{
+		let copy = Database::open(db_config, copy_path)?;
+		copy_columns(db, &copy)?;
+	}
+
+	db.restore(copy_path)
+}
+
+// Copy all columns of `src` to `dest` in batches of transactions.
+fn copy_columns(src: &dyn KeyValueDB, dest: &dyn KeyValueDB) -> io::Result<()> {
+	for column in 0..columns::NUM_COLUMNS {
+		let mut tx = DBTransaction::new();
+
+		for (key, value) in src.iter(column) {
+			tx.put(column, &key, &value);
+
+			if tx.ops.len() >= COPY_BATCH_SIZE {
+				dest.write(std::mem::take(&mut tx))?;
+			}
+		}
+
+		if !tx.ops.is_empty() {
+			dest.write(tx)?;
+		}
+	}
+
+	Ok(())
+}
+
+// The number of columns of the rocksdb database at `path`, read from its latest OPTIONS file.
+// Returns `None` if there is no database yet.
+//
+// `kvdb-rocksdb` opens the columns of its config without checking those on disk, which fails
+// with an obscure rocksdb error on a mismatch, so they are counted upfront.
+fn column_count_on_disk(path: &Path) -> io::Result<Option<u32>> {
+	const OPTIONS_FILE_PREFIX: &str = "OPTIONS-";
+
+	let entries = match std::fs::read_dir(path) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(e),
+	};
+
+	let mut latest = None;
+	for entry in entries {
+		let name = entry?.file_name().to_string_lossy().into_owned();
+		let number = match name.strip_prefix(OPTIONS_FILE_PREFIX).and_then(|n| n.parse::<u64>().ok()) {
+			Some(number) => number,
+			None => continue,
+		};
+
+		if latest.as_ref().map_or(true, |(latest, _)| number > *latest) {
+			latest = Some((number, name));
+		}
+	}
+
+	let options = match latest {
+		Some((_, name)) => std::fs::read_to_string(path.join(name))?,
+		None => return Ok(None),
+	};
+
+	// Besides the rocksdb `default` column, `kvdb-rocksdb` names its columns `col0`, `col1`, ...
+	let count = options.lines()
+		.filter(|line| line.trim_start().starts_with("[CFOptions \"col"))
+		.count();
+
+	Ok(Some(count as u32))
+}
+
+// Check that a database opened in read-only mode can be read without migrating it first.
+fn check_read_only_schema_version(db: &Arc<dyn KeyValueDB>) -> Result<(), Error> {
+	let version = query_inner(db, columns::META, &SCHEMA_VERSION_KEY).unwrap_or(0);
+
+	match version.cmp(&CURRENT_SCHEMA_VERSION) {
+		Ordering::Greater => Err(Error::UnsupportedSchemaVersion {
+			found: version,
+			supported: CURRENT_SCHEMA_VERSION,
+		}),
+		Ordering::Less => {
+			tracing::warn!(
+				target: LOG_TARGET,
+				version,
+				"The database schema has to be migrated, which is not possible in read-only mode",
 			);
+			Err(Error::ReadOnly)
 		}
+		Ordering::Equal => Ok(()),
+	}
+}
+
+// Check the schema version of the database, migrating it to the current one if it is older.
+fn ensure_schema_version(db: &Arc<dyn KeyValueDB>) -> Result<(), Error> {
+	let version = query_inner(db, columns::META, &SCHEMA_VERSION_KEY).unwrap_or(0);
+
+	if version > CURRENT_SCHEMA_VERSION {
+		return Err(Error::UnsupportedSchemaVersion {
+			found: version,
+			supported: CURRENT_SCHEMA_VERSION,
+		});
 	}
 
-	db.write(tx)?;
+	if version < CURRENT_SCHEMA_VERSION {
+		tracing::info!(
+			target: LOG_TARGET,
+			from = version,
+			to = CURRENT_SCHEMA_VERSION,
+			"Migrating the availability store schema",
+		);
+
+		migrate(db, version, CURRENT_SCHEMA_VERSION)?;
+	}
 
 	Ok(())
 }
 
-// produces a block number by block's hash.
-// in the the event of an invalid `block_hash`, returns `Ok(0)`
-async fn get_block_number<Context>(
+// Migrate the database from schema version `from` to `to` and record the new version.
+fn migrate(db: &Arc<dyn KeyValueDB>, from: u32, to: u32) -> Result<(), Error> {
+	let mut tx = DBTransaction::new();
+
+	for version in from..to {
+		match version {
+			// Unversioned databases already use the layout of version 1.
+			0 => {}
+			// Data stored before version 2 has no stored-at record, which is reported as unknown.
+			1 => {}
+			// The sorted vector of chunk pruning records is split into individual records.
+			2 => {
+				let chunk_pruning: Vec<ChunkPruningRecord> = query_inner(
+					db,
+					columns::META,
+					&CHUNK_PRUNING_KEY,
+				).unwrap_or_default();
+
+				for record in &chunk_pruning {
+					put_chunk_pruning_record(&mut tx, record);
+				}
+
+				tx.delete(columns::META, &CHUNK_PRUNING_KEY);
+				tx.delete(columns::META, &NEXT_CHUNK_PRUNING);
+			}
+			// Version 3 databases keep all PoV pruning records under one key, which is still read.
+			3 => {}
+			// The candidates with stored data are indexed by their block number.
+			4 => {
+				for record in pov_pruning(db).unwrap_or_default() {
+					tx.put_vec(
+						columns::META,
+						&block_candidate_key(record.block_number, &record.candidate_hash),
+						Vec::new(),
+					);
+				}
+			}
+			// The next PoV pruning is stored as a `PruningDelay` instead of a bare time.
+			5 => {
+				stage_next_pov_pruning(&mut tx, &pov_pruning(db).unwrap_or_default());
+			}
+			// The candidates with a stored receipt are indexed by parachain.
+			6 => {
+				for (key, value) in db.iter(columns::META) {
+					let candidate_hash = match <(CandidateHash, i8)>::decode(&mut &key[..]) {
+						Ok((candidate_hash, 4)) if key.len() == 33 => candidate_hash,
+						_ => continue,
+					};
+
+					if let Ok(receipt) = CandidateReceipt::decode(&mut &value[..]) {
+						tx.put_vec(
+							columns::META,
+							&para_candidate_key(receipt.descriptor.para_id, &candidate_hash),
+							Vec::new(),
+						);
+					}
+				}
+			}
+			// The stored values are counted by `write_counted` below, as no counts are stored yet.
+			7 => {}
+			_ => unreachable!("No migration is defined from schema version {}", version),
+		}
+	}
+
+	tx.put_vec(
+		columns::META,
+		&SCHEMA_VERSION_KEY,
+		to.encode(),
+	);
+
+	write_counted(db, tx)?;
+
+	Ok(())
+}
+
+// The pruning delay of the first PoV pruning record, `None` if there are no records.
+fn next_pov_pruning_delay(db: &Arc<dyn KeyValueDB>) -> Option<PruningDelay> {
+	query_inner(db, columns::META, &NEXT_POV_PRUNING)
+}
+
+// The pruning delay of the first chunk pruning record, `None` if there are no records.
+//
+// Only records with a pruning time are indexed, so the records are only looked at if the index
+// is empty.
+fn next_chunk_pruning_delay(db: &Arc<dyn KeyValueDB>) -> Option<PruningDelay> {
+	match get_next_chunk_pruning_time(db) {
+		Some(next_pruning) => Some(PruningDelay::In(next_pruning.0)),
+		None => db.iter_with_prefix(columns::META, &CHUNK_PRUNING_RECORD_PREFIX)
+			.next()
+			.map(|_| PruningDelay::Indefinite),
+	}
+}
+
+fn get_next_pov_pruning_time(db: &Arc<dyn KeyValueDB>) -> Option<NextPoVPruning> {
+	match next_pov_pruning_delay(db)? {
+		PruningDelay::In(prune_at) => Some(NextPoVPruning(prune_at)),
+		PruningDelay::Indefinite => None,
+	}
+}
+
+fn get_next_chunk_pruning_time(db: &Arc<dyn KeyValueDB>) -> Option<NextChunkPruning> {
+	db.iter_with_prefix(columns::META, &CHUNK_PRUNING_INDEX_PREFIX)
+		.next()
+		.and_then(|(key, _)| decode_chunk_pruning_index_key(&key))
+		.map(|(prune_at, _, _)| NextChunkPruning(prune_at))
+}
+
+// The time from now until the next PoV and chunk prunes, as recorded in the database.
+//
+// The next PoV pruning is read from `NEXT_POV_PRUNING`, the next chunk pruning from the head of
+// the chunk pruning index that replaced `NEXT_CHUNK_PRUNING`. Overdue prunes are due in zero.
+fn next_prune_times(db: &Arc<dyn KeyValueDB>) -> Result<(Option<Duration>, Option<Duration>), Error> {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+	let from_now = |delay: &Option<PruningDelay>| match delay {
+		Some(PruningDelay::In(prune_at)) => Some(prune_at.checked_sub(now).unwrap_or_default()),
+		_ => None,
+	};
+
+	let pov_delay = next_pov_pruning_delay(db);
+	let chunk_delay = next_chunk_pruning_delay(db);
+
+	tracing::trace!(
+		target: LOG_TARGET,
+		pov_records = pov_delay.is_some(),
+		chunk_records = chunk_delay.is_some(),
+		"Queried the next prune times",
+	);
+
+	Ok((from_now(&pov_delay), from_now(&chunk_delay)))
+}
+
+#[tracing::instrument(skip(subsystem, ctx), fields(subsystem = LOG_TARGET))]
+async fn run<Context>(mut subsystem: AvailabilityStoreSubsystem, mut ctx: Context)
+where
+	Context: SubsystemContext<Message=AvailabilityStoreMessage>,
+{
+	if let Err(e) = subsystem.reconcile() {
+		e.trace();
+	}
+
+	if let Err(e) = subsystem.catch_up_prune() {
+		e.trace();
+	}
+
+	let mut consecutive_errors = 0u32;
+
+	loop {
+		let res = run_iteration(&mut subsystem, &mut ctx).await;
+		match res {
+			Err(e) => {
+				e.trace();
+				subsystem.last_error = Some(e.to_string());
+
+				// Don't spin on an error that persists, e.g. a full disk.
+				consecutive_errors = consecutive_errors.saturating_add(1);
+				Delay::new(error_backoff(consecutive_errors)).await;
+			}
+			Ok(true) => {
+				tracing::info!(target: LOG_TARGET, "received `Conclude` signal, exiting");
+				break;
+			},
+			Ok(false) => consecutive_errors = 0,
+		}
+	}
+}
+
+// The delay before the next iteration of the main loop after `consecutive_errors` failed ones.
+fn error_backoff(consecutive_errors: u32) -> Duration {
+	1u32.checked_shl(consecutive_errors.saturating_sub(1))
+		.and_then(|factor| ERROR_BACKOFF_INITIAL.checked_mul(factor))
+		.map_or(MAX_ERROR_BACKOFF, |backoff| backoff.min(MAX_ERROR_BACKOFF))
+}
+
+#[tracing::instrument(level = "trace", skip(subsystem, ctx), fields(subsystem = LOG_TARGET))]
+async fn run_iteration<Context>(subsystem: &mut AvailabilityStoreSubsystem, ctx: &mut Context)
+	-> Result<bool, Error>
+where
+	Context: SubsystemContext<Message=AvailabilityStoreMessage>,
+{
+	// The next pruning times are kept in memory alongside their DB records
+	// (`NextChunkPruning` and `NextPoVPruning`) so no reads are performed here.
+	let mut pruning_time = subsystem.maybe_prune()?.fuse();
+	let mut io_stats_time = subsystem.maybe_update_io_stats()?.fuse();
+	let mut prune_age_time = subsystem.maybe_update_prune_age().fuse();
+	let mut integrity_sample_time = subsystem.maybe_sample_integrity().fuse();
+
+	queue_waiting_messages(subsystem, ctx).await;
+	let queued = !subsystem.incoming.is_empty();
+
+	select! {
+		// Nothing is received while messages are queued, to keep the order they arrived in.
+		_ = if queued { future::ready(()).fuse() } else { future::Fuse::terminated() } => {
+			let incoming = subsystem.incoming.pop_front().expect("the queue was checked to be non-empty; qed");
+			subsystem.metrics.on_message_queue_depth(subsystem.incoming.len());
+
+			return process_incoming(subsystem, ctx, incoming).await;
+		}
+		incoming = if queued { future::Fuse::terminated() } else { ctx.recv().fuse() } => {
+			return process_incoming(subsystem, ctx, incoming?).await;
+		}
+		_ = pruning_time => {
+			subsystem.prune_due()?;
+		}
+		_ = io_stats_time => {
+			subsystem.update_io_stats()?;
+		}
+		_ = prune_age_time => {
+			subsystem.update_prune_age();
+		}
+		_ = integrity_sample_time => {
+			subsystem.sample_integrity();
+		}
+		pending = subsystem.pending_stores.select_next_some() => {
+			subsystem.metrics.on_pending_stores(subsystem.pending_stores.len());
+			finish_pending_store(subsystem, pending)?;
+		}
+		pending = subsystem.pending_reconstructions.select_next_some() => {
+			finish_reconstruction(subsystem, pending)?;
+		}
+		complete => return Ok(true),
+	}
+
+	Ok(false)
+}
+
+// Process a signal or message from the overseer. Returns `true` on `Conclude`.
+async fn process_incoming<Context>(
+	subsystem: &mut AvailabilityStoreSubsystem,
 	ctx: &mut Context,
-	block_hash: Hash,
-) -> Result<BlockNumber, Error>
+	incoming: FromOverseer<AvailabilityStoreMessage>,
+) -> Result<bool, Error>
 where
 	Context: SubsystemContext<Message=AvailabilityStoreMessage>,
 {
-	let (tx, rx) = oneshot::channel();
+	match incoming {
+		FromOverseer::Signal(OverseerSignal::Conclude) => return Ok(true),
+		FromOverseer::Signal(OverseerSignal::ActiveLeaves(
+			ActiveLeavesUpdate { activated, .. })
+		) => {
+			// Retry the blocks whose candidate events could not be fetched before, ahead of the
+			// newly activated ones.
+			let mut blocks = std::mem::take(&mut subsystem.deferred_activations);
+			blocks.extend(activated.into_iter().map(|(hash, _span)| (hash, 0)));
+
+			while let Some((hash, attempts)) = blocks.pop_front() {
+				if let Err(e) = process_block_activated(ctx, subsystem, hash, attempts).await {
+					// Keep the failed block and the ones not processed yet for the next update.
+					subsystem.defer_activation(hash, attempts + 1);
+					for (hash, attempts) in blocks {
+						subsystem.defer_activation(hash, attempts);
+					}
+
+					return Err(e);
+				}
+			}
+		}
+		FromOverseer::Signal(OverseerSignal::BlockFinalized(_hash, number)) => {
+			process_block_finalized(subsystem, number).await?;
+		}
+		FromOverseer::Communication { msg } => {
+			let overloaded = subsystem.shed_queue_depth
+				.map_or(false, |max| subsystem.incoming.len() >= max);
+
+			let msg = if overloaded {
+				match shed_message(&subsystem.metrics, msg)? {
+					Some(msg) => msg,
+					None => return Ok(false),
+				}
+			} else {
+				msg
+			};
+
+			if subsystem.store_batch_size > 1 && matches!(msg, AvailabilityStoreMessage::StoreChunk { .. }) {
+				process_store_chunk_batch(subsystem, ctx, msg).await?;
+			} else {
+				let candidate_hash = msg.candidate_hash();
+
+				process_message(subsystem, ctx, msg).await.map_err(|e| match candidate_hash {
+					Some(candidate_hash) => e.for_candidate(candidate_hash),
+					None => e,
+				})?;
+			}
+		}
+	}
+
+	Ok(false)
+}
+
+// Take the signals and messages that are already waiting from the overseer, up to
+// `MAX_QUEUED_MESSAGES` of them, so the number of messages received but not processed yet is
+// known.
+async fn queue_waiting_messages<Context>(subsystem: &mut AvailabilityStoreSubsystem, ctx: &mut Context)
+where
+	Context: SubsystemContext<Message=AvailabilityStoreMessage>,
+{
+	while subsystem.incoming.len() < MAX_QUEUED_MESSAGES {
+		match ctx.try_recv().await {
+			Ok(Some(incoming)) => subsystem.incoming.push_back(incoming),
+			// A closed channel is reported by the next `recv`.
+			Ok(None) | Err(()) => break,
+		}
+	}
+
+	subsystem.metrics.on_message_queue_depth(subsystem.incoming.len());
+}
+
+// Answer a costly message without processing it, returning any other message.
+//
+// Stores are rejected with `StoreError::Overloaded`. Queries reading data or chunks cannot
+// answer with an error, so they are dropped and the requester sees the response canceled.
+fn shed_message(
+	metrics: &Metrics,
+	msg: AvailabilityStoreMessage,
+) -> Result<Option<AvailabilityStoreMessage>, Error> {
+	use AvailabilityStoreMessage::*;
+
+	let candidate_hash = msg.candidate_hash();
+
+	match msg {
+		StoreChunk { tx, .. } |
+		ImportChunks { tx, .. } |
+		StoreAvailableData(.., tx) |
+		StoreAvailableDataWithExpiry { tx, .. } |
+		StoreAvailableDataIfAbsent(.., tx) => send_response(metrics, tx, Err(StoreError::Overloaded))?,
+		StoreChunkWithPruneTime { tx, .. } |
+		StoreAvailableDataWithPruneTime(.., tx) => send_response(metrics, tx, Err(StoreError::Overloaded))?,
+		ReconstructAvailableData { tx, .. } => send_response(metrics, tx, Err(StoreError::Overloaded))?,
+		QueryAvailableData(..) |
+		QueryAvailableDataWithMeta(..) |
+		QueryAvailableDataRaw(..) |
+		QueryChunk(..) |
+		QueryChunkWithRoot(..) |
+		QueryChunksByIndices(..) |
+		QueryChunkRange(..) |
+		QueryChunkProof(..) |
+		VerifyIntegrity(..) => {}
+		msg => return Ok(Some(msg)),
+	}
+
+	tracing::debug!(target: LOG_TARGET, ?candidate_hash, "Shed a message, too many are waiting");
+
+	Ok(None)
+}
+
+// Process a `StoreChunk` request along with the ones queued right behind it, up to
+// `store_batch_size` of them, writing all of their chunks in a single transaction.
+//
+// Other signals and messages stay queued, so the order in which requests are answered is kept.
+async fn process_store_chunk_batch<Context>(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	ctx: &mut Context,
+	first: AvailabilityStoreMessage,
+) -> Result<(), Error>
+where
+	Context: SubsystemContext<Message=AvailabilityStoreMessage>,
+{
+	let mut requests = vec![first];
+
+	queue_waiting_messages(subsystem, ctx).await;
+
+	while requests.len() < subsystem.store_batch_size {
+		match subsystem.incoming.pop_front() {
+			Some(FromOverseer::Communication { msg: msg @ AvailabilityStoreMessage::StoreChunk { .. } }) => {
+				requests.push(msg);
+			}
+			Some(incoming) => {
+				subsystem.incoming.push_front(incoming);
+				break;
+			}
+			None => break,
+		}
+	}
+
+	subsystem.metrics.on_message_queue_depth(subsystem.incoming.len());
+
+	let mut chunks = Vec::with_capacity(requests.len());
+	let mut senders = Vec::with_capacity(requests.len());
+	let mut last_error = None;
+
+	for request in requests {
+		if let AvailabilityStoreMessage::StoreChunk { candidate_hash, relay_parent, block_number, chunk, tx, .. } = request {
+			match chunk_block_number(ctx, relay_parent, block_number).await {
+				Ok(block_number) => {
+					chunks.push((candidate_hash, chunk, block_number));
+					senders.push(tx);
+				}
+				Err(e) => {
+					send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+					last_error = Some(e.for_candidate(candidate_hash));
+				}
+			}
+		}
+	}
+
+	tracing::trace!(target: LOG_TARGET, n_chunks = chunks.len(), "Storing a batch of chunks");
+
+	match store_chunks(subsystem, &chunks) {
+		Err(e) => {
+			for tx in senders {
+				send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+			}
+			return Err(e);
+		}
+		Ok(results) => {
+			for ((candidate_hash, ..), (tx, result)) in chunks.iter().zip(senders.into_iter().zip(results)) {
+				match result {
+					Err(e) => {
+						send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+						last_error = Some(e.for_candidate(*candidate_hash));
+					}
+					Ok(()) => {
+						send_response(&subsystem.metrics, tx, Ok(()))?;
+						auto_reconstruct(subsystem, ctx, candidate_hash).await?;
+					}
+				}
+			}
+		}
+	}
+
+	last_error.map_or(Ok(()), Err)
+}
+
+// Store the data of a `StoreAvailableData` request once its chunks have been erasure-coded.
+fn finish_pending_store(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	pending: PendingStore,
+) -> Result<(), Error> {
+	let PendingStore {
+		candidate_hash,
+		index,
+		n_validators,
+		available_data,
+		coding_version,
+		expire_at,
+		chunks,
+		tx,
+	} = pending;
+
+	let result = chunks.and_then(|chunks| store_available_data_with_chunks(
+		subsystem,
+		&candidate_hash,
+		Some(chunks),
+		n_validators,
+		available_data,
+		coding_version,
+		expire_at,
+	));
+
+	if result.is_ok() {
+		debug_assert_chunk_stored(subsystem, &candidate_hash, index);
+	}
+
+	tracing::trace!(target: LOG_TARGET, ?candidate_hash, ?result, "Stored available data");
+
+	match result {
+		Err(e) => {
+			send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+			Err(e.for_candidate(candidate_hash))
+		}
+		Ok(()) => {
+			send_response(&subsystem.metrics, tx, Ok(()))?;
+			Ok(())
+		}
+	}
+}
+
+/// As soon as certain block is finalized its pruning records and records of all
+/// blocks that we keep that are `older` than the block in question have to be updated.
+///
+/// The state of data has to be changed from
+/// `CandidateState::Included` to `CandidateState::Finalized` and their pruning times have
+/// to be updated to `now` + keep_finalized_{block, chunk}_for`.
+#[tracing::instrument(level = "trace", skip(subsystem), fields(subsystem = LOG_TARGET))]
+async fn process_block_finalized(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	block_number: BlockNumber,
+) -> Result<(), Error> {
+	let _timer = subsystem.metrics.time_process_block_finalized();
+
+	if subsystem.read_only {
+		return Ok(());
+	}
+
+	let mut finalized = HashSet::new();
+	let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+
+	// Finality may advance by many blocks at once, the records of all of them are updated
+	// in a single pass and written in a single transaction.
+	let mut tx = DBTransaction::new();
+	let mut next_pov_pruning = None;
+
+	if let Some(mut pov_pruning) = pov_pruning(&subsystem.inner) {
+		let mut changed = 0;
+		let mut changed_buckets = HashSet::new();
+
+		// Since the records are sorted by time in which they need to be pruned and not by block
+		// numbers we have to iterate through the whole collection here.
+		for record in pov_pruning.iter_mut() {
+			if record.block_number <= block_number {
+				let keep_for = subsystem.pruning_config.keep_finalized_block_for;
+				let prune_at = subsystem.pruning_config.finalized_prune_at(
+					record.block_number,
+					block_number,
+					Some(subsystem.extended_retention(&record.candidate_hash, keep_for)),
+					now,
+				);
+				if prune_at == record.prune_at && record.candidate_state == CandidateState::Finalized {
+					continue;
+				}
+
+				tracing::trace!(
+					target: LOG_TARGET,
+					block_number = %record.block_number,
+					"Updating pruning record for finalized block",
+				);
+
+				changed += 1;
+				changed_buckets.insert(pov_pruning_bucket_key(&record.prune_at));
+				changed_buckets.insert(pov_pruning_bucket_key(&prune_at));
+
+				record.prune_at = prune_at;
+				if record.candidate_state != CandidateState::Finalized {
+					finalized.insert(record.candidate_hash);
+				}
+				record.candidate_state = CandidateState::Finalized;
+			}
+		}
+
+		// Few changed records are written without rewriting all of them.
+		if changed > 0 {
+			pov_pruning.sort();
+
+			next_pov_pruning = Some(stage_changed_pov_pruning(
+				&subsystem.inner,
+				subsystem.pruning_config.pov_pruning_spill_threshold,
+				subsystem.pov_pruning_incremental_percent,
+				&mut tx,
+				pov_pruning,
+				changed,
+				&changed_buckets,
+			));
+		}
+	}
+
+	for mut record in chunk_pruning(&subsystem.inner).unwrap_or_default() {
+		if record.block_number <= block_number {
+			let prune_at = subsystem.pruning_config.finalized_prune_at(
+				record.block_number,
+				block_number,
+				subsystem.pruning_config.keep_finalized_chunk_for
+					.map(|keep_for| subsystem.extended_retention(&record.candidate_hash, keep_for)),
+				now,
+			);
+			if prune_at == record.prune_at && record.candidate_state == CandidateState::Finalized {
+				continue;
+			}
+
+			tracing::trace!(
+				target: LOG_TARGET,
+				block_number = %record.block_number,
+				"Updating chunk pruning record for finalized block",
+			);
+
+			delete_chunk_pruning_record(&mut tx, &record);
+
+			record.prune_at = prune_at;
+			if record.candidate_state != CandidateState::Finalized {
+				finalized.insert(record.candidate_hash);
+			}
+			record.candidate_state = CandidateState::Finalized;
+
+			put_chunk_pruning_record(&mut tx, &record);
+		}
+	}
+
+	write_counted(&subsystem.inner, tx)?;
+
+	// Only update the cached values once the write succeeded.
+	if let Some(next_pov_pruning) = next_pov_pruning {
+		subsystem.next_pov_pruning = next_pov_pruning;
+	}
+	subsystem.next_chunk_pruning = get_next_chunk_pruning_time(&subsystem.inner);
+
+	for candidate_hash in &finalized {
+		trace_state_transition(candidate_hash, CandidateState::Finalized);
+	}
+
+	inform_finalized_listeners(&finalized, &mut subsystem.finalized_listeners).await;
+
+	// Records that are old enough in terms of finalized blocks are due right away.
+	if let Retention::ByBlocks(_) = subsystem.pruning_config.retention {
+		subsystem.prune(true, true)?;
+	}
+
+	Ok(())
+}
+
+// Inform all registered listeners about the newly finalized candidates.
+//
+// Removes all closed listeners.
+async fn inform_finalized_listeners(
+	finalized: &HashSet<CandidateHash>,
+	listeners: &mut Vec<mpsc::Sender<CandidateHash>>,
+) {
+	if finalized.is_empty() {
+		return;
+	}
+
+	// Ignore the errors since these will be removed later.
+	stream::iter(listeners.iter_mut()).for_each_concurrent(
+		None,
+		|listener| async move {
+			for candidate_hash in finalized {
+				if listener.send(*candidate_hash).await.is_err() {
+					break;
+				}
+			}
+		}
+	).await;
+	// Remove any closed listeners.
+	listeners.retain(|tx| !tx.is_closed());
+}
+
+#[tracing::instrument(level = "trace", skip(ctx, subsystem), fields(subsystem = LOG_TARGET))]
+async fn process_block_activated<Context>(
+	ctx: &mut Context,
+	subsystem: &mut AvailabilityStoreSubsystem,
+	hash: Hash,
+	attempts: u32,
+) -> Result<(), Error>
+where
+	Context: SubsystemContext<Message=AvailabilityStoreMessage>
+{
+	let _timer = subsystem.metrics.time_block_activated();
+
+	if subsystem.read_only {
+		return Ok(());
+	}
+
+	let events = match request_candidate_events(ctx, hash).await {
+		Ok(events) => events,
+		Err(err) => {
+			tracing::debug!(target: LOG_TARGET, err = ?err, "requesting candidate events failed");
+			subsystem.defer_activation(hash, attempts + 1);
+			return Ok(());
+		}
+	};
+
+	tracing::trace!(target: LOG_TARGET, hash = %hash, "block activated");
+	let mut included = HashMap::new();
+
+	for event in events.into_iter() {
+		if let CandidateEvent::CandidateIncluded(receipt, _) = event {
+			tracing::trace!(
+				target: LOG_TARGET,
+				hash = %receipt.hash(),
+				"Candidate {:?} was included", receipt.hash(),
+			);
+			included.insert(receipt.hash(), receipt);
+		}
+	}
+
+	let mut transitioned = HashSet::new();
+	let mut stored = HashSet::new();
+
+	if let Some(mut pov_pruning) = pov_pruning(&subsystem.inner) {
+		for record in pov_pruning.iter_mut() {
+			if included.contains_key(&record.candidate_hash) {
+				stored.insert(record.candidate_hash);
+				if record.candidate_state != CandidateState::Included {
+					transitioned.insert(record.candidate_hash);
+				}
+				record.prune_at = PruningDelay::Indefinite;
+				record.candidate_state = CandidateState::Included;
+			}
+		}
+
+		pov_pruning.sort();
+
+		put_pov_pruning_presorted(subsystem, None, pov_pruning)?;
+	}
+
+	let mut tx = DBTransaction::new();
+
+	for candidate_hash in included.keys() {
+		for mut record in chunk_pruning_records(&subsystem.inner, candidate_hash) {
+			stored.insert(record.candidate_hash);
+			delete_chunk_pruning_record(&mut tx, &record);
+
+			if record.candidate_state != CandidateState::Included {
+				transitioned.insert(record.candidate_hash);
+			}
+			record.prune_at = PruningDelay::Indefinite;
+			record.candidate_state = CandidateState::Included;
+
+			put_chunk_pruning_record(&mut tx, &record);
+		}
+	}
+
+	// Receipts are only kept for candidates with stored data or chunks and are pruned with them,
+	// as is the parachain index derived from them.
+	for candidate_hash in &stored {
+		let receipt = &included[candidate_hash];
+
+		tx.put_vec(
+			columns::META,
+			candidate_receipt_key(candidate_hash).as_slice(),
+			receipt.encode(),
+		);
+		tx.put_vec(
+			columns::META,
+			&para_candidate_key(receipt.descriptor.para_id, candidate_hash),
+			Vec::new(),
+		);
+	}
+
+	write_counted(&subsystem.inner, tx)?;
+
+	// Only update the cached value once the write succeeded.
+	subsystem.next_chunk_pruning = get_next_chunk_pruning_time(&subsystem.inner);
+
+	for candidate_hash in &transitioned {
+		trace_state_transition(candidate_hash, CandidateState::Included);
+	}
+
+	Ok(())
+}
+
+// Emit a single structured event for a candidate whose records moved to a new state.
+fn trace_state_transition(candidate_hash: &CandidateHash, state: CandidateState) {
+	tracing::debug!(
+		target: LOG_TARGET,
+		candidate_hash = ?candidate_hash,
+		state = ?state,
+		"Candidate state transition",
+	);
+}
+
+#[tracing::instrument(level = "trace", skip(ctx), fields(subsystem = LOG_TARGET))]
+async fn request_candidate_events<Context>(
+	ctx: &mut Context,
+	hash: Hash,
+) -> Result<Vec<CandidateEvent>, Error>
+where
+	Context: SubsystemContext<Message=AvailabilityStoreMessage>
+{
+	let (tx, rx) = oneshot::channel();
+
+	let msg = AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+		hash,
+		RuntimeApiRequest::CandidateEvents(tx),
+	));
+
+	ctx.send_message(msg.into()).await;
+
+	Ok(rx.await??)
+}
+
+#[tracing::instrument(level = "trace", skip(subsystem, ctx), fields(subsystem = LOG_TARGET))]
+async fn process_message<Context>(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	ctx: &mut Context,
+	msg: AvailabilityStoreMessage,
+) -> Result<(), Error>
+where
+	Context: SubsystemContext<Message=AvailabilityStoreMessage>
+{
+	use AvailabilityStoreMessage::*;
+
+	let _timer = subsystem.metrics.time_process_message();
+
+	// Stores answering with their prune time are processed like the plain ones, whose answer
+	// is forwarded along with the prune time once they are done.
+	let msg = match msg {
+		StoreChunkWithPruneTime { candidate_hash, relay_parent, block_number, validator_index, chunk, tx } => {
+			let chunk_index = Some(chunk.index);
+			let tx = forward_prune_time(ctx, subsystem.inner.clone(), subsystem.metrics.clone(), candidate_hash, chunk_index, tx).await?;
+
+			StoreChunk { candidate_hash, relay_parent, block_number, validator_index, chunk, tx }
+		}
+		StoreAvailableDataWithPruneTime(hash, id, n_validators, av_data, expected_erasure_root, tx) => {
+			let tx = forward_prune_time(ctx, subsystem.inner.clone(), subsystem.metrics.clone(), hash, None, tx).await?;
+
+			StoreAvailableData(hash, id, n_validators, av_data, expected_erasure_root, ErasureCodingVersion::V1, tx)
+		}
+		msg => msg,
+	};
+
+	// Stores with an expiry are processed like the plain ones, pruned at the requested time.
+	let (msg, expire_at) = match msg {
+		StoreAvailableDataWithExpiry { hash, id, n_validators, data, expire_at, tx } => {
+			if let Err(e) = check_expiry(expire_at) {
+				send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+				return Err(e);
+			}
+
+			(StoreAvailableData(hash, id, n_validators, data, None, ErasureCodingVersion::V1, tx), Some(expire_at))
+		}
+		msg => (msg, None),
+	};
+
+	match msg {
+		QueryAvailableData(hash, tx) => {
+			let result = checked_available_data(subsystem, &hash)?.map(|d| d.data);
+
+			send_response(&subsystem.metrics, tx, result)?;
+		}
+		QueryAvailableDataWithMeta(hash, tx) => {
+			let result = checked_available_data(subsystem, &hash)?.map(|d| (d.data, d.n_validators));
+
+			send_response(&subsystem.metrics, tx, result)?;
+		}
+		QueryAvailableDataRaw(hash, tx) => {
+			let result = query_raw_inner(&subsystem.inner, columns::DATA, &available_data_key(&hash));
+
+			send_response(&subsystem.metrics, tx, result)?;
+		}
+		QueryDataAvailability(hash, tx) => {
+			let result = available_data(&subsystem.inner, &hash).is_some();
+
+			tracing::trace!(
+				target: LOG_TARGET,
+				candidate_hash = ?hash,
+				availability = ?result,
+				"Queried data availability",
+			);
+
+			send_response(&subsystem.metrics, tx, result)?;
+		}
+		QueryDataAvailabilityBatch(hashes, tx) => {
+			let result: Vec<_> = hashes.iter()
+				.map(|hash| available_data(&subsystem.inner, hash).is_some())
+				.collect();
+
+			tracing::trace!(
+				target: LOG_TARGET,
+				candidates = hashes.len(),
+				available = result.iter().filter(|a| **a).count(),
+				"Queried data availability of a batch",
+			);
+
+			send_response(&subsystem.metrics, tx, result)?;
+		}
+		QueryAvailabilityStatus(hash, tx) => {
+			let chunks_held = chunk_count(&subsystem.inner, &hash);
+			let n_validators = n_validators(&subsystem.inner, &hash);
+
+			let status = AvailabilityStatus {
+				has_full_data: query_len_inner(
+					&subsystem.inner,
+					columns::DATA,
+					&available_data_key(&hash),
+				).is_some(),
+				chunks_held,
+				n_validators,
+				can_reconstruct: n_validators
+					.map_or(false, |n| chunks_held as usize >= recovery_threshold(n as usize)),
+			};
+
+			tracing::trace!(
+				target: LOG_TARGET,
+				candidate_hash = ?hash,
+				status = ?status,
+				"Queried availability status",
+			);
+
+			send_response(&subsystem.metrics, tx, status)?;
+		}
+		QueryChunk(hash, id, tx) => {
+			let result = get_chunk(subsystem, &hash, id)?;
+			send_response(&subsystem.metrics, tx, result)?;
+		}
+		QueryChunkWithRoot(hash, id, tx) => {
+			let result = match get_chunk(subsystem, &hash, id)? {
+				Some(chunk) => erasure_root(&subsystem.inner, &hash).map(|root| (chunk, root)),
+				None => None,
+			};
+
+			send_response(&subsystem.metrics, tx, result)?;
+		}
+		QueryChunksByIndices(hash, indices, tx) => {
+			let result = get_chunks_by_indices(subsystem, &hash, &indices)?;
+			send_response(&subsystem.metrics, tx, result)?;
+		}
+		QueryChunkRange(hash, start, end, tx) => {
+			let result = get_chunk_range(subsystem, &hash, start, end)?;
+			send_response(&subsystem.metrics, tx, result)?;
+		}
+		QueryChunkAvailability(hash, id, tx) => {
+			let result = get_chunk(subsystem, &hash, id).map(|r| r.is_some());
+
+			tracing::trace!(
+				target: LOG_TARGET,
+				candidate_hash = ?hash,
+				availability = ?result,
+				"Queried chunk availability",
+			);
+
+			send_response(&subsystem.metrics, tx, result?)?;
+		}
+		QueryChunkAvailabilityBatch(chunks, tx) => {
+			// Only the presence of the key is checked, missing chunks are not regenerated.
+			let result: Vec<_> = chunks.iter()
+				.map(|(hash, id)| {
+					query_len_inner(&subsystem.inner, columns::DATA, &erasure_chunk_key(hash, *id)).is_some()
+				})
+				.collect();
+
+			tracing::trace!(
+				target: LOG_TARGET,
+				chunks = chunks.len(),
+				available = result.iter().filter(|a| **a).count(),
+				"Queried chunk availability of a batch",
+			);
+
+			send_response(&subsystem.metrics, tx, result)?;
+		}
+		QueryChunkSize(hash, id, tx) => {
+			let result = chunk_size(subsystem, &hash, id);
+
+			send_response(&subsystem.metrics, tx, result)?;
+		}
+		QueryChunkProof(hash, id, tx) => {
+			let result = chunk_proof(subsystem, &hash, id)?;
+
+			send_response(&subsystem.metrics, tx, result)?;
+		}
+		QueryValidatorCount(hash, tx) => {
+			send_response(&subsystem.metrics, tx, n_validators(&subsystem.inner, &hash))?;
+		}
+		QueryChunkCount(hash, tx) => {
+			let count = n_validators(&subsystem.inner, &hash)
+				.map(|n_validators| (chunk_count(&subsystem.inner, &hash), n_validators));
+
+			send_response(&subsystem.metrics, tx, count)?;
+		}
+		QueryTotalChunkCount(tx) => {
+			send_response(&subsystem.metrics, tx, total_chunk_count(&subsystem.inner))?;
+		}
+		QueryCandidateReceipt(hash, tx) => {
+			send_response(&subsystem.metrics, tx, candidate_receipt(&subsystem.inner, &hash))?;
+		}
+		QueryCandidatesForPara(para_id, tx) => {
+			send_response(&subsystem.metrics, tx, candidates_for_para(&subsystem.inner, para_id))?;
+		}
+		SubscribeToFinalized(listener) => {
+			subsystem.finalized_listeners.push(listener);
+		}
+		QueryStoredAt(hash, tx) => {
+			send_response(&subsystem.metrics, tx, stored_at(&subsystem.inner, &hash))?;
+		}
+		QueryPruningSchedule(tx) => {
+			let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+
+			let schedule = pov_pruning(&subsystem.inner)
+				.unwrap_or_default()
+				.into_iter()
+				.map(|record| {
+					let prune_in = match record.prune_at {
+						PruningDelay::In(prune_at) => Some(prune_at.checked_sub(now).unwrap_or_default()),
+						PruningDelay::Indefinite => None,
+					};
+
+					(record.candidate_hash, record.candidate_state, prune_in)
+				})
+				.collect();
+
+			send_response(&subsystem.metrics, tx, schedule)?;
+		}
+		DryRunPrune(tx) => {
+			send_response(&subsystem.metrics, tx, subsystem.dry_run_prune()?)?;
+		}
+		SampleAvailableData(count, tx) => {
+			let sample: Vec<_> = pov_pruning(&subsystem.inner)
+				.unwrap_or_default()
+				.into_iter()
+				.take(count)
+				.map(|record| record.candidate_hash)
+				.collect();
+
+			send_response(&subsystem.metrics, tx, sample)?;
+		}
+		QueryCandidatesAtBlock(block_number, tx) => {
+			send_response(&subsystem.metrics, tx, candidates_at_block(&subsystem.inner, block_number))?;
+		}
+		QueryCandidateState(hash, tx) => {
+			let state = pov_pruning(&subsystem.inner)
+				.unwrap_or_default()
+				.into_iter()
+				.find(|record| record.candidate_hash == hash)
+				.map(|record| record.candidate_state);
+
+			send_response(&subsystem.metrics, tx, state)?;
+		}
+		QueryOldestUnfinalized(tx) => {
+			let oldest = pov_pruning(&subsystem.inner)
+				.unwrap_or_default()
+				.into_iter()
+				.filter(|record| record.candidate_state != CandidateState::Finalized)
+				.map(|record| record.block_number)
+				.min();
+
+			send_response(&subsystem.metrics, tx, oldest)?;
+		}
+		QueryNextPruneTimes(tx) => {
+			send_response(&subsystem.metrics, tx, next_prune_times(&subsystem.inner)?)?;
+		}
+		QueryHealth(tx) => {
+			let health = Health {
+				db_reachable: subsystem.inner.get(columns::META, &SCHEMA_VERSION_KEY).is_ok(),
+				last_error: subsystem.last_error.clone(),
+				next_pov_pruning: subsystem.next_pov_pruning.map(|pruning| pruning.0),
+				next_chunk_pruning: subsystem.next_chunk_pruning.map(|pruning| pruning.0),
+			};
+
+			send_response(&subsystem.metrics, tx, health)?;
+		}
+		QueryStorageStats(tx) => {
+			let stats = StorageStats::from(stored_counts(&subsystem.inner));
+			send_response(&subsystem.metrics, tx, stats)?;
+		}
+		VerifyIntegrity(tx) => {
+			let report = verify_integrity(&subsystem.inner);
+
+			if !report.corrupt.is_empty() {
+				tracing::warn!(
+					target: LOG_TARGET,
+					checked = report.checked,
+					corrupt = ?report.corrupt,
+					"Found corrupt values in the availability store",
+				);
+			}
+
+			send_response(&subsystem.metrics, tx, report)?;
+		}
+		StoreChunk { candidate_hash, relay_parent, block_number, validator_index, chunk, tx } => {
+			let chunk_index = chunk.index;
+			let block_number = match chunk_block_number(ctx, relay_parent, block_number).await {
+				Ok(block_number) => block_number,
+				Err(e) => {
+					send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+					return Err(e);
+				}
+			};
+			let result = store_chunk(subsystem, &candidate_hash, validator_index, chunk, block_number, None);
+
+			tracing::trace!(
+				target: LOG_TARGET,
+				%chunk_index,
+				?candidate_hash,
+				%block_number,
+				?result,
+				"Stored chunk",
+			);
+
+			match result {
+				Err(e) => {
+					send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+					return Err(e);
+				}
+				Ok(()) => {
+					send_response(&subsystem.metrics, tx, Ok(()))?;
+					auto_reconstruct(subsystem, ctx, &candidate_hash).await?;
+				}
+			}
+		}
+		ImportChunks { records, tx } => {
+			let n_chunks = records.len();
+			let result = import_chunks(subsystem, records);
+
+			tracing::debug!(target: LOG_TARGET, n_chunks, ?result, "Imported chunks");
+
+			match result {
+				Err(e) => {
+					send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+					return Err(e);
+				}
+				Ok(()) => {
+					send_response(&subsystem.metrics, tx, Ok(()))?;
+				}
+			}
+		}
+		// Erasure coding can take a while for large validator sets, so it is done off the main
+		// loop and the data is stored once the chunks are ready.
+		StoreAvailableData(hash, Some(id), n_validators, av_data, expected_erasure_root, coding_version, tx) => {
+			// Read-only and overloaded stores reject the request before any erasure coding is done.
+			if subsystem.read_only {
+				send_response(&subsystem.metrics, tx, Err(StoreError::ReadOnly))?;
+				return Err(Error::ReadOnly);
+			}
+
+			if subsystem.max_pending_stores.map_or(false, |max| subsystem.pending_stores.len() >= max) {
+				tracing::debug!(target: LOG_TARGET, candidate_hash = ?hash, "Rejected store, too many pending");
+
+				send_response(&subsystem.metrics, tx, Err(StoreError::Overloaded))?;
+				return Err(Error::Overloaded);
+			}
+
+			// A bad validator count or index is rejected before it reaches the erasure coding.
+			if let Err(e) = check_validator_count(n_validators).and_then(|()| check_chunk_index(id, n_validators)) {
+				send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+				return Err(e);
+			}
+
+			let (chunks_tx, chunks_rx) = oneshot::channel();
+			let metrics = subsystem.metrics.clone();
+			let data = av_data.clone();
+
+			let job = async move {
+				let _ = chunks_tx.send(
+					get_chunks_to_store(&data, n_validators, id, coding_version, &metrics)
+						.and_then(|(chunks, erasure_root)| {
+							check_erasure_root(expected_erasure_root, &erasure_root)?;
+							Ok((chunks, erasure_root))
+						})
+				);
+			};
+
+			ctx.spawn_blocking("av-store-erasure-coding", job.boxed()).await?;
+
+			subsystem.pending_stores.push(async move {
+				PendingStore {
+					candidate_hash: hash,
+					index: id,
+					n_validators,
+					available_data: av_data,
+					coding_version,
+					expire_at,
+					chunks: chunks_rx.await.unwrap_or_else(|e| Err(e.into())),
+					tx,
+				}
+			}.boxed());
+			subsystem.metrics.on_pending_stores(subsystem.pending_stores.len());
+		}
+		StoreAvailableData(hash, None, n_validators, av_data, expected_erasure_root, coding_version, tx) => {
+			// No chunks are stored, so the data is only erasure-coded if there is a root to check.
+			let result = check_validator_count(n_validators).and_then(|()| match expected_erasure_root {
+				Some(expected) => get_chunks_with_root(
+					&av_data,
+					n_validators as usize,
+					coding_version,
+					&subsystem.metrics,
+				).and_then(|(_, erasure_root)| check_erasure_root(Some(expected), &erasure_root)),
+				None => Ok(()),
+			}).and_then(|()| store_available_data(
+				subsystem,
+				&hash,
+				None,
+				n_validators,
+				av_data,
+				coding_version,
+				expire_at,
+			));
+
+			tracing::trace!(target: LOG_TARGET, candidate_hash = ?hash, ?result, "Stored available data");
+
+			match result {
+				Err(e) => {
+					send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+					return Err(e);
+				}
+				Ok(()) => {
+					send_response(&subsystem.metrics, tx, Ok(()))?;
+				}
+			}
+		}
+		StoreChunkWithPruneTime { .. } | StoreAvailableDataWithPruneTime(..) | StoreAvailableDataWithExpiry { .. } => {
+			unreachable!("stores answering with their prune time or with an expiry were turned into plain ones above; qed");
+		}
+		PurgeAll(tx) => {
+			let result = purge_all(subsystem);
+
+			tracing::debug!(target: LOG_TARGET, ?result, "Purged the availability store");
+
+			match result {
+				Err(e) => {
+					send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+					return Err(e);
+				}
+				Ok(()) => {
+					send_response(&subsystem.metrics, tx, Ok(()))?;
+				}
+			}
+		}
+		ReconstructAvailableData { candidate_hash, n_validators, chunks, tx } => {
+			let result = reconstruct_available_data(subsystem, &candidate_hash, n_validators, &chunks);
+
+			tracing::trace!(
+				target: LOG_TARGET,
+				?candidate_hash,
+				n_chunks = chunks.len(),
+				success = result.is_ok(),
+				"Reconstructed available data",
+			);
+
+			match result {
+				Err(e) => {
+					send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+					return Err(e);
+				}
+				Ok(data) => {
+					send_response(&subsystem.metrics, tx, Ok(data))?;
+				}
+			}
+		}
+		ExtendRetention(hash, keep_for, tx) => {
+			let result = extend_retention(subsystem, &hash, keep_for);
+
+			tracing::debug!(target: LOG_TARGET, candidate_hash = ?hash, ?keep_for, ?result, "Extended retention");
+
+			match result {
+				Err(e) => {
+					send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+					return Err(e);
+				}
+				Ok(found) => {
+					let response = if found { Ok(()) } else { Err(StoreError::UnknownCandidate) };
+					send_response(&subsystem.metrics, tx, response)?;
+				}
+			}
+		}
+		RunPruningNow(tx) => {
+			// Only records whose pruning time has passed are pruned, as on a timer.
+			if !subsystem.read_only {
+				subsystem.prune(true, true)?;
+			}
+
+			tracing::debug!(target: LOG_TARGET, "Ran a requested prune pass");
+
+			send_response(&subsystem.metrics, tx, ())?;
+		}
+		StoreAvailableDataIfAbsent(hash, id, n_validators, av_data, tx) => {
+			if available_data(&subsystem.inner, &hash).is_some() {
+				tracing::trace!(target: LOG_TARGET, candidate_hash = ?hash, "Available data already stored");
+
+				send_response(&subsystem.metrics, tx, Ok(()))?;
+				return Ok(());
+			}
+
+			let result = store_available_data(subsystem, &hash, id, n_validators, av_data, ErasureCodingVersion::V1, None);
+
+			tracing::trace!(target: LOG_TARGET, candidate_hash = ?hash, ?result, "Stored available data");
+
+			match result {
+				Err(e) => {
+					send_response(&subsystem.metrics, tx, Err(e.store_error()))?;
+					return Err(e);
+				}
+				Ok(()) => {
+					send_response(&subsystem.metrics, tx, Ok(()))?;
+				}
+			}
+		}
+	}
+
+	Ok(())
+}
+
+fn available_data(
+	db: &Arc<dyn KeyValueDB>,
+	candidate_hash: &CandidateHash,
+) -> Option<StoredAvailableData> {
+	query_inner(db, columns::DATA, &available_data_key(candidate_hash))
+}
+
+// Like `available_data`, but verifying the checksum of the data if the subsystem is configured to.
+fn checked_available_data(
+	subsystem: &AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+) -> Result<Option<StoredAvailableData>, Error> {
+	query_verified(subsystem, columns::DATA, &available_data_key(candidate_hash))
+}
+
+fn n_validators(
+	db: &Arc<dyn KeyValueDB>,
+	candidate_hash: &CandidateHash,
+) -> Option<u32> {
+	query_inner(db, columns::META, &n_validators_key(candidate_hash))
+}
+
+// The number of chunks stored for a candidate.
+fn chunk_count(
+	db: &Arc<dyn KeyValueDB>,
+	candidate_hash: &CandidateHash,
+) -> u32 {
+	stored_chunk_indices(db, candidate_hash).len() as u32
+}
+
+// The indices of the chunks stored for a candidate in ascending order.
+fn stored_chunk_indices(
+	db: &Arc<dyn KeyValueDB>,
+	candidate_hash: &CandidateHash,
+) -> Vec<u32> {
+	let mut indices: Vec<_> = db.iter_with_prefix(columns::DATA, &candidate_data_prefix(candidate_hash))
+		.filter_map(|(key, _)| decode_erasure_chunk_key(&key))
+		.map(|(_, index)| index)
+		.collect();
+
+	// The indices are encoded little-endian, so the keys are not ordered by them.
+	indices.sort();
+	indices
+}
+
+fn stored_at(
+	db: &Arc<dyn KeyValueDB>,
+	candidate_hash: &CandidateHash,
+) -> Option<Duration> {
+	query_inner(db, columns::META, &stored_at_key(candidate_hash))
+}
+
+// The root of the merkle tree of the erasure chunks of a candidate, known once the chunks have
+// been computed from the full data.
+fn erasure_root(
+	db: &Arc<dyn KeyValueDB>,
+	candidate_hash: &CandidateHash,
+) -> Option<Hash> {
+	query_inner(db, columns::META, &erasure_root_key(candidate_hash))
+}
+
+// The version of the erasure coding the data of a candidate was stored with. Data stored before
+// the version was recorded was coded with `ErasureCodingVersion::V1`.
+fn coding_version(
+	db: &Arc<dyn KeyValueDB>,
+	candidate_hash: &CandidateHash,
+) -> ErasureCodingVersion {
+	query_inner(db, columns::META, &coding_version_key(candidate_hash)).unwrap_or_default()
+}
+
+// The receipt of a candidate with stored data or chunks, known once the candidate was included.
+fn candidate_receipt(
+	db: &Arc<dyn KeyValueDB>,
+	candidate_hash: &CandidateHash,
+) -> Option<CandidateReceipt> {
+	query_inner(db, columns::META, &candidate_receipt_key(candidate_hash))
+}
+
+// The prune passes only look at the head of the pruning vectors, which has to be sorted.
+fn is_sorted<T: Ord>(records: &[T]) -> bool {
+	records.windows(2).all(|w| w[0] <= w[1])
+}
+
+// All PoV pruning records, sorted in the order they have to be pruned in.
+fn pov_pruning(db: &Arc<dyn KeyValueDB>) -> Option<Vec<PoVPruningRecord>> {
+	if let Some(pov_pruning) = query_inner(db, columns::META, &POV_PRUNING_KEY) {
+		return Some(pov_pruning);
+	}
+
+	// The buckets are ordered by pruning time and so is their concatenation.
+	let mut buckets = db.iter_with_prefix(columns::META, &POV_PRUNING_BUCKET_PREFIX).peekable();
+	buckets.peek()?;
+
+	Some(buckets
+		.filter_map(|(_, value)| Vec::<PoVPruningRecord>::decode(&mut &value[..]).ok())
+		.flatten()
+		.collect())
+}
+
+// The key of the bucket a spilled PoV pruning record due at `prune_at` is kept in.
+fn pov_pruning_bucket_key(prune_at: &PruningDelay) -> Vec<u8> {
+	let bucket = match prune_at {
+		PruningDelay::In(prune_at) => prune_at.as_secs() / POV_PRUNING_BUCKET_WIDTH.as_secs(),
+		PruningDelay::Indefinite => u64::MAX,
+	};
+
+	POV_PRUNING_BUCKET_PREFIX.iter().copied().chain(bucket.to_be_bytes().iter().copied()).collect()
+}
+
+// All chunk pruning records, sorted in the order they have to be pruned in.
+fn chunk_pruning(db: &Arc<dyn KeyValueDB>) -> Option<Vec<ChunkPruningRecord>> {
+	let mut records: Vec<ChunkPruningRecord> = db.iter_with_prefix(columns::META, &CHUNK_PRUNING_RECORD_PREFIX)
+		.filter_map(|(_, value)| ChunkPruningRecord::decode(&mut &value[..]).ok())
+		.collect();
+
+	if records.is_empty() {
+		return None;
+	}
+
+	records.sort();
+
+	Some(records)
+}
+
+// Every stored chunk has a pruning record of its own, which is counted without decoding it.
+fn total_chunk_count(db: &Arc<dyn KeyValueDB>) -> usize {
+	db.iter_with_prefix(columns::META, &CHUNK_PRUNING_RECORD_PREFIX).count()
+}
+
+// The counts and sizes of the stored values, kept under `STORAGE_STATS_KEY` and updated by
+// `write_counted` in the same transaction as the values. Sizes include the keys.
+#[derive(Debug, Clone, Default, PartialEq, Encode, Decode)]
+struct StoredCounts {
+	candidates: u32,
+	chunks: u32,
+	data_bytes: u64,
+	meta_bytes: u64,
+}
+
+impl From<StoredCounts> for StorageStats {
+	fn from(counts: StoredCounts) -> Self {
+		StorageStats {
+			candidates: counts.candidates,
+			chunks: counts.chunks,
+			data_bytes: counts.data_bytes,
+			meta_bytes: counts.meta_bytes,
+		}
+	}
+}
+
+// Count the stored values with a full scan, for databases which have no stored counts yet.
+fn count_stored(db: &Arc<dyn KeyValueDB>) -> StoredCounts {
+	let mut counts = StoredCounts::default();
+	let mut candidates = HashSet::new();
+
+	for (key, value) in db.iter(columns::DATA) {
+		if decode_erasure_chunk_key(&key).is_some() {
+			counts.chunks += 1;
+		}
+		if let Ok(candidate_hash) = CandidateHash::decode(&mut &key[..]) {
+			candidates.insert(candidate_hash);
+		}
+		counts.data_bytes += (key.len() + value.len()) as u64;
+	}
+
+	for (key, value) in db.iter(columns::META) {
+		if &key[..] != &STORAGE_STATS_KEY[..] {
+			counts.meta_bytes += (key.len() + value.len()) as u64;
+		}
+	}
+
+	counts.candidates = candidates.len() as u32;
+	counts
+}
+
+fn stored_counts(db: &Arc<dyn KeyValueDB>) -> StoredCounts {
+	query_inner(db, columns::META, &STORAGE_STATS_KEY).unwrap_or_else(|| count_stored(db))
+}
+
+// Write a transaction along with the counts of the stored values it changes.
+//
+// The size each key had before the transaction is looked up once, later operations on the same
+// key see the outcome of the earlier ones. A candidate is counted as long as any of its keys
+// remain in the data column.
+fn write_counted(db: &Arc<dyn KeyValueDB>, mut tx: DBTransaction) -> io::Result<()> {
+	let mut counts = stored_counts(db);
+
+	// The size of every key written by the transaction so far, `None` if it was deleted.
+	let mut overlay = HashMap::<(u32, Vec<u8>), Option<usize>>::new();
+	// Whether each candidate whose data is written had any before the transaction.
+	let mut touched = HashMap::<CandidateHash, bool>::new();
+
+	// Prefix deletions are expanded into the deletion of every stored key, so each is counted.
+	let mut ops = Vec::with_capacity(tx.ops.len());
+	for op in std::mem::take(&mut tx.ops) {
+		match op {
+			DBOp::DeletePrefix { col, prefix } => ops.extend(
+				db.iter_with_prefix(col, &prefix)
+					.map(|(key, _)| DBOp::Delete { col, key: DBKey::from_slice(&key) })
+			),
+			op => ops.push(op),
+		}
+	}
+
+	for op in &ops {
+		let (col, key, new_size) = match op {
+			DBOp::Insert { col, key, value } => (*col, &key[..], Some(key.len() + value.len())),
+			DBOp::Delete { col, key } => (*col, &key[..], None),
+			DBOp::DeletePrefix { .. } => unreachable!("prefix deletions are expanded above; qed"),
+		};
+
+		if col == columns::META && key == &STORAGE_STATS_KEY[..] {
+			continue;
+		}
+
+		let old_size = match overlay.get(&(col, key.to_vec())) {
+			Some(size) => *size,
+			None => db.get(col, key)?.map(|value| key.len() + value.len()),
+		};
+		overlay.insert((col, key.to_vec()), new_size);
+
+		let bytes = if col == columns::DATA { &mut counts.data_bytes } else { &mut counts.meta_bytes };
+		*bytes = bytes.saturating_sub(old_size.unwrap_or(0) as u64) + new_size.unwrap_or(0) as u64;
+
+		if col != columns::DATA {
+			continue;
+		}
+
+		if decode_erasure_chunk_key(key).is_some() {
+			match (old_size.is_some(), new_size.is_some()) {
+				(false, true) => counts.chunks += 1,
+				(true, false) => counts.chunks = counts.chunks.saturating_sub(1),
+				_ => {}
+			}
+		}
+
+		if let Ok(candidate_hash) = CandidateHash::decode(&mut &key[..]) {
+			if !touched.contains_key(&candidate_hash) {
+				let had_values = db.iter_with_prefix(columns::DATA, &candidate_data_prefix(&candidate_hash))
+					.next()
+					.is_some();
+				touched.insert(candidate_hash, had_values);
+			}
+		}
+	}
+
+	for (candidate_hash, had_values) in touched {
+		let prefix = candidate_data_prefix(&candidate_hash);
+		let has_values = overlay.iter()
+			.any(|((col, key), size)| *col == columns::DATA && key.starts_with(&prefix) && size.is_some())
+			|| db.iter_with_prefix(columns::DATA, &prefix)
+				.any(|(key, _)| !overlay.contains_key(&(columns::DATA, key.into_vec())));
+
+		match (had_values, has_values) {
+			(false, true) => counts.candidates += 1,
+			(true, false) => counts.candidates = counts.candidates.saturating_sub(1),
+			_ => {}
+		}
+	}
+
+	tx.ops = ops;
+	tx.put_vec(columns::META, &STORAGE_STATS_KEY, counts.encode());
+
+	db.write(tx)
+}
+
+// Decode every value of a candidate, including any checksum, and check the proofs of chunks
+// against the erasure root of their candidate if it is known.
+//
+// Unlike `query_inner` this never panics on values that fail to decode.
+fn verify_integrity(db: &Arc<dyn KeyValueDB>) -> IntegrityReport {
+	let mut checked = 0;
+	let mut corrupt = HashSet::new();
+
+	for (key, value) in db.iter(columns::DATA) {
+		let (candidate_hash, intact) = match check_data_value(db, &key, &value) {
+			Some(checked) => checked,
+			None => continue,
+		};
+
+		checked += 1;
+		if !intact {
+			corrupt.insert(candidate_hash);
+		}
+	}
+
+	for (key, value) in db.iter(columns::META) {
+		// Only the values of a candidate are keyed by its 32 byte hash and a tag.
+		let (candidate_hash, tag) = match <(CandidateHash, i8)>::decode(&mut &key[..]) {
+			Ok(decoded) if key.len() == 33 => decoded,
+			_ => continue,
+		};
+
+		let input = &mut &value[..];
+		let intact = match tag {
+			1 => u32::decode(input).is_ok(),
+			2 => Duration::decode(input).is_ok(),
+			3 => Hash::decode(input).is_ok(),
+			4 => CandidateReceipt::decode(input).is_ok(),
+			5 => ErasureCodingVersion::decode(input).is_ok(),
+			_ => continue,
+		};
+
+		checked += 1;
+		if !intact {
+			corrupt.insert(candidate_hash);
+		}
+	}
+
+	let mut corrupt: Vec<_> = corrupt.into_iter().collect();
+	corrupt.sort_by_key(|candidate_hash| candidate_hash.0);
+
+	IntegrityReport { checked, corrupt }
+}
+
+// Check a value of `columns::DATA`, returning the candidate it belongs to and whether it is
+// intact. Returns `None` for keys that are neither of available data nor of a chunk.
+fn check_data_value(db: &Arc<dyn KeyValueDB>, key: &[u8], value: &[u8]) -> Option<(CandidateHash, bool)> {
+	if let Some(candidate_hash) = decode_available_data_key(key) {
+		Some((candidate_hash, decode_checked::<StoredAvailableData>(value).is_ok()))
+	} else if let Some((candidate_hash, index)) = decode_erasure_chunk_key(key) {
+		let erasure_root = db.get(columns::META, &erasure_root_key(&candidate_hash))
+			.ok()
+			.flatten()
+			.and_then(|raw| Hash::decode(&mut &raw[..]).ok());
+
+		let intact = decode_checked::<ErasureChunk>(value).map_or(false, |chunk| {
+			chunk.index == index &&
+				erasure_root.map_or(true, |root| chunk_matches_root(&root, &chunk))
+		});
+
+		Some((candidate_hash, intact))
+	} else {
+		None
+	}
+}
+
+// Check up to `sample_size` values of `columns::DATA` for corruption, like `verify_integrity`.
+//
+// The keys start with the candidate hash, so the values under a random first byte are a random
+// sample. The following first bytes are sampled as well until enough values were checked.
+// Every corrupt value is reported along with its candidate.
+fn sample_data_integrity(db: &Arc<dyn KeyValueDB>, sample_size: usize) -> IntegrityReport {
+	let start = RandomState::new().build_hasher().finish() as u8;
+	let mut report = IntegrityReport::default();
+
+	for offset in 0..=u8::MAX {
+		let prefix = [start.wrapping_add(offset)];
+
+		for (key, value) in db.iter_with_prefix(columns::DATA, &prefix) {
+			if report.checked >= sample_size {
+				return report;
+			}
+
+			if let Some((candidate_hash, intact)) = check_data_value(db, &key, &value) {
+				report.checked += 1;
+				if !intact {
+					report.corrupt.push(candidate_hash);
+				}
+			}
+		}
+	}
+
+	report
+}
+
+fn chunk_pruning_record(
+	db: &Arc<dyn KeyValueDB>,
+	candidate_hash: &CandidateHash,
+	index: u32,
+) -> Option<ChunkPruningRecord> {
+	query_inner(db, columns::META, &chunk_pruning_record_key(candidate_hash, index))
+}
+
+// The chunk pruning records of a single candidate.
+fn chunk_pruning_records(
+	db: &Arc<dyn KeyValueDB>,
+	candidate_hash: &CandidateHash,
+) -> Vec<ChunkPruningRecord> {
+	db.iter_with_prefix(columns::META, &(CHUNK_PRUNING_RECORD_PREFIX, candidate_hash).encode())
+		.filter_map(|(_, value)| ChunkPruningRecord::decode(&mut &value[..]).ok())
+		.collect()
+}
+
+// The number of sorted PoV pruning records at the head that are due at `now`, up to `limit`.
+fn outdated_pov_pruning_count(
+	pov_pruning: &[PoVPruningRecord],
+	now: Duration,
+	limit: usize,
+) -> usize {
+	let now = PruningDelay::In(now);
+	pov_pruning.iter().take_while(|r| r.prune_at <= now).take(limit).count()
+}
+
+fn outdated_chunk_pruning_records(
+	db: &Arc<dyn KeyValueDB>,
+	now: Duration,
+	limit: usize,
+) -> Vec<ChunkPruningRecord> {
+	db.iter_with_prefix(columns::META, &CHUNK_PRUNING_INDEX_PREFIX)
+		.filter_map(|(key, _)| decode_chunk_pruning_index_key(&key))
+		.take_while(|(prune_at, _, _)| *prune_at <= now)
+		.take(limit)
+		.filter_map(|(_, candidate_hash, index)| chunk_pruning_record(db, &candidate_hash, index))
+		.collect()
+}
+
+#[tracing::instrument(level = "trace", skip(subsystem, tx), fields(subsystem = LOG_TARGET))]
+fn put_pov_pruning(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	tx: Option<DBTransaction>,
+	mut pov_pruning: Vec<PoVPruningRecord>,
+) -> Result<(), Error> {
+	pov_pruning.sort();
+
+	put_pov_pruning_presorted(subsystem, tx, pov_pruning)
+}
+
+// Like `put_pov_pruning`, for records the caller already keeps in sorted order.
+fn put_pov_pruning_presorted(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	tx: Option<DBTransaction>,
+	pov_pruning: Vec<PoVPruningRecord>,
+) -> Result<(), Error> {
+	let mut tx = tx.unwrap_or_default();
+
+	let next_pruning = stage_pov_pruning_presorted(
+		&subsystem.inner,
+		subsystem.pruning_config.pov_pruning_spill_threshold,
+		&mut tx,
+		pov_pruning,
+	);
+
+	// Only update the cached value once the write succeeded.
+	write_counted(&subsystem.inner, tx)?;
+	subsystem.next_pov_pruning = next_pruning;
+
+	Ok(())
+}
+
+// Add the sorted PoV pruning records and the next pruning time derived from them to `tx`.
+//
+// Up to `spill_threshold` records are kept under `POV_PRUNING_KEY`. Beyond that they are spilled
+// into buckets by pruning time and only the buckets that changed are rewritten.
+fn stage_pov_pruning_presorted(
+	db: &Arc<dyn KeyValueDB>,
+	spill_threshold: usize,
+	tx: &mut DBTransaction,
+	pov_pruning: Vec<PoVPruningRecord>,
+) -> Option<NextPoVPruning> {
+	debug_assert!(is_sorted(&pov_pruning));
+
+	let mut stale_buckets: HashMap<Box<[u8]>, Box<[u8]>> = db
+		.iter_with_prefix(columns::META, &POV_PRUNING_BUCKET_PREFIX)
+		.collect();
+
+	if pov_pruning.len() > spill_threshold {
+		let mut buckets: BTreeMap<Vec<u8>, Vec<&PoVPruningRecord>> = BTreeMap::new();
+		for record in &pov_pruning {
+			buckets.entry(pov_pruning_bucket_key(&record.prune_at)).or_default().push(record);
+		}
+
+		for (key, records) in buckets {
+			let encoded = records.encode();
+			if stale_buckets.remove(&key[..]).map_or(true, |stored| stored[..] != encoded[..]) {
+				tx.put_vec(columns::META, &key, encoded);
+			}
+		}
+
+		tx.delete(columns::META, &POV_PRUNING_KEY);
+	} else {
+		tx.put_vec(
+			columns::META,
+			&POV_PRUNING_KEY,
+			pov_pruning.encode(),
+		);
+	}
+
+	for key in stale_buckets.keys() {
+		tx.delete(columns::META, key);
+	}
+
+	stage_next_pov_pruning(tx, &pov_pruning)
+}
+
+// Like `stage_pov_pruning_presorted`, for records of which `changed` moved from or to the
+// buckets `changed_buckets` since they were staged last.
+//
+// If the records are spilled into buckets and at most `incremental_percent` of them changed,
+// only the affected buckets are encoded and written. Otherwise all records are staged.
+fn stage_changed_pov_pruning(
+	db: &Arc<dyn KeyValueDB>,
+	spill_threshold: usize,
+	incremental_percent: usize,
+	tx: &mut DBTransaction,
+	pov_pruning: Vec<PoVPruningRecord>,
+	changed: usize,
+	changed_buckets: &HashSet<Vec<u8>>,
+) -> Option<NextPoVPruning> {
+	debug_assert!(is_sorted(&pov_pruning));
+
+	let spilled = pov_pruning.len() > spill_threshold &&
+		matches!(db.get(columns::META, &POV_PRUNING_KEY), Ok(None));
+
+	if !spilled || changed * 100 > pov_pruning.len() * incremental_percent {
+		return stage_pov_pruning_presorted(db, spill_threshold, tx, pov_pruning);
+	}
+
+	let mut buckets: BTreeMap<&[u8], Vec<&PoVPruningRecord>> = changed_buckets.iter()
+		.map(|key| (&key[..], Vec::new()))
+		.collect();
+	for record in &pov_pruning {
+		if let Some(records) = buckets.get_mut(&pov_pruning_bucket_key(&record.prune_at)[..]) {
+			records.push(record);
+		}
+	}
+
+	for (key, records) in buckets {
+		if records.is_empty() {
+			tx.delete(columns::META, key);
+		} else {
+			tx.put_vec(columns::META, key, records.encode());
+		}
+	}
+
+	stage_next_pov_pruning(tx, &pov_pruning)
+}
+
+// Add the next pruning time derived from the sorted PoV pruning records to `tx`.
+fn stage_next_pov_pruning(
+	tx: &mut DBTransaction,
+	pov_pruning: &[PoVPruningRecord],
+) -> Option<NextPoVPruning> {
+	match pov_pruning.get(0) {
+		// We want to wake up in case we have some records that are not scheduled to be kept
+		// indefinitely (data is included and waiting to move to the finalized state) and so
+		// the is at least one value that is not `PruningDelay::Indefinite`. Otherwise it is
+		// recorded that all records are kept indefinitely.
+		Some(PoVPruningRecord { prune_at, .. }) => {
+			tx.put_vec(
+				columns::META,
+				&NEXT_POV_PRUNING,
+				prune_at.encode(),
+			);
+
+			match prune_at {
+				PruningDelay::In(prune_at) => Some(NextPoVPruning(*prune_at)),
+				PruningDelay::Indefinite => None,
+			}
+		}
+		None => {
+			// If there is no longer any records, delete the cached pruning time record.
+			tx.delete(
+				columns::META,
+				&NEXT_POV_PRUNING,
+			);
+			None
+		}
+	}
+}
+
+// Add a chunk pruning record and, unless it is kept indefinitely, its index entry to `tx`.
+fn put_chunk_pruning_record(tx: &mut DBTransaction, record: &ChunkPruningRecord) {
+	tx.put_vec(
+		columns::META,
+		&chunk_pruning_record_key(&record.candidate_hash, record.chunk_index),
+		record.encode(),
+	);
+
+	if let PruningDelay::In(prune_at) = record.prune_at {
+		tx.put_vec(
+			columns::META,
+			&chunk_pruning_index_key(&prune_at, &record.candidate_hash, record.chunk_index),
+			Vec::new(),
+		);
+	}
+}
+
+// Remove a chunk pruning record and its index entry in `tx`.
+fn delete_chunk_pruning_record(tx: &mut DBTransaction, record: &ChunkPruningRecord) {
+	tx.delete(
+		columns::META,
+		&chunk_pruning_record_key(&record.candidate_hash, record.chunk_index),
+	);
+
+	if let PruningDelay::In(prune_at) = record.prune_at {
+		tx.delete(
+			columns::META,
+			&chunk_pruning_index_key(&prune_at, &record.candidate_hash, record.chunk_index),
+		);
+	}
+}
+
+// Answer a request, counting answers that are dropped as the caller has gone away.
+fn send_response<T>(metrics: &Metrics, tx: oneshot::Sender<T>, response: T) -> Result<(), oneshot::Canceled> {
+	tx.send(response).map_err(|_| {
+		metrics.on_response_send_failure();
+		oneshot::Canceled
+	})
+}
+
+// Return a sender for the answer to a store, which is forwarded to `tx` along with the time
+// until the data of the candidate, or the chunk with `chunk_index`, is pruned.
+async fn forward_prune_time<Context>(
+	ctx: &mut Context,
+	db: Arc<dyn KeyValueDB>,
+	metrics: Metrics,
+	candidate_hash: CandidateHash,
+	chunk_index: Option<u32>,
+	tx: oneshot::Sender<Result<Option<Duration>, StoreError>>,
+) -> Result<oneshot::Sender<Result<(), StoreError>>, Error>
+where
+	Context: SubsystemContext<Message=AvailabilityStoreMessage>,
+{
+	let (stored_tx, stored_rx) = oneshot::channel();
+
+	let forward = async move {
+		let result = match stored_rx.await {
+			Ok(Ok(())) => prune_in(&db, &candidate_hash, chunk_index),
+			Ok(Err(e)) => Err(e),
+			// The store was dropped without an answer.
+			Err(oneshot::Canceled) => Err(StoreError::Internal),
+		};
+
+		let _ = send_response(&metrics, tx, result);
+	};
+
+	ctx.spawn("av-store-prune-time", forward.boxed()).await?;
+
+	Ok(stored_tx)
+}
+
+// The time from now until the data of a candidate, or one of its chunks, is pruned, `None` if
+// it is kept indefinitely. Data that is already gone is reported as due right away.
+fn prune_in(
+	db: &Arc<dyn KeyValueDB>,
+	candidate_hash: &CandidateHash,
+	chunk_index: Option<u32>,
+) -> Result<Option<Duration>, StoreError> {
+	let prune_at = match chunk_index {
+		Some(index) => chunk_pruning_record(db, candidate_hash, index).map(|record| record.prune_at),
+		None => pov_pruning(db).unwrap_or_default()
+			.into_iter()
+			.find(|record| record.candidate_hash == *candidate_hash)
+			.map(|record| record.prune_at),
+	};
+
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|_| StoreError::Internal)?;
+
+	Ok(match prune_at {
+		Some(PruningDelay::In(prune_at)) => Some(prune_at.checked_sub(now).unwrap_or_default()),
+		Some(PruningDelay::Indefinite) => None,
+		None => Some(Duration::default()),
+	})
+}
+
+// The number of the block a chunk is stored for, looked up by its relay parent unless given.
+async fn chunk_block_number<Context>(
+	ctx: &mut Context,
+	relay_parent: Hash,
+	block_number: Option<BlockNumber>,
+) -> Result<BlockNumber, Error>
+where
+	Context: SubsystemContext<Message=AvailabilityStoreMessage>,
+{
+	match block_number {
+		Some(number) => Ok(number),
+		// Current block number is relay_parent block number + 1.
+		None => match get_block_number(ctx, relay_parent).await? {
+			Some(number) => number.checked_add(1).ok_or(Error::BlockNumberOverflow),
+			None => Err(Error::UnknownRelayParent(relay_parent)),
+		},
+	}
+}
+
+// produces a block number by block's hash.
+// in the the event of an unknown `block_hash`, returns `Ok(None)`
+async fn get_block_number<Context>(
+	ctx: &mut Context,
+	block_hash: Hash,
+) -> Result<Option<BlockNumber>, Error>
+where
+	Context: SubsystemContext<Message=AvailabilityStoreMessage>,
+{
+	let (tx, rx) = oneshot::channel();
+
+	ctx.send_message(AllMessages::ChainApi(ChainApiMessage::BlockNumber(block_hash, tx))).await;
+
+	Ok(rx.await??)
+}
+
+// Store the `AvailableData` of a candidate. If our validator index is given, its chunks are
+// stored as well.
+//
+// Erasure coding is all-or-nothing: a single chunk can't be computed on its own, and its proof
+// is a branch of the merkle tree over all chunks. As the whole set has to be computed anyway,
+// all of it is kept instead of regenerating it on the first query of another chunk.
+#[tracing::instrument(level = "trace", skip(subsystem, available_data), fields(subsystem = LOG_TARGET))]
+fn store_available_data(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+	id: Option<ValidatorIndex>,
+	n_validators: u32,
+	available_data: AvailableData,
+	coding_version: ErasureCodingVersion,
+	expire_at: Option<Duration>,
+) -> Result<(), Error> {
+	check_validator_count(n_validators)?;
+
+	let chunks = match id {
+		Some(index) => Some(get_chunks_to_store(
+			&available_data,
+			n_validators,
+			index,
+			coding_version,
+			&subsystem.metrics,
+		)?),
+		None => None,
+	};
+
+	store_available_data_with_chunks(
+		subsystem,
+		candidate_hash,
+		chunks,
+		n_validators,
+		available_data,
+		coding_version,
+		expire_at,
+	)?;
+
+	if let Some(index) = id {
+		debug_assert_chunk_stored(subsystem, candidate_hash, index);
+	}
+
+	Ok(())
+}
+
+// Once data is stored along with the chunk at `index`, that chunk must be retrievable.
+fn debug_assert_chunk_stored(
+	subsystem: &AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+	index: ValidatorIndex,
+) {
+	debug_assert!(
+		query_len_inner(&subsystem.inner, columns::DATA, &erasure_chunk_key(candidate_hash, index)).is_some(),
+		"chunk {} of candidate {:?} is missing right after storing it",
+		index,
+		candidate_hash,
+	);
+}
+
+// Store the `AvailableData` of a candidate along with its chunks and the erasure root their
+// proofs are checked against, if any.
+#[tracing::instrument(level = "trace", skip(subsystem, chunks, available_data), fields(subsystem = LOG_TARGET))]
+fn store_available_data_with_chunks(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+	chunks: Option<(Vec<ErasureChunk>, Hash)>,
+	n_validators: u32,
+	available_data: AvailableData,
+	coding_version: ErasureCodingVersion,
+	expire_at: Option<Duration>,
+) -> Result<(), Error> {
+	let _timer = subsystem.metrics.time_store_available_data();
+
+	if subsystem.read_only {
+		return Err(Error::ReadOnly);
+	}
+
+	let mut tx = DBTransaction::new();
+
+	let block_number = available_data.validation_data.block_number;
+	let stores_chunks = chunks.is_some();
+
+	if let Some((chunks, erasure_root)) = chunks {
+		stage_own_chunks(subsystem, &mut tx, candidate_hash, &chunks, erasure_root, block_number, expire_at)?;
+	}
+
+	let stored_data = StoredAvailableData {
+		data: available_data,
+		n_validators,
+	};
+
+	let mut pov_pruning = pov_pruning(&subsystem.inner).unwrap_or_default();
+	let prune_at = PruningDelay::for_stored(expire_at, subsystem.pruning_config.keep_stored_block_for)?;
+	let mut evicted = false;
+
+	match pov_pruning.iter().position(|r| r.candidate_hash == *candidate_hash) {
+		// Storing the same candidate again only refreshes the retention of data that is not
+		// included yet, included and finalized candidates keep their pruning schedule.
+		Some(pos) => if pov_pruning[pos].candidate_state == CandidateState::Stored {
+			// The record is moved to keep the records sorted.
+			let mut record = pov_pruning.remove(pos);
+			record.prune_at = prune_at;
+
+			let idx = pov_pruning.binary_search(&record).unwrap_or_else(|insert_idx| insert_idx);
+			pov_pruning.insert(idx, record);
+		},
+		None => {
+			if let Some(max) = subsystem.max_stored_candidates {
+				while pov_pruning.len() >= max {
+					match evict_oldest_candidate(&subsystem.inner, &mut tx, &mut pov_pruning) {
+						Some(_) => evicted = true,
+						None => break,
+					}
+				}
+			}
+
+			let pruning_record = PoVPruningRecord {
+				candidate_hash: *candidate_hash,
+				block_number,
+				candidate_state: CandidateState::Stored,
+				prune_at,
+			};
+
+			let idx = pov_pruning.binary_search(&pruning_record).unwrap_or_else(|insert_idx| insert_idx);
+
+			pov_pruning.insert(idx, pruning_record);
+		}
+	}
+
+	let encoded_data = stored_data.encode();
+	let encoded_len = encoded_data.len();
+
+	tx.put_vec(
+		columns::DATA,
+		available_data_key(&candidate_hash).as_slice(),
+		stored_value(subsystem, encoded_data),
+	);
+
+	tx.put_vec(
+		columns::META,
+		n_validators_key(&candidate_hash).as_slice(),
+		n_validators.encode(),
+	);
+
+	tx.put_vec(
+		columns::META,
+		coding_version_key(&candidate_hash).as_slice(),
+		coding_version.encode(),
+	);
+
+	tx.put_vec(
+		columns::META,
+		block_candidate_key(block_number, candidate_hash).as_slice(),
+		Vec::new(),
+	);
+
+	// Only the first time the data is stored is recorded.
+	if stored_at(&subsystem.inner, candidate_hash).is_none() {
+		let stored_at = SystemTime::now().duration_since(UNIX_EPOCH)?;
+
+		tx.put_vec(
+			columns::META,
+			stored_at_key(&candidate_hash).as_slice(),
+			stored_at.encode(),
+		);
+	}
+
+	put_pov_pruning_presorted(subsystem, Some(tx), pov_pruning)?;
+
+	if stores_chunks || evicted {
+		subsystem.next_chunk_pruning = get_next_chunk_pruning_time(&subsystem.inner);
+	}
+
+	subsystem.metrics.on_available_data_stored(encoded_len);
+
+	Ok(())
+}
+
+// Remove the oldest candidate that is not finalized, along with its chunks, staging the
+// deletions in `tx`. Returns the hash of the evicted candidate.
+//
+// The PoV pruning records are sorted by pruning time, which is set from the time the data was
+// stored, so the first record that is not finalized is the oldest without reading any other
+// values.
+fn evict_oldest_candidate(
+	db: &Arc<dyn KeyValueDB>,
+	tx: &mut DBTransaction,
+	pov_pruning: &mut Vec<PoVPruningRecord>,
+) -> Option<CandidateHash> {
+	let idx = pov_pruning.iter()
+		.position(|record| record.candidate_state != CandidateState::Finalized)?;
+
+	let PoVPruningRecord { candidate_hash, block_number, .. } = pov_pruning.remove(idx);
+
+	tracing::debug!(
+		target: LOG_TARGET,
+		candidate_hash = ?candidate_hash,
+		"Evicting candidate to stay within the maximum number of stored candidates",
+	);
+
+	tx.delete(columns::DATA, available_data_key(&candidate_hash).as_slice());
+	tx.delete(columns::META, stored_at_key(&candidate_hash).as_slice());
+	tx.delete(columns::META, n_validators_key(&candidate_hash).as_slice());
+	tx.delete(columns::META, erasure_root_key(&candidate_hash).as_slice());
+	tx.delete(columns::META, coding_version_key(&candidate_hash).as_slice());
+	delete_candidate_receipt(db, tx, &candidate_hash);
+	tx.delete(columns::META, block_candidate_key(block_number, &candidate_hash).as_slice());
+
+	for record in chunk_pruning_records(db, &candidate_hash) {
+		tx.delete(columns::DATA, erasure_chunk_key(&candidate_hash, record.chunk_index).as_slice());
+		delete_chunk_pruning_record(tx, &record);
+	}
+
+	Some(candidate_hash)
+}
+
+#[tracing::instrument(level = "trace", skip(subsystem), fields(subsystem = LOG_TARGET))]
+fn store_chunk(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+	_n_validators: u32,
+	chunk: ErasureChunk,
+	block_number: BlockNumber,
+	expire_at: Option<Duration>,
+) -> Result<(), Error> {
+	let _timer = subsystem.metrics.time_store_chunk();
+
+	if subsystem.read_only {
+		return Err(Error::ReadOnly);
+	}
+
+	let mut tx = DBTransaction::new();
+
+	stage_chunk(subsystem, &mut tx, candidate_hash, chunk, block_number, expire_at)?;
+
+	write_counted(&subsystem.inner, tx)?;
+
+	// Only update the cached value once the write succeeded.
+	subsystem.next_chunk_pruning = get_next_chunk_pruning_time(&subsystem.inner);
+
+	Ok(())
+}
+
+// Store several chunks in a single transaction.
+//
+// Returns the result of checking each of the chunks, those that pass are stored unless the
+// write fails. A chunk that occurs several times is only stored once.
+fn store_chunks(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	chunks: &[(CandidateHash, ErasureChunk, BlockNumber)],
+) -> Result<Vec<Result<(), Error>>, Error> {
+	if subsystem.read_only {
+		return Err(Error::ReadOnly);
+	}
+
+	let mut tx = DBTransaction::new();
+	let mut staged = HashSet::new();
+
+	let results = chunks.iter().map(|(candidate_hash, chunk, block_number)| {
+		if !staged.insert((*candidate_hash, chunk.index)) {
+			return Ok(());
+		}
+
+		stage_chunk(subsystem, &mut tx, candidate_hash, chunk.clone(), *block_number, None)
+	}).collect();
+
+	write_counted(&subsystem.inner, tx)?;
+
+	// Only update the cached value once the write succeeded.
+	subsystem.next_chunk_pruning = get_next_chunk_pruning_time(&subsystem.inner);
+
+	Ok(results)
+}
+
+// Check a chunk and add it along with its pruning record to `tx`.
+fn stage_chunk(
+	subsystem: &AvailabilityStoreSubsystem,
+	tx: &mut DBTransaction,
+	candidate_hash: &CandidateHash,
+	chunk: ErasureChunk,
+	block_number: BlockNumber,
+	expire_at: Option<Duration>,
+) -> Result<(), Error> {
+	// The erasure root can only be derived if the full data is known, chunks of
+	// candidates without stored data are accepted as they are. A root that is already
+	// known spares erasure coding the data once more for every chunk.
+	if let Some(stored) = available_data(&subsystem.inner, candidate_hash) {
+		let root = match erasure_root(&subsystem.inner, candidate_hash) {
+			Some(root) => root,
+			None => {
+				let version = coding_version(&subsystem.inner, candidate_hash);
+				let encoded = obtain_chunks(version, stored.n_validators as usize, &stored.data)?;
+				erasure::branches(encoded.as_ref()).root()
+			}
+		};
+
+		if !chunk_matches_root(&root, &chunk) {
+			return Err(Error::InvalidChunkProof);
+		}
+	}
+
+	stage_verified_chunk(subsystem, tx, candidate_hash, chunk, block_number, expire_at)
+}
+
+// Add a chunk that is known to be valid along with its pruning record to `tx`.
+fn stage_verified_chunk(
+	subsystem: &AvailabilityStoreSubsystem,
+	tx: &mut DBTransaction,
+	candidate_hash: &CandidateHash,
+	chunk: ErasureChunk,
+	block_number: BlockNumber,
+	expire_at: Option<Duration>,
+) -> Result<(), Error> {
+	let dbkey = erasure_chunk_key(candidate_hash, chunk.index);
+
+	let prune_at = PruningDelay::for_stored(expire_at, subsystem.pruning_config.keep_stored_block_for)?;
+
+	match chunk_pruning_record(&subsystem.inner, candidate_hash, chunk.index) {
+		// Storing the same chunk again only refreshes the retention of chunks that are not
+		// included yet, included and finalized chunks keep their pruning schedule.
+		Some(record) if record.candidate_state != CandidateState::Stored => {}
+		existing => {
+			if let Some(record) = existing {
+				delete_chunk_pruning_record(tx, &record);
+			}
+
+			put_chunk_pruning_record(tx, &ChunkPruningRecord {
+				candidate_hash: *candidate_hash,
+				block_number,
+				candidate_state: CandidateState::Stored,
+				chunk_index: chunk.index,
+				prune_at,
+			});
+		}
+	}
+
+	tx.put_vec(
+		columns::DATA,
+		&dbkey,
+		stored_value(subsystem, chunk.encode()),
+	);
+
+	Ok(())
+}
+
+// Add the chunks erasure-coded from the data of a candidate and their erasure root to `tx`.
+//
+// The chunks were generated by the store itself, so their proofs are not checked.
+fn stage_own_chunks(
+	subsystem: &AvailabilityStoreSubsystem,
+	tx: &mut DBTransaction,
+	candidate_hash: &CandidateHash,
+	chunks: &[ErasureChunk],
+	erasure_root: Hash,
+	block_number: BlockNumber,
+	expire_at: Option<Duration>,
+) -> Result<(), Error> {
+	tx.put_vec(
+		columns::META,
+		erasure_root_key(candidate_hash).as_slice(),
+		erasure_root.encode(),
+	);
+
+	for chunk in chunks {
+		stage_verified_chunk(subsystem, tx, candidate_hash, chunk.clone(), block_number, expire_at)?;
+	}
+
+	Ok(())
+}
+
+// Store the chunks regenerated from the stored data of a candidate and their erasure root in
+// a single transaction.
+fn store_regenerated_chunks(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+	chunks: &[ErasureChunk],
+	erasure_root: Hash,
+	block_number: BlockNumber,
+) -> Result<(), Error> {
+	let _timer = subsystem.metrics.time_store_chunk();
+
+	let mut tx = DBTransaction::new();
+
+	stage_own_chunks(subsystem, &mut tx, candidate_hash, chunks, erasure_root, block_number, None)?;
+
+	write_counted(&subsystem.inner, tx)?;
+
+	// Only update the cached value once the write succeeded.
+	subsystem.next_chunk_pruning = get_next_chunk_pruning_time(&subsystem.inner);
+
+	Ok(())
+}
+
+// Store chunks provided from elsewhere in a single transaction, scheduling them for pruning
+// according to the state of their candidate.
+//
+// Chunks of candidates with a known erasure root are checked against it.
+fn import_chunks(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	records: Vec<(CandidateHash, u32, ErasureChunk, BlockNumber, CandidateState)>,
+) -> Result<(), Error> {
+	if subsystem.read_only {
+		return Err(Error::ReadOnly);
+	}
+
+	let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+	let mut tx = DBTransaction::new();
+	let mut imported = HashSet::new();
+
+	// Only the last of several records for the same chunk is imported.
+	for (candidate_hash, n_validators, chunk, block_number, candidate_state) in records.into_iter().rev() {
+		if !imported.insert((candidate_hash, chunk.index)) {
+			continue;
+		}
+
+		check_validator_count(n_validators).and_then(|()| check_chunk_index(chunk.index, n_validators))?;
+
+		if let Some(root) = erasure_root(&subsystem.inner, &candidate_hash) {
+			if !chunk_matches_root(&root, &chunk) {
+				return Err(Error::InvalidChunkProof);
+			}
+		}
+
+		let prune_at = match candidate_state {
+			CandidateState::Stored => PruningDelay::In(now + subsystem.pruning_config.keep_stored_block_for),
+			CandidateState::Included => PruningDelay::Indefinite,
+			// The block of the candidate is finalized at least up to its own number.
+			CandidateState::Finalized => subsystem.pruning_config.finalized_prune_at(
+				block_number,
+				block_number,
+				subsystem.pruning_config.keep_finalized_chunk_for
+					.map(|keep_for| subsystem.extended_retention(&candidate_hash, keep_for)),
+				now,
+			),
+		};
+
+		if let Some(record) = chunk_pruning_record(&subsystem.inner, &candidate_hash, chunk.index) {
+			delete_chunk_pruning_record(&mut tx, &record);
+		}
+
+		put_chunk_pruning_record(&mut tx, &ChunkPruningRecord {
+			candidate_hash,
+			block_number,
+			candidate_state,
+			chunk_index: chunk.index,
+			prune_at,
+		});
+
+		tx.put_vec(
+			columns::META,
+			n_validators_key(&candidate_hash).as_slice(),
+			n_validators.encode(),
+		);
+
+		tx.put_vec(
+			columns::DATA,
+			&erasure_chunk_key(&candidate_hash, chunk.index),
+			stored_value(subsystem, chunk.encode()),
+		);
+	}
+
+	write_counted(&subsystem.inner, tx)?;
+
+	// Only update the cached value once the write succeeded.
+	subsystem.next_chunk_pruning = get_next_chunk_pruning_time(&subsystem.inner);
+
+	Ok(())
+}
+
+#[tracing::instrument(level = "trace", skip(subsystem), fields(subsystem = LOG_TARGET))]
+fn get_chunk(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+	index: u32,
+) -> Result<Option<ErasureChunk>, Error> {
+	let _timer = subsystem.metrics.time_get_chunk();
+
+	if let Some(chunk) = query_verified(
+		subsystem,
+		columns::DATA,
+		&erasure_chunk_key(candidate_hash, index)
+	)? {
+		return Ok(Some(chunk));
+	}
+
+	if let Some(data) = checked_available_data(subsystem, candidate_hash)? {
+		let (chunks, erasure_root) = regenerate_chunks(subsystem, candidate_hash, &data)?;
+		let desired_chunk = chunks.get(index as usize).cloned();
+		// Regenerated chunks are handed out either way, but only stored when the store is writable.
+		if subsystem.read_only {
+			return Ok(desired_chunk);
+		}
+		store_regenerated_chunks(
+			subsystem,
+			candidate_hash,
+			&chunks,
+			erasure_root,
+			data.data.validation_data.block_number,
+		)?;
+		return Ok(desired_chunk);
+	}
+
+	Ok(None)
+}
+
+// Erasure-code the stored data of a candidate again to serve its chunks.
+//
+// The chunks are kept in memory for a short while, so that a burst of queries for chunks that
+// are not stored, e.g. on a read-only store, only erasure-codes the data once.
+fn regenerate_chunks(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+	data: &StoredAvailableData,
+) -> Result<(Arc<Vec<ErasureChunk>>, Hash), Error> {
+	let cache = &mut subsystem.regenerated_chunks;
+	cache.retain(|(_, regenerated_at, _, _)| regenerated_at.elapsed() < REGENERATED_CHUNKS_TTL);
+
+	if let Some(pos) = cache.iter().position(|(hash, _, _, _)| hash == candidate_hash) {
+		let entry = cache.remove(pos).expect("position was just found; qed");
+		let result = (entry.2.clone(), entry.3);
+		cache.push_back(entry);
+		return Ok(result);
+	}
+
+	let (chunks, erasure_root) = get_chunks_with_root(
+		&data.data,
+		data.n_validators as usize,
+		coding_version(&subsystem.inner, candidate_hash),
+		&subsystem.metrics,
+	)?;
+	subsystem.metrics.on_chunks_regenerated();
+
+	let chunks = Arc::new(chunks);
+	let cache = &mut subsystem.regenerated_chunks;
+	if cache.len() >= REGENERATED_CHUNKS_CACHE_SIZE {
+		cache.pop_front();
+	}
+	cache.push_back((*candidate_hash, Instant::now(), chunks.clone(), erasure_root));
+
+	Ok((chunks, erasure_root))
+}
+
+// Like `get_chunk`, but for several indices at once. If any of the requested chunks is missing
+// and the full data is available, all chunks are regenerated and stored just once.
+fn get_chunks_by_indices(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+	indices: &[u32],
+) -> Result<Vec<Option<ErasureChunk>>, Error> {
+	let _timer = subsystem.metrics.time_get_chunk();
+
+	let mut result: Vec<Option<ErasureChunk>> = indices.iter()
+		.map(|index| query_verified(
+			subsystem,
+			columns::DATA,
+			&erasure_chunk_key(candidate_hash, *index),
+		))
+		.collect::<Result<_, _>>()?;
+
+	if result.iter().all(Option::is_some) {
+		return Ok(result);
+	}
+
+	if let Some(data) = checked_available_data(subsystem, candidate_hash)? {
+		let (chunks, erasure_root) = regenerate_chunks(subsystem, candidate_hash, &data)?;
+
+		for (index, slot) in indices.iter().zip(result.iter_mut()) {
+			if slot.is_none() {
+				*slot = chunks.get(*index as usize).cloned();
+			}
+		}
+
+		if subsystem.read_only {
+			return Ok(result);
+		}
+
+		store_regenerated_chunks(
+			subsystem,
+			candidate_hash,
+			&chunks,
+			erasure_root,
+			data.data.validation_data.block_number,
+		)?;
+	}
+
+	Ok(result)
+}
+
+// Like `get_chunks_by_indices`, for the indices from `start` up to but excluding `end`.
+//
+// The stored chunks in the range are found in a single pass over the keys of the candidate.
+// Indices at or beyond the number of validators, or `MAX_VALIDATORS` if that is not known,
+// are left out.
+fn get_chunk_range(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+	start: u32,
+	end: u32,
+) -> Result<Vec<Option<ErasureChunk>>, Error> {
+	let end = end.min(n_validators(&subsystem.inner, candidate_hash).unwrap_or(MAX_VALIDATORS));
+	if start >= end {
+		return Ok(Vec::new());
+	}
+
+	let mut result = vec![None; (end - start) as usize];
+
+	for (key, value) in subsystem.inner.iter_with_prefix(columns::DATA, &candidate_data_prefix(candidate_hash)) {
+		match decode_erasure_chunk_key(&key) {
+			Some((_, index)) if start <= index && index < end => {
+				result[(index - start) as usize] = Some(decode_verified(subsystem, &key, &value)?);
+			}
+			_ => {}
+		}
+	}
+
+	let missing: Vec<_> = (start..end)
+		.zip(result.iter())
+		.filter(|(_, chunk)| chunk.is_none())
+		.map(|(index, _)| index)
+		.collect();
+
+	if missing.is_empty() {
+		return Ok(result);
+	}
+
+	let regenerated = get_chunks_by_indices(subsystem, candidate_hash, &missing)?;
+	for (index, chunk) in missing.into_iter().zip(regenerated) {
+		result[(index - start) as usize] = chunk;
+	}
+
+	Ok(result)
+}
+
+// The length of a stored encoded chunk, leaving out its checksum if checksums are verified.
+fn chunk_size(
+	subsystem: &AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+	index: u32,
+) -> Option<usize> {
+	let raw = query_raw_inner(&subsystem.inner, columns::DATA, &erasure_chunk_key(candidate_hash, index))?;
+
+	if subsystem.verify_checksums {
+		Some(len_without_checksum(&raw))
+	} else {
+		Some(raw.len())
+	}
+}
+
+// The merkle proof of a stored chunk. Unless checksums are verified, which requires decoding
+// the whole chunk, the chunk data is skipped instead of being decoded.
+fn chunk_proof(
+	subsystem: &AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+	index: u32,
+) -> Result<Option<Vec<Vec<u8>>>, Error> {
+	let key = erasure_chunk_key(candidate_hash, index);
+
+	if subsystem.verify_checksums {
+		let chunk: Option<ErasureChunk> = query_verified(subsystem, columns::DATA, &key)?;
+		return Ok(chunk.map(|chunk| chunk.proof));
+	}
+
+	let raw = match subsystem.inner.get(columns::DATA, &key)? {
+		Some(raw) => raw,
+		None => return Ok(None),
+	};
+
+	// The fields of `ErasureChunk` in order: `chunk`, `index` and `proof`.
+	let mut input = &raw[..];
+	let chunk_len = <Compact<u32>>::decode(&mut input)?.0 as usize;
+	input = input.get(chunk_len..).ok_or_else(|| parity_scale_codec::Error::from("Not enough data for chunk"))?;
+	u32::decode(&mut input)?;
+
+	Ok(Some(Decode::decode(&mut input)?))
+}
+
+// Like `query_inner`, but returning the stored bytes without decoding them.
+fn query_raw_inner(
+	db: &Arc<dyn KeyValueDB>,
+	column: u32,
+	key: &[u8],
+) -> Option<Vec<u8>> {
+	match db.get(column, key) {
+		Ok(raw) => raw.map(|raw| raw.to_vec()),
+		Err(e) => {
+			tracing::warn!(target: LOG_TARGET, err = ?e, "Error reading from the availability store");
+			None
+		}
+	}
+}
+
+fn query_len_inner(
+	db: &Arc<dyn KeyValueDB>,
+	column: u32,
+	key: &[u8],
+) -> Option<usize> {
+	match db.get(column, key) {
+		Ok(Some(raw)) => Some(raw.len()),
+		Ok(None) => None,
+		Err(e) => {
+			tracing::warn!(target: LOG_TARGET, err = ?e, "Error reading from the availability store");
+			None
+		}
+	}
+}
+
+// Like `query_inner`, but verifying the checksum of the value if the subsystem is configured to.
+fn query_verified<D: Decode>(
+	subsystem: &AvailabilityStoreSubsystem,
+	column: u32,
+	key: &[u8],
+) -> Result<Option<D>, Error> {
+	if !subsystem.verify_checksums {
+		return Ok(query_inner(&subsystem.inner, column, key));
+	}
+
+	let raw = match subsystem.inner.get(column, key)? {
+		Some(raw) => raw,
+		None => return Ok(None),
+	};
+
+	decode_verified(subsystem, key, &raw).map(Some)
+}
+
+// Decode a value read from the database, verifying its checksum if the subsystem is
+// configured to.
+fn decode_verified<D: Decode>(
+	subsystem: &AvailabilityStoreSubsystem,
+	key: &[u8],
+	raw: &[u8],
+) -> Result<D, Error> {
+	if !subsystem.verify_checksums {
+		return Ok(D::decode(&mut &raw[..])?);
+	}
+
+	match decode_checked(raw) {
+		Err(Error::ChecksumMismatch) => {
+			tracing::warn!(target: LOG_TARGET, key = ?key, "Stored value does not match its checksum");
+			Err(Error::ChecksumMismatch)
+		}
+		result => result,
+	}
+}
+
+// Decode a stored value, failing if it is followed by anything but its checksum.
+fn decode_checked<D: Decode>(raw: &[u8]) -> Result<D, Error> {
+	let mut input = raw;
+	let value = D::decode(&mut input)?;
+	let encoded = &raw[..raw.len() - input.len()];
+
+	match input {
+		// Stored without a checksum.
+		[] => Ok(value),
+		[CHECKSUM_VERSION, checksum @ ..] if BlakeTwo256::hash(encoded).as_ref() == checksum => Ok(value),
+		_ => Err(Error::ChecksumMismatch),
+	}
+}
+
+// The length of a stored value without the checksum appended to it, if it has a valid one.
+fn len_without_checksum(raw: &[u8]) -> usize {
+	const SUFFIX_LEN: usize = 1 + 32;
+
+	match raw.len().checked_sub(SUFFIX_LEN) {
+		Some(len) if raw[len] == CHECKSUM_VERSION
+			&& BlakeTwo256::hash(&raw[..len]).as_ref() == &raw[len + 1..] => len,
+		_ => raw.len(),
+	}
+}
+
+// The value to store for `encoded`, with a checksum appended if the subsystem is configured to.
+//
+// Decoding ignores trailing bytes, so reads that don't verify the checksum are unaffected by it.
+fn stored_value(subsystem: &AvailabilityStoreSubsystem, mut encoded: Vec<u8>) -> Vec<u8> {
+	if subsystem.verify_checksums {
+		let checksum = BlakeTwo256::hash(&encoded);
+		encoded.push(CHECKSUM_VERSION);
+		encoded.extend_from_slice(checksum.as_ref());
+	}
+
+	encoded
+}
+
+fn query_inner<D: Decode>(
+	db: &Arc<dyn KeyValueDB>,
+	column: u32,
+	key: &[u8],
+) -> Option<D> {
+	match db.get(column, key) {
+		Ok(Some(raw)) => {
+			let res = D::decode(&mut &raw[..]).expect("all stored data serialized correctly; qed");
+			Some(res)
+		}
+		Ok(None) => None,
+		Err(e) => {
+			tracing::warn!(target: LOG_TARGET, err = ?e, "Error reading from the availability store");
+			None
+		}
+	}
+}
+
+impl<Context> Subsystem<Context> for AvailabilityStoreSubsystem
+where
+	Context: SubsystemContext<Message = AvailabilityStoreMessage>,
+{
+	fn start(self, ctx: Context) -> SpawnedSubsystem {
+		let future = run(self, ctx)
+			.map(|_| Ok(()))
+			.boxed();
+
+		SpawnedSubsystem {
+			name: "availability-store-subsystem",
+			future,
+		}
+	}
+}
+
+// Remove everything but the schema version from the database in a single transaction.
+fn purge_all(subsystem: &mut AvailabilityStoreSubsystem) -> Result<(), Error> {
+	if subsystem.read_only {
+		return Err(Error::ReadOnly);
+	}
+
+	let mut tx = DBTransaction::new();
+
+	for (key, _) in subsystem.inner.iter(columns::DATA) {
+		tx.delete(columns::DATA, &key);
+	}
+
+	for (key, _) in subsystem.inner.iter(columns::META) {
+		if key[..] != SCHEMA_VERSION_KEY[..] {
+			tx.delete(columns::META, &key);
+		}
+	}
 
-	ctx.send_message(AllMessages::ChainApi(ChainApiMessage::BlockNumber(block_hash, tx))).await;
+	write_counted(&subsystem.inner, tx)?;
+
+	// Only reset the cached values once the write succeeded.
+	subsystem.next_pov_pruning = None;
+	subsystem.next_chunk_pruning = None;
 
-	Ok(rx.await??.map(|number| number).unwrap_or_default())
+	Ok(())
 }
 
-#[tracing::instrument(level = "trace", skip(subsystem, available_data), fields(subsystem = LOG_TARGET))]
-fn store_available_data(
+// Reconstruct the data of a candidate from its chunks and store it unless already present.
+//
+// The chunks are only accepted if their proofs match the erasure root of the reconstructed data.
+fn reconstruct_available_data(
 	subsystem: &mut AvailabilityStoreSubsystem,
 	candidate_hash: &CandidateHash,
-	id: Option<ValidatorIndex>,
 	n_validators: u32,
-	available_data: AvailableData,
-) -> Result<(), Error> {
-	let _timer = subsystem.metrics.time_store_available_data();
+	chunks: &[ErasureChunk],
+) -> Result<AvailableData, Error> {
+	let data = reconstruct_from_chunks(n_validators, chunks)?;
 
-	let mut tx = DBTransaction::new();
+	if !subsystem.read_only && available_data(&subsystem.inner, candidate_hash).is_none() {
+		store_available_data(subsystem, candidate_hash, None, n_validators, data.clone(), ErasureCodingVersion::V1, None)?;
+	}
 
-	let block_number = available_data.validation_data.block_number;
+	Ok(data)
+}
 
-	if let Some(index) = id {
-		let chunks = get_chunks(&available_data, n_validators as usize, &subsystem.metrics)?;
-		store_chunk(
-			subsystem,
-			candidate_hash,
-			n_validators,
-			chunks[index as usize].clone(),
-			block_number,
-		)?;
-	}
+// Reconstruct the data of a candidate from its chunks, which have to match the erasure root
+// of the reconstructed data.
+fn reconstruct_from_chunks(n_validators: u32, chunks: &[ErasureChunk]) -> Result<AvailableData, Error> {
+	let data: AvailableData = erasure::reconstruct_v1(
+		n_validators as usize,
+		chunks.iter().map(|chunk| (chunk.chunk.as_slice(), chunk.index as usize)),
+	)?;
 
-	let stored_data = StoredAvailableData {
-		data: available_data,
-		n_validators,
-	};
+	let encoded = erasure::obtain_chunks_v1(n_validators as usize, &data)?;
+	let root = erasure::branches(encoded.as_ref()).root();
 
-	let mut pov_pruning = pov_pruning(&subsystem.inner).unwrap_or_default();
-	let prune_at = PruningDelay::into_the_future(subsystem.pruning_config.keep_stored_block_for)?;
+	if !chunks.iter().all(|chunk| chunk_matches_root(&root, chunk)) {
+		return Err(Error::InvalidChunkProof);
+	}
 
-	if let Some(next_pruning) = prune_at.as_duration() {
-		tx.put_vec(
-			columns::META,
-			&NEXT_POV_PRUNING,
-			NextPoVPruning(next_pruning).encode(),
-		);
+	Ok(data)
+}
+
+// Reconstruct the data of a candidate in the background if it is missing and enough of its
+// chunks are stored. Failing to do so is not an error of the store that triggered it.
+//
+// A candidate is only attempted again once more chunks are stored than the previous attempt
+// started with, whether that one is still pending or failed.
+async fn auto_reconstruct<Context>(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	ctx: &mut Context,
+	candidate_hash: &CandidateHash,
+) -> Result<(), Error>
+where
+	Context: SubsystemContext<Message=AvailabilityStoreMessage>,
+{
+	if !subsystem.auto_reconstruct || available_data(&subsystem.inner, candidate_hash).is_some() {
+		return Ok(());
 	}
 
-	let pruning_record = PoVPruningRecord {
-		candidate_hash: *candidate_hash,
-		block_number,
-		candidate_state: CandidateState::Stored,
-		prune_at,
+	let n_validators = match n_validators(&subsystem.inner, candidate_hash) {
+		Some(n_validators) => n_validators,
+		None => return Ok(()),
 	};
 
-	let idx = pov_pruning.binary_search(&pruning_record).unwrap_or_else(|insert_idx| insert_idx);
+	let indices = stored_chunk_indices(&subsystem.inner, candidate_hash);
+	let attempted = subsystem.reconstruction_attempts.get(candidate_hash).copied().unwrap_or_default();
+	if indices.len() < recovery_threshold(n_validators as usize) || indices.len() <= attempted {
+		return Ok(());
+	}
 
-	pov_pruning.insert(idx, pruning_record);
+	subsystem.reconstruction_attempts.insert(*candidate_hash, indices.len());
 
-	tx.put_vec(
-		columns::DATA,
-		available_data_key(&candidate_hash).as_slice(),
-		stored_data.encode(),
-	);
+	let chunks = indices.into_iter()
+		.map(|index| query_verified(subsystem, columns::DATA, &erasure_chunk_key(candidate_hash, index)))
+		.filter_map(Result::transpose)
+		.collect::<Result<Vec<ErasureChunk>, _>>();
 
-	tx.put_vec(
-		columns::META,
-		&POV_PRUNING_KEY,
-		pov_pruning.encode(),
-	);
+	let chunks = match chunks {
+		Ok(chunks) => chunks,
+		Err(e) => {
+			tracing::debug!(target: LOG_TARGET, ?candidate_hash, err = ?e, "Failed to read chunks to reconstruct from");
+			return Ok(());
+		}
+	};
+
+	let (data_tx, data_rx) = oneshot::channel();
+	let job = async move {
+		let _ = data_tx.send(reconstruct_from_chunks(n_validators, &chunks));
+	};
 
-	subsystem.inner.write(tx)?;
+	ctx.spawn_blocking("av-store-reconstruction", job.boxed()).await?;
+
+	let candidate_hash = *candidate_hash;
+	subsystem.pending_reconstructions.push(async move {
+		PendingReconstruction {
+			candidate_hash,
+			n_validators,
+			data: data_rx.await.unwrap_or_else(|e| Err(e.into())),
+		}
+	}.boxed());
 
 	Ok(())
 }
 
-#[tracing::instrument(level = "trace", skip(subsystem), fields(subsystem = LOG_TARGET))]
-fn store_chunk(
+// Store the data of an automatic reconstruction, unless it was stored in the meantime.
+fn finish_reconstruction(
 	subsystem: &mut AvailabilityStoreSubsystem,
-	candidate_hash: &CandidateHash,
-	_n_validators: u32,
-	chunk: ErasureChunk,
-	block_number: BlockNumber,
+	pending: PendingReconstruction,
 ) -> Result<(), Error> {
-	let _timer = subsystem.metrics.time_store_chunk();
+	let PendingReconstruction { candidate_hash, n_validators, data } = pending;
 
-	let mut tx = DBTransaction::new();
+	let data = match data {
+		Ok(data) => data,
+		Err(e) => {
+			tracing::debug!(
+				target: LOG_TARGET,
+				?candidate_hash,
+				err = ?e,
+				"Failed to reconstruct available data from stored chunks",
+			);
+			return Ok(());
+		}
+	};
 
-	let dbkey = erasure_chunk_key(candidate_hash, chunk.index);
+	subsystem.reconstruction_attempts.remove(&candidate_hash);
 
-	let mut chunk_pruning = chunk_pruning(&subsystem.inner).unwrap_or_default();
-	let prune_at = PruningDelay::into_the_future(subsystem.pruning_config.keep_stored_block_for)?;
+	if available_data(&subsystem.inner, &candidate_hash).is_none() {
+		store_available_data(subsystem, &candidate_hash, None, n_validators, data, ErasureCodingVersion::V1, None)
+			.map_err(|e| e.for_candidate(candidate_hash))?;
 
-	if let Some(delay) = prune_at.as_duration() {
-		tx.put_vec(
-			columns::META,
-			&NEXT_CHUNK_PRUNING,
-			NextChunkPruning(delay).encode(),
-		);
+		tracing::debug!(target: LOG_TARGET, ?candidate_hash, "Reconstructed available data from stored chunks");
+	}
+
+	Ok(())
+}
+
+// Postpone the pruning of the data and chunks of a candidate to at least `keep_for` from now,
+// writing all records in a single transaction. Returns whether any record was found.
+fn extend_retention(
+	subsystem: &mut AvailabilityStoreSubsystem,
+	candidate_hash: &CandidateHash,
+	keep_for: Duration,
+) -> Result<bool, Error> {
+	if subsystem.read_only {
+		return Err(Error::ReadOnly);
 	}
 
-	let pruning_record = ChunkPruningRecord {
-		candidate_hash: candidate_hash.clone(),
-		block_number,
-		candidate_state: CandidateState::Stored,
-		chunk_index: chunk.index,
-		prune_at,
+	let keep_until = SystemTime::now().duration_since(UNIX_EPOCH)? + keep_for;
+	let extend = |prune_at: &mut PruningDelay| if *prune_at < PruningDelay::In(keep_until) {
+		*prune_at = PruningDelay::In(keep_until);
 	};
 
-	let idx = chunk_pruning.binary_search(&pruning_record).unwrap_or_else(|insert_idx| insert_idx);
+	let mut tx = DBTransaction::new();
+	let mut pov_pruning = pov_pruning(&subsystem.inner).unwrap_or_default();
+	let mut found = false;
 
-	chunk_pruning.insert(idx, pruning_record);
+	for record in pov_pruning.iter_mut().filter(|r| r.candidate_hash == *candidate_hash) {
+		extend(&mut record.prune_at);
+		found = true;
+	}
 
-	tx.put_vec(
-		columns::DATA,
-		&dbkey,
-		chunk.encode(),
-	);
+	for mut record in chunk_pruning_records(&subsystem.inner, candidate_hash) {
+		delete_chunk_pruning_record(&mut tx, &record);
+		extend(&mut record.prune_at);
+		put_chunk_pruning_record(&mut tx, &record);
+		found = true;
+	}
 
-	tx.put_vec(
-		columns::META,
-		&CHUNK_PRUNING_KEY,
-		chunk_pruning.encode(),
-	);
+	if !found {
+		return Ok(false);
+	}
 
-	subsystem.inner.write(tx)?;
+	// `put_pov_pruning` sorts the records again.
+	put_pov_pruning(subsystem, Some(tx), pov_pruning)?;
+	subsystem.next_chunk_pruning = get_next_chunk_pruning_time(&subsystem.inner);
 
-	Ok(())
+	Ok(true)
 }
 
-#[tracing::instrument(level = "trace", skip(subsystem), fields(subsystem = LOG_TARGET))]
-fn get_chunk(
-	subsystem: &mut AvailabilityStoreSubsystem,
-	candidate_hash: &CandidateHash,
-	index: u32,
-) -> Result<Option<ErasureChunk>, Error> {
-	let _timer = subsystem.metrics.time_get_chunk();
+// Check the merkle proof of a chunk against an erasure root.
+fn chunk_matches_root(root: &Hash, chunk: &ErasureChunk) -> bool {
+	match erasure::branch_hash(root, &chunk.proof, chunk.index as usize) {
+		Ok(hash) => hash == BlakeTwo256::hash(&chunk.chunk),
+		Err(_) => false,
+	}
+}
 
-	if let Some(chunk) = query_inner(
-		&subsystem.inner,
-		columns::DATA,
-		&erasure_chunk_key(candidate_hash, index)
-	) {
-		return Ok(Some(chunk));
+// Check that data can be erasure-coded for `n_validators` before doing any work.
+fn check_validator_count(n_validators: u32) -> Result<(), Error> {
+	if n_validators < 2 || n_validators > MAX_VALIDATORS {
+		return Err(Error::InvalidValidatorCount(n_validators));
 	}
 
-	if let Some(data) = available_data(&subsystem.inner, candidate_hash) {
-		let mut chunks = get_chunks(&data.data, data.n_validators as usize, &subsystem.metrics)?;
-		let desired_chunk = chunks.get(index as usize).cloned();
-		for chunk in chunks.drain(..) {
-			store_chunk(
-				subsystem,
-				candidate_hash,
-				data.n_validators,
-				chunk,
-				data.data.validation_data.block_number,
-			)?;
-		}
-		return Ok(desired_chunk);
+	Ok(())
+}
+
+// Check that `index` refers to one of the `n_validators` chunks.
+fn check_chunk_index(index: ValidatorIndex, n_validators: u32) -> Result<(), Error> {
+	if index >= n_validators {
+		return Err(Error::ChunkIndexOutOfRange { index, n_validators });
 	}
 
-	Ok(None)
+	Ok(())
 }
 
-fn query_inner<D: Decode>(
-	db: &Arc<dyn KeyValueDB>,
-	column: u32,
-	key: &[u8],
-) -> Option<D> {
-	match db.get(column, key) {
-		Ok(Some(raw)) => {
-			let res = D::decode(&mut &raw[..]).expect("all stored data serialized correctly; qed");
-			Some(res)
-		}
-		Ok(None) => None,
-		Err(e) => {
-			tracing::warn!(target: LOG_TARGET, err = ?e, "Error reading from the availability store");
-			None
-		}
+// Reject an expiry time that has already passed.
+fn check_expiry(expire_at: Duration) -> Result<(), Error> {
+	if expire_at <= SystemTime::now().duration_since(UNIX_EPOCH)? {
+		return Err(Error::ExpiryInThePast(expire_at));
 	}
+
+	Ok(())
 }
 
-impl<Context> Subsystem<Context> for AvailabilityStoreSubsystem
-where
-	Context: SubsystemContext<Message = AvailabilityStoreMessage>,
-{
-	fn start(self, ctx: Context) -> SpawnedSubsystem {
-		let future = run(self, ctx)
-			.map(|_| Ok(()))
-			.boxed();
+// Erasure-code the data, making sure the chunk with our index is among the chunks.
+fn get_chunks_to_store(
+	data: &AvailableData,
+	n_validators: u32,
+	index: ValidatorIndex,
+	coding_version: ErasureCodingVersion,
+	metrics: &Metrics,
+) -> Result<(Vec<ErasureChunk>, Hash), Error> {
+	check_chunk_index(index, n_validators)?;
 
-		SpawnedSubsystem {
-			name: "availability-store-subsystem",
-			future,
-		}
+	get_chunks_with_root(data, n_validators as usize, coding_version, metrics)
+}
+
+// Fail if an erasure root is expected and `erasure_root` is a different one.
+fn check_erasure_root(expected: Option<Hash>, erasure_root: &Hash) -> Result<(), Error> {
+	match expected {
+		Some(expected) if expected != *erasure_root => Err(Error::ErasureRootMismatch {
+			expected,
+			found: *erasure_root,
+		}),
+		_ => Ok(()),
+	}
+}
+
+// Erasure-code the data with the given version of the erasure coding.
+fn obtain_chunks(
+	coding_version: ErasureCodingVersion,
+	n_validators: usize,
+	data: &AvailableData,
+) -> Result<Vec<Vec<u8>>, Error> {
+	match coding_version {
+		ErasureCodingVersion::V1 => Ok(erasure::obtain_chunks_v1(n_validators, data)?),
 	}
 }
 
-#[tracing::instrument(level = "trace", skip(metrics), fields(subsystem = LOG_TARGET))]
-fn get_chunks(data: &AvailableData, n_validators: usize, metrics: &Metrics) -> Result<Vec<ErasureChunk>, Error> {
-	let chunks = erasure::obtain_chunks_v1(n_validators, data)?;
+// Erasure-code the data into chunks, also returning the root of the merkle tree of the chunks.
+fn get_chunks_with_root(
+	data: &AvailableData,
+	n_validators: usize,
+	coding_version: ErasureCodingVersion,
+	metrics: &Metrics,
+) -> Result<(Vec<ErasureChunk>, Hash), Error> {
+	let chunks = obtain_chunks(coding_version, n_validators, data)?;
 	metrics.on_chunks_received(chunks.len());
 	let branches = erasure::branches(chunks.as_ref());
+	let erasure_root = branches.root();
 
-	Ok(chunks
+	let chunks = chunks
 		.iter()
 		.zip(branches.map(|(proof, _)| proof))
 		.enumerate()
@@ -1102,13 +4909,19 @@ fn get_chunks(data: &AvailableData, n_validators: usize, metrics: &Metrics) -> R
 			proof,
 			index: index as u32,
 		})
-		.collect()
-	)
+		.collect();
+
+	Ok((chunks, erasure_root))
 }
 
 #[derive(Clone)]
 struct MetricsInner {
 	received_availability_chunks_total: prometheus::Counter<prometheus::U64>,
+	compactions_total: prometheus::Counter<prometheus::U64>,
+	pruned_povs_total: prometheus::Counter<prometheus::U64>,
+	pruned_chunks_total: prometheus::Counter<prometheus::U64>,
+	pruned_total: prometheus::CounterVec<prometheus::U64>,
+	chunk_regenerations_total: prometheus::Counter<prometheus::U64>,
 	prune_povs: prometheus::Histogram,
 	prune_chunks: prometheus::Histogram,
 	process_block_finalized: prometheus::Histogram,
@@ -1117,6 +4930,24 @@ struct MetricsInner {
 	store_available_data: prometheus::Histogram,
 	store_chunk: prometheus::Histogram,
 	get_chunk: prometheus::Histogram,
+	available_data_bytes: prometheus::Histogram,
+	cache_hit_ratio: prometheus::Gauge<prometheus::F64>,
+	bytes_read: prometheus::Gauge<prometheus::U64>,
+	bytes_written: prometheus::Gauge<prometheus::U64>,
+	pending_stores: prometheus::Gauge<prometheus::U64>,
+	message_queue_depth: prometheus::Gauge<prometheus::U64>,
+	seconds_since_last_prune: prometheus::Gauge<prometheus::F64>,
+	response_send_failures_total: prometheus::Counter<prometheus::U64>,
+	corrupt_values_total: prometheus::Counter<prometheus::U64>,
+}
+
+// The value of the `state` label of records pruned in `state`.
+fn candidate_state_label(state: CandidateState) -> &'static str {
+	match state {
+		CandidateState::Stored => "stored",
+		CandidateState::Included => "included",
+		CandidateState::Finalized => "finalized",
+	}
 }
 
 /// Availability metrics.
@@ -1133,12 +4964,94 @@ impl Metrics {
 		}
 	}
 
-	/// Provide a timer for `prune_povs` which observes on drop.
+	fn on_compaction(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.compactions_total.inc();
+		}
+	}
+
+	fn on_povs_pruned(&self, records: &[PoVPruningRecord]) {
+		if let Some(metrics) = &self.0 {
+			metrics.pruned_povs_total.inc_by(records.len() as u64);
+			for record in records {
+				metrics.pruned_total
+					.with_label_values(&["pov", candidate_state_label(record.candidate_state)])
+					.inc();
+			}
+		}
+	}
+
+	fn on_chunks_pruned(&self, records: &[ChunkPruningRecord]) {
+		if let Some(metrics) = &self.0 {
+			metrics.pruned_chunks_total.inc_by(records.len() as u64);
+			for record in records {
+				metrics.pruned_total
+					.with_label_values(&["chunk", candidate_state_label(record.candidate_state)])
+					.inc();
+			}
+		}
+	}
+
+	fn on_chunks_regenerated(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.chunk_regenerations_total.inc();
+		}
+	}
+
+	fn on_io_stats(&self, stats: &IoStats) {
+		if let Some(metrics) = &self.0 {
+			// A single read may hit several cached blocks, so the ratio is capped.
+			if stats.reads > 0 {
+				let ratio = stats.cache_reads as f64 / stats.reads as f64;
+				metrics.cache_hit_ratio.set(ratio.min(1.0));
+			}
+			metrics.bytes_read.set(stats.bytes_read);
+			metrics.bytes_written.set(stats.bytes_written);
+		}
+	}
+
+	fn on_pending_stores(&self, count: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.pending_stores.set(count as u64);
+		}
+	}
+
+	fn on_message_queue_depth(&self, depth: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.message_queue_depth.set(depth as u64);
+		}
+	}
+
+	fn on_prune_age(&self, age: Duration) {
+		if let Some(metrics) = &self.0 {
+			metrics.seconds_since_last_prune.set(age.as_secs_f64());
+		}
+	}
+
+	fn on_response_send_failure(&self) {
+		if let Some(metrics) = &self.0 {
+			metrics.response_send_failures_total.inc();
+		}
+	}
+
+	fn on_corrupt_values(&self, count: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.corrupt_values_total.inc_by(count as u64);
+		}
+	}
+
+	fn on_available_data_stored(&self, bytes: usize) {
+		if let Some(metrics) = &self.0 {
+			metrics.available_data_bytes.observe(bytes as f64);
+		}
+	}
+
+	/// Provide a timer for pruning PoVs which observes on drop.
 	fn time_prune_povs(&self) -> Option<metrics::prometheus::prometheus::HistogramTimer> {
 		self.0.as_ref().map(|metrics| metrics.prune_povs.start_timer())
 	}
 
-	/// Provide a timer for `prune_chunks` which observes on drop.
+	/// Provide a timer for pruning chunks which observes on drop.
 	fn time_prune_chunks(&self) -> Option<metrics::prometheus::prometheus::HistogramTimer> {
 		self.0.as_ref().map(|metrics| metrics.prune_chunks.start_timer())
 	}
@@ -1184,11 +5097,49 @@ impl metrics::Metrics for Metrics {
 				)?,
 				registry,
 			)?,
+			compactions_total: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_av_store_compactions_total",
+					"Number of compactions of the data column run after pruning.",
+				)?,
+				registry,
+			)?,
+			pruned_povs_total: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_av_store_pruned_povs_total",
+					"Number of available data records pruned.",
+				)?,
+				registry,
+			)?,
+			pruned_chunks_total: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_av_store_pruned_chunks_total",
+					"Number of erasure chunk records pruned.",
+				)?,
+				registry,
+			)?,
+			pruned_total: prometheus::register(
+				prometheus::CounterVec::new(
+					prometheus::Opts::new(
+						"parachain_av_store_pruned_total",
+						"Number of records pruned by kind of record and state of the candidate.",
+					),
+					&["record", "state"],
+				)?,
+				registry,
+			)?,
+			chunk_regenerations_total: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_av_store_chunk_regenerations_total",
+					"Number of times missing chunks were regenerated from the full data.",
+				)?,
+				registry,
+			)?,
 			prune_povs: prometheus::register(
 				prometheus::Histogram::with_opts(
 					prometheus::HistogramOpts::new(
 						"parachain_av_store_prune_povs",
-						"Time spent within `av_store::prune_povs`",
+						"Time spent pruning PoVs",
 					)
 				)?,
 				registry,
@@ -1197,7 +5148,7 @@ impl metrics::Metrics for Metrics {
 				prometheus::Histogram::with_opts(
 					prometheus::HistogramOpts::new(
 						"parachain_av_store_prune_chunks",
-						"Time spent within `av_store::prune_chunks`",
+						"Time spent pruning chunks",
 					)
 				)?,
 				registry,
@@ -1256,6 +5207,75 @@ impl metrics::Metrics for Metrics {
 				)?,
 				registry,
 			)?,
+			available_data_bytes: prometheus::register(
+				prometheus::Histogram::with_opts(
+					prometheus::HistogramOpts {
+						common_opts: prometheus::Opts::new(
+							"parachain_av_store_available_data_bytes",
+							"Encoded size of the available data stored",
+						),
+						// PoVs range from a few KiB up to several MiB: `1024 * (2 ^ 14)` is 16 MiB.
+						buckets: prometheus::exponential_buckets(1024.0, 2.0, 15).expect("inputs are within documented range; qed"),
+					}
+				)?,
+				registry,
+			)?,
+			cache_hit_ratio: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_av_store_cache_hit_ratio",
+					"Share of database reads served from the block cache since the last update.",
+				)?,
+				registry,
+			)?,
+			bytes_read: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_av_store_bytes_read",
+					"Number of bytes read from the database since the last update.",
+				)?,
+				registry,
+			)?,
+			bytes_written: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_av_store_bytes_written",
+					"Number of bytes written to the database since the last update.",
+				)?,
+				registry,
+			)?,
+			pending_stores: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_av_store_pending_stores",
+					"Number of `StoreAvailableData` requests waiting to be erasure-coded.",
+				)?,
+				registry,
+			)?,
+			message_queue_depth: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_av_store_message_queue_depth",
+					"Number of signals and messages received but not processed yet.",
+				)?,
+				registry,
+			)?,
+			seconds_since_last_prune: prometheus::register(
+				prometheus::Gauge::new(
+					"parachain_av_store_seconds_since_last_prune",
+					"Number of seconds since the last successful prune.",
+				)?,
+				registry,
+			)?,
+			response_send_failures_total: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_av_store_response_send_failures_total",
+					"Number of responses that could not be sent as the caller dropped the receiver.",
+				)?,
+				registry,
+			)?,
+			corrupt_values_total: prometheus::register(
+				prometheus::Counter::new(
+					"parachain_av_store_corrupt_values_total",
+					"Number of corrupt values found by the periodic integrity samples.",
+				)?,
+				registry,
+			)?,
 		};
 		Ok(Metrics(Some(metrics)))
 	}
@@ -18,7 +18,7 @@ use super::*;
 
 use assert_matches::assert_matches;
 use futures::{
-	future,
+	future::{self, BoxFuture},
 	channel::oneshot,
 	executor,
 	Future,
@@ -33,6 +33,140 @@ use polkadot_subsystem::{
 	ActiveLeavesUpdate, errors::RuntimeApiError, JaegerSpan,
 };
 use polkadot_node_subsystem_test_helpers as test_helpers;
+use sp_core::traits::SpawnNamed;
+use std::sync::{Mutex, atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering}};
+
+/// An in-memory database whose writes can be made to fail.
+struct FailingDb {
+	inner: kvdb_memorydb::InMemory,
+	fail_writes: AtomicBool,
+	writes: AtomicUsize,
+}
+
+impl FailingDb {
+	fn new() -> Self {
+		Self {
+			inner: kvdb_memorydb::create(columns::NUM_COLUMNS),
+			fail_writes: AtomicBool::new(false),
+			writes: AtomicUsize::new(0),
+		}
+	}
+
+	fn set_fail_writes(&self, fail: bool) {
+		self.fail_writes.store(fail, AtomicOrdering::SeqCst);
+	}
+
+	fn writes(&self) -> usize {
+		self.writes.load(AtomicOrdering::SeqCst)
+	}
+}
+
+impl parity_util_mem::MallocSizeOf for FailingDb {
+	fn size_of(&self, ops: &mut parity_util_mem::MallocSizeOfOps) -> usize {
+		parity_util_mem::MallocSizeOf::size_of(&self.inner, ops)
+	}
+}
+
+impl KeyValueDB for FailingDb {
+	fn get(&self, col: u32, key: &[u8]) -> io::Result<Option<kvdb::DBValue>> {
+		self.inner.get(col, key)
+	}
+
+	fn get_by_prefix(&self, col: u32, prefix: &[u8]) -> Option<Box<[u8]>> {
+		self.inner.get_by_prefix(col, prefix)
+	}
+
+	fn write(&self, transaction: DBTransaction) -> io::Result<()> {
+		if self.fail_writes.load(AtomicOrdering::SeqCst) {
+			return Err(io::Error::new(io::ErrorKind::Other, "write failed"));
+		}
+
+		self.writes.fetch_add(1, AtomicOrdering::SeqCst);
+		self.inner.write(transaction)
+	}
+
+	fn iter<'a>(&'a self, col: u32) -> Box<dyn Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+		self.inner.iter(col)
+	}
+
+	fn iter_with_prefix<'a>(
+		&'a self,
+		col: u32,
+		prefix: &'a [u8],
+	) -> Box<dyn Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+		self.inner.iter_with_prefix(col, prefix)
+	}
+
+	fn restore(&self, new_db: &str) -> io::Result<()> {
+		self.inner.restore(new_db)
+	}
+}
+
+/// A tracing subscriber capturing the candidate hash and state of every state transition event.
+#[derive(Clone, Default)]
+struct TransitionCapture(Arc<Mutex<Vec<(String, String)>>>);
+
+#[derive(Default)]
+struct TransitionVisitor {
+	message: String,
+	candidate_hash: String,
+	state: String,
+}
+
+impl tracing::field::Visit for TransitionVisitor {
+	fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+		match field.name() {
+			"message" => self.message = format!("{:?}", value),
+			"candidate_hash" => self.candidate_hash = format!("{:?}", value),
+			"state" => self.state = format!("{:?}", value),
+			_ => {}
+		}
+	}
+}
+
+impl tracing::Subscriber for TransitionCapture {
+	fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+		true
+	}
+
+	fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+		tracing::span::Id::from_u64(1)
+	}
+
+	fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+	fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+	fn event(&self, event: &tracing::Event<'_>) {
+		let mut visitor = TransitionVisitor::default();
+		event.record(&mut visitor);
+
+		if visitor.message == "Candidate state transition" {
+			self.0.lock().unwrap().push((visitor.candidate_hash, visitor.state));
+		}
+	}
+
+	fn enter(&self, _: &tracing::span::Id) {}
+
+	fn exit(&self, _: &tracing::span::Id) {}
+}
+
+/// A spawner holding back blocking tasks until they are run by hand.
+#[derive(Clone)]
+struct HoldingSpawner {
+	inner: sp_core::testing::TaskExecutor,
+	held: Arc<Mutex<Vec<BoxFuture<'static, ()>>>>,
+}
+
+impl SpawnNamed for HoldingSpawner {
+	fn spawn_blocking(&self, _: &'static str, future: BoxFuture<'static, ()>) {
+		self.held.lock().unwrap().push(future);
+	}
+
+	fn spawn(&self, name: &'static str, future: BoxFuture<'static, ()>) {
+		self.inner.spawn(name, future);
+	}
+}
 
 struct TestHarness {
 	virtual_overseer: test_helpers::TestSubsystemContextHandle<AvailabilityStoreMessage>,
@@ -79,7 +213,10 @@ impl Default for TestState {
 		let pruning_config = PruningConfig {
 			keep_stored_block_for: Duration::from_secs(1),
 			keep_finalized_block_for: Duration::from_secs(2),
-			keep_finalized_chunk_for: Duration::from_secs(2),
+			keep_finalized_chunk_for: Some(Duration::from_secs(2)),
+			coalesce_pruning_within: Duration::from_secs(1),
+			retention: Retention::ByTime,
+			..PruningConfig::default()
 		};
 
 		Self {
@@ -93,6 +230,23 @@ fn test_harness<T: Future<Output=()>>(
 	pruning_config: PruningConfig,
 	store: Arc<dyn KeyValueDB>,
 	test: impl FnOnce(TestHarness) -> T,
+) {
+	let subsystem = AvailabilityStoreSubsystem::with_db(store, pruning_config, Metrics(None));
+
+	test_harness_with_subsystem(subsystem, test)
+}
+
+fn test_harness_with_subsystem<T: Future<Output=()>>(
+	subsystem: AvailabilityStoreSubsystem,
+	test: impl FnOnce(TestHarness) -> T,
+) {
+	test_harness_with_spawner(subsystem, sp_core::testing::TaskExecutor::new(), test)
+}
+
+fn test_harness_with_spawner<T: Future<Output=()>>(
+	subsystem: AvailabilityStoreSubsystem,
+	spawner: impl SpawnNamed + Send + 'static,
+	test: impl FnOnce(TestHarness) -> T,
 ) {
 	let _ = env_logger::builder()
 		.is_test(true)
@@ -106,10 +260,8 @@ fn test_harness<T: Future<Output=()>>(
 		)
 		.try_init();
 
-	let pool = sp_core::testing::TaskExecutor::new();
-	let (context, virtual_overseer) = test_helpers::make_subsystem_context(pool.clone());
+	let (context, virtual_overseer) = test_helpers::make_subsystem_context(spawner);
 
-	let subsystem = AvailabilityStoreSubsystem::new_in_memory(store, pruning_config);
 	let subsystem = run(subsystem, context);
 
 	let test_fut = test(TestHarness {
@@ -170,6 +322,10 @@ async fn overseer_signal(
 		.expect(&format!("{:?} is more than enough for sending signals.", TIMEOUT));
 }
 
+fn get_chunks(data: &AvailableData, n_validators: usize, metrics: &Metrics) -> Result<Vec<ErasureChunk>, Error> {
+	get_chunks_with_root(data, n_validators, ErasureCodingVersion::V1, metrics).map(|(chunks, _)| chunks)
+}
+
 #[test]
 fn runtime_api_error_does_not_stop_the_subsystem() {
 	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
@@ -216,163 +372,195 @@ fn runtime_api_error_does_not_stop_the_subsystem() {
 }
 
 #[test]
-fn store_chunk_works() {
-	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
-	test_harness(PruningConfig::default(), store.clone(), |test_harness| async move {
-		let TestHarness { mut virtual_overseer } = test_harness;
-		let relay_parent = Hash::repeat_byte(32);
-		let candidate_hash = CandidateHash(Hash::repeat_byte(33));
-		let validator_index = 5;
-
-		let chunk = ErasureChunk {
-			chunk: vec![1, 2, 3],
-			index: validator_index,
-			proof: vec![vec![3, 4, 5]],
-		};
-
-		let (tx, rx) = oneshot::channel();
-
-		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
-			candidate_hash,
-			relay_parent,
-			validator_index,
-			chunk: chunk.clone(),
-			tx,
-		};
-
-		overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
-
-		assert_matches!(
-			overseer_recv(&mut virtual_overseer).await,
-			AllMessages::ChainApi(ChainApiMessage::BlockNumber(
-				hash,
-				tx,
-			)) => {
-				assert_eq!(hash, relay_parent);
-				tx.send(Ok(Some(4))).unwrap();
-			}
-		);
-
-		assert_eq!(rx.await.unwrap(), Ok(()));
-
-		let (tx, rx) = oneshot::channel();
-		let query_chunk = AvailabilityStoreMessage::QueryChunk(
-			candidate_hash,
-			validator_index,
-			tx,
-		);
-
-		overseer_send(&mut virtual_overseer, query_chunk.into()).await;
-
-		assert_eq!(rx.await.unwrap().unwrap(), chunk);
-	});
-}
-
-#[test]
-fn store_block_works() {
+fn candidate_events_are_refetched_after_failure() {
 	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
 	let test_state = TestState::default();
+
 	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
 		let TestHarness { mut virtual_overseer } = test_harness;
-		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
-		let validator_index = 5;
 		let n_validators = 10;
 
 		let pov = PoV {
 			block_data: BlockData(vec![4, 5, 6]),
 		};
 
+		let candidate = TestCandidateBuilder {
+			pov_hash: pov.hash(),
+			..Default::default()
+		}.build();
+
+		let candidate_hash = candidate.hash();
+
 		let available_data = AvailableData {
 			pov: Arc::new(pov),
 			validation_data: test_state.persisted_validation_data,
 		};
 
-
 		let (tx, rx) = oneshot::channel();
 		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
 			candidate_hash,
-			Some(validator_index),
+			None,
 			n_validators,
-			available_data.clone(),
+			available_data,
+			None,
+			ErasureCodingVersion::V1,
 			tx,
 		);
 
 		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
-		assert_eq!(rx.await.unwrap(), Ok(()));
+		rx.await.unwrap().unwrap();
 
-		let pov = query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap();
-		assert_eq!(pov, available_data);
+		let failed_leaf = Hash::repeat_byte(2);
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
+				activated: vec![(failed_leaf, Arc::new(JaegerSpan::Disabled))].into(),
+				deactivated: vec![].into(),
+			}),
+		).await;
 
-		let chunk = query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap();
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::CandidateEvents(tx),
+			)) => {
+				assert_eq!(relay_parent, failed_leaf);
+				tx.send(Err(RuntimeApiError::from("oh no".to_string()))).unwrap();
+			}
+		);
 
-		let chunks = erasure::obtain_chunks_v1(10, &available_data).unwrap();
+		let new_leaf = Hash::repeat_byte(3);
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
+				activated: vec![(new_leaf, Arc::new(JaegerSpan::Disabled))].into(),
+				deactivated: vec![].into(),
+			}),
+		).await;
 
-		let mut branches = erasure::branches(chunks.as_ref());
+		// The failed block is retried before the newly activated one.
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::CandidateEvents(tx),
+			)) => {
+				assert_eq!(relay_parent, failed_leaf);
+				tx.send(Ok(vec![
+					CandidateEvent::CandidateIncluded(candidate, HeadData::default()),
+				])).unwrap();
+			}
+		);
 
-		let branch = branches.nth(5).unwrap();
-		let expected_chunk = ErasureChunk {
-			chunk: branch.1.to_vec(),
-			index: 5,
-			proof: branch.0,
-		};
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::CandidateEvents(tx),
+			)) => {
+				assert_eq!(relay_parent, new_leaf);
+				tx.send(Ok(vec![])).unwrap();
+			}
+		);
 
-		assert_eq!(chunk, expected_chunk);
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryPruningSchedule(tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+		assert_eq!(
+			rx.await.unwrap(),
+			vec![(candidate_hash, CandidateState::Included, None)],
+		);
 	});
 }
 
-
 #[test]
-fn store_pov_and_query_chunk_works() {
-	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+fn activated_blocks_are_kept_when_processing_fails() {
+	let db = Arc::new(FailingDb::new());
+	let store: Arc<dyn KeyValueDB> = db.clone();
 	let test_state = TestState::default();
 
-	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+	test_harness(test_state.pruning_config.clone(), store, |test_harness| async move {
 		let TestHarness { mut virtual_overseer } = test_harness;
-		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
-		let n_validators = 10;
-
-		let pov = PoV {
-			block_data: BlockData(vec![4, 5, 6]),
-		};
 
 		let available_data = AvailableData {
-			pov: Arc::new(pov),
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
 			validation_data: test_state.persisted_validation_data,
 		};
 
-		let no_metrics = Metrics(None);
-		let chunks_expected = get_chunks(&available_data, n_validators as usize, &no_metrics).unwrap();
-
 		let (tx, rx) = oneshot::channel();
-		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
-			candidate_hash,
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::StoreAvailableData(
+			CandidateHash(Hash::repeat_byte(1)),
 			None,
-			n_validators,
+			10,
 			available_data,
+			None,
+			ErasureCodingVersion::V1,
 			tx,
-		);
+		)).await;
+		rx.await.unwrap().unwrap();
 
-		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		let activate = |leaves: &[Hash]| OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
+			activated: leaves.iter()
+				.map(|leaf| (*leaf, Arc::new(JaegerSpan::Disabled)))
+				.collect::<Vec<_>>()
+				.into(),
+			deactivated: vec![].into(),
+		});
+
+		async fn expect_events_request(
+			virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityStoreMessage>,
+			leaf: Hash,
+			fail: bool,
+		) {
+			assert_matches!(
+				overseer_recv(virtual_overseer).await,
+				AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+					relay_parent,
+					RuntimeApiRequest::CandidateEvents(tx),
+				)) => {
+					assert_eq!(relay_parent, leaf);
+					if fail {
+						tx.send(Err(RuntimeApiError::from("oh no".to_string()))).unwrap();
+					} else {
+						tx.send(Ok(Vec::new())).unwrap();
+					}
+				}
+			);
+		}
 
-		assert_eq!(rx.await.unwrap(), Ok(()));
+		let (failed, second, third, fourth) = (
+			Hash::repeat_byte(2),
+			Hash::repeat_byte(3),
+			Hash::repeat_byte(4),
+			Hash::repeat_byte(5),
+		);
 
-		for validator_index in 0..n_validators {
-			let chunk = query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap();
+		overseer_signal(&mut virtual_overseer, activate(&[failed])).await;
+		expect_events_request(&mut virtual_overseer, failed, true).await;
 
-			assert_eq!(chunk, chunks_expected[validator_index as usize]);
+		// Writing the retried block fails before the newly activated ones are processed.
+		db.set_fail_writes(true);
+		overseer_signal(&mut virtual_overseer, activate(&[second, third])).await;
+		expect_events_request(&mut virtual_overseer, failed, false).await;
+
+		// None of the blocks is lost.
+		db.set_fail_writes(false);
+		overseer_signal(&mut virtual_overseer, activate(&[fourth])).await;
+		for leaf in &[failed, second, third, fourth] {
+			expect_events_request(&mut virtual_overseer, *leaf, false).await;
 		}
 	});
 }
 
 #[test]
-fn stored_but_not_included_chunk_is_pruned() {
+fn store_chunk_works() {
 	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
-	let test_state = TestState::default();
-
-	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+	test_harness(PruningConfig::default(), store.clone(), |test_harness| async move {
 		let TestHarness { mut virtual_overseer } = test_harness;
-		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
-		let relay_parent = Hash::repeat_byte(2);
+		let relay_parent = Hash::repeat_byte(32);
+		let candidate_hash = CandidateHash(Hash::repeat_byte(33));
 		let validator_index = 5;
 
 		let chunk = ErasureChunk {
@@ -382,9 +570,11 @@ fn stored_but_not_included_chunk_is_pruned() {
 		};
 
 		let (tx, rx) = oneshot::channel();
+
 		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
 			candidate_hash,
 			relay_parent,
+			block_number: None,
 			validator_index,
 			chunk: chunk.clone(),
 			tx,
@@ -403,181 +593,121 @@ fn stored_but_not_included_chunk_is_pruned() {
 			}
 		);
 
-		rx.await.unwrap().unwrap();
+		assert_eq!(rx.await.unwrap(), Ok(()));
 
-		// At this point data should be in the store.
-		assert_eq!(
-			query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap(),
-			chunk,
+		let (tx, rx) = oneshot::channel();
+		let query_chunk = AvailabilityStoreMessage::QueryChunk(
+			candidate_hash,
+			validator_index,
+			tx,
 		);
 
-		// Wait for twice as long as the stored block kept for.
-		Delay::new(test_state.pruning_config.keep_stored_block_for * 2).await;
+		overseer_send(&mut virtual_overseer, query_chunk.into()).await;
 
-		// The block was not included by this point so it should be pruned now.
-		assert!(query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.is_none());
+		assert_eq!(rx.await.unwrap().unwrap(), chunk);
 	});
 }
 
 #[test]
-fn stored_but_not_included_data_is_pruned() {
-	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
-	let test_state = TestState::default();
-
-	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+fn store_chunk_with_block_number_skips_chain_api() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	test_harness(PruningConfig::default(), store.clone(), |test_harness| async move {
 		let TestHarness { mut virtual_overseer } = test_harness;
-		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
-		let n_validators = 10;
-
-		let pov = PoV {
-			block_data: BlockData(vec![4, 5, 6]),
-		};
+		let relay_parent = Hash::repeat_byte(32);
+		let candidate_hash = CandidateHash(Hash::repeat_byte(33));
+		let validator_index = 5;
 
-		let available_data = AvailableData {
-			pov: Arc::new(pov),
-			validation_data: test_state.persisted_validation_data,
+		let chunk = ErasureChunk {
+			chunk: vec![1, 2, 3],
+			index: validator_index,
+			proof: vec![vec![3, 4, 5]],
 		};
 
 		let (tx, rx) = oneshot::channel();
-		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+
+		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
 			candidate_hash,
-			None,
-			n_validators,
-			available_data.clone(),
+			relay_parent,
+			block_number: Some(5),
+			validator_index,
+			chunk: chunk.clone(),
 			tx,
-		);
+		};
 
-		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
 
-		rx.await.unwrap().unwrap();
+		assert_eq!(rx.await.unwrap(), Ok(()));
+		assert!(overseer_recv_with_timeout(&mut virtual_overseer, TIMEOUT).await.is_none());
 
-		// At this point data should be in the store.
 		assert_eq!(
-			query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
-			available_data,
+			query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap(),
+			chunk,
 		);
 
-		// Wait for twice as long as the stored block kept for.
-		Delay::new(test_state.pruning_config.keep_stored_block_for * 2).await;
-
-		// The block was not included by this point so it should be pruned now.
-		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+		let record = chunk_pruning_record(&store, &candidate_hash, validator_index).unwrap();
+		assert_eq!(record.block_number, 5);
 	});
 }
 
 #[test]
-fn stored_data_kept_until_finalized() {
-	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+fn imported_chunks_are_scheduled_by_candidate_state() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
 	let test_state = TestState::default();
+	let keep_finalized_chunk_for = test_state.pruning_config.keep_finalized_chunk_for.unwrap();
 
 	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
 		let TestHarness { mut virtual_overseer } = test_harness;
-		let n_validators = 10;
+		let finalized = CandidateHash(Hash::repeat_byte(1));
+		let included = CandidateHash(Hash::repeat_byte(2));
 
-		let pov = PoV {
-			block_data: BlockData(vec![4, 5, 6]),
+		let chunk = |index| ErasureChunk {
+			chunk: vec![1, 2, 3],
+			index,
+			proof: vec![vec![3, 4, 5]],
 		};
 
-		let pov_hash = pov.hash();
-
-		let candidate = TestCandidateBuilder {
-			pov_hash,
-			..Default::default()
-		}.build();
-
-		let candidate_hash = candidate.hash();
-
-		let available_data = AvailableData {
-			pov: Arc::new(pov),
-			validation_data: test_state.persisted_validation_data,
-		};
+		let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
 
 		let (tx, rx) = oneshot::channel();
-		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
-			candidate_hash,
-			None,
-			n_validators,
-			available_data.clone(),
+		let import_msg = AvailabilityStoreMessage::ImportChunks {
+			records: vec![
+				(finalized, 10, chunk(3), 5, CandidateState::Finalized),
+				(included, 10, chunk(4), 6, CandidateState::Included),
+			],
 			tx,
-		);
-
-		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
-
-		rx.await.unwrap().unwrap();
-
-		// At this point data should be in the store.
-		assert_eq!(
-			query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
-			available_data,
-		);
-
-		let new_leaf = Hash::repeat_byte(2);
-		overseer_signal(
-			&mut virtual_overseer,
-			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
-				activated: vec![(new_leaf, Arc::new(JaegerSpan::Disabled))].into(),
-				deactivated: vec![].into(),
-			}),
-		).await;
-
-		assert_matches!(
-			overseer_recv(&mut virtual_overseer).await,
-			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
-				relay_parent,
-				RuntimeApiRequest::CandidateEvents(tx),
-			)) => {
-				assert_eq!(relay_parent, new_leaf);
-				tx.send(Ok(vec![
-					CandidateEvent::CandidateIncluded(candidate, HeadData::default()),
-				])).unwrap();
-			}
-		);
-
-		Delay::new(test_state.pruning_config.keep_stored_block_for * 10).await;
-
-		// At this point data should _still_ be in the store.
-		assert_eq!(
-			query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
-			available_data,
-		);
+		};
 
-		overseer_signal(
-			&mut virtual_overseer,
-			OverseerSignal::BlockFinalized(new_leaf, 10)
-		).await;
+		overseer_send(&mut virtual_overseer, import_msg).await;
+		assert_eq!(rx.await.unwrap(), Ok(()));
 
-		// Wait for a half of the time finalized data should be available for
-		Delay::new(test_state.pruning_config.keep_finalized_block_for / 2).await;
+		let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
 
-		// At this point data should _still_ be in the store.
-		assert_eq!(
-			query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
-			available_data,
-		);
+		assert_eq!(query_chunk(&mut virtual_overseer, finalized, 3).await.unwrap(), chunk(3));
+		assert_eq!(query_chunk(&mut virtual_overseer, included, 4).await.unwrap(), chunk(4));
+		assert_eq!(query_validator_count(&mut virtual_overseer, finalized).await, Some(10));
 
-		// Wait until it is should be gone.
-		Delay::new(test_state.pruning_config.keep_finalized_block_for).await;
+		let record = chunk_pruning_record(&store, &finalized, 3).unwrap();
+		assert_eq!(record.block_number, 5);
+		assert_eq!(record.candidate_state, CandidateState::Finalized);
+		assert_matches!(record.prune_at, PruningDelay::In(prune_at) => {
+			assert!(before + keep_finalized_chunk_for <= prune_at);
+			assert!(prune_at <= after + keep_finalized_chunk_for);
+		});
 
-		// At this point data should be gone from the store.
-		assert!(
-			query_available_data(&mut virtual_overseer, candidate_hash).await.is_none(),
-		);
+		let record = chunk_pruning_record(&store, &included, 4).unwrap();
+		assert_eq!(record.candidate_state, CandidateState::Included);
+		assert_eq!(record.prune_at, PruningDelay::Indefinite);
 	});
 }
 
 #[test]
-fn stored_chunk_kept_until_finalized() {
+fn store_chunk_rejects_block_number_overflow() {
 	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
-	let test_state = TestState::default();
-
-	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+	test_harness(PruningConfig::default(), store.clone(), |test_harness| async move {
 		let TestHarness { mut virtual_overseer } = test_harness;
-		let relay_parent = Hash::repeat_byte(2);
+		let relay_parent = Hash::repeat_byte(32);
+		let candidate_hash = CandidateHash(Hash::repeat_byte(33));
 		let validator_index = 5;
-		let candidate = TestCandidateBuilder {
-			..Default::default()
-		}.build();
-		let candidate_hash = candidate.hash();
 
 		let chunk = ErasureChunk {
 			chunk: vec![1, 2, 3],
@@ -586,11 +716,13 @@ fn stored_chunk_kept_until_finalized() {
 		};
 
 		let (tx, rx) = oneshot::channel();
+
 		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
 			candidate_hash,
 			relay_parent,
+			block_number: None,
 			validator_index,
-			chunk: chunk.clone(),
+			chunk,
 			tx,
 		};
 
@@ -603,217 +735,5801 @@ fn stored_chunk_kept_until_finalized() {
 				tx,
 			)) => {
 				assert_eq!(hash, relay_parent);
-				tx.send(Ok(Some(4))).unwrap();
+				tx.send(Ok(Some(BlockNumber::max_value()))).unwrap();
 			}
 		);
 
-		rx.await.unwrap().unwrap();
+		assert_eq!(rx.await.unwrap(), Err(StoreError::BlockNumberOverflow));
 
-		// At this point data should be in the store.
-		assert_eq!(
-			query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap(),
-			chunk,
-		);
+		// The subsystem is still alive and nothing was stored.
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.is_none());
+	});
+}
 
-		let new_leaf = Hash::repeat_byte(2);
-		overseer_signal(
-			&mut virtual_overseer,
-			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
-				activated: vec![(new_leaf, Arc::new(JaegerSpan::Disabled))].into(),
-				deactivated: vec![].into(),
-			}),
-		).await;
+#[test]
+fn store_chunk_rejects_invalid_proof() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let relay_parent = Hash::repeat_byte(32);
+		let candidate_hash = CandidateHash(Hash::repeat_byte(33));
+		let validator_index = 5;
+		let n_validators = 10;
 
-		assert_matches!(
-			overseer_recv(&mut virtual_overseer).await,
-			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
-				relay_parent,
-				RuntimeApiRequest::CandidateEvents(tx),
-			)) => {
-				assert_eq!(relay_parent, new_leaf);
-				tx.send(Ok(vec![
-					CandidateEvent::CandidateIncluded(candidate, HeadData::default()),
-				])).unwrap();
-			}
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			n_validators,
+			data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
 		);
 
-		Delay::new(test_state.pruning_config.keep_stored_block_for * 10).await;
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
 
-		// At this point data should _still_ be in the store.
-		assert_eq!(
-			query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap(),
-			chunk,
-		);
+		let valid_chunk = get_chunks(&data, n_validators as usize, &Metrics(None))
+			.unwrap()
+			.remove(validator_index as usize);
 
-		overseer_signal(
-			&mut virtual_overseer,
-			OverseerSignal::BlockFinalized(new_leaf, 10)
-		).await;
+		let mut chunk = valid_chunk.clone();
+		chunk.proof = get_chunks(&data, n_validators as usize, &Metrics(None))
+			.unwrap()
+			.remove(validator_index as usize + 1)
+			.proof;
 
-		// Wait for a half of the time finalized data should be available for
-		Delay::new(test_state.pruning_config.keep_finalized_block_for / 2).await;
+		let (tx, rx) = oneshot::channel();
 
-		// At this point data should _still_ be in the store.
-		assert_eq!(
-			query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap(),
+		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+			candidate_hash,
+			relay_parent,
+			block_number: None,
+			validator_index,
 			chunk,
+			tx,
+		};
+
+		overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::BlockNumber(
+				hash,
+				tx,
+			)) => {
+				assert_eq!(hash, relay_parent);
+				tx.send(Ok(Some(test_state.relay_parent_number))).unwrap();
+			}
 		);
 
-		// Wait until it is should be gone.
-		Delay::new(test_state.pruning_config.keep_finalized_chunk_for).await;
+		assert_eq!(rx.await.unwrap(), Err(StoreError::InvalidChunkProof));
 
-		// At this point data should be gone from the store.
-		assert!(
-			query_available_data(&mut virtual_overseer, candidate_hash).await.is_none(),
+		// The chunk served is the one regenerated from the stored data.
+		assert_eq!(
+			query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await,
+			Some(valid_chunk),
 		);
+		assert!(chunk_pruning(&store).unwrap_or_default().is_empty());
 	});
 }
 
 #[test]
-fn forkfullness_works() {
-	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
-	let test_state = TestState::default();
-
-	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+fn store_chunk_rejects_unknown_relay_parent() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	test_harness(PruningConfig::default(), store.clone(), |test_harness| async move {
 		let TestHarness { mut virtual_overseer } = test_harness;
-		let n_validators = 10;
+		let relay_parent = Hash::repeat_byte(32);
+		let candidate_hash = CandidateHash(Hash::repeat_byte(33));
+		let validator_index = 5;
 
-		let pov_1 = PoV {
-			block_data: BlockData(vec![1, 2, 3]),
+		let chunk = ErasureChunk {
+			chunk: vec![1, 2, 3],
+			index: validator_index,
+			proof: vec![vec![3, 4, 5]],
 		};
 
-		let pov_1_hash = pov_1.hash();
+		let (tx, rx) = oneshot::channel();
 
-		let pov_2 = PoV {
-			block_data: BlockData(vec![4, 5, 6]),
+		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+			candidate_hash,
+			relay_parent,
+			block_number: None,
+			validator_index,
+			chunk,
+			tx,
+		};
+
+		overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::BlockNumber(
+				hash,
+				tx,
+			)) => {
+				assert_eq!(hash, relay_parent);
+				tx.send(Ok(None)).unwrap();
+			}
+		);
+
+		assert_eq!(rx.await.unwrap(), Err(StoreError::UnknownRelayParent));
+
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.is_none());
+		assert!(chunk_pruning(&store).unwrap_or_default().is_empty());
+	});
+}
+
+#[test]
+fn query_data_availability_batch_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidates: Vec<_> = (1..=4).map(|i| CandidateHash(Hash::repeat_byte(i))).collect();
+
+		for candidate_hash in candidates.iter().step_by(2) {
+			let data = AvailableData {
+				pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+				validation_data: test_state.persisted_validation_data.clone(),
+			};
+
+			let (tx, rx) = oneshot::channel();
+			let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+				*candidate_hash,
+				None,
+				5,
+				data,
+				None,
+				ErasureCodingVersion::V1,
+				tx,
+			);
+
+			virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+			rx.await.unwrap().unwrap();
+		}
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryDataAvailabilityBatch(candidates, tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+		assert_eq!(rx.await.unwrap(), vec![true, false, true, false]);
+	});
+}
+
+#[test]
+fn query_chunk_availability_batch_does_not_regenerate() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let relay_parent = Hash::repeat_byte(32);
+		let chunks_candidate = CandidateHash(Hash::repeat_byte(1));
+		let data_candidate = CandidateHash(Hash::repeat_byte(2));
+
+		for validator_index in vec![1, 3] {
+			let chunk = ErasureChunk {
+				chunk: vec![1, 2, 3],
+				index: validator_index,
+				proof: vec![vec![3, 4, 5]],
+			};
+
+			let (tx, rx) = oneshot::channel();
+			let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+				candidate_hash: chunks_candidate,
+				relay_parent,
+				block_number: Some(5),
+				validator_index,
+				chunk,
+				tx,
+			};
+
+			overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
+			rx.await.unwrap().unwrap();
+		}
+
+		// The full data is stored without its chunks, which could be regenerated from it.
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			data_candidate,
+			None,
+			10,
+			data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryChunkAvailabilityBatch(
+			vec![
+				(chunks_candidate, 1),
+				(chunks_candidate, 2),
+				(chunks_candidate, 3),
+				(data_candidate, 0),
+				(CandidateHash(Hash::repeat_byte(3)), 1),
+			],
+			tx,
+		);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+		assert_eq!(rx.await.unwrap(), vec![true, false, true, false, false]);
+	});
+}
+
+#[test]
+fn query_stored_at_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryStoredAt(candidate_hash, tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+		assert!(rx.await.unwrap().is_none());
+
+		let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			5,
+			data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryStoredAt(candidate_hash, tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+		let stored_at = rx.await.unwrap().unwrap();
+		assert!(before <= stored_at && stored_at <= after);
+	});
+}
+
+#[test]
+fn sampled_candidates_have_available_data() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidates: Vec<_> = (1..=3).map(|i| CandidateHash(Hash::repeat_byte(i))).collect();
+
+		for candidate_hash in &candidates {
+			let data = AvailableData {
+				pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+				validation_data: test_state.persisted_validation_data.clone(),
+			};
+
+			let (tx, rx) = oneshot::channel();
+			let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+				*candidate_hash,
+				None,
+				5,
+				data,
+				None,
+				ErasureCodingVersion::V1,
+				tx,
+			);
+
+			virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+			rx.await.unwrap().unwrap();
+		}
+
+		for (count, expected) in vec![(0, 0), (2, 2), (10, 3)] {
+			let (tx, rx) = oneshot::channel();
+			let query = AvailabilityStoreMessage::SampleAvailableData(count, tx);
+			virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+			let sample = rx.await.unwrap();
+			assert_eq!(sample.len(), expected);
+
+			for candidate_hash in sample {
+				assert!(candidates.contains(&candidate_hash));
+				assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_some());
+			}
+		}
+	});
+}
+
+#[test]
+fn query_candidates_at_block_works() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	let candidates: Vec<_> = (1..=3).map(|i| CandidateHash(Hash::repeat_byte(i))).collect();
+
+	for (candidate_hash, block_number) in candidates.iter().zip(&[5, 7, 5]) {
+		let mut validation_data = test_state.persisted_validation_data.clone();
+		validation_data.block_number = *block_number;
+
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data,
+		};
+
+		store_available_data(&mut subsystem, candidate_hash, None, 10, data, ErasureCodingVersion::V1, None).unwrap();
+	}
+
+	let expected_candidates = vec![
+		(5, vec![candidates[0], candidates[2]]),
+		(6, vec![]),
+		(7, vec![candidates[1]]),
+	];
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		for (block_number, expected) in expected_candidates {
+			let (tx, rx) = oneshot::channel();
+			let query = AvailabilityStoreMessage::QueryCandidatesAtBlock(block_number, tx);
+			virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+			assert_eq!(rx.await.unwrap(), expected);
+		}
+	});
+
+	// Pruned data is dropped from the index.
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	let mut records = pov_pruning(&store).unwrap();
+	for record in records.iter_mut().filter(|r| r.block_number == 5) {
+		record.prune_at = PruningDelay::In(Duration::from_secs(1));
+	}
+	put_pov_pruning(&mut subsystem, None, records).unwrap();
+
+	subsystem.prune(true, false).unwrap();
+
+	assert!(candidates_at_block(&store, 5).is_empty());
+	assert_eq!(candidates_at_block(&store, 7), vec![candidates[1]]);
+}
+
+#[test]
+fn query_chunk_proof_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	test_harness(PruningConfig::default(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(33));
+		let validator_index = 5;
+
+		let chunk = ErasureChunk {
+			chunk: vec![1, 2, 3],
+			index: validator_index,
+			proof: vec![vec![3, 4, 5], vec![6, 7]],
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+			candidate_hash,
+			relay_parent: Hash::repeat_byte(32),
+			block_number: Some(5),
+			validator_index,
+			chunk: chunk.clone(),
+			tx,
+		};
+
+		overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
+		rx.await.unwrap().unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryChunkProof(candidate_hash, validator_index, tx);
+		overseer_send(&mut virtual_overseer, query).await;
+		assert_eq!(rx.await.unwrap(), Some(chunk.proof));
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryChunkProof(candidate_hash, validator_index + 1, tx);
+		overseer_send(&mut virtual_overseer, query).await;
+		assert!(rx.await.unwrap().is_none());
+	});
+}
+
+#[test]
+fn query_chunk_size_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	test_harness(PruningConfig::default(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let relay_parent = Hash::repeat_byte(32);
+		let candidate_hash = CandidateHash(Hash::repeat_byte(33));
+		let validator_index = 5;
+
+		let chunk = ErasureChunk {
+			chunk: vec![1, 2, 3],
+			index: validator_index,
+			proof: vec![vec![3, 4, 5]],
+		};
+
+		let (tx, rx) = oneshot::channel();
+
+		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+			candidate_hash,
+			relay_parent,
+			block_number: None,
+			validator_index,
+			chunk: chunk.clone(),
+			tx,
+		};
+
+		overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::BlockNumber(
+				hash,
+				tx,
+			)) => {
+				assert_eq!(hash, relay_parent);
+				tx.send(Ok(Some(4))).unwrap();
+			}
+		);
+
+		rx.await.unwrap().unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryChunkSize(candidate_hash, validator_index, tx);
+		overseer_send(&mut virtual_overseer, query).await;
+		assert_eq!(rx.await.unwrap(), Some(chunk.encode().len()));
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryChunkSize(candidate_hash, validator_index + 1, tx);
+		overseer_send(&mut virtual_overseer, query).await;
+		assert!(rx.await.unwrap().is_none());
+	});
+}
+
+#[test]
+fn store_block_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let validator_index = 5;
+		let n_validators = 10;
+
+		let pov = PoV {
+			block_data: BlockData(vec![4, 5, 6]),
+		};
+
+		let available_data = AvailableData {
+			pov: Arc::new(pov),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			Some(validator_index),
+			n_validators,
+			available_data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		assert_eq!(rx.await.unwrap(), Ok(()));
+
+		let pov = query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap();
+		assert_eq!(pov, available_data);
+
+		let chunk = query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap();
+
+		let chunks = erasure::obtain_chunks_v1(10, &available_data).unwrap();
+
+		let mut branches = erasure::branches(chunks.as_ref());
+
+		let branch = branches.nth(5).unwrap();
+		let expected_chunk = ErasureChunk {
+			chunk: branch.1.to_vec(),
+			index: 5,
+			proof: branch.0,
+		};
+
+		assert_eq!(chunk, expected_chunk);
+	});
+}
+
+
+#[test]
+fn queries_are_served_while_erasure_coding() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let spawner = HoldingSpawner {
+		inner: sp_core::testing::TaskExecutor::new(),
+		held: Default::default(),
+	};
+	let held = spawner.held.clone();
+
+	let subsystem = AvailabilityStoreSubsystem::with_db(store, test_state.pruning_config, Metrics(None));
+
+	test_harness_with_spawner(subsystem, spawner, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let validator_index = 1;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4; 64 * 1024]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, mut rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			Some(validator_index),
+			1000,
+			available_data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+
+		// The erasure coding is held back, but queries are still answered.
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+		assert!(query_chunk(&mut virtual_overseer, CandidateHash(Hash::repeat_byte(2)), 0).await.is_none());
+		assert_eq!(rx.try_recv().unwrap(), None);
+
+		let jobs: Vec<_> = held.lock().unwrap().drain(..).collect();
+		assert_eq!(jobs.len(), 1);
+
+		for job in jobs {
+			job.await;
+		}
+
+		assert_eq!(rx.await.unwrap(), Ok(()));
+
+		assert_eq!(
+			query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
+			available_data,
+		);
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.is_some());
+	});
+}
+
+#[test]
+fn stores_are_rejected_when_too_many_are_pending() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let spawner = HoldingSpawner {
+		inner: sp_core::testing::TaskExecutor::new(),
+		held: Default::default(),
+	};
+	let held = spawner.held.clone();
+
+	let metrics = <Metrics as metrics::Metrics>::try_register(&prometheus::Registry::new()).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(store, test_state.pruning_config, metrics.clone());
+	subsystem.max_pending_stores = Some(2);
+
+	test_harness_with_spawner(subsystem, spawner, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let pending_stores = || metrics.0.as_ref().unwrap().pending_stores.get();
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let mut receivers = Vec::new();
+
+		for i in 1..=3 {
+			let (tx, rx) = oneshot::channel();
+			let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+				CandidateHash(Hash::repeat_byte(i)),
+				Some(0),
+				10,
+				available_data.clone(),
+				None,
+				ErasureCodingVersion::V1,
+				tx,
+			);
+
+			virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+			receivers.push(rx);
+		}
+
+		// The erasure coding of the first two is held back, so the third one is turned away.
+		let rejected = receivers.pop().unwrap();
+		assert_eq!(rejected.await.unwrap(), Err(StoreError::Overloaded));
+		assert_eq!(pending_stores(), 2);
+
+		for rx in &mut receivers {
+			assert_eq!(rx.try_recv().unwrap(), None);
+		}
+
+		let jobs: Vec<_> = held.lock().unwrap().drain(..).collect();
+		assert_eq!(jobs.len(), 2);
+
+		for job in jobs {
+			job.await;
+		}
+
+		for rx in receivers {
+			assert_eq!(rx.await.unwrap(), Ok(()));
+		}
+
+		assert_eq!(pending_stores(), 0);
+		assert!(query_available_data(&mut virtual_overseer, CandidateHash(Hash::repeat_byte(1))).await.is_some());
+		assert!(query_available_data(&mut virtual_overseer, CandidateHash(Hash::repeat_byte(3))).await.is_none());
+	});
+}
+
+#[test]
+fn costly_messages_are_shed_from_a_deep_queue() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+	let chunk = ErasureChunk {
+		chunk: vec![1, 2, 3],
+		index: 5,
+		proof: vec![vec![3, 4, 5]],
+	};
+
+	let metrics = <Metrics as metrics::Metrics>::try_register(&prometheus::Registry::new()).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(store, test_state.pruning_config, metrics.clone());
+	subsystem.shed_queue_depth = Some(2);
+	store_chunk(&mut subsystem, &candidate_hash, 10, chunk.clone(), 5, None).unwrap();
+
+	// A backlog of four messages, the first two are processed with at least two others waiting.
+	let (query_tx, shed_query) = oneshot::channel();
+	let (store_tx, shed_store) = oneshot::channel();
+	let (availability_tx, availability) = oneshot::channel();
+	let (last_query_tx, last_query) = oneshot::channel();
+
+	subsystem.incoming.extend(vec![
+		AvailabilityStoreMessage::QueryChunk(candidate_hash, 5, query_tx),
+		AvailabilityStoreMessage::StoreChunk {
+			candidate_hash,
+			relay_parent: Hash::repeat_byte(2),
+			block_number: Some(5),
+			validator_index: 6,
+			chunk: ErasureChunk { index: 6, ..chunk.clone() },
+			tx: store_tx,
+		},
+		AvailabilityStoreMessage::QueryChunkAvailability(candidate_hash, 5, availability_tx),
+		AvailabilityStoreMessage::QueryChunk(candidate_hash, 5, last_query_tx),
+	].into_iter().map(|msg| FromOverseer::Communication { msg }));
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let _test_harness = test_harness;
+
+		assert!(shed_query.await.is_err());
+		assert_eq!(shed_store.await.unwrap(), Err(StoreError::Overloaded));
+
+		// Cheap queries are answered whatever the depth of the queue.
+		assert!(availability.await.unwrap());
+		assert_eq!(last_query.await.unwrap(), Some(chunk));
+
+		assert_eq!(metrics.0.as_ref().unwrap().message_queue_depth.get(), 0);
+	});
+}
+
+#[test]
+fn query_chunks_by_indices_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let n_validators = 10;
+
+		let pov = PoV {
+			block_data: BlockData(vec![4, 5, 6]),
+		};
+
+		let available_data = AvailableData {
+			pov: Arc::new(pov),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let chunks = erasure::obtain_chunks_v1(n_validators as usize, &available_data).unwrap();
+		let branches: Vec<_> = erasure::branches(chunks.as_ref()).collect();
+		let expected: Vec<_> = [1u32, 3].iter().map(|&index| {
+			let branch = &branches[index as usize];
+			Some(ErasureChunk {
+				chunk: branch.1.to_vec(),
+				index,
+				proof: branch.0.clone(),
+			})
+		}).collect();
+
+		// Only the chunk with index 1 is stored alongside the full data.
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			n_validators,
+			available_data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		assert_eq!(rx.await.unwrap(), Ok(()));
+
+		let (tx, rx) = oneshot::channel();
+		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+			candidate_hash,
+			relay_parent: Hash::repeat_byte(32),
+			block_number: Some(1),
+			validator_index: 1,
+			chunk: expected[0].clone().unwrap(),
+			tx,
+		};
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: chunk_msg }).await;
+		assert_eq!(rx.await.unwrap(), Ok(()));
+
+		assert!(store.get(columns::DATA, &erasure_chunk_key(&candidate_hash, 1)).unwrap().is_some());
+		assert!(store.get(columns::DATA, &erasure_chunk_key(&candidate_hash, 3)).unwrap().is_none());
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryChunksByIndices(candidate_hash, vec![1, 3], tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+		assert_eq!(rx.await.unwrap(), expected);
+
+		// The missing chunk was regenerated and stored.
+		assert!(store.get(columns::DATA, &erasure_chunk_key(&candidate_hash, 3)).unwrap().is_some());
+	});
+}
+
+#[test]
+fn query_chunk_range_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let chunks_candidate = CandidateHash(Hash::repeat_byte(1));
+		let data_candidate = CandidateHash(Hash::repeat_byte(2));
+		let n_validators = 10;
+
+		let chunk = |index| ErasureChunk {
+			chunk: vec![1, 2, 3],
+			index,
+			proof: vec![vec![3, 4, 5]],
+		};
+
+		// Only some chunks of the first candidate are stored, without its full data.
+		for index in vec![1, 2, 5] {
+			let (tx, rx) = oneshot::channel();
+			let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+				candidate_hash: chunks_candidate,
+				relay_parent: Hash::repeat_byte(32),
+				block_number: Some(5),
+				validator_index: index,
+				chunk: chunk(index),
+				tx,
+			};
+
+			overseer_send(&mut virtual_overseer, chunk_msg).await;
+			rx.await.unwrap().unwrap();
+		}
+
+		let query_range = |start, end| {
+			let (tx, rx) = oneshot::channel();
+			(AvailabilityStoreMessage::QueryChunkRange(chunks_candidate, start, end, tx), rx)
+		};
+
+		let (query, rx) = query_range(0, 4);
+		overseer_send(&mut virtual_overseer, query).await;
+		assert_eq!(rx.await.unwrap(), vec![None, Some(chunk(1)), Some(chunk(2)), None]);
+
+		let (query, rx) = query_range(4, 100);
+		overseer_send(&mut virtual_overseer, query).await;
+		let result = rx.await.unwrap();
+		assert_eq!(result.len(), 96);
+		assert_eq!(result[1], Some(chunk(5)));
+		assert_eq!(result.iter().filter(|c| c.is_some()).count(), 1);
+
+		let (query, rx) = query_range(4, 4);
+		overseer_send(&mut virtual_overseer, query).await;
+		assert!(rx.await.unwrap().is_empty());
+
+		// The chunks of the second candidate are regenerated from its full data and the range
+		// ends at the number of validators.
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let chunks = erasure::obtain_chunks_v1(n_validators as usize, &available_data).unwrap();
+		let branches: Vec<_> = erasure::branches(chunks.as_ref()).collect();
+		let expected: Vec<_> = (8..10).map(|index: u32| {
+			let branch = &branches[index as usize];
+			Some(ErasureChunk {
+				chunk: branch.1.to_vec(),
+				index,
+				proof: branch.0.clone(),
+			})
+		}).collect();
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			data_candidate,
+			None,
+			n_validators,
+			available_data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryChunkRange(data_candidate, 8, 12, tx);
+		overseer_send(&mut virtual_overseer, query).await;
+		assert_eq!(rx.await.unwrap(), expected);
+	});
+}
+
+#[test]
+fn query_oldest_unfinalized_works() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store,
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	let states = [
+		(3, CandidateState::Finalized),
+		(5, CandidateState::Included),
+		(7, CandidateState::Stored),
+		(4, CandidateState::Finalized),
+	];
+
+	let mut records = Vec::new();
+
+	for (i, (block_number, candidate_state)) in states.iter().enumerate() {
+		let candidate_hash = CandidateHash(Hash::repeat_byte(i as u8 + 1));
+		let mut validation_data = test_state.persisted_validation_data.clone();
+		validation_data.block_number = *block_number;
+
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data,
+		};
+
+		store_available_data(&mut subsystem, &candidate_hash, None, 10, data, ErasureCodingVersion::V1, None).unwrap();
+
+		records.push(PoVPruningRecord {
+			candidate_hash,
+			block_number: *block_number,
+			candidate_state: *candidate_state,
+			prune_at: PruningDelay::Indefinite,
+		});
+	}
+
+	put_pov_pruning(&mut subsystem, None, records).unwrap();
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryOldestUnfinalized(tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+		assert_eq!(rx.await.unwrap(), Some(5));
+	});
+
+	let subsystem = AvailabilityStoreSubsystem::new_in_memory(None, Metrics(None));
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryOldestUnfinalized(tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+		assert!(rx.await.unwrap().is_none());
+	});
+}
+
+#[test]
+fn query_storage_stats_works() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let metrics = <Metrics as metrics::Metrics>::try_register(&prometheus::Registry::new()).unwrap();
+	let subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		metrics,
+	);
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		let (tx, rx) = oneshot::channel();
+		virtual_overseer.send(FromOverseer::Communication {
+			msg: AvailabilityStoreMessage::QueryStorageStats(tx),
+		}).await;
+
+		// Only the schema version is stored.
+		let stats = rx.await.unwrap();
+		assert_eq!((stats.candidates, stats.chunks, stats.data_bytes), (0, 0, 0));
+
+		// Two candidates with their full data and all of their chunks.
+		for i in 1..=2 {
+			let available_data = AvailableData {
+				pov: Arc::new(PoV { block_data: BlockData(vec![i, 5, 6]) }),
+				validation_data: test_state.persisted_validation_data.clone(),
+			};
+
+			let (tx, rx) = oneshot::channel();
+			let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+				CandidateHash(Hash::repeat_byte(i)),
+				Some(1),
+				10,
+				available_data,
+				None,
+				ErasureCodingVersion::V1,
+				tx,
+			);
+
+			virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+			assert_eq!(rx.await.unwrap(), Ok(()));
+		}
+
+		let column_bytes = |column| store.iter(column)
+			.filter(|(key, _)| &key[..] != &STORAGE_STATS_KEY[..])
+			.map(|(key, value)| (key.len() + value.len()) as u64)
+			.sum::<u64>();
+
+		let (tx, rx) = oneshot::channel();
+		virtual_overseer.send(FromOverseer::Communication {
+			msg: AvailabilityStoreMessage::QueryStorageStats(tx),
+		}).await;
+
+		let stats = rx.await.unwrap();
+		assert_eq!(stats.candidates, 2);
+		assert_eq!(stats.chunks, 20);
+		assert_eq!(stats.data_bytes, column_bytes(columns::DATA));
+		assert_eq!(stats.meta_bytes, column_bytes(columns::META));
+
+		let (tx, rx) = oneshot::channel();
+		virtual_overseer.send(FromOverseer::Communication {
+			msg: AvailabilityStoreMessage::PurgeAll(tx),
+		}).await;
+		assert_eq!(rx.await.unwrap(), Ok(()));
+
+		let (tx, rx) = oneshot::channel();
+		virtual_overseer.send(FromOverseer::Communication {
+			msg: AvailabilityStoreMessage::QueryStorageStats(tx),
+		}).await;
+
+		// The counts are stored along with the values and follow their removal.
+		let stats = rx.await.unwrap();
+		assert_eq!((stats.candidates, stats.chunks, stats.data_bytes), (0, 0, 0));
+		assert_eq!(stats.meta_bytes, column_bytes(columns::META));
+		assert_eq!(
+			query_inner::<StoredCounts>(&store, columns::META, &STORAGE_STATS_KEY).map(StorageStats::from),
+			Some(stats),
+		);
+	});
+}
+
+#[test]
+fn verify_integrity_reports_corrupt_candidates() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hashes: Vec<_> = (1..=3).map(|i| CandidateHash(Hash::repeat_byte(i))).collect();
+
+		for (i, candidate_hash) in candidate_hashes.iter().enumerate() {
+			let available_data = AvailableData {
+				pov: Arc::new(PoV { block_data: BlockData(vec![i as u8, 5, 6]) }),
+				validation_data: test_state.persisted_validation_data.clone(),
+			};
+
+			let (tx, rx) = oneshot::channel();
+			let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+				*candidate_hash,
+				Some(1),
+				10,
+				available_data,
+				None,
+				ErasureCodingVersion::V1,
+				tx,
+			);
+
+			virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+			assert_eq!(rx.await.unwrap(), Ok(()));
+		}
+
+		// The full data, 10 chunks, the validator count, stored-at time, erasure root and erasure
+		// coding version of each.
+		let (tx, rx) = oneshot::channel();
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::VerifyIntegrity(tx)).await;
+		assert_eq!(rx.await.unwrap(), IntegrityReport { checked: 45, corrupt: Vec::new() });
+
+		// The full data of the first candidate no longer decodes and a chunk of the second one
+		// no longer matches its proof.
+		let chunk_key = erasure_chunk_key(&candidate_hashes[1], 3);
+		let mut chunk = ErasureChunk::decode(&mut &store.get(columns::DATA, &chunk_key).unwrap().unwrap()[..])
+			.unwrap();
+		chunk.chunk[0] ^= 1;
+
+		let mut tx = DBTransaction::new();
+		tx.put_vec(columns::DATA, &available_data_key(&candidate_hashes[0]), vec![0xff; 3]);
+		tx.put_vec(columns::DATA, &chunk_key, chunk.encode());
+		store.write(tx).unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::VerifyIntegrity(tx)).await;
+		assert_eq!(
+			rx.await.unwrap(),
+			IntegrityReport { checked: 45, corrupt: candidate_hashes[..2].to_vec() },
+		);
+	});
+}
+
+#[test]
+fn integrity_samples_count_corrupt_values() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+	let metrics = <Metrics as metrics::Metrics>::try_register(&prometheus::Registry::new()).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		metrics.clone(),
+	);
+	subsystem.integrity_sampling = Some(IntegritySampling::new(Duration::from_millis(10)));
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let corrupt_values = || metrics.0.as_ref().unwrap().corrupt_values_total.get();
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			10,
+			available_data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		)).await;
+		rx.await.unwrap().unwrap();
+
+		// Intact values are not counted.
+		Delay::new(Duration::from_millis(30)).await;
+		assert_eq!(corrupt_values(), 0);
+
+		let mut tx = DBTransaction::new();
+		tx.put_vec(columns::DATA, &available_data_key(&candidate_hash), vec![0xff; 3]);
+		store.write(tx).unwrap();
+
+		async {
+			while corrupt_values() == 0 {
+				Delay::new(Duration::from_millis(10)).await;
+			}
+		}.timeout(TIMEOUT).await.expect("the corrupt value is found by a sample");
+	});
+}
+
+#[test]
+fn query_availability_status_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	test_harness(PruningConfig::default(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let relay_parent = Hash::repeat_byte(32);
+		let candidate_hash = CandidateHash(Hash::repeat_byte(33));
+
+		let (tx, rx) = oneshot::channel();
+		virtual_overseer.send(FromOverseer::Communication {
+			msg: AvailabilityStoreMessage::QueryAvailabilityStatus(candidate_hash, tx),
+		}).await;
+		assert_eq!(rx.await.unwrap(), AvailabilityStatus::default());
+
+		// Only chunks are stored, the full data is not.
+		for validator_index in &[2, 5] {
+			let (tx, rx) = oneshot::channel();
+			let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+				candidate_hash,
+				relay_parent,
+				block_number: Some(5),
+				validator_index: *validator_index,
+				chunk: ErasureChunk {
+					chunk: vec![1, 2, 3],
+					index: *validator_index,
+					proof: vec![vec![3, 4, 5]],
+				},
+				tx,
+			};
+
+			overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
+			assert_eq!(rx.await.unwrap(), Ok(()));
+		}
+
+		let (tx, rx) = oneshot::channel();
+		virtual_overseer.send(FromOverseer::Communication {
+			msg: AvailabilityStoreMessage::QueryAvailabilityStatus(candidate_hash, tx),
+		}).await;
+		assert_eq!(
+			rx.await.unwrap(),
+			AvailabilityStatus {
+				has_full_data: false,
+				chunks_held: 2,
+				n_validators: None,
+				can_reconstruct: false,
+			},
+		);
+	});
+}
+
+#[test]
+fn threshold_number_of_chunks_can_reconstruct() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let candidate_hash = CandidateHash(Hash::repeat_byte(33));
+	let n_validators = 10;
+
+	assert_eq!(recovery_threshold(1), 1);
+	assert_eq!(recovery_threshold(n_validators as usize), 4);
+
+	let mut tx = DBTransaction::new();
+	tx.put_vec(columns::META, &n_validators_key(&candidate_hash), n_validators.encode());
+	store.write(tx).unwrap();
+
+	test_harness(PruningConfig::default(), store, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let relay_parent = Hash::repeat_byte(32);
+
+		for validator_index in 0..4 {
+			let (tx, rx) = oneshot::channel();
+			let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+				candidate_hash,
+				relay_parent,
+				block_number: Some(5),
+				validator_index,
+				chunk: ErasureChunk {
+					chunk: vec![1, 2, 3],
+					index: validator_index,
+					proof: vec![vec![3, 4, 5]],
+				},
+				tx,
+			};
+
+			overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
+			assert_eq!(rx.await.unwrap(), Ok(()));
+
+			let (tx, rx) = oneshot::channel();
+			virtual_overseer.send(FromOverseer::Communication {
+				msg: AvailabilityStoreMessage::QueryAvailabilityStatus(candidate_hash, tx),
+			}).await;
+
+			let status = rx.await.unwrap();
+			assert_eq!(status.chunks_held, validator_index + 1);
+			assert_eq!(status.n_validators, Some(n_validators));
+			assert_eq!(status.can_reconstruct, validator_index == 3);
+		}
+	});
+}
+
+#[test]
+fn corrupted_values_are_detected_with_checksums() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config,
+		Metrics(None),
+	);
+	subsystem.verify_checksums = true;
+
+	store_available_data(&mut subsystem, &candidate_hash, Some(0), 10, data.clone(), ErasureCodingVersion::V1, None).unwrap();
+
+	let chunk = get_chunk(&mut subsystem, &candidate_hash, 0).unwrap().unwrap();
+	assert_eq!(checked_available_data(&subsystem, &candidate_hash).unwrap().unwrap().data, data);
+
+	// Flip a byte of the chunk data, the value still decodes.
+	let key = erasure_chunk_key(&candidate_hash, 0);
+	let mut raw = store.get(columns::DATA, &key).unwrap().unwrap();
+	raw[1] ^= 0xff;
+
+	let mut tx = DBTransaction::new();
+	tx.put_vec(columns::DATA, &key, raw);
+	store.write(tx).unwrap();
+
+	assert_matches!(get_chunk(&mut subsystem, &candidate_hash, 0), Err(Error::ChecksumMismatch));
+
+	// Without verification the corrupted chunk goes unnoticed.
+	subsystem.verify_checksums = false;
+	let corrupted = get_chunk(&mut subsystem, &candidate_hash, 0).unwrap().unwrap();
+	assert_ne!(corrupted, chunk);
+	subsystem.verify_checksums = true;
+
+	let key = available_data_key(&candidate_hash);
+	let mut raw = store.get(columns::DATA, &key).unwrap().unwrap();
+	let last_data_byte = raw.len() - 34;
+	raw[last_data_byte] ^= 0xff;
+
+	let mut tx = DBTransaction::new();
+	tx.put_vec(columns::DATA, &key, raw);
+	store.write(tx).unwrap();
+
+	assert!(matches!(checked_available_data(&subsystem, &candidate_hash), Err(Error::ChecksumMismatch)));
+}
+
+#[test]
+fn chunk_queries_verify_checksums() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+	let n_validators = 10;
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+	let chunks = get_chunks(&data, n_validators as usize, &Metrics(None)).unwrap();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config,
+		Metrics(None),
+	);
+	subsystem.verify_checksums = true;
+
+	store_available_data(&mut subsystem, &candidate_hash, Some(0), n_validators, data, ErasureCodingVersion::V1, None).unwrap();
+
+	// The reported size leaves out the checksum.
+	let key = erasure_chunk_key(&candidate_hash, 1);
+	let raw = store.get(columns::DATA, &key).unwrap().unwrap();
+	assert!(raw.len() > chunks[1].encode().len());
+	assert_eq!(chunk_size(&subsystem, &candidate_hash, 1), Some(chunks[1].encode().len()));
+	assert_eq!(get_chunks_by_indices(&mut subsystem, &candidate_hash, &[1]).unwrap(), vec![Some(chunks[1].clone())]);
+	assert_eq!(get_chunk_range(&mut subsystem, &candidate_hash, 1, 2).unwrap(), vec![Some(chunks[1].clone())]);
+
+	// Flip a byte of the chunk data, the value still decodes.
+	let mut raw = raw.to_vec();
+	raw[1] ^= 0xff;
+
+	let mut tx = DBTransaction::new();
+	tx.put_vec(columns::DATA, &key, raw);
+	store.write(tx).unwrap();
+
+	assert_matches!(get_chunks_by_indices(&mut subsystem, &candidate_hash, &[1]), Err(Error::ChecksumMismatch));
+	assert_matches!(get_chunk_range(&mut subsystem, &candidate_hash, 0, 2), Err(Error::ChecksumMismatch));
+
+	// Missing chunks are not regenerated from corrupted data.
+	let key = available_data_key(&candidate_hash);
+	let mut raw = store.get(columns::DATA, &key).unwrap().unwrap();
+	let last_data_byte = raw.len() - 34;
+	raw[last_data_byte] ^= 0xff;
+
+	let mut tx = DBTransaction::new();
+	tx.delete(columns::DATA, &erasure_chunk_key(&candidate_hash, 2));
+	tx.put_vec(columns::DATA, &key, raw);
+	store.write(tx).unwrap();
+
+	assert_matches!(get_chunks_by_indices(&mut subsystem, &candidate_hash, &[2]), Err(Error::ChecksumMismatch));
+}
+
+#[test]
+fn chunks_are_iterated_by_candidate_prefix() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	// The hashes only differ in their last byte.
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+	let mut other_hash = Hash::repeat_byte(1);
+	other_hash.as_mut()[31] = 2;
+	let other_hash = CandidateHash(other_hash);
+
+	let chunk = |index| ErasureChunk {
+		chunk: vec![1, 2, 3],
+		index,
+		proof: vec![vec![3, 4, 5]],
+	};
+
+	for index in vec![256, 0, 1] {
+		store_chunk(&mut subsystem, &candidate_hash, 300, chunk(index), 5, None).unwrap();
+	}
+	store_chunk(&mut subsystem, &other_hash, 300, chunk(2), 5, None).unwrap();
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+	store_available_data(&mut subsystem, &candidate_hash, None, 300, data, ErasureCodingVersion::V1, None).unwrap();
+
+	// The available data shares the prefix but is not a chunk.
+	assert_eq!(store.iter_with_prefix(columns::DATA, &candidate_data_prefix(&candidate_hash)).count(), 4);
+	assert_eq!(stored_chunk_indices(&store, &candidate_hash), vec![0, 1, 256]);
+	assert_eq!(stored_chunk_indices(&store, &other_hash), vec![2]);
+	assert_eq!(chunk_count(&store, &candidate_hash), 3);
+}
+
+#[test]
+fn query_chunk_count_works() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+	let n_validators = 4;
+
+	let available_data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+
+	let chunks = erasure::obtain_chunks_v1(n_validators as usize, &available_data).unwrap();
+	let branches: Vec<_> = erasure::branches(chunks.as_ref()).collect();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store,
+		test_state.pruning_config,
+		Metrics(None),
+	);
+
+	store_available_data(&mut subsystem, &candidate_hash, None, n_validators, available_data, ErasureCodingVersion::V1, None).unwrap();
+
+	for index in &[0u32, 2] {
+		let branch = &branches[*index as usize];
+		let chunk = ErasureChunk {
+			chunk: branch.1.to_vec(),
+			index: *index,
+			proof: branch.0.clone(),
+		};
+
+		store_chunk(&mut subsystem, &candidate_hash, n_validators, chunk, 1, None).unwrap();
+	}
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryChunkCount(candidate_hash, tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+		assert_eq!(rx.await.unwrap(), Some((2, n_validators)));
+
+		let (tx, rx) = oneshot::channel();
+		let unknown = CandidateHash(Hash::repeat_byte(2));
+		let query = AvailabilityStoreMessage::QueryChunkCount(unknown, tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+		assert!(rx.await.unwrap().is_none());
+	});
+}
+
+#[test]
+fn query_total_chunk_count_works() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let n_validators = 4;
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store,
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	// The full data of the first candidate is stored along with our chunk.
+	let available_data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data.clone(),
+	};
+	store_available_data(
+		&mut subsystem,
+		&CandidateHash(Hash::repeat_byte(1)),
+		Some(0),
+		n_validators,
+		available_data,
+		ErasureCodingVersion::V1,
+		None,
+	).unwrap();
+
+	// Only three chunks are stored for the second one.
+	let available_data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![7, 8, 9]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+	let chunks = get_chunks(&available_data, n_validators as usize, &Metrics(None)).unwrap();
+	for chunk in chunks.into_iter().take(3) {
+		store_chunk(&mut subsystem, &CandidateHash(Hash::repeat_byte(2)), n_validators, chunk, 1, None).unwrap();
+	}
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryTotalChunkCount(tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+		assert_eq!(rx.await.unwrap(), n_validators as usize + 3);
+	});
+}
+
+#[test]
+fn query_chunk_with_root_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let validator_index = 1;
+		let n_validators = 10;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let chunks = erasure::obtain_chunks_v1(n_validators as usize, &available_data).unwrap();
+		let branches = erasure::branches(chunks.as_ref());
+		let expected_root = branches.root();
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			Some(validator_index),
+			n_validators,
+			available_data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		assert_eq!(rx.await.unwrap(), Ok(()));
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryChunkWithRoot(candidate_hash, validator_index, tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+		let (chunk, root) = rx.await.unwrap().unwrap();
+		assert_eq!(root, expected_root);
+		assert_eq!(chunk.index, validator_index);
+		assert!(erasure::branch_hash(&root, &chunk.proof, chunk.index as usize).is_ok());
+
+		// The root of a candidate we only hold a chunk of is not known.
+		let other_hash = CandidateHash(Hash::repeat_byte(2));
+		let (proof, chunk) = erasure::branches(chunks.as_ref()).nth(0).unwrap();
+		let chunk = ErasureChunk { chunk: chunk.to_vec(), index: 0, proof };
+
+		let (tx, rx) = oneshot::channel();
+		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+			candidate_hash: other_hash,
+			relay_parent: Hash::repeat_byte(32),
+			block_number: Some(1),
+			validator_index: 0,
+			chunk,
+			tx,
+		};
+		virtual_overseer.send(FromOverseer::Communication{ msg: chunk_msg }).await;
+		assert_eq!(rx.await.unwrap(), Ok(()));
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryChunkWithRoot(other_hash, 0, tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+		assert!(rx.await.unwrap().is_none());
+	});
+}
+
+#[test]
+fn run_pruning_now_prunes_outdated_data() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let pruning_config = PruningConfig::default()
+		.with_keep_stored_block_for(Duration::from_millis(100));
+
+	let subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		pruning_config.clone(),
+		Metrics(None),
+	);
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		// The data is written next to the running subsystem, so its pruning timer is not armed.
+		let mut writer = AvailabilityStoreSubsystem::with_db(store, pruning_config, Metrics(None));
+		store_available_data(&mut writer, &candidate_hash, None, 10, available_data, ErasureCodingVersion::V1, None).unwrap();
+
+		Delay::new(Duration::from_millis(200)).await;
+
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_some());
+
+		let (tx, rx) = oneshot::channel();
+		let prune = AvailabilityStoreMessage::RunPruningNow(tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: prune }).await;
+		rx.await.unwrap();
+
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+	});
+}
+
+#[test]
+fn store_block_rejects_chunk_index_out_of_range() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		// There is no chunk with this index for 10 validators.
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			Some(10),
+			10,
+			available_data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		assert_eq!(rx.await.unwrap(), Err(StoreError::ChunkIndexOutOfRange));
+
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+	});
+}
+
+#[test]
+fn store_block_rejects_invalid_validator_count() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let requests = vec![
+			(None, 0),
+			(Some(0), 0),
+			(Some(0), 1),
+			(None, MAX_VALIDATORS + 1),
+			(Some(0), MAX_VALIDATORS + 1),
+			(Some(0), u32::MAX),
+		];
+
+		for (id, n_validators) in requests {
+			let (tx, rx) = oneshot::channel();
+			let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+				candidate_hash,
+				id,
+				n_validators,
+				available_data.clone(),
+				None,
+				ErasureCodingVersion::V1,
+				tx,
+			);
+
+			virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+			assert_eq!(rx.await.unwrap(), Err(StoreError::InvalidValidatorCount));
+		}
+
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+	});
+}
+
+#[test]
+fn store_block_rejects_wrong_erasure_root() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let n_validators = 10;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let chunks = erasure::obtain_chunks_v1(n_validators as usize, &available_data).unwrap();
+		let erasure_root = erasure::branches(chunks.as_ref()).root();
+		let wrong_root = Hash::repeat_byte(0xff);
+
+		// Both with and without a chunk to store alongside the data.
+		for (i, id) in [Some(0), None].iter().enumerate() {
+			let candidate_hash = CandidateHash(Hash::repeat_byte(i as u8));
+
+			for (expected_erasure_root, expected_result) in vec![
+				(wrong_root, Err(StoreError::ErasureRootMismatch)),
+				(erasure_root, Ok(())),
+			] {
+				let (tx, rx) = oneshot::channel();
+				let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+					candidate_hash,
+					*id,
+					n_validators,
+					available_data.clone(),
+					Some(expected_erasure_root),
+					ErasureCodingVersion::V1,
+					tx,
+				);
+
+				virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+				assert_eq!(rx.await.unwrap(), expected_result);
+
+				assert_eq!(
+					query_available_data(&mut virtual_overseer, candidate_hash).await.is_some(),
+					expected_result.is_ok(),
+				);
+			}
+		}
+	});
+}
+
+#[test]
+fn storing_same_data_twice_keeps_single_pruning_record() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let n_validators = 10;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		for _ in 0..2 {
+			let (tx, rx) = oneshot::channel();
+			let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+				candidate_hash,
+				None,
+				n_validators,
+				available_data.clone(),
+				None,
+				ErasureCodingVersion::V1,
+				tx,
+			);
+
+			virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+			rx.await.unwrap().unwrap();
+		}
+
+		let records = pov_pruning(&store).unwrap();
+		assert_eq!(records.len(), 1);
+		assert_eq!(records[0].candidate_hash, candidate_hash);
+	});
+}
+
+#[test]
+fn storing_same_chunk_twice_keeps_single_pruning_record() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	test_harness(PruningConfig::default(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let relay_parent = Hash::repeat_byte(32);
+		let candidate_hash = CandidateHash(Hash::repeat_byte(33));
+		let validator_index = 5;
+
+		let chunk = ErasureChunk {
+			chunk: vec![1, 2, 3],
+			index: validator_index,
+			proof: vec![vec![3, 4, 5]],
+		};
+
+		let mut prune_times = Vec::new();
+
+		for _ in 0..2 {
+			let (tx, rx) = oneshot::channel();
+
+			let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+				candidate_hash,
+				relay_parent,
+				block_number: Some(5),
+				validator_index,
+				chunk: chunk.clone(),
+				tx,
+			};
+
+			overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
+			assert_eq!(rx.await.unwrap(), Ok(()));
+
+			let mut records = chunk_pruning_records(&store, &candidate_hash);
+			assert_eq!(records.len(), 1);
+			prune_times.push(records.remove(0).prune_at);
+
+			Delay::new(Duration::from_millis(10)).await;
+		}
+
+		// The existing record was refreshed in place rather than joined by a second one.
+		assert_eq!(chunk_pruning(&store).unwrap().len(), 1);
+		assert!(prune_times[1] > prune_times[0]);
+	});
+}
+
+#[test]
+fn query_available_data_with_meta_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let n_validators = 7;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			n_validators,
+			available_data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryAvailableDataWithMeta(candidate_hash, tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+		assert_eq!(rx.await.unwrap(), Some((available_data, n_validators)));
+	});
+}
+
+#[test]
+fn query_available_data_raw_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let n_validators = 7;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryAvailableDataRaw(candidate_hash, tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+		assert!(rx.await.unwrap().is_none());
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			n_validators,
+			available_data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryAvailableDataRaw(candidate_hash, tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+		let raw = rx.await.unwrap().unwrap();
+
+		assert_eq!(AvailableData::decode(&mut &raw[..]).unwrap(), available_data);
+
+		let stored = StoredAvailableData::decode(&mut &raw[..]).unwrap();
+		assert_eq!(stored.data, available_data);
+		assert_eq!(stored.n_validators, n_validators);
+	});
+}
+
+/// Forwards the next request sent through an `AvailabilityStoreClient` to the subsystem.
+async fn forward_client_request(
+	requests: &mut mpsc::UnboundedReceiver<AllMessages>,
+	overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityStoreMessage>,
+) {
+	match requests.next().await {
+		Some(AllMessages::AvailabilityStore(msg)) => overseer_send(overseer, msg).await,
+		other => panic!("Unexpected request {:?}", other),
+	}
+}
+
+#[test]
+fn client_requests_are_answered_by_the_subsystem() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let (sender, mut requests) = mpsc::unbounded();
+		let mut client = AvailabilityStoreClient::new(sender);
+
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let chunk_candidate_hash = CandidateHash(Hash::repeat_byte(2));
+		let n_validators = 10;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+		let chunks = get_chunks(&available_data, n_validators as usize, &Metrics(None)).unwrap();
+
+		let (available, _) = future::join(
+			client.query_data_availability(candidate_hash),
+			forward_client_request(&mut requests, &mut virtual_overseer),
+		).await;
+		assert!(!available.unwrap());
+
+		let (stored, _) = future::join(
+			client.store_available_data(
+				candidate_hash,
+				None,
+				n_validators,
+				available_data.clone(),
+				None,
+				ErasureCodingVersion::V1,
+			),
+			forward_client_request(&mut requests, &mut virtual_overseer),
+		).await;
+		stored.unwrap();
+
+		let (queried, _) = future::join(
+			client.query_available_data(candidate_hash),
+			forward_client_request(&mut requests, &mut virtual_overseer),
+		).await;
+		assert_eq!(queried.unwrap(), Some(available_data.clone()));
+
+		let (chunk, _) = future::join(
+			client.query_chunk(candidate_hash, 3),
+			forward_client_request(&mut requests, &mut virtual_overseer),
+		).await;
+		assert_eq!(chunk.unwrap(), Some(chunks[3].clone()));
+
+		let (stored, _) = future::join(
+			client.store_chunk(
+				chunk_candidate_hash,
+				Hash::repeat_byte(10),
+				Some(5),
+				2,
+				chunks[2].clone(),
+			),
+			forward_client_request(&mut requests, &mut virtual_overseer),
+		).await;
+		stored.unwrap();
+
+		let (available, _) = future::join(
+			client.query_chunk_availability(chunk_candidate_hash, 2),
+			forward_client_request(&mut requests, &mut virtual_overseer),
+		).await;
+		assert!(available.unwrap());
+
+		// Requests without a method of their own are sent as built by the caller.
+		let (validator_count, _) = future::join(
+			client.request(|tx| AvailabilityStoreMessage::QueryValidatorCount(candidate_hash, tx)),
+			forward_client_request(&mut requests, &mut virtual_overseer),
+		).await;
+		assert_eq!(validator_count.unwrap(), Some(n_validators));
+
+		// Errors of the store are passed on to the caller.
+		let (stored, _) = future::join(
+			client.store_available_data(
+				CandidateHash(Hash::repeat_byte(3)),
+				None,
+				n_validators,
+				available_data,
+				Some(Hash::repeat_byte(0xff)),
+				ErasureCodingVersion::V1,
+			),
+			forward_client_request(&mut requests, &mut virtual_overseer),
+		).await;
+		assert_matches!(stored, Err(ClientError::Store(StoreError::ErasureRootMismatch)));
+	});
+}
+
+#[test]
+fn stores_with_expiry_are_pruned_at_the_requested_time() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let n_validators = 10;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+		let expire_at = now + Duration::from_secs(3600);
+
+		let (tx, rx) = oneshot::channel();
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::StoreAvailableDataWithExpiry {
+			hash: candidate_hash,
+			id: Some(2),
+			n_validators,
+			data: available_data.clone(),
+			expire_at,
+			tx,
+		}).await;
+		rx.await.unwrap().unwrap();
+
+		let records = pov_pruning(&store).unwrap();
+		assert_eq!(records.len(), 1);
+		assert_eq!(records[0].prune_at, PruningDelay::In(expire_at));
+
+		let record = chunk_pruning_record(&store, &candidate_hash, 2).unwrap();
+		assert_eq!(record.prune_at, PruningDelay::In(expire_at));
+
+		// An expiry that has already passed is rejected.
+		let (tx, rx) = oneshot::channel();
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::StoreAvailableDataWithExpiry {
+			hash: CandidateHash(Hash::repeat_byte(2)),
+			id: None,
+			n_validators,
+			data: available_data,
+			expire_at: now,
+			tx,
+		}).await;
+		assert_eq!(rx.await.unwrap(), Err(StoreError::ExpiryInThePast));
+		assert!(query_available_data(&mut virtual_overseer, CandidateHash(Hash::repeat_byte(2))).await.is_none());
+	});
+}
+
+#[test]
+fn stores_answer_with_their_prune_time() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let keep_stored_block_for = test_state.pruning_config.keep_stored_block_for;
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let n_validators = 10;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+		let chunk = get_chunks(&available_data, n_validators as usize, &Metrics(None)).unwrap().remove(0);
+
+		let (tx, rx) = oneshot::channel();
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::StoreAvailableDataWithPruneTime(
+			CandidateHash(Hash::repeat_byte(1)),
+			None,
+			n_validators,
+			available_data,
+			None,
+			tx,
+		)).await;
+		let data_prune_in = rx.await.unwrap().unwrap().unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::StoreChunkWithPruneTime {
+			candidate_hash: CandidateHash(Hash::repeat_byte(2)),
+			relay_parent: Hash::repeat_byte(3),
+			block_number: Some(1),
+			validator_index: 0,
+			chunk,
+			tx,
+		}).await;
+		let chunk_prune_in = rx.await.unwrap().unwrap().unwrap();
+
+		for prune_in in &[data_prune_in, chunk_prune_in] {
+			assert!(*prune_in <= keep_stored_block_for);
+			assert!(keep_stored_block_for - *prune_in < Duration::from_millis(500));
+		}
+
+		// Failures are answered as for the plain stores.
+		let (tx, rx) = oneshot::channel();
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::StoreAvailableDataWithPruneTime(
+			CandidateHash(Hash::repeat_byte(4)),
+			None,
+			1,
+			AvailableData {
+				pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+				validation_data: test_state.persisted_validation_data,
+			},
+			None,
+			tx,
+		)).await;
+		assert_eq!(rx.await.unwrap(), Err(StoreError::InvalidValidatorCount));
+	});
+}
+
+#[test]
+fn query_pruning_schedule_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let keep_stored_block_for = test_state.pruning_config.keep_stored_block_for;
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			5,
+			data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryPruningSchedule(tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+		let schedule = rx.await.unwrap();
+		assert_eq!(schedule.len(), 1);
+
+		let (hash, state, prune_in) = schedule[0];
+		assert_eq!(hash, candidate_hash);
+		assert_eq!(state, CandidateState::Stored);
+
+		let prune_in = prune_in.unwrap();
+		assert!(prune_in > Duration::from_secs(0));
+		assert!(prune_in <= keep_stored_block_for);
+		assert!(keep_stored_block_for - prune_in < Duration::from_secs(5));
+	});
+}
+
+#[test]
+fn query_candidate_state_follows_transitions() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		let pov = PoV {
+			block_data: BlockData(vec![4, 5, 6]),
+		};
+
+		let candidate = TestCandidateBuilder {
+			pov_hash: pov.hash(),
+			..Default::default()
+		}.build();
+
+		let candidate_hash = candidate.hash();
+
+		assert!(query_candidate_state(&mut virtual_overseer, candidate_hash).await.is_none());
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			10,
+			AvailableData {
+				pov: Arc::new(pov),
+				validation_data: test_state.persisted_validation_data,
+			},
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		assert_eq!(
+			query_candidate_state(&mut virtual_overseer, candidate_hash).await,
+			Some(CandidateState::Stored),
+		);
+
+		let new_leaf = Hash::repeat_byte(2);
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
+				activated: vec![(new_leaf, Arc::new(JaegerSpan::Disabled))].into(),
+				deactivated: vec![].into(),
+			}),
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::CandidateEvents(tx),
+			)) => {
+				assert_eq!(relay_parent, new_leaf);
+				tx.send(Ok(vec![
+					CandidateEvent::CandidateIncluded(candidate, HeadData::default()),
+				])).unwrap();
+			}
+		);
+
+		assert_eq!(
+			query_candidate_state(&mut virtual_overseer, candidate_hash).await,
+			Some(CandidateState::Included),
+		);
+
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::BlockFinalized(new_leaf, 10)
+		).await;
+
+		assert_eq!(
+			query_candidate_state(&mut virtual_overseer, candidate_hash).await,
+			Some(CandidateState::Finalized),
+		);
+	});
+}
+
+#[test]
+fn store_available_data_if_absent_skips_erasure_coding() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let metrics = <Metrics as metrics::Metrics>::try_register(&prometheus::Registry::new()).unwrap();
+	let subsystem = AvailabilityStoreSubsystem::with_db(
+		store,
+		test_state.pruning_config.clone(),
+		metrics.clone(),
+	);
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let n_validators = 10;
+
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let received_chunks = || metrics.0.as_ref().unwrap().received_availability_chunks_total.get();
+
+		for _ in 0..2 {
+			let (tx, rx) = oneshot::channel();
+			let block_msg = AvailabilityStoreMessage::StoreAvailableDataIfAbsent(
+				candidate_hash,
+				Some(0),
+				n_validators,
+				data.clone(),
+				tx,
+			);
+
+			virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+			rx.await.unwrap().unwrap();
+
+			assert_eq!(received_chunks(), n_validators as u64);
+		}
+
+		assert_eq!(query_available_data(&mut virtual_overseer, candidate_hash).await, Some(data));
+	});
+}
+
+#[test]
+fn chunk_regenerations_are_counted() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let metrics = <Metrics as metrics::Metrics>::try_register(&prometheus::Registry::new()).unwrap();
+	let subsystem = AvailabilityStoreSubsystem::with_db(
+		store,
+		test_state.pruning_config.clone(),
+		metrics.clone(),
+	);
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let regenerations = || metrics.0.as_ref().unwrap().chunk_regenerations_total.get();
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			10,
+			data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+		assert_eq!(regenerations(), 0);
+
+		// No chunk is stored yet, so all of them are regenerated from the full data.
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, 0).await.is_some());
+		assert_eq!(regenerations(), 1);
+
+		// The regenerated chunks were stored along the way.
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, 1).await.is_some());
+		assert_eq!(regenerations(), 1);
+	});
+}
+
+#[test]
+fn stored_chunks_are_reconstructed_once_enough_are_stored() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+	let n_validators = 10;
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+	subsystem.auto_reconstruct = true;
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+		let chunks = get_chunks(&available_data, n_validators as usize, &Metrics(None)).unwrap();
+		let threshold = recovery_threshold(n_validators as usize);
+
+		// Importing a chunk records the number of validators.
+		let (tx, rx) = oneshot::channel();
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::ImportChunks {
+			records: vec![(candidate_hash, n_validators, chunks[0].clone(), 5, CandidateState::Stored)],
+			tx,
+		}).await;
+		rx.await.unwrap().unwrap();
+
+		for chunk in &chunks[1..threshold] {
+			assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+
+			let (tx, rx) = oneshot::channel();
+			overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::StoreChunk {
+				candidate_hash,
+				relay_parent: Hash::repeat_byte(10),
+				block_number: Some(5),
+				validator_index: chunk.index,
+				chunk: chunk.clone(),
+				tx,
+			}).await;
+			rx.await.unwrap().unwrap();
+		}
+
+		// The data is reconstructed in the background.
+		let mut reconstructed = None;
+		for _ in 0..50 {
+			reconstructed = query_available_data(&mut virtual_overseer, candidate_hash).await;
+			if reconstructed.is_some() {
+				break;
+			}
+
+			Delay::new(Duration::from_millis(10)).await;
+		}
+
+		assert_eq!(reconstructed, Some(available_data));
+	});
+}
+
+#[test]
+fn failed_reconstructions_are_retried_with_more_chunks() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+	let n_validators = 10;
+	let threshold = recovery_threshold(n_validators as usize);
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+	subsystem.auto_reconstruct = true;
+
+	let (mut ctx, _virtual_overseer) = test_helpers::make_subsystem_context::<AvailabilityStoreMessage, _>(
+		sp_core::testing::TaskExecutor::new(),
+	);
+
+	let data = |i| AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![i, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data.clone(),
+	};
+	let chunks = get_chunks(&data(1), n_validators as usize, &Metrics(None)).unwrap();
+	let other_chunks = get_chunks(&data(2), n_validators as usize, &Metrics(None)).unwrap();
+
+	// The last of the chunks belongs to other data, so the reconstruction fails.
+	let records = chunks[..threshold - 1].iter()
+		.chain(Some(&other_chunks[threshold - 1]))
+		.map(|chunk| (candidate_hash, n_validators, chunk.clone(), 5, CandidateState::Stored))
+		.collect();
+	import_chunks(&mut subsystem, records).unwrap();
+
+	executor::block_on(async {
+		auto_reconstruct(&mut subsystem, &mut ctx, &candidate_hash).await.unwrap();
+		assert_eq!(subsystem.pending_reconstructions.len(), 1);
+
+		// No other attempt is made while one is pending.
+		auto_reconstruct(&mut subsystem, &mut ctx, &candidate_hash).await.unwrap();
+		assert_eq!(subsystem.pending_reconstructions.len(), 1);
+
+		let pending = subsystem.pending_reconstructions.next().await.unwrap();
+		assert!(pending.data.is_err());
+		finish_reconstruction(&mut subsystem, pending).unwrap();
+		assert!(available_data(&store, &candidate_hash).is_none());
+
+		// Nor after it failed, until more chunks are stored.
+		auto_reconstruct(&mut subsystem, &mut ctx, &candidate_hash).await.unwrap();
+		assert!(subsystem.pending_reconstructions.is_empty());
+
+		import_chunks(
+			&mut subsystem,
+			vec![(candidate_hash, n_validators, chunks[threshold].clone(), 5, CandidateState::Stored)],
+		).unwrap();
+
+		auto_reconstruct(&mut subsystem, &mut ctx, &candidate_hash).await.unwrap();
+		assert_eq!(subsystem.pending_reconstructions.len(), 1);
+	});
+}
+
+#[test]
+fn responses_to_dropped_callers_are_counted() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let metrics = <Metrics as metrics::Metrics>::try_register(&prometheus::Registry::new()).unwrap();
+	let subsystem = AvailabilityStoreSubsystem::with_db(
+		store,
+		test_state.pruning_config.clone(),
+		metrics.clone(),
+	);
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+		let send_failures = || metrics.0.as_ref().unwrap().response_send_failures_total.get();
+
+		let (tx, rx) = oneshot::channel();
+		drop(rx);
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::QueryDataAvailability(candidate_hash, tx)).await;
+
+		// Once a later query is answered, the one before it has been processed.
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+		assert_eq!(send_failures(), 1);
+
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+		assert_eq!(send_failures(), 1);
+	});
+}
+
+#[test]
+fn burst_of_chunk_queries_regenerates_once() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+	let n_validators = 10;
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+
+	let metrics = <Metrics as metrics::Metrics>::try_register(&prometheus::Registry::new()).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store,
+		test_state.pruning_config.clone(),
+		metrics.clone(),
+	);
+	store_available_data(&mut subsystem, &candidate_hash, None, n_validators, data, ErasureCodingVersion::V1, None).unwrap();
+
+	// Regenerated chunks are not stored by a read-only store.
+	subsystem.read_only = true;
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let regenerations = || metrics.0.as_ref().unwrap().chunk_regenerations_total.get();
+
+		for index in 0..n_validators {
+			let chunk = query_chunk(&mut virtual_overseer, candidate_hash, index).await.unwrap();
+			assert_eq!(chunk.index, index);
+		}
+
+		assert_eq!(regenerations(), 1);
+	});
+}
+
+#[test]
+fn all_chunks_are_stored_with_available_data() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+	let n_validators = 10;
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+
+	let metrics = <Metrics as metrics::Metrics>::try_register(&prometheus::Registry::new()).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config,
+		metrics,
+	);
+
+	let expected = get_chunks(&data, n_validators as usize, &Metrics(None)).unwrap();
+
+	store_available_data(&mut subsystem, &candidate_hash, Some(2), n_validators, data, ErasureCodingVersion::V1, None).unwrap();
+
+	assert_eq!(chunk_count(&store, &candidate_hash), n_validators);
+	assert_eq!(chunk_pruning_records(&store, &candidate_hash).len(), n_validators as usize);
+
+	for chunk in expected {
+		assert_eq!(get_chunk(&mut subsystem, &candidate_hash, chunk.index).unwrap(), Some(chunk));
+	}
+
+	// None of the chunks had to be regenerated.
+	assert_eq!(subsystem.metrics.0.as_ref().unwrap().chunk_regenerations_total.get(), 0);
+}
+
+#[test]
+fn purge_all_works() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	ensure_schema_version(&store).unwrap();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidates: Vec<_> = (1..=3).map(|i| CandidateHash(Hash::repeat_byte(i))).collect();
+
+		for candidate_hash in &candidates {
+			let data = AvailableData {
+				pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+				validation_data: test_state.persisted_validation_data.clone(),
+			};
+
+			let (tx, rx) = oneshot::channel();
+			let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+				*candidate_hash,
+				Some(1),
+				5,
+				data,
+				None,
+				ErasureCodingVersion::V1,
+				tx,
+			);
+
+			virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+			rx.await.unwrap().unwrap();
+		}
+
+		let (tx, rx) = oneshot::channel();
+		virtual_overseer.send(FromOverseer::Communication{ msg: AvailabilityStoreMessage::PurgeAll(tx) }).await;
+		rx.await.unwrap().unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let query = AvailabilityStoreMessage::QueryPruningSchedule(tx);
+		virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+		assert!(rx.await.unwrap().is_empty());
+
+		for candidate_hash in &candidates {
+			assert!(query_available_data(&mut virtual_overseer, *candidate_hash).await.is_none());
+			assert!(query_chunk(&mut virtual_overseer, *candidate_hash, 1).await.is_none());
+		}
+
+		assert_eq!(store.iter(columns::DATA).count(), 0);
+		assert!(get_next_pov_pruning_time(&store).is_none());
+		assert!(get_next_chunk_pruning_time(&store).is_none());
+		assert_eq!(
+			query_inner::<u32>(&store, columns::META, &SCHEMA_VERSION_KEY),
+			Some(CURRENT_SCHEMA_VERSION),
+		);
+	});
+}
+
+#[test]
+fn reconstruct_available_data_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let n_validators = 10;
+
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let threshold = erasure::recovery_threshold(n_validators as usize).unwrap();
+		let chunks: Vec<_> = get_chunks(&data, n_validators as usize, &Metrics(None))
+			.unwrap()
+			.into_iter()
+			.skip(n_validators as usize - threshold)
+			.collect();
+
+		let mut corrupted = chunks.clone();
+		corrupted[0].chunk[0] ^= 0xff;
+
+		let (tx, rx) = oneshot::channel();
+		let reconstruct_msg = AvailabilityStoreMessage::ReconstructAvailableData {
+			candidate_hash,
+			n_validators,
+			chunks: corrupted,
+			tx,
+		};
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: reconstruct_msg }).await;
+		assert_matches!(rx.await.unwrap(), Err(StoreError::InvalidChunkProof) | Err(StoreError::Erasure));
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+
+		let (tx, rx) = oneshot::channel();
+		let reconstruct_msg = AvailabilityStoreMessage::ReconstructAvailableData {
+			candidate_hash,
+			n_validators,
+			chunks,
+			tx,
+		};
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: reconstruct_msg }).await;
+		assert_eq!(rx.await.unwrap(), Ok(data.clone()));
+		assert_eq!(query_available_data(&mut virtual_overseer, candidate_hash).await, Some(data));
+	});
+}
+
+#[test]
+fn in_memory_subsystem_works() {
+	let test_state = TestState::default();
+	let subsystem = AvailabilityStoreSubsystem::new_in_memory(None, Metrics(None));
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			10,
+			available_data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		assert_eq!(
+			query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
+			available_data,
+		);
+	});
+}
+
+#[test]
+fn subsystem_with_custom_db_works() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let subsystem = AvailabilityStoreSubsystem::new_with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	).unwrap();
+
+	assert_eq!(
+		query_inner::<u32>(&store, columns::META, &SCHEMA_VERSION_KEY),
+		Some(CURRENT_SCHEMA_VERSION),
+	);
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			5,
+			data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		assert_eq!(query_available_data(&mut virtual_overseer, candidate_hash).await, Some(data));
+	});
+}
+
+#[test]
+fn store_pov_and_query_chunk_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let n_validators = 10;
+
+		let pov = PoV {
+			block_data: BlockData(vec![4, 5, 6]),
+		};
+
+		let available_data = AvailableData {
+			pov: Arc::new(pov),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let no_metrics = Metrics(None);
+		let chunks_expected = get_chunks(&available_data, n_validators as usize, &no_metrics).unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			n_validators,
+			available_data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+
+		assert_eq!(rx.await.unwrap(), Ok(()));
+
+		for validator_index in 0..n_validators {
+			let chunk = query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap();
+
+			assert_eq!(chunk, chunks_expected[validator_index as usize]);
+		}
+	});
+}
+
+#[test]
+fn stored_but_not_included_chunk_is_pruned() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let relay_parent = Hash::repeat_byte(2);
+		let validator_index = 5;
+
+		let chunk = ErasureChunk {
+			chunk: vec![1, 2, 3],
+			index: validator_index,
+			proof: vec![vec![3, 4, 5]],
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+			candidate_hash,
+			relay_parent,
+			block_number: None,
+			validator_index,
+			chunk: chunk.clone(),
+			tx,
+		};
+
+		overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::BlockNumber(
+				hash,
+				tx,
+			)) => {
+				assert_eq!(hash, relay_parent);
+				tx.send(Ok(Some(4))).unwrap();
+			}
+		);
+
+		rx.await.unwrap().unwrap();
+
+		// At this point data should be in the store.
+		assert_eq!(
+			query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap(),
+			chunk,
+		);
+
+		// Wait for twice as long as the stored block kept for.
+		Delay::new(test_state.pruning_config.keep_stored_block_for * 2).await;
+
+		// The block was not included by this point so it should be pruned now.
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.is_none());
+	});
+}
+
+#[test]
+fn stored_but_not_included_data_is_pruned() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let n_validators = 10;
+
+		let pov = PoV {
+			block_data: BlockData(vec![4, 5, 6]),
+		};
+
+		let available_data = AvailableData {
+			pov: Arc::new(pov),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			n_validators,
+			available_data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+
+		rx.await.unwrap().unwrap();
+
+		// At this point data should be in the store.
+		assert_eq!(
+			query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
+			available_data,
+		);
+
+		// Wait for twice as long as the stored block kept for.
+		Delay::new(test_state.pruning_config.keep_stored_block_for * 2).await;
+
+		// The block was not included by this point so it should be pruned now.
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+	});
+}
+
+#[test]
+fn stored_data_kept_until_finalized() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let n_validators = 10;
+
+		let pov = PoV {
+			block_data: BlockData(vec![4, 5, 6]),
+		};
+
+		let pov_hash = pov.hash();
+
+		let candidate = TestCandidateBuilder {
+			pov_hash,
+			..Default::default()
+		}.build();
+
+		let candidate_hash = candidate.hash();
+
+		let available_data = AvailableData {
+			pov: Arc::new(pov),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			n_validators,
+			available_data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+
+		rx.await.unwrap().unwrap();
+
+		// At this point data should be in the store.
+		assert_eq!(
+			query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
+			available_data,
+		);
+
+		let new_leaf = Hash::repeat_byte(2);
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
+				activated: vec![(new_leaf, Arc::new(JaegerSpan::Disabled))].into(),
+				deactivated: vec![].into(),
+			}),
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::CandidateEvents(tx),
+			)) => {
+				assert_eq!(relay_parent, new_leaf);
+				tx.send(Ok(vec![
+					CandidateEvent::CandidateIncluded(candidate, HeadData::default()),
+				])).unwrap();
+			}
+		);
+
+		Delay::new(test_state.pruning_config.keep_stored_block_for * 10).await;
+
+		// At this point data should _still_ be in the store.
+		assert_eq!(
+			query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
+			available_data,
+		);
+
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::BlockFinalized(new_leaf, 10)
+		).await;
+
+		// Wait for a half of the time finalized data should be available for
+		Delay::new(test_state.pruning_config.keep_finalized_block_for / 2).await;
+
+		// At this point data should _still_ be in the store.
+		assert_eq!(
+			query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
+			available_data,
+		);
+
+		// Wait until it is should be gone.
+		Delay::new(test_state.pruning_config.keep_finalized_block_for).await;
+
+		// At this point data should be gone from the store.
+		assert!(
+			query_available_data(&mut virtual_overseer, candidate_hash).await.is_none(),
+		);
+	});
+}
+
+#[test]
+fn candidate_receipt_is_stored_on_inclusion() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		let candidate = TestCandidateBuilder::default().build();
+		let candidate_hash = candidate.hash();
+		let unknown_candidate = TestCandidateBuilder {
+			para_id: 5.into(),
+			..Default::default()
+		}.build();
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			10,
+			available_data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		assert!(query_candidate_receipt(&mut virtual_overseer, candidate_hash).await.is_none());
+
+		let new_leaf = Hash::repeat_byte(2);
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
+				activated: vec![(new_leaf, Arc::new(JaegerSpan::Disabled))].into(),
+				deactivated: vec![].into(),
+			}),
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::CandidateEvents(tx),
+			)) => {
+				assert_eq!(relay_parent, new_leaf);
+				tx.send(Ok(vec![
+					CandidateEvent::CandidateIncluded(candidate.clone(), HeadData::default()),
+					CandidateEvent::CandidateIncluded(unknown_candidate.clone(), HeadData::default()),
+				])).unwrap();
+			}
+		);
+
+		assert_eq!(
+			query_candidate_receipt(&mut virtual_overseer, candidate_hash).await,
+			Some(candidate),
+		);
+
+		// Receipts of candidates without any stored data are not kept.
+		assert!(
+			query_candidate_receipt(&mut virtual_overseer, unknown_candidate.hash()).await.is_none(),
+		);
+	});
+}
+
+#[test]
+fn candidates_are_indexed_by_para() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		// Two candidates of the first parachain and one of the second.
+		let candidates: Vec<_> = [(1u32, 1u8), (1, 2), (2, 3)].iter()
+			.map(|(para_id, byte)| TestCandidateBuilder {
+				para_id: (*para_id).into(),
+				pov_hash: Hash::repeat_byte(*byte),
+				..Default::default()
+			}.build())
+			.collect();
+
+		for candidate in &candidates {
+			let available_data = AvailableData {
+				pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+				validation_data: test_state.persisted_validation_data.clone(),
+			};
+
+			let (tx, rx) = oneshot::channel();
+			let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+				candidate.hash(),
+				None,
+				10,
+				available_data,
+				None,
+				ErasureCodingVersion::V1,
+				tx,
+			);
+
+			virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+			rx.await.unwrap().unwrap();
+		}
+
+		let new_leaf = Hash::repeat_byte(2);
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
+				activated: vec![(new_leaf, Arc::new(JaegerSpan::Disabled))].into(),
+				deactivated: vec![].into(),
+			}),
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::CandidateEvents(tx),
+			)) => {
+				assert_eq!(relay_parent, new_leaf);
+				tx.send(Ok(candidates.iter()
+					.map(|candidate| CandidateEvent::CandidateIncluded(candidate.clone(), HeadData::default()))
+					.collect()
+				)).unwrap();
+			}
+		);
+
+		for (para_id, expected) in vec![
+			(1u32, vec![candidates[0].hash(), candidates[1].hash()]),
+			(2, vec![candidates[2].hash()]),
+			(3, Vec::new()),
+		] {
+			let (tx, rx) = oneshot::channel();
+			overseer_send(
+				&mut virtual_overseer,
+				AvailabilityStoreMessage::QueryCandidatesForPara(para_id.into(), tx),
+			).await;
+
+			let mut found = rx.await.unwrap();
+			let mut expected = expected;
+			found.sort_by_key(|candidate_hash| candidate_hash.0);
+			expected.sort_by_key(|candidate_hash| candidate_hash.0);
+
+			assert_eq!(found, expected);
+		}
+	});
+}
+
+#[test]
+fn para_index_is_built_from_stored_receipts() {
+	let db: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let candidate = TestCandidateBuilder { para_id: 7.into(), ..Default::default() }.build();
+
+	let mut tx = DBTransaction::new();
+	tx.put_vec(columns::META, &SCHEMA_VERSION_KEY, 6u32.encode());
+	tx.put_vec(columns::META, &candidate_receipt_key(&candidate.hash()), candidate.encode());
+	db.write(tx).unwrap();
+
+	ensure_schema_version(&db).unwrap();
+
+	assert_eq!(candidates_for_para(&db, 7.into()), vec![candidate.hash()]);
+	assert!(candidates_for_para(&db, 8.into()).is_empty());
+
+	let mut tx = DBTransaction::new();
+	delete_candidate_receipt(&db, &mut tx, &candidate.hash());
+	db.write(tx).unwrap();
+
+	assert!(candidates_for_para(&db, 7.into()).is_empty());
+	assert!(candidate_receipt(&db, &candidate.hash()).is_none());
+}
+
+#[test]
+fn finalized_candidates_are_sent_to_listeners() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+		let (listener, mut finalized) = mpsc::channel(8);
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::SubscribeToFinalized(listener)).await;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			10,
+			available_data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::BlockFinalized(Hash::repeat_byte(2), 10),
+		).await;
+
+		assert_eq!(finalized.next().await, Some(candidate_hash));
+
+		// Candidates that are finalized already are not reported again.
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::BlockFinalized(Hash::repeat_byte(3), 11),
+		).await;
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_some());
+
+		assert!(finalized.try_next().is_err());
+	});
+}
+
+#[test]
+fn state_transitions_are_traced() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let capture = TransitionCapture::default();
+
+	let candidate = TestCandidateBuilder::default().build();
+	let candidate_hash = candidate.hash();
+
+	tracing::subscriber::with_default(capture.clone(), || {
+		test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+			let TestHarness { mut virtual_overseer } = test_harness;
+
+			let available_data = AvailableData {
+				pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+				validation_data: test_state.persisted_validation_data,
+			};
+
+			let (tx, rx) = oneshot::channel();
+			let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+				candidate_hash,
+				None,
+				10,
+				available_data,
+				None,
+				ErasureCodingVersion::V1,
+				tx,
+			);
+
+			virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+			rx.await.unwrap().unwrap();
+
+			let new_leaf = Hash::repeat_byte(2);
+			overseer_signal(
+				&mut virtual_overseer,
+				OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
+					activated: vec![(new_leaf, Arc::new(JaegerSpan::Disabled))].into(),
+					deactivated: vec![].into(),
+				}),
+			).await;
+
+			assert_matches!(
+				overseer_recv(&mut virtual_overseer).await,
+				AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+					relay_parent,
+					RuntimeApiRequest::CandidateEvents(tx),
+				)) => {
+					assert_eq!(relay_parent, new_leaf);
+					tx.send(Ok(vec![
+						CandidateEvent::CandidateIncluded(candidate, HeadData::default()),
+					])).unwrap();
+				}
+			);
+
+			overseer_signal(
+				&mut virtual_overseer,
+				OverseerSignal::BlockFinalized(new_leaf, 10)
+			).await;
+
+			// Finalizing another block does not move the candidate again.
+			overseer_signal(
+				&mut virtual_overseer,
+				OverseerSignal::BlockFinalized(Hash::repeat_byte(3), 11)
+			).await;
+
+			// Make sure all signals have been processed.
+			assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_some());
+		});
+	});
+
+	let hash = format!("{:?}", candidate_hash);
+	assert_eq!(
+		*capture.0.lock().unwrap(),
+		vec![
+			(hash.clone(), format!("{:?}", CandidateState::Included)),
+			(hash, format!("{:?}", CandidateState::Finalized)),
+		],
+	);
+}
+
+#[test]
+fn extended_retention_keeps_data_past_prune_time() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let keep_stored_block_for = test_state.pruning_config.keep_stored_block_for;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			Some(1),
+			10,
+			available_data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let extend_msg = AvailabilityStoreMessage::ExtendRetention(
+			candidate_hash,
+			keep_stored_block_for * 4,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: extend_msg }).await;
+		assert_eq!(rx.await.unwrap(), Ok(()));
+
+		// Nothing is stored for an unknown candidate.
+		let (tx, rx) = oneshot::channel();
+		let extend_msg = AvailabilityStoreMessage::ExtendRetention(
+			CandidateHash(Hash::repeat_byte(2)),
+			keep_stored_block_for * 4,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: extend_msg }).await;
+		assert_eq!(rx.await.unwrap(), Err(StoreError::UnknownCandidate));
+
+		// Wait past the original pruning time.
+		Delay::new(keep_stored_block_for * 2).await;
+
+		assert_eq!(
+			query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
+			available_data,
+		);
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, 1).await.is_some());
+
+		// Wait past the extended pruning time.
+		Delay::new(keep_stored_block_for * 3).await;
+
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, 1).await.is_none());
+	});
+}
+
+#[test]
+fn failed_chunk_stores_are_attributed_to_their_candidate() {
+	let db = Arc::new(FailingDb::new());
+	let store: Arc<dyn KeyValueDB> = db.clone();
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(7));
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+		let chunk = get_chunks(&available_data, 10, &Metrics(None)).unwrap().remove(0);
+
+		db.set_fail_writes(true);
+
+		let (tx, rx) = oneshot::channel();
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::StoreChunk {
+			candidate_hash,
+			relay_parent: Hash::repeat_byte(10),
+			block_number: Some(5),
+			validator_index: 0,
+			chunk,
+			tx,
+		}).await;
+		assert_eq!(rx.await.unwrap(), Err(StoreError::Database));
+
+		db.set_fail_writes(false);
+
+		let last_error = query_health(&mut virtual_overseer).await.last_error.unwrap();
+		assert!(last_error.contains(&format!("{:?}", candidate_hash)), "{}", last_error);
+	});
+}
+
+#[test]
+fn health_reports_last_error() {
+	let db = Arc::new(FailingDb::new());
+	let store: Arc<dyn KeyValueDB> = db.clone();
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+		assert_eq!(query_health(&mut virtual_overseer).await, Health {
+			db_reachable: true,
+			..Default::default()
+		});
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		for fail_writes in &[true, false] {
+			db.set_fail_writes(*fail_writes);
+
+			let (tx, rx) = oneshot::channel();
+			let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+				candidate_hash,
+				None,
+				10,
+				available_data.clone(),
+				None,
+				ErasureCodingVersion::V1,
+				tx,
+			);
+
+			virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+			assert_eq!(rx.await.unwrap().is_err(), *fail_writes);
+		}
+
+		// The error is still reported after the store recovered.
+		let health = query_health(&mut virtual_overseer).await;
+		assert!(health.db_reachable);
+		assert_eq!(
+			health.last_error,
+			Some(format!("Failed to process candidate {:?}: write failed", candidate_hash)),
+		);
+		assert!(health.next_pov_pruning.is_some());
+		assert!(health.next_chunk_pruning.is_none());
+	});
+}
+
+#[test]
+fn finality_jump_is_processed_in_a_single_write() {
+	let db = Arc::new(FailingDb::new());
+	let store: Arc<dyn KeyValueDB> = db.clone();
+	let test_state = TestState::default();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	for block_number in &[2, 50, 99] {
+		let candidate_hash = CandidateHash(Hash::repeat_byte(*block_number as u8));
+		let mut validation_data = test_state.persisted_validation_data.clone();
+		validation_data.block_number = *block_number;
+
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data,
+		};
+
+		store_available_data(&mut subsystem, &candidate_hash, Some(0), 10, data, ErasureCodingVersion::V1, None).unwrap();
+	}
+
+	executor::block_on(process_block_finalized(&mut subsystem, 1)).unwrap();
+	assert!(pov_pruning(&store).unwrap().iter().all(|r| r.candidate_state == CandidateState::Stored));
+
+	let writes = db.writes();
+	executor::block_on(process_block_finalized(&mut subsystem, 100)).unwrap();
+	assert_eq!(db.writes(), writes + 1);
+
+	let pov_records = pov_pruning(&store).unwrap();
+	assert_eq!(pov_records.len(), 3);
+	assert!(pov_records.iter().all(|r| r.candidate_state == CandidateState::Finalized));
+
+	let chunk_records = chunk_pruning(&store).unwrap();
+	assert_eq!(chunk_records.len(), 30);
+	assert!(chunk_records.iter().all(|r| r.candidate_state == CandidateState::Finalized));
+}
+
+#[test]
+fn retention_overrides_extend_retention_of_their_parachain() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let keep_finalized_block_for = test_state.pruning_config.keep_finalized_block_for;
+	let extended = Duration::from_secs(3600);
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+	subsystem.pruning_config.retention_overrides.insert(1.into(), extended);
+
+	let candidates: Vec<_> = [1u32, 2].iter().map(|para_id| TestCandidateBuilder {
+		para_id: (*para_id).into(),
+		..Default::default()
+	}.build()).collect();
+
+	let mut tx = DBTransaction::new();
+	for candidate in &candidates {
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+
+		store_available_data(&mut subsystem, &candidate.hash(), Some(0), 10, data, ErasureCodingVersion::V1, None).unwrap();
+
+		// As written when the candidate is seen included.
+		tx.put_vec(columns::META, &candidate_receipt_key(&candidate.hash()), candidate.encode());
+	}
+	store.write(tx).unwrap();
+
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+	let block_number = test_state.persisted_validation_data.block_number;
+	executor::block_on(process_block_finalized(&mut subsystem, block_number)).unwrap();
+
+	let prune_in = |candidate_hash: CandidateHash| {
+		let pov_record = pov_pruning(&store).unwrap().into_iter()
+			.find(|r| r.candidate_hash == candidate_hash)
+			.unwrap();
+		let chunk_record = chunk_pruning_records(&store, &candidate_hash).pop().unwrap();
+
+		[pov_record.prune_at, chunk_record.prune_at].iter().map(|prune_at| match prune_at {
+			PruningDelay::In(at) => *at - now,
+			PruningDelay::Indefinite => panic!("finalized records are pruned eventually"),
+		}).collect::<Vec<_>>()
+	};
+
+	// Both the data and the chunks of the overridden parachain are kept longer.
+	for prune_in in prune_in(candidates[0].hash()) {
+		assert!(prune_in >= extended);
+		assert!(prune_in - extended < Duration::from_secs(5));
+	}
+
+	for prune_in in prune_in(candidates[1].hash()) {
+		assert!(prune_in < extended);
+		assert!(prune_in <= keep_finalized_block_for + Duration::from_secs(5));
+	}
+}
+
+#[test]
+fn imported_chunks_are_checked_and_kept_by_retention_overrides() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let extended = Duration::from_secs(3600);
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+	subsystem.pruning_config.retention_overrides.insert(1.into(), extended);
+
+	let candidate = TestCandidateBuilder {
+		para_id: 1.into(),
+		..Default::default()
+	}.build();
+	let candidate_hash = candidate.hash();
+
+	let mut tx = DBTransaction::new();
+	tx.put_vec(columns::META, &candidate_receipt_key(&candidate_hash), candidate.encode());
+	store.write(tx).unwrap();
+
+	let chunk = |index| ErasureChunk {
+		chunk: vec![1, 2, 3],
+		index,
+		proof: vec![vec![3, 4, 5]],
+	};
+
+	// The index has to be below the number of validators, nothing is imported otherwise.
+	assert_matches!(
+		import_chunks(&mut subsystem, vec![
+			(candidate_hash, 10, chunk(3), 5, CandidateState::Finalized),
+			(candidate_hash, 10, chunk(10), 5, CandidateState::Finalized),
+		]),
+		Err(Error::ChunkIndexOutOfRange { index: 10, n_validators: 10 })
+	);
+	assert!(chunk_pruning_records(&store, &candidate_hash).is_empty());
+
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+	import_chunks(&mut subsystem, vec![(candidate_hash, 10, chunk(3), 5, CandidateState::Finalized)]).unwrap();
+
+	let record = chunk_pruning_record(&store, &candidate_hash, 3).unwrap();
+	assert_matches!(record.prune_at, PruningDelay::In(prune_at) => {
+		assert!(prune_at - now >= extended);
+		assert!(prune_at - now - extended < Duration::from_secs(5));
+	});
+}
+
+#[test]
+fn finalized_data_is_pruned_after_configured_number_of_blocks() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let pruning_config = PruningConfig::default().with_retention(Retention::ByBlocks(2));
+
+	test_harness(pruning_config, store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+		let block_number = test_state.persisted_validation_data.block_number;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			Some(0),
+			10,
+			available_data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		assert_eq!(rx.await.unwrap(), Ok(()));
+
+		// The block itself and the one on top of it being finalized is not enough.
+		for finalized in block_number..block_number + 2 {
+			overseer_signal(
+				&mut virtual_overseer,
+				OverseerSignal::BlockFinalized(Hash::repeat_byte(finalized as u8), finalized),
+			).await;
+
+			assert_eq!(
+				query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
+				available_data,
+			);
+			assert!(query_chunk(&mut virtual_overseer, candidate_hash, 0).await.is_some());
+		}
+
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::BlockFinalized(Hash::repeat_byte(42), block_number + 2),
+		).await;
+
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, 0).await.is_none());
+		assert!(pov_pruning(&store).unwrap_or_default().is_empty());
+		assert!(chunk_pruning(&store).unwrap_or_default().is_empty());
+	});
+}
+
+#[test]
+fn stored_chunk_kept_until_finalized() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let relay_parent = Hash::repeat_byte(2);
+		let validator_index = 5;
+		let candidate = TestCandidateBuilder {
+			..Default::default()
+		}.build();
+		let candidate_hash = candidate.hash();
+
+		let chunk = ErasureChunk {
+			chunk: vec![1, 2, 3],
+			index: validator_index,
+			proof: vec![vec![3, 4, 5]],
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+			candidate_hash,
+			relay_parent,
+			block_number: None,
+			validator_index,
+			chunk: chunk.clone(),
+			tx,
+		};
+
+		overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::ChainApi(ChainApiMessage::BlockNumber(
+				hash,
+				tx,
+			)) => {
+				assert_eq!(hash, relay_parent);
+				tx.send(Ok(Some(4))).unwrap();
+			}
+		);
+
+		rx.await.unwrap().unwrap();
+
+		// At this point data should be in the store.
+		assert_eq!(
+			query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap(),
+			chunk,
+		);
+
+		let new_leaf = Hash::repeat_byte(2);
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
+				activated: vec![(new_leaf, Arc::new(JaegerSpan::Disabled))].into(),
+				deactivated: vec![].into(),
+			}),
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::CandidateEvents(tx),
+			)) => {
+				assert_eq!(relay_parent, new_leaf);
+				tx.send(Ok(vec![
+					CandidateEvent::CandidateIncluded(candidate, HeadData::default()),
+				])).unwrap();
+			}
+		);
+
+		Delay::new(test_state.pruning_config.keep_stored_block_for * 10).await;
+
+		// At this point data should _still_ be in the store.
+		assert_eq!(
+			query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap(),
+			chunk,
+		);
+
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::BlockFinalized(new_leaf, 10)
+		).await;
+
+		// Wait for a half of the time finalized data should be available for
+		Delay::new(test_state.pruning_config.keep_finalized_block_for / 2).await;
+
+		// At this point data should _still_ be in the store.
+		assert_eq!(
+			query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.unwrap(),
+			chunk,
+		);
+
+		// Wait until it is should be gone.
+		Delay::new(test_state.pruning_config.keep_finalized_chunk_for.unwrap()).await;
+
+		// At this point data should be gone from the store.
+		assert!(
+			query_available_data(&mut virtual_overseer, candidate_hash).await.is_none(),
+		);
+	});
+}
+
+#[test]
+fn forkfullness_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	test_harness(test_state.pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let n_validators = 10;
+
+		let pov_1 = PoV {
+			block_data: BlockData(vec![1, 2, 3]),
+		};
+
+		let pov_1_hash = pov_1.hash();
+
+		let pov_2 = PoV {
+			block_data: BlockData(vec![4, 5, 6]),
 		};
 
 		let pov_2_hash = pov_2.hash();
 
-		let candidate_1 = TestCandidateBuilder {
-			pov_hash: pov_1_hash,
-			..Default::default()
-		}.build();
+		let candidate_1 = TestCandidateBuilder {
+			pov_hash: pov_1_hash,
+			..Default::default()
+		}.build();
+
+		let candidate_1_hash = candidate_1.hash();
+
+		let candidate_2 = TestCandidateBuilder {
+			pov_hash: pov_2_hash,
+			..Default::default()
+		}.build();
+
+		let candidate_2_hash = candidate_2.hash();
+
+		let available_data_1 = AvailableData {
+			pov: Arc::new(pov_1),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+
+		let available_data_2 = AvailableData {
+			pov: Arc::new(pov_2),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_1_hash,
+			None,
+			n_validators,
+			available_data_1.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg }).await;
+
+		rx.await.unwrap().unwrap();
+
+		let (tx, rx) = oneshot::channel();
+		let msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_2_hash,
+			None,
+			n_validators,
+			available_data_2.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg }).await;
+
+		rx.await.unwrap().unwrap();
+
+		assert_eq!(
+			query_available_data(&mut virtual_overseer, candidate_1_hash).await.unwrap(),
+			available_data_1,
+		);
+
+		assert_eq!(
+			query_available_data(&mut virtual_overseer, candidate_2_hash).await.unwrap(),
+			available_data_2,
+		);
+
+
+		let new_leaf_1 = Hash::repeat_byte(2);
+		let new_leaf_2 = Hash::repeat_byte(3);
+
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
+				activated: vec![(new_leaf_1, Arc::new(JaegerSpan::Disabled)), (new_leaf_2, Arc::new(JaegerSpan::Disabled))].into(),
+				deactivated: vec![].into(),
+			}),
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				leaf,
+				RuntimeApiRequest::CandidateEvents(tx),
+			)) => {
+				assert_eq!(leaf, new_leaf_1);
+				tx.send(Ok(vec![
+					CandidateEvent::CandidateIncluded(candidate_1, HeadData::default()),
+				])).unwrap();
+			}
+		);
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				leaf,
+				RuntimeApiRequest::CandidateEvents(tx),
+			)) => {
+				assert_eq!(leaf, new_leaf_2);
+				tx.send(Ok(vec![
+					CandidateEvent::CandidateIncluded(candidate_2, HeadData::default()),
+				])).unwrap();
+			}
+		);
+
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::BlockFinalized(new_leaf_1, 5)
+		).await;
+
+		// Data of both candidates should be still present in the DB.
+		assert_eq!(
+			query_available_data(&mut virtual_overseer, candidate_1_hash).await.unwrap(),
+			available_data_1,
+		);
+
+		assert_eq!(
+			query_available_data(&mut virtual_overseer, candidate_2_hash).await.unwrap(),
+			available_data_2,
+		);
+		// Wait for longer than finalized blocks should be kept for
+		Delay::new(test_state.pruning_config.keep_finalized_block_for + Duration::from_secs(1)).await;
+
+		// Data of both candidates should be gone now.
+		assert!(
+			query_available_data(&mut virtual_overseer, candidate_1_hash).await.is_none(),
+		);
+
+		assert!(
+			query_available_data(&mut virtual_overseer, candidate_2_hash).await.is_none(),
+		);
+	});
+}
+
+#[test]
+fn validator_count_outlives_pruned_data() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let pruning_config = PruningConfig {
+		keep_stored_block_for: Duration::from_secs(1),
+		keep_finalized_block_for: Duration::from_secs(1),
+		keep_finalized_chunk_for: Some(Duration::from_secs(4)),
+		coalesce_pruning_within: Duration::from_secs(1),
+		retention: Retention::ByTime,
+		..PruningConfig::default()
+	};
+
+	test_harness(pruning_config.clone(), store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+		let n_validators = 10;
+		let validator_index = 3;
+
+		let pov = PoV {
+			block_data: BlockData(vec![4, 5, 6]),
+		};
+
+		let candidate = TestCandidateBuilder {
+			pov_hash: pov.hash(),
+			..Default::default()
+		}.build();
+
+		let candidate_hash = candidate.hash();
+
+		let available_data = AvailableData {
+			pov: Arc::new(pov),
+			validation_data: test_state.persisted_validation_data,
+		};
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			Some(validator_index),
+			n_validators,
+			available_data.clone(),
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		rx.await.unwrap().unwrap();
+
+		assert_eq!(query_validator_count(&mut virtual_overseer, candidate_hash).await, Some(n_validators));
+
+		let new_leaf = Hash::repeat_byte(2);
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
+				activated: vec![(new_leaf, Arc::new(JaegerSpan::Disabled))].into(),
+				deactivated: vec![].into(),
+			}),
+		).await;
+
+		assert_matches!(
+			overseer_recv(&mut virtual_overseer).await,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				relay_parent,
+				RuntimeApiRequest::CandidateEvents(tx),
+			)) => {
+				assert_eq!(relay_parent, new_leaf);
+				tx.send(Ok(vec![
+					CandidateEvent::CandidateIncluded(candidate, HeadData::default()),
+				])).unwrap();
+			}
+		);
+
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::BlockFinalized(new_leaf, 10)
+		).await;
+
+		// Wait until the full data is pruned but the chunk is still kept.
+		Delay::new(pruning_config.keep_finalized_block_for * 2).await;
+
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.is_some());
+		assert_eq!(query_validator_count(&mut virtual_overseer, candidate_hash).await, Some(n_validators));
+
+		// Wait until the chunk is gone as well.
+		Delay::new(pruning_config.keep_finalized_chunk_for.unwrap()).await;
+
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, validator_index).await.is_none());
+		assert!(query_validator_count(&mut virtual_overseer, candidate_hash).await.is_none());
+	});
+}
+
+#[test]
+fn finalized_chunks_can_be_kept_indefinitely() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let pruning_config = test_state.pruning_config.clone()
+		.with_keep_finalized_block_for(Duration::from_secs(0))
+		.with_keep_finalized_chunk_for(None);
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(store.clone(), pruning_config, Metrics(None));
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+	let chunk = ErasureChunk {
+		chunk: vec![1, 2, 3],
+		index: 3,
+		proof: vec![vec![3, 4, 5]],
+	};
+	store_chunk(&mut subsystem, &candidate_hash, 10, chunk.clone(), 5, None).unwrap();
+
+	executor::block_on(process_block_finalized(&mut subsystem, 10)).unwrap();
+
+	let records = chunk_pruning_records(&store, &candidate_hash);
+	assert_eq!(records.len(), 1);
+	assert_eq!(records[0].candidate_state, CandidateState::Finalized);
+	assert_eq!(records[0].prune_at, PruningDelay::Indefinite);
+	assert!(subsystem.next_chunk_pruning.is_none());
+
+	subsystem.prune(true, true).unwrap();
+
+	let key = erasure_chunk_key(&candidate_hash, 3);
+	assert_eq!(query_inner::<ErasureChunk>(&store, columns::DATA, &key), Some(chunk));
+}
+
+#[test]
+fn compaction_is_triggered_after_threshold() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let pruning_config = PruningConfig {
+		keep_stored_block_for: Duration::from_secs(0),
+		..test_state.pruning_config.clone()
+	};
+
+	let compacted = Arc::new(Mutex::new(Vec::new()));
+	let compact = {
+		let compacted = compacted.clone();
+		Arc::new(move |column: u32| -> io::Result<()> {
+			compacted.lock().unwrap().push(column);
+			Ok(())
+		})
+	};
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(store, pruning_config, metrics)
+		.with_compaction(compact);
+	subsystem.compaction.threshold = 1;
+
+	let compactions = |subsystem: &AvailabilityStoreSubsystem| {
+		subsystem.metrics.0.as_ref().unwrap().compactions_total.get()
+	};
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+	// Nothing was pruned yet, so no compaction is due.
+	subsystem.prune(true, false).unwrap();
+	assert_eq!(compactions(&subsystem), 0);
+	assert!(compacted.lock().unwrap().is_empty());
+
+	store_available_data(&mut subsystem, &candidate_hash, None, 10, data, ErasureCodingVersion::V1, None).unwrap();
+	subsystem.prune(true, false).unwrap();
+
+	assert!(available_data(&subsystem.inner, &candidate_hash).is_none());
+	assert_eq!(*compacted.lock().unwrap(), vec![columns::DATA]);
+	assert_eq!(compactions(&subsystem), 1);
+	assert_eq!(subsystem.compaction.pruned_bytes, 0);
+}
+
+#[test]
+fn nothing_is_compacted_without_a_compaction() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let pruning_config = PruningConfig {
+		keep_stored_block_for: Duration::from_secs(0),
+		..test_state.pruning_config.clone()
+	};
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(store, pruning_config, metrics);
+	subsystem.compaction.threshold = 1;
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+	store_available_data(&mut subsystem, &candidate_hash, None, 10, data, ErasureCodingVersion::V1, None).unwrap();
+	subsystem.prune(true, false).unwrap();
+
+	// Without a compaction nothing is counted and the pruned bytes keep adding up.
+	assert!(available_data(&subsystem.inner, &candidate_hash).is_none());
+	assert_eq!(subsystem.metrics.0.as_ref().unwrap().compactions_total.get(), 0);
+	assert!(subsystem.compaction.pruned_bytes > 0);
+}
+
+#[test]
+fn on_disk_store_is_compacted_after_threshold() {
+	let dir = tempfile::tempdir().unwrap();
+	let config = || Config {
+		compaction_threshold: Some(1),
+		pruning: PruningConfig::default().with_keep_stored_block_for(Duration::from_secs(0)),
+		..Config::with_subdir(dir.path().to_owned(), "av-store")
+	};
+
+	let test_state = TestState::default();
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+	let pruned_hash = CandidateHash(Hash::repeat_byte(1));
+	let kept_hash = CandidateHash(Hash::repeat_byte(2));
+
+	let metrics = <Metrics as metrics::Metrics>::try_register(&prometheus::Registry::new()).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::new_on_disk(config(), metrics).unwrap();
+
+	store_available_data(&mut subsystem, &pruned_hash, None, 10, data.clone(), ErasureCodingVersion::V1, None).unwrap();
+	subsystem.prune(true, false).unwrap();
+
+	assert!(available_data(&subsystem.inner, &pruned_hash).is_none());
+	assert_eq!(subsystem.metrics.0.as_ref().unwrap().compactions_total.get(), 1);
+	assert_eq!(subsystem.compaction.pruned_bytes, 0);
+	assert!(!dir.path().join("av-store-compaction").exists());
+
+	// The compacted database is in place and keeps working, also after reopening it.
+	store_available_data(&mut subsystem, &kept_hash, None, 10, data.clone(), ErasureCodingVersion::V1, None).unwrap();
+	assert_eq!(available_data(&subsystem.inner, &kept_hash).unwrap().data, data);
+	drop(subsystem);
+
+	let subsystem = AvailabilityStoreSubsystem::new_on_disk(config(), Metrics(None)).unwrap();
+	assert_eq!(available_data(&subsystem.inner, &kept_hash).unwrap().data, data);
+	assert!(available_data(&subsystem.inner, &pruned_hash).is_none());
+}
+
+#[test]
+fn pruning_is_counted_in_metrics() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let pruning_config = PruningConfig {
+		keep_stored_block_for: Duration::from_secs(0),
+		..test_state.pruning_config.clone()
+	};
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(store, pruning_config, metrics);
+
+	for i in 1..=2 {
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![i, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+		let candidate_hash = CandidateHash(Hash::repeat_byte(i));
+
+		store_available_data(&mut subsystem, &candidate_hash, Some(0), 10, data, ErasureCodingVersion::V1, None).unwrap();
+	}
+
+	subsystem.prune(true, false).unwrap();
+	subsystem.prune(false, true).unwrap();
+
+	let metrics = subsystem.metrics.0.as_ref().unwrap();
+	assert_eq!(metrics.pruned_povs_total.get(), 2);
+	assert_eq!(metrics.pruned_chunks_total.get(), 20);
+}
+
+#[test]
+fn pruning_is_counted_by_candidate_state() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let pruning_config = PruningConfig {
+		keep_stored_block_for: Duration::from_secs(0),
+		..test_state.pruning_config.clone()
+	};
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(store, pruning_config, metrics);
+
+	let states = [CandidateState::Stored, CandidateState::Included, CandidateState::Finalized];
+	let mut records = Vec::new();
+
+	for (i, candidate_state) in states.iter().enumerate() {
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![i as u8, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+		let candidate_hash = CandidateHash(Hash::repeat_byte(i as u8));
+
+		store_available_data(&mut subsystem, &candidate_hash, Some(0), 10, data, ErasureCodingVersion::V1, None).unwrap();
+
+		records.push(PoVPruningRecord {
+			candidate_hash,
+			block_number: 1,
+			candidate_state: *candidate_state,
+			prune_at: PruningDelay::In(Duration::from_secs(1)),
+		});
+	}
+
+	// The chunk records of all candidates are left in the stored state.
+	put_pov_pruning(&mut subsystem, None, records).unwrap();
+
+	subsystem.prune(true, false).unwrap();
+	subsystem.prune(false, true).unwrap();
+
+	let pruned_total = &subsystem.metrics.0.as_ref().unwrap().pruned_total;
+	let pruned = |record: &str, state: &str| pruned_total.with_label_values(&[record, state]).get();
+
+	assert_eq!(pruned("pov", "stored"), 1);
+	assert_eq!(pruned("pov", "included"), 1);
+	assert_eq!(pruned("pov", "finalized"), 1);
+	assert_eq!(pruned("chunk", "stored"), 30);
+	assert_eq!(pruned("chunk", "included"), 0);
+	assert_eq!(pruned("chunk", "finalized"), 0);
+}
+
+#[test]
+fn available_data_size_is_observed() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let registry = prometheus::Registry::new();
+	let metrics = <Metrics as metrics::Metrics>::try_register(&registry).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(store, test_state.pruning_config.clone(), metrics);
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![0; 3000]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+	let encoded_len = StoredAvailableData { data: data.clone(), n_validators: 10 }.encode().len();
+	assert!(encoded_len > 2048 && encoded_len <= 4096);
+
+	store_available_data(&mut subsystem, &CandidateHash(Hash::repeat_byte(1)), None, 10, data, ErasureCodingVersion::V1, None).unwrap();
+
+	let families = registry.gather();
+	let family = families.iter()
+		.find(|family| family.get_name() == "parachain_av_store_available_data_bytes")
+		.unwrap();
+	let histogram = family.get_metric()[0].get_histogram();
+
+	assert_eq!(histogram.get_sample_count(), 1);
+	assert_eq!(histogram.get_sample_sum() as usize, encoded_len);
+
+	let cumulative_count = |upper_bound: u64| histogram.get_bucket()
+		.iter()
+		.find(|bucket| bucket.get_upper_bound() as u64 == upper_bound)
+		.unwrap()
+		.get_cumulative_count();
+
+	assert_eq!(cumulative_count(2048), 0);
+	assert_eq!(cumulative_count(4096), 1);
+}
+
+#[test]
+fn error_backoff_grows_up_to_the_maximum() {
+	assert_eq!(error_backoff(1), ERROR_BACKOFF_INITIAL);
+	assert_eq!(error_backoff(2), ERROR_BACKOFF_INITIAL * 2);
+	assert_eq!(error_backoff(3), ERROR_BACKOFF_INITIAL * 4);
+
+	let backoffs: Vec<_> = (1..=20).map(error_backoff).collect();
+	assert!(backoffs.windows(2).all(|pair| pair[0] <= pair[1]));
+	assert_eq!(backoffs.last(), Some(&MAX_ERROR_BACKOFF));
+
+	// The delay doesn't overflow however long the errors persist.
+	assert_eq!(error_backoff(u32::MAX), MAX_ERROR_BACKOFF);
+}
+
+#[test]
+fn batched_chunks_are_stored_in_a_single_write() {
+	let db = Arc::new(FailingDb::new());
+	let store: Arc<dyn KeyValueDB> = db.clone();
+	let test_state = TestState::default();
+	let n_validators = 10;
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data.clone(),
+	};
+	let chunks = get_chunks(&data, n_validators as usize, &Metrics(None)).unwrap();
+	let candidate_hashes = [CandidateHash(Hash::repeat_byte(1)), CandidateHash(Hash::repeat_byte(2))];
+
+	// One write per chunk without batching.
+	let writes = db.writes();
+	for chunk in &chunks[..4] {
+		store_chunk(&mut subsystem, &candidate_hashes[0], n_validators, chunk.clone(), 1, None).unwrap();
+	}
+	assert_eq!(db.writes(), writes + 4);
+
+	// A single write for the whole batch, a repeated chunk is only stored once.
+	let mut batch: Vec<_> = chunks[..4].iter()
+		.map(|chunk| (candidate_hashes[1], chunk.clone(), 1))
+		.collect();
+	batch.push((candidate_hashes[1], chunks[0].clone(), 1));
+
+	let writes = db.writes();
+	let results = store_chunks(&mut subsystem, &batch).unwrap();
+	assert_eq!(db.writes(), writes + 1);
+	assert!(results.iter().all(Result::is_ok));
+
+	for candidate_hash in &candidate_hashes {
+		assert_eq!(stored_chunk_indices(&store, candidate_hash), vec![0, 1, 2, 3]);
+		assert_eq!(chunk_pruning_records(&store, candidate_hash).len(), 4);
+	}
+	assert!(subsystem.next_chunk_pruning.is_some());
+}
+
+#[test]
+fn data_and_own_chunks_are_stored_in_a_single_write() {
+	let db = Arc::new(FailingDb::new());
+	let store: Arc<dyn KeyValueDB> = db.clone();
+	let test_state = TestState::default();
+	let n_validators = 10;
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data.clone(),
+	};
+	let candidate_hashes = [CandidateHash(Hash::repeat_byte(1)), CandidateHash(Hash::repeat_byte(2))];
+
+	// The data, its erasure root and all of its chunks.
+	let writes = db.writes();
+	store_available_data(
+		&mut subsystem,
+		&candidate_hashes[0],
+		Some(0),
+		n_validators,
+		data.clone(),
+		ErasureCodingVersion::V1,
+		None,
+	).unwrap();
+	assert_eq!(db.writes(), writes + 1);
+
+	assert!(available_data(&store, &candidate_hashes[0]).is_some());
+	assert!(erasure_root(&store, &candidate_hashes[0]).is_some());
+	assert_eq!(stored_chunk_indices(&store, &candidate_hashes[0]), (0..n_validators).collect::<Vec<_>>());
+	assert_eq!(chunk_pruning_records(&store, &candidate_hashes[0]).len(), n_validators as usize);
+	assert!(subsystem.next_chunk_pruning.is_some());
+
+	// Chunks regenerated from stored data are written at once as well.
+	store_available_data(
+		&mut subsystem,
+		&candidate_hashes[1],
+		None,
+		n_validators,
+		data,
+		ErasureCodingVersion::V1,
+		None,
+	).unwrap();
+
+	let writes = db.writes();
+	assert!(get_chunk(&mut subsystem, &candidate_hashes[1], 3).unwrap().is_some());
+	assert_eq!(db.writes(), writes + 1);
+	assert_eq!(stored_chunk_indices(&store, &candidate_hashes[1]), (0..n_validators).collect::<Vec<_>>());
+}
+
+#[test]
+fn iter_chunks_works() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let n_validators = 10;
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store,
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	let mut expected = Vec::new();
+
+	for i in 1..=2u8 {
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![i, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+		let candidate_hash = CandidateHash(Hash::repeat_byte(i));
+		let chunks = get_chunks(&data, n_validators as usize, &Metrics(None)).unwrap();
+
+		// The full data lives in the same column and must not show up as a chunk.
+		store_available_data(&mut subsystem, &candidate_hash, None, n_validators, data, ErasureCodingVersion::V1, None).unwrap();
+
+		for chunk in chunks.into_iter().take(3) {
+			store_chunk(&mut subsystem, &candidate_hash, n_validators, chunk.clone(), 1, None).unwrap();
+			expected.push((candidate_hash, chunk.index, chunk));
+		}
+	}
+
+	let mut chunks: Vec<_> = subsystem.iter_chunks().map(Result::unwrap).collect();
+
+	let order = |(candidate_hash, index, _): &(CandidateHash, u32, ErasureChunk)| (candidate_hash.0, *index);
+	chunks.sort_by_key(order);
+	expected.sort_by_key(order);
+
+	assert_eq!(chunks, expected);
+}
+
+#[test]
+fn migrate_to_copies_the_whole_store() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let n_validators = 10;
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	let candidate_hashes: Vec<_> = (1..=3u8).map(|i| CandidateHash(Hash::repeat_byte(i))).collect();
+	for (i, candidate_hash) in candidate_hashes.iter().enumerate() {
+		let mut validation_data = test_state.persisted_validation_data.clone();
+		validation_data.block_number = i as BlockNumber;
+
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![i as u8, 5, 6]) }),
+			validation_data,
+		};
+
+		store_available_data(&mut subsystem, candidate_hash, Some(0), n_validators, data, ErasureCodingVersion::V1, None).unwrap();
+	}
+
+	// Some of the pruning records are updated on finalization.
+	executor::block_on(process_block_finalized(&mut subsystem, 1)).unwrap();
+
+	let dest: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	subsystem.migrate_to(dest.clone()).unwrap();
+
+	for column in 0..columns::NUM_COLUMNS {
+		assert_eq!(
+			store.iter(column).collect::<Vec<_>>(),
+			dest.iter(column).collect::<Vec<_>>(),
+		);
+	}
+
+	let mut migrated = AvailabilityStoreSubsystem::new_with_db(
+		dest.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	).unwrap();
+
+	assert_eq!(migrated.next_pov_pruning.map(|n| n.0), subsystem.next_pov_pruning.map(|n| n.0));
+	assert_eq!(migrated.next_chunk_pruning.map(|n| n.0), subsystem.next_chunk_pruning.map(|n| n.0));
+	assert_eq!(pov_pruning(&dest), pov_pruning(&store));
+	assert_eq!(chunk_pruning(&dest), chunk_pruning(&store));
+
+	for candidate_hash in &candidate_hashes {
+		assert_eq!(
+			available_data(&dest, candidate_hash).map(|stored| stored.data),
+			available_data(&store, candidate_hash).map(|stored| stored.data),
+		);
+
+		for index in 0..n_validators {
+			assert_eq!(
+				get_chunk(&mut migrated, candidate_hash, index).unwrap(),
+				get_chunk(&mut subsystem, candidate_hash, index).unwrap(),
+			);
+		}
+	}
+}
+
+#[test]
+fn next_pruning_times_are_cached() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	assert!(subsystem.next_pov_pruning.is_none());
+	assert!(subsystem.next_chunk_pruning.is_none());
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+	store_available_data(&mut subsystem, &candidate_hash, Some(0), 10, data, ErasureCodingVersion::V1, None).unwrap();
+
+	let db_pov_pruning = get_next_pov_pruning_time(&store).unwrap().0;
+	let db_chunk_pruning = get_next_chunk_pruning_time(&store).unwrap().0;
+
+	assert_eq!(subsystem.next_pov_pruning.unwrap().0, db_pov_pruning);
+	assert_eq!(subsystem.next_chunk_pruning.unwrap().0, db_chunk_pruning);
+
+	// A subsystem started on top of the same database picks the values up.
+	let subsystem = AvailabilityStoreSubsystem::with_db(store, test_state.pruning_config, Metrics(None));
+
+	assert_eq!(subsystem.next_pov_pruning.unwrap().0, db_pov_pruning);
+	assert_eq!(subsystem.next_chunk_pruning.unwrap().0, db_chunk_pruning);
+}
+
+#[test]
+fn oldest_candidate_is_evicted_past_max_stored_candidates() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+	subsystem.max_stored_candidates = Some(2);
+
+	let candidates: Vec<_> = (1..=4).map(|i| CandidateHash(Hash::repeat_byte(i))).collect();
+	let data = |i: u8| AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![i, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data.clone(),
+	};
+
+	for (i, candidate_hash) in candidates[..3].iter().enumerate() {
+		store_available_data(&mut subsystem, candidate_hash, Some(0), 10, data(i as u8), ErasureCodingVersion::V1, None).unwrap();
+	}
+
+	// The third candidate evicted the first one along with its chunks.
+	assert!(available_data(&store, &candidates[0]).is_none());
+	assert!(query_inner::<ErasureChunk>(&store, columns::DATA, &erasure_chunk_key(&candidates[0], 0)).is_none());
+	assert!(stored_at(&store, &candidates[0]).is_none());
+	assert!(chunk_pruning_records(&store, &candidates[0]).is_empty());
+
+	for candidate_hash in &candidates[1..3] {
+		assert!(available_data(&store, candidate_hash).is_some());
+	}
+	assert_eq!(pov_pruning(&store).unwrap().len(), 2);
+
+	// Finalized candidates are never evicted.
+	let mut records = pov_pruning(&store).unwrap();
+	for record in records.iter_mut().filter(|r| r.candidate_hash == candidates[1]) {
+		record.candidate_state = CandidateState::Finalized;
+	}
+	put_pov_pruning(&mut subsystem, None, records).unwrap();
+
+	store_available_data(&mut subsystem, &candidates[3], Some(0), 10, data(3), ErasureCodingVersion::V1, None).unwrap();
+
+	assert!(available_data(&store, &candidates[1]).is_some());
+	assert!(available_data(&store, &candidates[2]).is_none());
+	assert!(available_data(&store, &candidates[3]).is_some());
+}
+
+#[test]
+fn reconcile_repairs_pruning_records() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let orphan = CandidateHash(Hash::repeat_byte(1));
+	let stale = CandidateHash(Hash::repeat_byte(2));
+
+	// Data and a chunk without pruning records, and pruning records without data.
+	let stored = StoredAvailableData {
+		data: AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		},
+		n_validators: 10,
+	};
+
+	let mut tx = DBTransaction::new();
+	tx.put_vec(columns::DATA, &available_data_key(&orphan), stored.encode());
+	tx.put_vec(
+		columns::DATA,
+		&erasure_chunk_key(&orphan, 3),
+		ErasureChunk { chunk: vec![1, 2, 3], index: 3, proof: vec![] }.encode(),
+	);
+	put_chunk_pruning_record(&mut tx, &ChunkPruningRecord {
+		candidate_hash: stale,
+		block_number: 1,
+		candidate_state: CandidateState::Stored,
+		chunk_index: 0,
+		prune_at: PruningDelay::In(Duration::from_secs(1)),
+	});
+	tx.put_vec(columns::META, &stored_at_key(&stale), Duration::from_secs(1).encode());
+	tx.put_vec(columns::META, &n_validators_key(&stale), 10u32.encode());
+	tx.put_vec(columns::META, &erasure_root_key(&stale), Hash::repeat_byte(3).encode());
+	store.write(tx).unwrap();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	put_pov_pruning(&mut subsystem, None, vec![PoVPruningRecord {
+		candidate_hash: stale,
+		block_number: 1,
+		candidate_state: CandidateState::Stored,
+		prune_at: PruningDelay::In(Duration::from_secs(1)),
+	}]).unwrap();
+
+	subsystem.reconcile().unwrap();
+
+	let records = pov_pruning(&store).unwrap();
+	assert_eq!(records.len(), 1);
+	assert_eq!(records[0].candidate_hash, orphan);
+	assert_eq!(records[0].block_number, test_state.persisted_validation_data.block_number);
+	assert_eq!(records[0].candidate_state, CandidateState::Stored);
+	assert_matches!(records[0].prune_at, PruningDelay::In(_));
+
+	let chunk_records = chunk_pruning(&store).unwrap();
+	assert_eq!(chunk_records.len(), 1);
+	assert_eq!((chunk_records[0].candidate_hash, chunk_records[0].chunk_index), (orphan, 3));
+	assert_matches!(chunk_records[0].prune_at, PruningDelay::In(_));
+
+	// Nothing of the stale candidate is left behind.
+	assert!(stored_at(&store, &stale).is_none());
+	assert!(n_validators(&store, &stale).is_none());
+	assert!(erasure_root(&store, &stale).is_none());
+
+	assert!(subsystem.next_pov_pruning.is_some());
+	assert!(subsystem.next_chunk_pruning.is_some());
+}
+
+#[test]
+fn records_with_equal_prune_times_are_all_pruned() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	let candidates: Vec<_> = (1..=4).map(|i| CandidateHash(Hash::repeat_byte(i))).collect();
+
+	for (i, candidate_hash) in candidates.iter().enumerate() {
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![i as u8, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+
+		store_available_data(&mut subsystem, candidate_hash, None, 10, data, ErasureCodingVersion::V1, None).unwrap();
+	}
+
+	// All but the last candidate share the same, already passed, pruning time.
+	let records = candidates.iter().rev().enumerate().map(|(i, candidate_hash)| PoVPruningRecord {
+		candidate_hash: *candidate_hash,
+		block_number: 1,
+		candidate_state: CandidateState::Stored,
+		prune_at: if i == 0 {
+			PruningDelay::Indefinite
+		} else {
+			PruningDelay::In(Duration::from_secs(1))
+		},
+	}).collect();
+
+	put_pov_pruning(&mut subsystem, None, records).unwrap();
+
+	subsystem.prune(true, false).unwrap();
+
+	for candidate_hash in &candidates[..3] {
+		assert!(available_data(&store, candidate_hash).is_none());
+	}
+	assert!(available_data(&store, &candidates[3]).is_some());
+
+	let records = pov_pruning(&store).unwrap();
+	assert_eq!(records.len(), 1);
+	assert_eq!(records[0].candidate_hash, candidates[3]);
+}
+
+#[test]
+fn on_prune_is_called_with_pruned_candidates() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let pruned = Arc::new(Mutex::new(Vec::new()));
+
+	let mut subsystem = {
+		let pruned = pruned.clone();
+		AvailabilityStoreSubsystem::with_db(
+			store.clone(),
+			PruningConfig::default(),
+			Metrics(None),
+		).with_on_prune(Arc::new(move |candidate_hash| pruned.lock().unwrap().push(*candidate_hash)))
+	};
+
+	let candidates: Vec<_> = (1..=2).map(|i| CandidateHash(Hash::repeat_byte(i))).collect();
+
+	for (i, candidate_hash) in candidates.iter().enumerate() {
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![i as u8, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+
+		store_available_data(&mut subsystem, candidate_hash, None, 10, data, ErasureCodingVersion::V1, None).unwrap();
+	}
+
+	// Only the data of the first candidate is due.
+	let records = candidates.iter().enumerate().map(|(i, candidate_hash)| PoVPruningRecord {
+		candidate_hash: *candidate_hash,
+		block_number: 1,
+		candidate_state: CandidateState::Stored,
+		prune_at: if i == 0 {
+			PruningDelay::In(Duration::from_secs(1))
+		} else {
+			PruningDelay::Indefinite
+		},
+	}).collect();
+
+	put_pov_pruning(&mut subsystem, None, records).unwrap();
+
+	subsystem.prune(true, false).unwrap();
+	assert_eq!(std::mem::take(&mut *pruned.lock().unwrap()), vec![candidates[0]]);
+
+	// Only a single chunk of the second candidate is due.
+	let record = chunk_pruning_record(&store, &candidates[1], 3).unwrap();
+	let mut tx = DBTransaction::new();
+	delete_chunk_pruning_record(&mut tx, &record);
+	put_chunk_pruning_record(&mut tx, &ChunkPruningRecord {
+		prune_at: PruningDelay::In(Duration::from_secs(1)),
+		..record
+	});
+	store.write(tx).unwrap();
+
+	subsystem.prune(false, true).unwrap();
+	assert_eq!(std::mem::take(&mut *pruned.lock().unwrap()), vec![candidates[1]]);
+}
+
+#[test]
+fn indefinite_records_sort_last() {
+	let low = CandidateHash(Hash::repeat_byte(1));
+	let high = CandidateHash(Hash::repeat_byte(2));
+
+	let pov_record = |candidate_hash, prune_at| PoVPruningRecord {
+		candidate_hash,
+		block_number: 1,
+		candidate_state: CandidateState::Stored,
+		prune_at,
+	};
+
+	// Indefinite records share or precede the hashes of the timed ones.
+	let mut pov_records = vec![
+		pov_record(low, PruningDelay::Indefinite),
+		pov_record(high, PruningDelay::In(Duration::from_secs(5))),
+		pov_record(low, PruningDelay::In(Duration::from_secs(3))),
+		pov_record(high, PruningDelay::Indefinite),
+		pov_record(high, PruningDelay::In(Duration::from_secs(3))),
+	];
+	pov_records.sort();
+
+	assert!(pov_records.windows(2).all(|w| w[0].prune_at <= w[1].prune_at));
+	assert_eq!(
+		pov_records.iter().map(|r| (r.candidate_hash, &r.prune_at)).collect::<Vec<_>>(),
+		vec![
+			(low, &PruningDelay::In(Duration::from_secs(3))),
+			(high, &PruningDelay::In(Duration::from_secs(3))),
+			(high, &PruningDelay::In(Duration::from_secs(5))),
+			(low, &PruningDelay::Indefinite),
+			(high, &PruningDelay::Indefinite),
+		],
+	);
+
+	let chunk_record = |candidate_hash, chunk_index, prune_at| ChunkPruningRecord {
+		candidate_hash,
+		block_number: 1,
+		candidate_state: CandidateState::Stored,
+		chunk_index,
+		prune_at,
+	};
+
+	let mut chunk_records = vec![
+		chunk_record(low, 0, PruningDelay::Indefinite),
+		chunk_record(low, 1, PruningDelay::In(Duration::from_secs(5))),
+		chunk_record(high, 0, PruningDelay::In(Duration::from_secs(3))),
+		chunk_record(low, 2, PruningDelay::Indefinite),
+		chunk_record(low, 0, PruningDelay::In(Duration::from_secs(3))),
+	];
+	chunk_records.sort();
+
+	assert!(chunk_records.windows(2).all(|w| w[0].prune_at <= w[1].prune_at));
+	assert_eq!(
+		chunk_records.iter()
+			.map(|r| (r.candidate_hash, r.chunk_index, &r.prune_at))
+			.collect::<Vec<_>>(),
+		vec![
+			(low, 0, &PruningDelay::In(Duration::from_secs(3))),
+			(high, 0, &PruningDelay::In(Duration::from_secs(3))),
+			(low, 1, &PruningDelay::In(Duration::from_secs(5))),
+			(low, 0, &PruningDelay::Indefinite),
+			(low, 2, &PruningDelay::Indefinite),
+		],
+	);
+}
+
+#[test]
+fn coinciding_prunes_are_written_in_one_transaction() {
+	let db = Arc::new(FailingDb::new());
+	let test_state = TestState::default();
+	let pruning_config = PruningConfig {
+		keep_stored_block_for: Duration::from_secs(0),
+		..test_state.pruning_config.clone()
+	};
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		db.clone(),
+		pruning_config,
+		Metrics(None),
+	);
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+	store_available_data(&mut subsystem, &candidate_hash, Some(0), 10, data, ErasureCodingVersion::V1, None).unwrap();
+
+	// Both prunes are due within the coalescing window, the later one is waited for.
+	let next_pov_pruning = subsystem.next_pov_pruning.unwrap().0;
+	let next_chunk_pruning = subsystem.next_chunk_pruning.unwrap().0;
+	assert_eq!(subsystem.next_prune_at(), Some(next_pov_pruning.max(next_chunk_pruning)));
+
+	let writes = db.writes();
+	subsystem.prune_due().unwrap();
+	assert_eq!(db.writes(), writes + 1);
+
+	let db: Arc<dyn KeyValueDB> = db;
+	assert!(pov_pruning(&db).unwrap_or_default().is_empty());
+	assert!(chunk_pruning(&db).unwrap_or_default().is_empty());
+	assert!(subsystem.next_pov_pruning.is_none());
+	assert!(subsystem.next_chunk_pruning.is_none());
+}
+
+#[test]
+fn overdue_records_are_pruned_on_startup() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let pruning_config = PruningConfig {
+		keep_stored_block_for: Duration::from_secs(0),
+		..test_state.pruning_config.clone()
+	};
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		pruning_config.clone(),
+		Metrics(None),
+	);
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+	store_available_data(&mut subsystem, &candidate_hash, Some(0), 10, data, ErasureCodingVersion::V1, None).unwrap();
+	drop(subsystem);
+
+	assert_eq!(pov_pruning(&store).unwrap().len(), 1);
+	assert_eq!(chunk_pruning(&store).unwrap().len(), 10);
+
+	test_harness(pruning_config, store.clone(), |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		assert!(query_available_data(&mut virtual_overseer, candidate_hash).await.is_none());
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, 0).await.is_none());
+
+		assert!(pov_pruning(&store).unwrap_or_default().is_empty());
+		assert!(chunk_pruning(&store).unwrap_or_default().is_empty());
+	});
+}
+
+#[test]
+fn failed_write_keeps_pruning_state_consistent() {
+	let db = Arc::new(FailingDb::new());
+	let test_state = TestState::default();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		db.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	let store = |subsystem: &mut AvailabilityStoreSubsystem, i: u8| {
+		let data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![i, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+
+		store_available_data(subsystem, &CandidateHash(Hash::repeat_byte(i)), Some(0), 10, data, ErasureCodingVersion::V1, None)
+	};
+
+	store(&mut subsystem, 1).unwrap();
+	let next_pov_pruning = subsystem.next_pov_pruning.unwrap().0;
+	let next_chunk_pruning = subsystem.next_chunk_pruning.unwrap().0;
+
+	db.set_fail_writes(true);
+	assert!(store(&mut subsystem, 2).is_err());
+
+	// Nothing cached was advanced by the failed write.
+	assert_eq!(subsystem.next_pov_pruning.unwrap().0, next_pov_pruning);
+	assert_eq!(subsystem.next_chunk_pruning.unwrap().0, next_chunk_pruning);
+
+	db.set_fail_writes(false);
+	store(&mut subsystem, 3).unwrap();
+
+	let db: Arc<dyn KeyValueDB> = db;
+	let records = pov_pruning(&db).unwrap();
+	let hashes: Vec<_> = records.iter().map(|r| r.candidate_hash).collect();
+
+	assert_eq!(hashes, vec![CandidateHash(Hash::repeat_byte(1)), CandidateHash(Hash::repeat_byte(3))]);
+	assert!(records.windows(2).all(|w| w[0].prune_at <= w[1].prune_at));
+	assert!(available_data(&db, &CandidateHash(Hash::repeat_byte(2))).is_none());
+	assert_eq!(
+		get_next_pov_pruning_time(&db).unwrap().0,
+		subsystem.next_pov_pruning.unwrap().0,
+	);
+	assert_eq!(subsystem.next_pov_pruning.unwrap().0, next_pov_pruning);
+}
+
+#[test]
+fn per_column_cache_budgets_are_applied() {
+	let config = |cache_size, data_cache_size, meta_cache_size| Config {
+		cache_size,
+		data_cache_size,
+		meta_cache_size,
+		path: PathBuf::new(),
+		compaction_threshold: None,
+		pruning: PruningConfig::default(),
+		max_stored_candidates: None,
+		read_only: false,
+		integrity: IntegrityConfig::default(),
+		max_pending_stores: None,
+		store_batch_size: None,
+		shed_queue_depth: None,
+		auto_reconstruct: false,
+	};
+
+	let db_config = database_config(&config(None, None, None));
+	assert!(db_config.memory_budget.is_empty());
+
+	let db_config = database_config(&config(Some(100), None, None));
+	assert_eq!(db_config.memory_budget.get(&columns::DATA), Some(&50));
+	assert_eq!(db_config.memory_budget.get(&columns::META), Some(&50));
+
+	let db_config = database_config(&config(None, Some(90), Some(10)));
+	assert_eq!(db_config.memory_budget.get(&columns::DATA), Some(&90));
+	assert_eq!(db_config.memory_budget.get(&columns::META), Some(&10));
+
+	let db_config = database_config(&config(Some(100), Some(80), None));
+	assert_eq!(db_config.memory_budget.get(&columns::DATA), Some(&80));
+	assert_eq!(db_config.memory_budget.get(&columns::META), Some(&50));
+}
+
+#[test]
+fn config_path_honors_subdir() {
+	use std::convert::TryFrom;
+
+	let base = PathBuf::from("/tmp/polkadot/chains/westend/db");
+
+	let config = Config::with_subdir(base.clone(), "westend/av-store");
+	assert_eq!(config.path, base.join("westend").join("av-store"));
+
+	let database = sc_service::config::DatabaseConfig::RocksDb {
+		path: base.clone(),
+		cache_size: 128,
+	};
+	let config = Config::try_from(database).unwrap();
+	assert_eq!(config.path, base.join("parachains").join("av-store"));
+	assert!(config.cache_size.is_none());
+}
+
+#[test]
+fn io_stats_are_exported_for_on_disk_stores() {
+	let dir = tempfile::tempdir().unwrap();
+	let config = Config::with_subdir(dir.path().to_owned(), "av-store");
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+	let metrics = <Metrics as metrics::Metrics>::try_register(&prometheus::Registry::new()).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::new_on_disk(config, metrics).unwrap();
+
+	// The first update is due right away.
+	assert_eq!(subsystem.next_io_stats_update, Some(Duration::default()));
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data.clone(),
+	};
+
+	store_available_data(&mut subsystem, &candidate_hash, Some(0), 10, data, ErasureCodingVersion::V1, None).unwrap();
+	assert!(available_data(&subsystem.inner, &candidate_hash).is_some());
+
+	let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+	subsystem.update_io_stats().unwrap();
+
+	assert!(subsystem.next_io_stats_update.unwrap() >= before + IO_STATS_INTERVAL);
+
+	let metrics = subsystem.metrics.0.as_ref().unwrap();
+	assert!(metrics.bytes_written.get() > 0);
+	assert!((0.0..=1.0).contains(&metrics.cache_hit_ratio.get()));
+
+	// Backends other than rocksdb don't collect any statistics.
+	let subsystem = AvailabilityStoreSubsystem::new_in_memory(None, Metrics(None));
+	assert!(subsystem.next_io_stats_update.is_none());
+}
+
+#[test]
+fn opening_database_with_wrong_column_count_fails() {
+	let dir = tempfile::tempdir().unwrap();
+	let config = Config::with_subdir(dir.path().to_owned(), "av-store");
+
+	std::fs::create_dir_all(&config.path).unwrap();
+	drop(Database::open(
+		&DatabaseConfig::with_columns(columns::NUM_COLUMNS + 1),
+		config.path.to_str().unwrap(),
+	).unwrap());
+
+	let err = AvailabilityStoreSubsystem::new_on_disk(config, Metrics(None)).err().unwrap();
+
+	assert_matches!(
+		err,
+		Error::ColumnCountMismatch { expected, found } => {
+			assert_eq!(expected, columns::NUM_COLUMNS);
+			assert_eq!(found, columns::NUM_COLUMNS + 1);
+		}
+	);
+	assert_eq!(err.to_string(), "Database has 3 columns but 2 are expected");
+}
+
+#[test]
+fn prune_resets_time_since_last_prune() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let metrics = <Metrics as metrics::Metrics>::try_register(&prometheus::Registry::new()).unwrap();
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store,
+		test_state.pruning_config.clone(),
+		metrics,
+	);
+
+	subsystem.last_prune = Instant::now() - Duration::from_secs(100);
+	subsystem.update_prune_age();
+
+	let age = || subsystem.metrics.0.as_ref().unwrap().seconds_since_last_prune.get();
+	assert!(age() >= 100.0);
+
+	subsystem.prune(true, true).unwrap();
+
+	let age = subsystem.metrics.0.as_ref().unwrap().seconds_since_last_prune.get();
+	assert!(age < 1.0);
+	assert!(subsystem.next_prune_age_update > Instant::now());
+}
+
+#[test]
+fn data_written_before_conclude_survives_reopening() {
+	let dir = tempfile::tempdir().unwrap();
+	let config = || Config {
+		cache_size: None,
+		data_cache_size: None,
+		meta_cache_size: None,
+		path: dir.path().to_owned(),
+		compaction_threshold: None,
+		pruning: PruningConfig::default(),
+		max_stored_candidates: None,
+		read_only: false,
+		integrity: IntegrityConfig::default(),
+		max_pending_stores: None,
+		store_batch_size: None,
+		shed_queue_depth: None,
+		auto_reconstruct: false,
+	};
+
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+	let expected = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+
+	let subsystem = AvailabilityStoreSubsystem::new_on_disk(config(), Metrics(None)).unwrap();
+	let data = expected.clone();
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			10,
+			data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		assert_eq!(rx.await.unwrap(), Ok(()));
+
+		overseer_signal(&mut virtual_overseer, OverseerSignal::Conclude).await;
+
+		// Let the subsystem exit on its own.
+		future::pending::<()>().await;
+	});
+
+	let subsystem = AvailabilityStoreSubsystem::new_on_disk(config(), Metrics(None)).unwrap();
+
+	assert_eq!(available_data(&subsystem.inner, &candidate_hash).unwrap().data, expected);
+}
+
+#[test]
+fn finalized_data_survives_reopening_without_conclude() {
+	let dir = tempfile::tempdir().unwrap();
+	let config = || Config::with_subdir(dir.path().to_owned(), "");
+
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+	let expected = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+
+	let subsystem = AvailabilityStoreSubsystem::new_on_disk(config(), Metrics(None)).unwrap();
+	let data = expected.clone();
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		let (tx, rx) = oneshot::channel();
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			10,
+			data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		);
+
+		virtual_overseer.send(FromOverseer::Communication{ msg: block_msg }).await;
+		assert_eq!(rx.await.unwrap(), Ok(()));
+
+		overseer_signal(
+			&mut virtual_overseer,
+			OverseerSignal::BlockFinalized(Hash::repeat_byte(2), 10),
+		).await;
 
-		let candidate_1_hash = candidate_1.hash();
+		assert_eq!(
+			query_candidate_state(&mut virtual_overseer, candidate_hash).await,
+			Some(CandidateState::Finalized),
+		);
 
-		let candidate_2 = TestCandidateBuilder {
-			pov_hash: pov_2_hash,
-			..Default::default()
-		}.build();
+		// The subsystem is dropped without receiving `Conclude`, as on a crash.
+	});
 
-		let candidate_2_hash = candidate_2.hash();
+	let subsystem = AvailabilityStoreSubsystem::new_on_disk(config(), Metrics(None)).unwrap();
 
-		let available_data_1 = AvailableData {
-			pov: Arc::new(pov_1),
-			validation_data: test_state.persisted_validation_data.clone(),
-		};
+	assert_eq!(available_data(&subsystem.inner, &candidate_hash).unwrap().data, expected);
+	assert!(pov_pruning(&subsystem.inner).unwrap().iter().all(|r| r.candidate_state == CandidateState::Finalized));
+}
 
-		let available_data_2 = AvailableData {
-			pov: Arc::new(pov_2),
-			validation_data: test_state.persisted_validation_data,
-		};
+#[test]
+fn read_only_store_rejects_stores_and_serves_queries() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+	let n_validators = 10;
+
+	let available_data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data.clone(),
+	};
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+	store_available_data(&mut subsystem, &candidate_hash, None, n_validators, available_data.clone(), ErasureCodingVersion::V1, None)
+		.unwrap();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config,
+		Metrics(None),
+	);
+	subsystem.read_only = true;
+
+	let contents_before: Vec<_> = store.iter(columns::DATA).chain(store.iter(columns::META)).collect();
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
 
 		let (tx, rx) = oneshot::channel();
-		let msg = AvailabilityStoreMessage::StoreAvailableData(
-			candidate_1_hash,
-			None,
-			n_validators,
-			available_data_1.clone(),
+		let chunk_msg = AvailabilityStoreMessage::StoreChunk {
+			candidate_hash,
+			relay_parent: Hash::repeat_byte(2),
+			block_number: Some(5),
+			validator_index: 5,
+			chunk: ErasureChunk {
+				chunk: vec![1, 2, 3],
+				index: 5,
+				proof: vec![vec![3, 4, 5]],
+			},
 			tx,
-		);
-
-		virtual_overseer.send(FromOverseer::Communication{ msg }).await;
+		};
 
-		rx.await.unwrap().unwrap();
+		overseer_send(&mut virtual_overseer, chunk_msg.into()).await;
+		assert_eq!(rx.await.unwrap(), Err(StoreError::ReadOnly));
 
+		// Stores are rejected before their data is erasure-coded.
 		let (tx, rx) = oneshot::channel();
-		let msg = AvailabilityStoreMessage::StoreAvailableData(
-			candidate_2_hash,
-			None,
+		let block_msg = AvailabilityStoreMessage::StoreAvailableData(
+			CandidateHash(Hash::repeat_byte(2)),
+			Some(0),
 			n_validators,
-			available_data_2.clone(),
+			available_data.clone(),
+			None,
+			ErasureCodingVersion::V1,
 			tx,
 		);
 
-		virtual_overseer.send(FromOverseer::Communication{ msg }).await;
-
-		rx.await.unwrap().unwrap();
+		overseer_send(&mut virtual_overseer, block_msg.into()).await;
+		assert_eq!(rx.await.unwrap(), Err(StoreError::ReadOnly));
 
 		assert_eq!(
-			query_available_data(&mut virtual_overseer, candidate_1_hash).await.unwrap(),
-			available_data_1,
+			query_available_data(&mut virtual_overseer, candidate_hash).await.unwrap(),
+			available_data,
 		);
 
-		assert_eq!(
-			query_available_data(&mut virtual_overseer, candidate_2_hash).await.unwrap(),
-			available_data_2,
+		// Chunks are still regenerated from the full data, but not stored.
+		assert!(query_chunk(&mut virtual_overseer, candidate_hash, 3).await.is_some());
+	});
+
+	let contents_after: Vec<_> = store.iter(columns::DATA).chain(store.iter(columns::META)).collect();
+	assert_eq!(contents_before, contents_after);
+}
+
+#[test]
+fn legacy_database_is_stamped_with_schema_version() {
+	let db: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+
+	assert!(query_inner::<u32>(&db, columns::META, &SCHEMA_VERSION_KEY).is_none());
+
+	ensure_schema_version(&db).unwrap();
+
+	assert_eq!(
+		query_inner::<u32>(&db, columns::META, &SCHEMA_VERSION_KEY),
+		Some(CURRENT_SCHEMA_VERSION),
+	);
+
+	// Checking again leaves the version untouched.
+	ensure_schema_version(&db).unwrap();
+
+	assert_eq!(
+		query_inner::<u32>(&db, columns::META, &SCHEMA_VERSION_KEY),
+		Some(CURRENT_SCHEMA_VERSION),
+	);
+}
+
+fn chunk_pruning_records_for_test() -> Vec<ChunkPruningRecord> {
+	let prune_at = |secs| PruningDelay::In(Duration::from_secs(secs));
+
+	vec![
+		(3, 0, prune_at(20)),
+		(1, 2, prune_at(10)),
+		(2, 1, prune_at(10)),
+		(1, 1, prune_at(10)),
+		(4, 0, PruningDelay::Indefinite),
+		(2, 0, prune_at(5)),
+		(5, 300, prune_at(30)),
+	].into_iter().map(|(i, chunk_index, prune_at)| ChunkPruningRecord {
+		candidate_hash: CandidateHash(Hash::repeat_byte(i)),
+		block_number: 1,
+		candidate_state: CandidateState::Stored,
+		chunk_index,
+		prune_at,
+	}).collect()
+}
+
+#[test]
+fn chunk_pruning_index_matches_sorted_records() {
+	let db: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let records = chunk_pruning_records_for_test();
+
+	let mut tx = DBTransaction::new();
+	for record in &records {
+		put_chunk_pruning_record(&mut tx, record);
+	}
+	db.write(tx).unwrap();
+
+	// The layout before schema version 3 kept a sorted vector of all records.
+	let mut sorted = chunk_pruning_records_for_test();
+	sorted.sort();
+
+	assert_eq!(chunk_pruning(&db).unwrap(), sorted);
+	assert_eq!(get_next_chunk_pruning_time(&db).unwrap().0, Duration::from_secs(5));
+
+	for secs in &[0, 5, 10, 15, 30, 60] {
+		let now = Duration::from_secs(*secs);
+		let expected: Vec<_> = sorted.iter()
+			.take_while(|r| r.prune_at <= PruningDelay::In(now))
+			.map(|r| (r.candidate_hash, r.chunk_index))
+			.collect();
+		let outdated: Vec<_> = outdated_chunk_pruning_records(&db, now, usize::MAX)
+			.into_iter()
+			.map(|r| (r.candidate_hash, r.chunk_index))
+			.collect();
+
+		assert_eq!(outdated, expected);
+	}
+
+	assert_eq!(chunk_pruning_records(&db, &CandidateHash(Hash::repeat_byte(1))).len(), 2);
+
+	let mut tx = DBTransaction::new();
+	for record in &records {
+		delete_chunk_pruning_record(&mut tx, record);
+	}
+	db.write(tx).unwrap();
+
+	assert!(chunk_pruning(&db).is_none());
+	assert!(get_next_chunk_pruning_time(&db).is_none());
+}
+
+#[test]
+fn legacy_chunk_pruning_vector_is_migrated() {
+	let db: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+
+	let mut legacy = chunk_pruning_records_for_test();
+	legacy.sort();
+
+	let mut tx = DBTransaction::new();
+	tx.put_vec(columns::META, &SCHEMA_VERSION_KEY, 2u32.encode());
+	tx.put_vec(columns::META, &CHUNK_PRUNING_KEY, legacy.encode());
+	tx.put_vec(columns::META, &NEXT_CHUNK_PRUNING, NextChunkPruning(Duration::from_secs(5)).encode());
+	db.write(tx).unwrap();
+
+	ensure_schema_version(&db).unwrap();
+
+	assert_eq!(chunk_pruning(&db).unwrap(), legacy);
+	assert_eq!(get_next_chunk_pruning_time(&db).unwrap().0, Duration::from_secs(5));
+	assert!(db.get(columns::META, &CHUNK_PRUNING_KEY).unwrap().is_none());
+	assert!(db.get(columns::META, &NEXT_CHUNK_PRUNING).unwrap().is_none());
+}
+
+#[test]
+fn records_kept_indefinitely_are_told_apart_from_no_records() {
+	let db: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+
+	assert!(next_pov_pruning_delay(&db).is_none());
+	assert!(next_chunk_pruning_delay(&db).is_none());
+
+	let mut tx = DBTransaction::new();
+	let next_pruning = stage_pov_pruning_presorted(&db, POV_PRUNING_SPILL_THRESHOLD, &mut tx, vec![
+		PoVPruningRecord {
+			candidate_hash,
+			block_number: 1,
+			candidate_state: CandidateState::Included,
+			prune_at: PruningDelay::Indefinite,
+		},
+	]);
+	put_chunk_pruning_record(&mut tx, &ChunkPruningRecord {
+		candidate_hash,
+		block_number: 1,
+		candidate_state: CandidateState::Included,
+		chunk_index: 0,
+		prune_at: PruningDelay::Indefinite,
+	});
+	db.write(tx).unwrap();
+
+	// Nothing has to be pruned either way.
+	assert!(next_pruning.is_none());
+	assert!(get_next_pov_pruning_time(&db).is_none());
+	assert!(get_next_chunk_pruning_time(&db).is_none());
+
+	assert_eq!(next_pov_pruning_delay(&db), Some(PruningDelay::Indefinite));
+	assert_eq!(next_chunk_pruning_delay(&db), Some(PruningDelay::Indefinite));
+
+	let mut tx = DBTransaction::new();
+	stage_pov_pruning_presorted(&db, POV_PRUNING_SPILL_THRESHOLD, &mut tx, Vec::new());
+	db.write(tx).unwrap();
+
+	assert!(next_pov_pruning_delay(&db).is_none());
+}
+
+#[test]
+fn next_pov_pruning_time_is_migrated_to_pruning_delay() {
+	let db: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let prune_at = Duration::from_secs(10);
+
+	let records = vec![
+		PoVPruningRecord {
+			candidate_hash: CandidateHash(Hash::repeat_byte(1)),
+			block_number: 1,
+			candidate_state: CandidateState::Stored,
+			prune_at: PruningDelay::In(prune_at),
+		},
+	];
+
+	// Before schema version 6 only the time of the next pruning was stored.
+	let mut tx = DBTransaction::new();
+	tx.put_vec(columns::META, &SCHEMA_VERSION_KEY, 5u32.encode());
+	tx.put_vec(columns::META, &POV_PRUNING_KEY, records.encode());
+	tx.put_vec(columns::META, &NEXT_POV_PRUNING, NextPoVPruning(prune_at).encode());
+	db.write(tx).unwrap();
+
+	ensure_schema_version(&db).unwrap();
+
+	assert_eq!(next_pov_pruning_delay(&db), Some(PruningDelay::In(prune_at)));
+	assert_eq!(get_next_pov_pruning_time(&db).unwrap().0, prune_at);
+}
+
+#[test]
+fn newer_schema_version_is_rejected() {
+	let db: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+
+	let mut tx = DBTransaction::new();
+	tx.put_vec(columns::META, &SCHEMA_VERSION_KEY, (CURRENT_SCHEMA_VERSION + 1).encode());
+	db.write(tx).unwrap();
+
+	assert_matches!(
+		ensure_schema_version(&db),
+		Err(Error::UnsupportedSchemaVersion { found, supported }) => {
+			assert_eq!(found, CURRENT_SCHEMA_VERSION + 1);
+			assert_eq!(supported, CURRENT_SCHEMA_VERSION);
+		}
+	);
+}
+
+#[test]
+fn pov_pruning_records_spill_into_buckets_in_order() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+	subsystem.pruning_config.pov_pruning_spill_threshold = 4;
+
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+	let day = Duration::from_secs(24 * 60 * 60);
+	let prune_times = vec![
+		PruningDelay::In(now + day),
+		PruningDelay::In(Duration::from_secs(2000)),
+		PruningDelay::Indefinite,
+		PruningDelay::In(Duration::from_secs(1)),
+		PruningDelay::In(now + 2 * day),
+		PruningDelay::In(Duration::from_secs(700)),
+	];
+	let records: Vec<_> = prune_times.into_iter().enumerate().map(|(i, prune_at)| PoVPruningRecord {
+		candidate_hash: CandidateHash(Hash::repeat_byte(i as u8)),
+		block_number: 1,
+		candidate_state: CandidateState::Stored,
+		prune_at,
+	}).collect();
+
+	let candidate_order = |records: Vec<PoVPruningRecord>| records.into_iter()
+		.map(|r| r.candidate_hash)
+		.collect::<Vec<_>>();
+	let candidates = |indices: &[u8]| indices.iter()
+		.map(|i| CandidateHash(Hash::repeat_byte(*i)))
+		.collect::<Vec<_>>();
+
+	put_pov_pruning(&mut subsystem, None, records).unwrap();
+
+	// Past the threshold the records moved out of the single value into buckets.
+	assert!(store.get(columns::META, &POV_PRUNING_KEY).unwrap().is_none());
+	assert_eq!(store.iter_with_prefix(columns::META, &POV_PRUNING_BUCKET_PREFIX).count(), 6);
+	assert_eq!(candidate_order(pov_pruning(&store).unwrap()), candidates(&[3, 5, 1, 0, 4, 2]));
+	assert_eq!(subsystem.next_pov_pruning.unwrap().0, Duration::from_secs(1));
+
+	// Outdated records are pruned from the head and the rest falls back to a single value.
+	subsystem.prune(true, false).unwrap();
+
+	assert!(store.get(columns::META, &POV_PRUNING_KEY).unwrap().is_some());
+	assert_eq!(store.iter_with_prefix(columns::META, &POV_PRUNING_BUCKET_PREFIX).count(), 0);
+	assert_eq!(candidate_order(pov_pruning(&store).unwrap()), candidates(&[0, 4, 2]));
+	assert_eq!(subsystem.next_pov_pruning.unwrap().0, now + day);
+}
+
+#[test]
+fn outdated_records_are_pruned_in_batches() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+	subsystem.pruning_config.max_prune_batch = Some(100);
+
+	let candidate = |i: u32| {
+		let mut hash = Hash::zero();
+		hash.as_mut()[..4].copy_from_slice(&i.to_be_bytes());
+		CandidateHash(hash)
+	};
+
+	let pov_records = (0..1000u32).map(|i| PoVPruningRecord {
+		candidate_hash: candidate(i),
+		block_number: 1,
+		candidate_state: CandidateState::Stored,
+		prune_at: PruningDelay::In(Duration::from_secs(1 + i as u64)),
+	}).collect();
+	put_pov_pruning(&mut subsystem, None, pov_records).unwrap();
+
+	let mut tx = DBTransaction::new();
+	for i in 0..1000u32 {
+		put_chunk_pruning_record(&mut tx, &ChunkPruningRecord {
+			candidate_hash: candidate(i / 10),
+			block_number: 1,
+			candidate_state: CandidateState::Stored,
+			chunk_index: i % 10,
+			prune_at: PruningDelay::In(Duration::from_secs(1 + i as u64)),
+		});
+	}
+	store.write(tx).unwrap();
+	subsystem.next_chunk_pruning = get_next_chunk_pruning_time(&store);
+
+	subsystem.prune_due().unwrap();
+
+	// A single pass only removes a batch of each kind of record.
+	assert_eq!(pov_pruning(&store).unwrap().len(), 900);
+	assert_eq!(chunk_pruning(&store).unwrap_or_default().len(), 900);
+
+	// The follow-up passes are due right away.
+	let mut passes = 1;
+	while subsystem.next_prune_at().is_some() {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+		assert!(subsystem.next_prune_at().unwrap() <= now);
+
+		subsystem.prune_due().unwrap();
+		passes += 1;
+		assert!(passes <= 10);
+	}
+
+	assert_eq!(passes, 10);
+	assert!(pov_pruning(&store).unwrap_or_default().is_empty());
+	assert!(chunk_pruning(&store).unwrap_or_default().is_empty());
+}
+
+#[test]
+fn next_prune_times_match_the_pruning_records() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	assert_eq!(next_prune_times(&store).unwrap(), (None, None));
+
+	let data = AvailableData {
+		pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+		validation_data: test_state.persisted_validation_data,
+	};
+	store_available_data(&mut subsystem, &CandidateHash(Hash::repeat_byte(1)), Some(0), 10, data, ErasureCodingVersion::V1, None).unwrap();
+
+	let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+	let (next_pov_pruning, next_chunk_pruning) = next_prune_times(&store).unwrap();
+	let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+	let pov_prune_at = match pov_pruning(&store).unwrap()[0].prune_at {
+		PruningDelay::In(prune_at) => prune_at,
+		PruningDelay::Indefinite => panic!("stored data is pruned eventually"),
+	};
+	let chunk_prune_at = match chunk_pruning(&store).unwrap()[0].prune_at {
+		PruningDelay::In(prune_at) => prune_at,
+		PruningDelay::Indefinite => panic!("stored chunks are pruned eventually"),
+	};
+
+	for (next, prune_at) in vec![(next_pov_pruning, pov_prune_at), (next_chunk_pruning, chunk_prune_at)] {
+		let next = next.unwrap();
+		assert!(prune_at - after <= next);
+		assert!(next <= prune_at - before);
+	}
+}
+
+#[test]
+fn dry_run_prune_reports_what_prune_removes() {
+	let store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+
+	let mut subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+	let prune_at = |i: u8| match i % 3 {
+		0 => PruningDelay::In(Duration::from_secs(i as u64)),
+		1 => PruningDelay::In(now + Duration::from_secs(3600)),
+		_ => PruningDelay::Indefinite,
+	};
+
+	let pov_records = (0..9).map(|i| PoVPruningRecord {
+		candidate_hash: CandidateHash(Hash::repeat_byte(i)),
+		block_number: 1,
+		candidate_state: CandidateState::Stored,
+		prune_at: prune_at(i),
+	}).collect();
+	put_pov_pruning(&mut subsystem, None, pov_records).unwrap();
+
+	let mut tx = DBTransaction::new();
+	for i in 0..9 {
+		put_chunk_pruning_record(&mut tx, &ChunkPruningRecord {
+			candidate_hash: CandidateHash(Hash::repeat_byte(i)),
+			block_number: 1,
+			candidate_state: CandidateState::Stored,
+			chunk_index: i as u32,
+			prune_at: prune_at(i),
+		});
+	}
+	store.write(tx).unwrap();
+
+	let (povs, chunks) = subsystem.dry_run_prune().unwrap();
+
+	// Nothing is removed by the dry run.
+	assert_eq!(pov_pruning(&store).unwrap().len(), 9);
+	assert_eq!(chunk_pruning(&store).unwrap().len(), 9);
+
+	let pov_hashes = |store: &Arc<dyn KeyValueDB>| pov_pruning(store)
+		.unwrap_or_default()
+		.into_iter()
+		.map(|r| r.candidate_hash)
+		.collect::<HashSet<_>>();
+	let chunk_keys = |store: &Arc<dyn KeyValueDB>| chunk_pruning(store)
+		.unwrap_or_default()
+		.into_iter()
+		.map(|r| (r.candidate_hash, r.chunk_index))
+		.collect::<HashSet<_>>();
+
+	let povs_before = pov_hashes(&store);
+	let chunks_before = chunk_keys(&store);
+
+	subsystem.prune(true, true).unwrap();
+
+	let removed_povs: HashSet<_> = povs_before.difference(&pov_hashes(&store)).cloned().collect();
+	let removed_chunks: HashSet<_> = chunks_before.difference(&chunk_keys(&store)).cloned().collect();
+
+	assert_eq!(povs.len(), 3);
+	assert_eq!(chunks.len(), 3);
+	assert_eq!(povs.into_iter().collect::<HashSet<_>>(), removed_povs);
+	assert_eq!(chunks.into_iter().collect::<HashSet<_>>(), removed_chunks);
+}
+
+#[test]
+fn changed_pov_pruning_is_written_like_a_full_rewrite() {
+	let test_state = TestState::default();
+
+	let records = || (0..40u8).map(|i| PoVPruningRecord {
+		candidate_hash: CandidateHash(Hash::repeat_byte(i)),
+		block_number: 1,
+		candidate_state: CandidateState::Stored,
+		prune_at: PruningDelay::In(Duration::from_secs(700 * i as u64)),
+	}).collect::<Vec<_>>();
+
+	let stores: Vec<Arc<dyn KeyValueDB>> = (0..2)
+		.map(|_| Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS)) as Arc<dyn KeyValueDB>)
+		.collect();
+
+	for store in &stores {
+		let mut subsystem = AvailabilityStoreSubsystem::with_db(
+			store.clone(),
+			test_state.pruning_config.clone(),
+			Metrics(None),
 		);
+		subsystem.pruning_config.pov_pruning_spill_threshold = 4;
 
+		put_pov_pruning(&mut subsystem, None, records()).unwrap();
+	}
 
-		let new_leaf_1 = Hash::repeat_byte(2);
-		let new_leaf_2 = Hash::repeat_byte(3);
+	// One record moves to the bucket of another one, one to a bucket of its own and one is
+	// kept indefinitely, which also empties the bucket of the first record.
+	let mut changed_buckets = HashSet::new();
+	let changed = |changed_buckets: &mut HashSet<Vec<u8>>| {
+		let mut records = records();
+		for (i, prune_at) in vec![
+			(0, PruningDelay::In(Duration::from_secs(700 * 39 + 1))),
+			(5, PruningDelay::In(Duration::from_secs(100_000))),
+			(20, PruningDelay::Indefinite),
+		] {
+			changed_buckets.insert(pov_pruning_bucket_key(&records[i].prune_at));
+			changed_buckets.insert(pov_pruning_bucket_key(&prune_at));
+			records[i].prune_at = prune_at;
+			records[i].candidate_state = CandidateState::Finalized;
+		}
 
-		overseer_signal(
-			&mut virtual_overseer,
-			OverseerSignal::ActiveLeaves(ActiveLeavesUpdate {
-				activated: vec![(new_leaf_1, Arc::new(JaegerSpan::Disabled)), (new_leaf_2, Arc::new(JaegerSpan::Disabled))].into(),
-				deactivated: vec![].into(),
-			}),
-		).await;
+		records.sort();
+		records
+	};
+
+	let mut tx = DBTransaction::new();
+	let incremental_next = stage_changed_pov_pruning(
+		&stores[0],
+		4,
+		POV_PRUNING_INCREMENTAL_PERCENT,
+		&mut tx,
+		changed(&mut changed_buckets),
+		3,
+		&changed_buckets,
+	);
+	stores[0].write(tx).unwrap();
+
+	let mut tx = DBTransaction::new();
+	let full_next = stage_pov_pruning_presorted(&stores[1], 4, &mut tx, changed(&mut HashSet::new()));
+	stores[1].write(tx).unwrap();
+
+	assert_eq!(
+		stores[0].iter(columns::META).collect::<Vec<_>>(),
+		stores[1].iter(columns::META).collect::<Vec<_>>(),
+	);
+	assert_eq!(incremental_next.map(|next| next.0), full_next.map(|next| next.0));
+	assert_eq!(pov_pruning(&stores[0]).unwrap().len(), 40);
+}
 
-		assert_matches!(
-			overseer_recv(&mut virtual_overseer).await,
-			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
-				leaf,
-				RuntimeApiRequest::CandidateEvents(tx),
-			)) => {
-				assert_eq!(leaf, new_leaf_1);
-				tx.send(Ok(vec![
-					CandidateEvent::CandidateIncluded(candidate_1, HeadData::default()),
-				])).unwrap();
-			}
+#[test]
+fn presorted_pov_pruning_is_written_like_sorted_records() {
+	let test_state = TestState::default();
+
+	let records = |n: u8| (0..n).map(|i| PoVPruningRecord {
+		candidate_hash: CandidateHash(Hash::repeat_byte(i)),
+		block_number: i as BlockNumber,
+		candidate_state: CandidateState::Stored,
+		prune_at: PruningDelay::In(Duration::from_secs(1000 * i as u64)),
+	}).collect::<Vec<_>>();
+
+	// Below and past the spill threshold.
+	for n in vec![3, 6] {
+		let sorted_store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+		let presorted_store: Arc<dyn KeyValueDB> = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+
+		let mut sorted = AvailabilityStoreSubsystem::with_db(
+			sorted_store.clone(),
+			test_state.pruning_config.clone(),
+			Metrics(None),
 		);
+		sorted.pruning_config.pov_pruning_spill_threshold = 4;
 
-		assert_matches!(
-			overseer_recv(&mut virtual_overseer).await,
-			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
-				leaf,
-				RuntimeApiRequest::CandidateEvents(tx),
-			)) => {
-				assert_eq!(leaf, new_leaf_2);
-				tx.send(Ok(vec![
-					CandidateEvent::CandidateIncluded(candidate_2, HeadData::default()),
-				])).unwrap();
-			}
+		let mut presorted = AvailabilityStoreSubsystem::with_db(
+			presorted_store.clone(),
+			test_state.pruning_config.clone(),
+			Metrics(None),
 		);
+		presorted.pruning_config.pov_pruning_spill_threshold = 4;
 
-		overseer_signal(
-			&mut virtual_overseer,
-			OverseerSignal::BlockFinalized(new_leaf_1, 5)
-		).await;
+		put_pov_pruning(&mut sorted, None, records(n)).unwrap();
+		put_pov_pruning_presorted(&mut presorted, None, records(n)).unwrap();
 
-		// Data of both candidates should be still present in the DB.
 		assert_eq!(
-			query_available_data(&mut virtual_overseer, candidate_1_hash).await.unwrap(),
-			available_data_1,
+			sorted_store.iter(columns::META).collect::<Vec<_>>(),
+			presorted_store.iter(columns::META).collect::<Vec<_>>(),
 		);
-
 		assert_eq!(
-			query_available_data(&mut virtual_overseer, candidate_2_hash).await.unwrap(),
-			available_data_2,
+			sorted.next_pov_pruning.map(|next| next.0),
+			presorted.next_pov_pruning.map(|next| next.0),
 		);
-		// Wait for longer than finalized blocks should be kept for
-		Delay::new(test_state.pruning_config.keep_finalized_block_for + Duration::from_secs(1)).await;
+	}
+}
 
-		// Data of both candidates should be gone now.
-		assert!(
-			query_available_data(&mut virtual_overseer, candidate_1_hash).await.is_none(),
-		);
+#[test]
+fn stored_data_records_its_coding_version() {
+	let store = Arc::new(kvdb_memorydb::create(columns::NUM_COLUMNS));
+	let test_state = TestState::default();
+	let candidate_hash = CandidateHash(Hash::repeat_byte(1));
+	let n_validators = 10;
 
-		assert!(
-			query_available_data(&mut virtual_overseer, candidate_2_hash).await.is_none(),
+	let subsystem = AvailabilityStoreSubsystem::with_db(
+		store.clone(),
+		test_state.pruning_config.clone(),
+		Metrics(None),
+	);
+
+	test_harness_with_subsystem(subsystem, |test_harness| async move {
+		let TestHarness { mut virtual_overseer } = test_harness;
+
+		let available_data = AvailableData {
+			pov: Arc::new(PoV { block_data: BlockData(vec![4, 5, 6]) }),
+			validation_data: test_state.persisted_validation_data.clone(),
+		};
+		let chunks = get_chunks(&available_data, n_validators as usize, &Metrics(None)).unwrap();
+
+		// Candidates stored before the version was recorded are read as version 1.
+		assert_eq!(coding_version(&store, &candidate_hash), ErasureCodingVersion::V1);
+		assert!(store.get(columns::META, &coding_version_key(&candidate_hash)).unwrap().is_none());
+
+		let (tx, rx) = oneshot::channel();
+		overseer_send(&mut virtual_overseer, AvailabilityStoreMessage::StoreAvailableData(
+			candidate_hash,
+			None,
+			n_validators,
+			available_data,
+			None,
+			ErasureCodingVersion::V1,
+			tx,
+		)).await;
+		rx.await.unwrap().unwrap();
+
+		assert_eq!(
+			store.get(columns::META, &coding_version_key(&candidate_hash)).unwrap(),
+			Some(ErasureCodingVersion::V1.encode()),
 		);
+
+		// Chunks regenerated from the stored data use the recorded version.
+		for chunk in &chunks {
+			assert_eq!(
+				query_chunk(&mut virtual_overseer, candidate_hash, chunk.index).await.as_ref(),
+				Some(chunk),
+			);
+		}
 	});
 }
 
@@ -829,6 +6545,18 @@ async fn query_available_data(
 	rx.await.unwrap()
 }
 
+async fn query_candidate_receipt(
+	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityStoreMessage>,
+	candidate_hash: CandidateHash,
+) -> Option<CandidateReceipt> {
+	let (tx, rx) = oneshot::channel();
+
+	let query = AvailabilityStoreMessage::QueryCandidateReceipt(candidate_hash, tx);
+	virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+	rx.await.unwrap()
+}
+
 async fn query_chunk(
 	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityStoreMessage>,
 	candidate_hash: CandidateHash,
@@ -841,3 +6569,38 @@ async fn query_chunk(
 
 	rx.await.unwrap()
 }
+
+async fn query_validator_count(
+	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityStoreMessage>,
+	candidate_hash: CandidateHash,
+) -> Option<u32> {
+	let (tx, rx) = oneshot::channel();
+
+	let query = AvailabilityStoreMessage::QueryValidatorCount(candidate_hash, tx);
+	virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+	rx.await.unwrap()
+}
+
+async fn query_candidate_state(
+	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityStoreMessage>,
+	candidate_hash: CandidateHash,
+) -> Option<CandidateState> {
+	let (tx, rx) = oneshot::channel();
+
+	let query = AvailabilityStoreMessage::QueryCandidateState(candidate_hash, tx);
+	virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+	rx.await.unwrap()
+}
+
+async fn query_health(
+	virtual_overseer: &mut test_helpers::TestSubsystemContextHandle<AvailabilityStoreMessage>,
+) -> Health {
+	let (tx, rx) = oneshot::channel();
+
+	let query = AvailabilityStoreMessage::QueryHealth(tx);
+	virtual_overseer.send(FromOverseer::Communication{ msg: query }).await;
+
+	rx.await.unwrap()
+}
@@ -40,7 +40,7 @@ use polkadot_subsystem::{
 	JaegerSpan, PerLeafSpan,
 	messages::{
 		AllMessages, AvailabilityStoreMessage, CandidateBackingMessage, CandidateSelectionMessage,
-		CandidateValidationMessage, PoVDistributionMessage, ProvisionableData,
+		CandidateValidationMessage, ErasureCodingVersion, PoVDistributionMessage, ProvisionableData,
 		ProvisionerMessage, StatementDistributionMessage, ValidationFailed, RuntimeApiRequest,
 	},
 };
@@ -274,6 +274,9 @@ async fn store_available_data(
 				id,
 				n_validators,
 				available_data,
+				// The erasure root was already checked by `make_pov_available`.
+				None,
+				ErasureCodingVersion::V1,
 				tx,
 			)
 		).into()
@@ -1461,7 +1464,7 @@ mod tests {
 			assert_matches!(
 				virtual_overseer.recv().await,
 				AllMessages::AvailabilityStore(
-					AvailabilityStoreMessage::StoreAvailableData(candidate_hash, _, _, _, tx)
+					AvailabilityStoreMessage::StoreAvailableData(candidate_hash, _, _, _, _, _, tx)
 				) if candidate_hash == candidate.hash() => {
 					tx.send(Ok(())).unwrap();
 				}
@@ -1599,7 +1602,7 @@ mod tests {
 			assert_matches!(
 				virtual_overseer.recv().await,
 				AllMessages::AvailabilityStore(
-					AvailabilityStoreMessage::StoreAvailableData(candidate_hash, _, _, _, tx)
+					AvailabilityStoreMessage::StoreAvailableData(candidate_hash, _, _, _, _, _, tx)
 				) if candidate_hash == candidate_a.hash() => {
 					tx.send(Ok(())).unwrap();
 				}
@@ -1894,7 +1897,7 @@ mod tests {
 			assert_matches!(
 				virtual_overseer.recv().await,
 				AllMessages::AvailabilityStore(
-					AvailabilityStoreMessage::StoreAvailableData(candidate_hash, _, _, _, tx)
+					AvailabilityStoreMessage::StoreAvailableData(candidate_hash, _, _, _, _, _, tx)
 				) if candidate_hash == candidate_a.hash() => {
 						tx.send(Ok(())).unwrap();
 					}
@@ -2079,7 +2082,7 @@ mod tests {
 			assert_matches!(
 				virtual_overseer.recv().await,
 				AllMessages::AvailabilityStore(
-					AvailabilityStoreMessage::StoreAvailableData(candidate_hash, _, _, _, tx)
+					AvailabilityStoreMessage::StoreAvailableData(candidate_hash, _, _, _, _, _, tx)
 				) if candidate_hash == candidate_b.hash() => {
 					tx.send(Ok(())).unwrap();
 				}
@@ -42,7 +42,7 @@ use polkadot_primitives::v1::{
 };
 use polkadot_subsystem::messages::{
 	AllMessages, AvailabilityDistributionMessage, AvailabilityStoreMessage, ChainApiMessage,
-	NetworkBridgeMessage, RuntimeApiMessage, RuntimeApiRequest,
+	NetworkBridgeMessage, RuntimeApiMessage, RuntimeApiRequest, StoreError,
 };
 use polkadot_subsystem::{
 	jaeger, errors::{ChainApiError, RuntimeApiError},
@@ -700,15 +700,16 @@ where
 		// save the chunk for our index
 		if Some(message.erasure_chunk.index) == candidate_entry.validator_index {
 			let _span = span.child("store-our-chunk");
-			if store_chunk(
+			if let Err(err) = store_chunk(
 				ctx,
 				message.candidate_hash,
 				candidate_entry.descriptor.relay_parent,
 				message.erasure_chunk.index,
 				message.erasure_chunk.clone(),
-			).await?.is_err() {
+			).await? {
 				tracing::warn!(
 					target: LOG_TARGET,
+					err = ?err,
 					"Failed to store erasure chunk to availability store"
 				);
 			}
@@ -1015,7 +1016,7 @@ async fn store_chunk<Context>(
 	relay_parent: Hash,
 	validator_index: ValidatorIndex,
 	erasure_chunk: ErasureChunk,
-) -> Result<std::result::Result<(), ()>>
+) -> Result<std::result::Result<(), StoreError>>
 where
 	Context: SubsystemContext<Message = AvailabilityDistributionMessage>,
 {
@@ -1024,6 +1025,7 @@ where
 		AvailabilityStoreMessage::StoreChunk {
 			candidate_hash,
 			relay_parent,
+			block_number: None,
 			validator_index,
 			chunk: erasure_chunk,
 			tx,
@@ -24,6 +24,7 @@
 
 use futures::channel::{mpsc, oneshot};
 use thiserror::Error;
+use parity_scale_codec::{Encode, Decode};
 use polkadot_node_network_protocol::{
 	v1 as protocol_v1, NetworkBridgeEvent, ReputationChange, PeerId,
 };
@@ -39,7 +40,7 @@ use polkadot_primitives::v1::{
 	ValidationCode, ValidatorId, ValidationData, CandidateHash,
 	ValidatorIndex, ValidatorSignature, InboundDownwardMessage, InboundHrmpMessage,
 };
-use std::{sync::Arc, collections::btree_map::BTreeMap};
+use std::{sync::Arc, collections::btree_map::BTreeMap, time::Duration};
 
 /// Subsystem messages where each message is always bound to a relay parent.
 pub trait BoundToRelayParent {
@@ -278,12 +279,141 @@ impl BoundToRelayParent for BitfieldSigningMessage {
 	}
 }
 
+/// The state of a candidate tracked by the availability store.
+#[derive(Debug, Clone, Copy, Decode, Encode, Eq, PartialEq)]
+pub enum CandidateState {
+	/// The candidate was stored but not yet seen included in a block.
+	Stored,
+	/// The candidate was included in a block.
+	Included,
+	/// The block the candidate was included in was finalized.
+	Finalized,
+}
+
+/// The version of the erasure coding the `AvailableData` of a candidate is split into chunks with.
+#[derive(Debug, Clone, Copy, Decode, Encode, Eq, PartialEq)]
+pub enum ErasureCodingVersion {
+	/// `polkadot_erasure_coding::obtain_chunks_v1`.
+	V1,
+}
+
+impl Default for ErasureCodingVersion {
+	fn default() -> Self {
+		Self::V1
+	}
+}
+
+/// What the availability store holds of a candidate.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AvailabilityStatus {
+	/// Whether the full `AvailableData` is stored.
+	pub has_full_data: bool,
+	/// The number of distinct chunks stored.
+	pub chunks_held: u32,
+	/// The number of validators the `AvailableData` was erasure-coded for, if known.
+	pub n_validators: Option<u32>,
+	/// Whether enough chunks are stored to reconstruct the `AvailableData` from them.
+	pub can_reconstruct: bool,
+}
+
+/// The footprint of the availability store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageStats {
+	/// The number of candidates with stored data or chunks.
+	pub candidates: u32,
+	/// The total number of stored chunks.
+	pub chunks: u32,
+	/// The size of the keys and values stored in the data column in bytes.
+	pub data_bytes: u64,
+	/// The size of the keys and values stored in the meta column in bytes.
+	pub meta_bytes: u64,
+}
+
+/// Whether the availability store is operational.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Health {
+	/// Whether the database could be read from.
+	pub db_reachable: bool,
+	/// The last error the subsystem ran into while processing messages and signals, if any.
+	pub last_error: Option<String>,
+	/// The time since UNIX_EPOCH at which stored data is pruned next, if scheduled.
+	pub next_pov_pruning: Option<Duration>,
+	/// The time since UNIX_EPOCH at which chunks are pruned next, if scheduled.
+	pub next_chunk_pruning: Option<Duration>,
+}
+
+/// The outcome of checking every value of the availability store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+	/// The number of values checked.
+	pub checked: usize,
+	/// The candidates with values that failed to decode or chunks that don't match their
+	/// erasure root, in ascending order.
+	pub corrupt: Vec<CandidateHash>,
+}
+
+/// The reason a store operation of the availability store failed.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum StoreError {
+	/// The data could not be erasure coded.
+	#[error("Erasure coding failed")]
+	Erasure,
+	/// The block number following the relay parent overflows.
+	#[error("Block number overflow")]
+	BlockNumberOverflow,
+	/// The relay parent is not known to the chain.
+	#[error("Unknown relay parent")]
+	UnknownRelayParent,
+	/// The merkle proof of the chunk does not match the erasure root of the stored data.
+	#[error("Invalid chunk proof")]
+	InvalidChunkProof,
+	/// Reading from or writing to the database failed.
+	#[error("Database error")]
+	Database,
+	/// The store was opened in read-only mode.
+	#[error("The store is read-only")]
+	ReadOnly,
+	/// Too many stores are already waiting to be processed.
+	#[error("The store is overloaded")]
+	Overloaded,
+	/// The erasure root of the data does not match the expected one.
+	#[error("Erasure root mismatch")]
+	ErasureRootMismatch,
+	/// The data cannot be erasure-coded for the given number of validators.
+	#[error("Invalid validator count")]
+	InvalidValidatorCount,
+	/// The chunk index is not below the number of validators.
+	#[error("Chunk index out of range")]
+	ChunkIndexOutOfRange,
+	/// The requested expiry time has already passed.
+	#[error("Expiry time in the past")]
+	ExpiryInThePast,
+	/// Nothing is stored for the candidate.
+	#[error("Unknown candidate")]
+	UnknownCandidate,
+	/// Any other internal failure.
+	#[error("Internal error")]
+	Internal,
+}
+
 /// Availability store subsystem message.
 #[derive(Debug)]
 pub enum AvailabilityStoreMessage {
 	/// Query a `AvailableData` from the AV store.
 	QueryAvailableData(CandidateHash, oneshot::Sender<Option<AvailableData>>),
 
+	/// Query a `AvailableData` from the AV store together with the number of validators
+	/// it was erasure-coded for.
+	QueryAvailableDataWithMeta(CandidateHash, oneshot::Sender<Option<(AvailableData, u32)>>),
+
+	/// Query the stored value of a `AvailableData` as raw bytes, for consumers that forward it
+	/// without decoding.
+	///
+	/// The bytes start with the SCALE-encoded `AvailableData`, followed by the encoded number of
+	/// validators it was erasure-coded for and possibly a checksum. Decoding an `AvailableData`
+	/// from them ignores the trailing bytes. The checksum is not verified.
+	QueryAvailableDataRaw(CandidateHash, oneshot::Sender<Option<Vec<u8>>>),
+
 	/// Query whether a `AvailableData` exists within the AV Store.
 	///
 	/// This is useful in cases when existence
@@ -291,9 +421,36 @@ pub enum AvailabilityStoreMessage {
 	/// megabytes of data to get a single bit of information.
 	QueryDataAvailability(CandidateHash, oneshot::Sender<bool>),
 
+	/// Query whether the `AvailableData` of multiple candidates exists within the AV Store.
+	///
+	/// The flags are returned in the order of the queried candidates.
+	QueryDataAvailabilityBatch(Vec<CandidateHash>, oneshot::Sender<Vec<bool>>),
+
+	/// Query whether the full `AvailableData` of a candidate is stored and how many of its
+	/// chunks are, e.g. to judge whether the data can be reconstructed.
+	QueryAvailabilityStatus(CandidateHash, oneshot::Sender<AvailabilityStatus>),
+
 	/// Query an `ErasureChunk` from the AV store by the candidate hash and validator index.
 	QueryChunk(CandidateHash, ValidatorIndex, oneshot::Sender<Option<ErasureChunk>>),
 
+	/// Query an `ErasureChunk` along with the erasure root its proof is checked against.
+	///
+	/// Returns `None` if either the chunk or the erasure root is not known.
+	QueryChunkWithRoot(CandidateHash, ValidatorIndex, oneshot::Sender<Option<(ErasureChunk, Hash)>>),
+
+	/// Query several `ErasureChunk`s of a candidate by their validator indices.
+	///
+	/// The results are in the same order as the requested indices.
+	QueryChunksByIndices(CandidateHash, Vec<ValidatorIndex>, oneshot::Sender<Vec<Option<ErasureChunk>>>),
+
+	/// Query the `ErasureChunk`s of a candidate for a contiguous range of validator indices,
+	/// from the first index up to but excluding the second.
+	///
+	/// The results are in the order of the indices. Indices at or beyond the number of validators
+	/// the data was erasure-coded for are left out. Like `QueryChunk`, missing chunks are
+	/// regenerated from the full data if it is stored.
+	QueryChunkRange(CandidateHash, ValidatorIndex, ValidatorIndex, oneshot::Sender<Vec<Option<ErasureChunk>>>),
+
 	/// Query whether an `ErasureChunk` exists within the AV Store.
 	///
 	/// This is useful in cases like bitfield signing, when existence
@@ -301,27 +458,264 @@ pub enum AvailabilityStoreMessage {
 	/// quantities of data to get a single bit of information.
 	QueryChunkAvailability(CandidateHash, ValidatorIndex, oneshot::Sender<bool>),
 
+	/// Query whether multiple `ErasureChunk`s exist within the AV Store.
+	///
+	/// The flags are returned in the order of the queried chunks. Unlike `QueryChunkAvailability`
+	/// this is a pure presence check: chunks are not regenerated from stored `AvailableData`,
+	/// so only chunks that are stored as such are reported.
+	QueryChunkAvailabilityBatch(Vec<(CandidateHash, ValidatorIndex)>, oneshot::Sender<Vec<bool>>),
+
+	/// Query the encoded size in bytes of a stored `ErasureChunk` without fetching it.
+	///
+	/// Chunks that would have to be regenerated from the full data are reported as missing.
+	QueryChunkSize(CandidateHash, ValidatorIndex, oneshot::Sender<Option<usize>>),
+
+	/// Query only the merkle proof of a stored `ErasureChunk`, for peers that already hold the
+	/// chunk data.
+	///
+	/// Chunks that would have to be regenerated from the full data are reported as missing.
+	QueryChunkProof(CandidateHash, ValidatorIndex, oneshot::Sender<Option<Vec<Vec<u8>>>>),
+
+	/// Query the number of validators the `AvailableData` of a candidate was erasure-coded for.
+	///
+	/// This remains available for as long as either the full data or any of its chunks are kept.
+	QueryValidatorCount(CandidateHash, oneshot::Sender<Option<u32>>),
+
+	/// Query how many distinct chunks of a candidate are stored, along with the number of
+	/// validators its `AvailableData` was erasure-coded for.
+	///
+	/// Returns `None` if the number of validators is not known.
+	QueryChunkCount(CandidateHash, oneshot::Sender<Option<(u32, u32)>>),
+
+	/// Query how many chunks are stored across all candidates, e.g. for capacity planning.
+	QueryTotalChunkCount(oneshot::Sender<usize>),
+
+	/// Query the receipt of a candidate whose `AvailableData` or chunks are stored.
+	///
+	/// Returns `None` until a block reporting the inclusion of the candidate has been activated.
+	QueryCandidateReceipt(CandidateHash, oneshot::Sender<Option<CandidateReceipt>>),
+
+	/// Query the candidates of a parachain whose `AvailableData` or chunks are stored.
+	///
+	/// Like `QueryCandidateReceipt`, a candidate is only known to belong to the parachain once
+	/// a block reporting its inclusion has been activated.
+	QueryCandidatesForPara(ParaId, oneshot::Sender<Vec<CandidateHash>>),
+
+	/// Register a listener that receives the hash of every candidate whose `AvailableData` or
+	/// chunks are marked finalized from now on.
+	SubscribeToFinalized(mpsc::Sender<CandidateHash>),
+
+	/// Query the time since UNIX_EPOCH at which the `AvailableData` of a candidate was first stored.
+	QueryStoredAt(CandidateHash, oneshot::Sender<Option<Duration>>),
+
+	/// Query the pruning schedule of all stored `AvailableData`.
+	///
+	/// Returns the candidate hash, state and the time left until pruning for every record,
+	/// where `None` means the data is kept indefinitely.
+	QueryPruningSchedule(oneshot::Sender<Vec<(CandidateHash, CandidateState, Option<Duration>)>>),
+
+	/// Query which `AvailableData` and chunks the next prune would remove, without removing them.
+	///
+	/// Returns the hashes of the candidates whose data would be removed, followed by the
+	/// candidate hash and validator index of every chunk that would be removed.
+	DryRunPrune(oneshot::Sender<(Vec<CandidateHash>, Vec<(CandidateHash, ValidatorIndex)>)>),
+
+	/// Query up to the given number of candidates whose full `AvailableData` is stored, e.g. to
+	/// sample it for audits.
+	///
+	/// The candidates are the first ones in the order in which their data is going to be pruned.
+	SampleAvailableData(usize, oneshot::Sender<Vec<CandidateHash>>),
+
+	/// Query the candidates whose `AvailableData` is stored for the given block number.
+	QueryCandidatesAtBlock(BlockNumber, oneshot::Sender<Vec<CandidateHash>>),
+
+	/// Query the state the AV store has recorded for the `AvailableData` of a candidate.
+	///
+	/// Returns `None` if the data is not stored.
+	QueryCandidateState(CandidateHash, oneshot::Sender<Option<CandidateState>>),
+
+	/// Query the lowest block number of the candidates whose `AvailableData` is kept and that
+	/// are not finalized yet.
+	///
+	/// This serves as a measure of how far finality lags behind.
+	QueryOldestUnfinalized(oneshot::Sender<Option<BlockNumber>>),
+
+	/// Query the time from now until the next prune of `AvailableData` and of chunks, as recorded
+	/// in the database, for diagnosing the pruning schedule.
+	///
+	/// `None` means that nothing is scheduled to be pruned, overdue prunes are reported as zero.
+	QueryNextPruneTimes(oneshot::Sender<(Option<Duration>, Option<Duration>)>),
+
+	/// Query whether the AV store is operational.
+	QueryHealth(oneshot::Sender<Health>),
+
+	/// Query the footprint of the store.
+	///
+	/// The counts and sizes are stored along with the values and updated in the same writes,
+	/// so they are read without scanning the store.
+	QueryStorageStats(oneshot::Sender<StorageStats>),
+
+	/// Check the integrity of the store by decoding every value of a candidate and checking
+	/// the proofs of chunks whose erasure root is known.
+	///
+	/// This reads the whole database. Values that don't belong to a single candidate, like the
+	/// pruning records, are not checked.
+	VerifyIntegrity(oneshot::Sender<IntegrityReport>),
+
 	/// Store an `ErasureChunk` in the AV store.
 	///
-	/// Return `Ok(())` if the store operation succeeded, `Err(())` if it failed.
+	/// Return `Ok(())` if the store operation succeeded, `Err(StoreError)` if it failed.
 	StoreChunk {
 		/// A hash of the candidate this chunk belongs to.
 		candidate_hash: CandidateHash,
 		/// A relevant relay parent.
 		relay_parent: Hash,
+		/// The number of the block the chunk is stored for, i.e. the number of the relay parent
+		/// plus one. If `None` it is looked up through the Chain API.
+		block_number: Option<BlockNumber>,
+		/// The index of the validator this chunk belongs to.
+		validator_index: ValidatorIndex,
+		/// The chunk itself.
+		chunk: ErasureChunk,
+		/// Sending side of the channel to send result to.
+		tx: oneshot::Sender<Result<(), StoreError>>,
+	},
+
+	/// Like `StoreChunk`, but answering with the time from now until the chunk is pruned, so
+	/// callers can plan to provide it again.
+	///
+	/// The time is `None` if the chunk is kept indefinitely, e.g. as its candidate was included.
+	StoreChunkWithPruneTime {
+		/// A hash of the candidate this chunk belongs to.
+		candidate_hash: CandidateHash,
+		/// A relevant relay parent.
+		relay_parent: Hash,
+		/// The number of the block the chunk is stored for. If `None` it is looked up through
+		/// the Chain API.
+		block_number: Option<BlockNumber>,
 		/// The index of the validator this chunk belongs to.
 		validator_index: ValidatorIndex,
 		/// The chunk itself.
 		chunk: ErasureChunk,
 		/// Sending side of the channel to send result to.
-		tx: oneshot::Sender<Result<(), ()>>,
+		tx: oneshot::Sender<Result<Option<Duration>, StoreError>>,
+	},
+
+	/// Import chunks provided from elsewhere, e.g. during a fast sync, along with the number of
+	/// validators they were erasure-coded for, the block number and the state of their candidate.
+	///
+	/// The chunks are scheduled for pruning according to the state of their candidate and are
+	/// either all stored or none of them. The import fails if the index of a chunk is not below
+	/// the number of validators given with it.
+	///
+	/// Return `Ok(())` if the import succeeded, `Err(StoreError)` if it failed.
+	ImportChunks {
+		/// The chunks along with their candidate hash, the number of validators their candidate
+		/// was erasure-coded for, the number of the block their candidate was stored for and
+		/// candidate state.
+		records: Vec<(CandidateHash, u32, ErasureChunk, BlockNumber, CandidateState)>,
+		/// Sending side of the channel to send result to.
+		tx: oneshot::Sender<Result<(), StoreError>>,
 	},
 
 	/// Store a `AvailableData` in the AV store.
 	/// If `ValidatorIndex` is present store corresponding chunk also.
+	/// If an erasure root is present the data is rejected unless its chunks have that root.
+	/// The data is erasure-coded with the given version of the erasure coding, which is recorded
+	/// so that chunks regenerated from the data later on match.
+	///
+	/// If `ValidatorIndex` is present the erasure coding is done in the background, so messages
+	/// sent after this one, including queries for the same candidate, may be answered before
+	/// the data is stored. Wait for the response before relying on the data being stored.
 	///
-	/// Return `Ok(())` if the store operation succeeded, `Err(())` if it failed.
-	StoreAvailableData(CandidateHash, Option<ValidatorIndex>, u32, AvailableData, oneshot::Sender<Result<(), ()>>),
+	/// Return `Ok(())` if the store operation succeeded, `Err(StoreError)` if it failed.
+	StoreAvailableData(
+		CandidateHash,
+		Option<ValidatorIndex>,
+		u32,
+		AvailableData,
+		Option<Hash>,
+		ErasureCodingVersion,
+		oneshot::Sender<Result<(), StoreError>>,
+	),
+
+	/// Like `StoreAvailableData`, but answering with the time from now until the data is pruned,
+	/// so callers can plan to provide it again.
+	///
+	/// The time is `None` if the data is kept indefinitely, e.g. as its candidate was included.
+	StoreAvailableDataWithPruneTime(
+		CandidateHash,
+		Option<ValidatorIndex>,
+		u32,
+		AvailableData,
+		Option<Hash>,
+		oneshot::Sender<Result<Option<Duration>, StoreError>>,
+	),
+
+	/// Like `StoreAvailableData`, but the data and its stored chunk are pruned at the given
+	/// time rather than after the usual retention of stored data.
+	///
+	/// Return `Ok(())` if the store operation succeeded, `Err(StoreError)` if it failed.
+	StoreAvailableDataWithExpiry {
+		/// A hash of the candidate the data belongs to.
+		hash: CandidateHash,
+		/// The index of the validator whose chunk is stored along with the data, if any.
+		id: Option<ValidatorIndex>,
+		/// The number of validators the data is erasure-coded for.
+		n_validators: u32,
+		/// The data itself.
+		data: AvailableData,
+		/// The time since UNIX_EPOCH at which the data is pruned. It must be in the future.
+		expire_at: Duration,
+		/// Sending side of the channel to send result to.
+		tx: oneshot::Sender<Result<(), StoreError>>,
+	},
+
+	/// Store a `AvailableData` in the AV store unless it is already present.
+	///
+	/// Behaves like `StoreAvailableData`, but skips the erasure coding and the write
+	/// and returns `Ok(())` right away if the data of the candidate is already stored.
+	StoreAvailableDataIfAbsent(
+		CandidateHash,
+		Option<ValidatorIndex>,
+		u32,
+		AvailableData,
+		oneshot::Sender<Result<(), StoreError>>,
+	),
+
+	/// Remove all data, chunks and their pruning records from the AV store.
+	///
+	/// Return `Ok(())` if the purge succeeded, the reason of the failure otherwise.
+	PurgeAll(oneshot::Sender<Result<(), StoreError>>),
+
+	/// Reconstruct a `AvailableData` from its `ErasureChunk`s and store it in the AV store.
+	///
+	/// The merkle proofs of all chunks are checked against the erasure root of the
+	/// reconstructed data. Returns the reason of the failure if the reconstruction or any
+	/// proof fails.
+	ReconstructAvailableData {
+		/// A hash of the candidate the chunks belong to.
+		candidate_hash: CandidateHash,
+		/// The number of validators the data was erasure-coded for.
+		n_validators: u32,
+		/// The chunks to reconstruct the data from.
+		chunks: Vec<ErasureChunk>,
+		/// Sending side of the channel to send result to.
+		tx: oneshot::Sender<Result<AvailableData, StoreError>>,
+	},
+
+	/// Keep the data and chunks of a candidate for at least the given `Duration` from now.
+	///
+	/// Pruning times that are already later are left untouched, a later finalization of the
+	/// block reschedules the pruning as usual. Returns `StoreError::UnknownCandidate` if
+	/// nothing is stored for the candidate.
+	ExtendRetention(CandidateHash, Duration, oneshot::Sender<Result<(), StoreError>>),
+
+	/// Prune the data and chunks whose pruning time has passed right away instead of waiting
+	/// for the pruning timer, e.g. to inspect pruning while debugging.
+	///
+	/// Signals completion once the prune pass is done.
+	RunPruningNow(oneshot::Sender<()>),
 }
 
 impl AvailabilityStoreMessage {
@@ -331,6 +725,40 @@ impl AvailabilityStoreMessage {
 			_ => None,
 		}
 	}
+
+	/// The hash of the candidate the message is about, if it concerns a single one.
+	pub fn candidate_hash(&self) -> Option<CandidateHash> {
+		use AvailabilityStoreMessage::*;
+
+		match self {
+			QueryAvailableData(hash, _) |
+			QueryAvailableDataWithMeta(hash, _) |
+			QueryAvailableDataRaw(hash, _) |
+			QueryDataAvailability(hash, _) |
+			QueryAvailabilityStatus(hash, _) |
+			QueryChunk(hash, ..) |
+			QueryChunkWithRoot(hash, ..) |
+			QueryChunksByIndices(hash, ..) |
+			QueryChunkRange(hash, ..) |
+			QueryChunkAvailability(hash, ..) |
+			QueryChunkSize(hash, ..) |
+			QueryChunkProof(hash, ..) |
+			QueryValidatorCount(hash, _) |
+			QueryChunkCount(hash, _) |
+			QueryCandidateReceipt(hash, _) |
+			QueryStoredAt(hash, _) |
+			QueryCandidateState(hash, _) |
+			StoreChunk { candidate_hash: hash, .. } |
+			StoreChunkWithPruneTime { candidate_hash: hash, .. } |
+			StoreAvailableData(hash, ..) |
+			StoreAvailableDataWithPruneTime(hash, ..) |
+			StoreAvailableDataWithExpiry { hash, .. } |
+			StoreAvailableDataIfAbsent(hash, ..) |
+			ReconstructAvailableData { candidate_hash: hash, .. } |
+			ExtendRetention(hash, ..) => Some(*hash),
+			_ => None,
+		}
+	}
 }
 
 /// A response channel for the result of a chain API request.